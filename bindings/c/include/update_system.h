@@ -13,6 +13,52 @@ extern char* c_local_state(
   void *data
 );
 
+/// HTTP(S) client options of the repository
+typedef struct CHttpsOptions CHttpsOptions;
+
+extern CHttpsOptions* c_https_options_new();
+extern void c_https_options_free(CHttpsOptions* https_options);
+
+/// The setters below return 1 on success, 0 if a pointer is null or a string isn't UTF-8
+
+/// Send every request through this proxy (http://, https:// or socks5://)
+extern uint8_t c_https_options_set_proxy(CHttpsOptions* https_options, const char* url);
+
+/// Timeouts in milliseconds, 0 means no timeout
+/// the read timeout is the maximum duration without receiving any byte
+extern uint8_t c_https_options_set_timeouts(
+  CHttpsOptions* https_options,
+  uint64_t connect_timeout_ms,
+  uint64_t read_timeout_ms
+);
+
+/// Add `name: value` to every request headers
+extern uint8_t c_https_options_add_header(
+  CHttpsOptions* https_options,
+  const char* name,
+  const char* value
+);
+
+extern uint8_t c_https_options_set_user_agent(CHttpsOptions* https_options, const char* user_agent);
+
+/// Trust the root certificates of `certificate` (PEM bundle or DER)
+extern uint8_t c_https_options_add_root_certificate(
+  CHttpsOptions* https_options,
+  const uint8_t* certificate,
+  size_t certificate_len
+);
+
+/// `provider` is called before every request, possibly from another thread
+/// it returns the token (nullable, ignored if not UTF-8) and must keep it valid until the next call
+/// if `query` is 0, the token is sent as an `Authorization: Bearer` header,
+/// otherwise it's appended to the url query string (i.e. "Expires=...&Signature=...")
+extern uint8_t c_https_options_set_token_provider(
+  CHttpsOptions* https_options,
+  uint8_t query,
+  const char* (*provider)(void*),
+  void *data
+);
+
 typedef struct {
   const char *version;
   const char *description;
@@ -29,6 +75,16 @@ extern uint8_t c_version_info(
   void *data
 );
 
+extern uint8_t c_version_info_with_options(
+  const char* repository_url,
+  const char* username, /* nullable */
+  const char* password, /* nullable */
+  const CHttpsOptions* https_options, /* nullable */
+  const char* version, /* nullable */
+  void (*version_callback)(const char *err, const CRemoteVersion* info, void*),
+  void *data
+);
+
 typedef struct {
   size_t packages_start;
   size_t packages_end;
@@ -55,8 +111,22 @@ typedef struct {
   double applied_output_bytes_per_sec;
 } CGlobalProgression;
 
+/// Download rate limit of updates, it can be changed from any thread while
+/// updates use it and freed before they end
+typedef struct CBandwidthLimit CBandwidthLimit;
+
+/// `bytes_per_sec` is the maximum download rate, 0 means unlimited
+extern CBandwidthLimit* c_bandwidth_limit_new(uint64_t bytes_per_sec);
+extern void c_bandwidth_limit_set(
+  const CBandwidthLimit* bandwidth_limit, /* nullable */
+  uint64_t bytes_per_sec
+);
+extern void c_bandwidth_limit_free(CBandwidthLimit* bandwidth_limit);
+
 /// Update workspace to goal_version
 /// if `goal_version` is nullptr, update to latest version
+/// if `progress_callback` returns 0, the update is cancelled once its state is
+/// saved, the next update resumes it
 extern uint8_t c_update_workspace(
   const char* workspace_path,
   const char* repository_url,
@@ -67,6 +137,31 @@ extern uint8_t c_update_workspace(
   void *data
 );
 
+extern uint8_t c_update_workspace_with_options(
+  const char* workspace_path,
+  const char* repository_url,
+  const char* username, /* nullable */
+  const char* password, /* nullable */
+  const CHttpsOptions* https_options, /* nullable */
+  const char* goal_version, /* nullable */
+  uint8_t (*progress_callback)(const char *err, const CGlobalProgression* progression, void*),
+  void *data
+);
+
+/// Same as `c_update_workspace_with_options`, downloads are limited by
+/// `bandwidth_limit`
+extern uint8_t c_update_workspace_with_bandwidth_limit(
+  const char* workspace_path,
+  const char* repository_url,
+  const char* username, /* nullable */
+  const char* password, /* nullable */
+  const CHttpsOptions* https_options, /* nullable */
+  const CBandwidthLimit* bandwidth_limit, /* nullable */
+  const char* goal_version, /* nullable */
+  uint8_t (*progress_callback)(const char *err, const CGlobalProgression* progression, void*),
+  void *data
+);
+
 typedef struct {
   size_t files_start;
   size_t files_end;
@@ -5,15 +5,16 @@ use std::io;
 use std::os::raw::{c_char, c_void};
 use std::path::Path;
 use std::ptr;
+use std::time::Duration;
 use std::{ffi::CStr, ops::Deref};
 
 use futures::prelude::*;
 use log::info;
-use speedupdate::link::{AutoRepository, RemoteRepository};
+use speedupdate::link::{AutoRepository, HttpsRepositoryOptions, RemoteRepository, Token};
 use speedupdate::metadata::v1::State;
 use speedupdate::metadata::{CleanName, Versions};
 use speedupdate::workspace::progress::SharedUpdateProgress;
-use speedupdate::workspace::{UpdateError, UpdateOptions, Workspace};
+use speedupdate::workspace::{BandwidthLimit, UpdateError, UpdateOptions, Workspace};
 
 #[repr(C)]
 pub struct CLocalState {
@@ -62,6 +63,152 @@ pub extern "C" fn c_local_state(
     }
 }
 
+/// HTTP(S) client options of the repository
+pub struct CHttpsOptions {
+    options: HttpsRepositoryOptions,
+}
+
+impl CHttpsOptions {
+    /// Apply `f` to the options behind `https_options`, 1 on success, 0 if it's null
+    fn update(
+        https_options: *mut CHttpsOptions,
+        f: impl FnOnce(HttpsRepositoryOptions) -> HttpsRepositoryOptions,
+    ) -> u8 {
+        match unsafe { https_options.as_mut() } {
+            Some(https_options) => {
+                https_options.options = f(std::mem::take(&mut https_options.options));
+                1
+            }
+            None => 0,
+        }
+    }
+}
+
+/// `https_options` or the default ones if it's null
+fn https_options_or_default(https_options: *const CHttpsOptions) -> HttpsRepositoryOptions {
+    match unsafe { https_options.as_ref() } {
+        Some(https_options) => https_options.options.clone(),
+        None => HttpsRepositoryOptions::default(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn c_https_options_new() -> *mut CHttpsOptions {
+    Box::into_raw(Box::new(CHttpsOptions { options: HttpsRepositoryOptions::default() }))
+}
+
+#[no_mangle]
+pub extern "C" fn c_https_options_free(https_options: *mut CHttpsOptions) {
+    if !https_options.is_null() {
+        drop(unsafe { Box::from_raw(https_options) });
+    }
+}
+
+/// Owned copy of the C string, `None` if it's null or isn't UTF-8
+fn owned_str(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(s) }.to_str().ok().map(str::to_owned)
+}
+
+#[no_mangle]
+pub extern "C" fn c_https_options_set_proxy(
+    https_options: *mut CHttpsOptions,
+    url: *const c_char,
+) -> u8 {
+    match owned_str(url) {
+        Some(url) => CHttpsOptions::update(https_options, |options| options.proxy(url)),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn c_https_options_set_timeouts(
+    https_options: *mut CHttpsOptions,
+    connect_timeout_ms: u64,
+    read_timeout_ms: u64,
+) -> u8 {
+    CHttpsOptions::update(https_options, |mut options| {
+        if connect_timeout_ms > 0 {
+            options = options.connect_timeout(Duration::from_millis(connect_timeout_ms));
+        }
+        if read_timeout_ms > 0 {
+            options = options.read_timeout(Duration::from_millis(read_timeout_ms));
+        }
+        options
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn c_https_options_add_header(
+    https_options: *mut CHttpsOptions,
+    name: *const c_char,
+    value: *const c_char,
+) -> u8 {
+    match (owned_str(name), owned_str(value)) {
+        (Some(name), Some(value)) => {
+            CHttpsOptions::update(https_options, |options| options.header(name, value))
+        }
+        _ => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn c_https_options_set_user_agent(
+    https_options: *mut CHttpsOptions,
+    user_agent: *const c_char,
+) -> u8 {
+    match owned_str(user_agent) {
+        Some(user_agent) => {
+            CHttpsOptions::update(https_options, |options| options.user_agent(user_agent))
+        }
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn c_https_options_add_root_certificate(
+    https_options: *mut CHttpsOptions,
+    certificate: *const u8,
+    certificate_len: usize,
+) -> u8 {
+    if certificate.is_null() {
+        return 0;
+    }
+    let certificate = unsafe { std::slice::from_raw_parts(certificate, certificate_len) }.to_vec();
+    CHttpsOptions::update(https_options, |options| options.root_certificate(certificate))
+}
+
+struct CTokenProvider {
+    query: bool,
+    provider: extern "C" fn(*mut c_void) -> *const c_char,
+    data: *mut c_void,
+}
+
+// The provider is required to be callable from any thread
+unsafe impl Send for CTokenProvider {}
+unsafe impl Sync for CTokenProvider {}
+
+impl CTokenProvider {
+    fn token(&self) -> Option<Token> {
+        let token = (self.provider)(self.data);
+        let token = owned_str(token)?;
+        Some(if self.query { Token::Query(token) } else { Token::Bearer(token) })
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn c_https_options_set_token_provider(
+    https_options: *mut CHttpsOptions,
+    query: u8,
+    provider: extern "C" fn(*mut c_void) -> *const c_char,
+    data: *mut c_void,
+) -> u8 {
+    let provider = CTokenProvider { query: query != 0, provider, data };
+    CHttpsOptions::update(https_options, |options| options.token_provider(move || provider.token()))
+}
+
 #[repr(C)]
 pub struct CRemoteVersion {
     pub version: *const c_char,
@@ -76,6 +223,27 @@ pub extern "C" fn c_version_info(
     version: *const c_char,
     version_callback: extern "C" fn(*const c_char, *const CRemoteVersion, *mut c_void),
     data: *mut c_void,
+) -> u8 {
+    c_version_info_with_options(
+        repository_url,
+        username,
+        password,
+        ptr::null(),
+        version,
+        version_callback,
+        data,
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn c_version_info_with_options(
+    repository_url: *const c_char,
+    username: *const c_char,
+    password: *const c_char,
+    https_options: *const CHttpsOptions,
+    version: *const c_char,
+    version_callback: extern "C" fn(*const c_char, *const CRemoteVersion, *mut c_void),
+    data: *mut c_void,
 ) -> u8 {
     let _ = env_logger::try_init();
     let repository_url = unsafe { CStr::from_ptr(repository_url) }.to_str().unwrap();
@@ -93,8 +261,10 @@ pub extern "C" fn c_version_info(
         ))
     };
 
-    let res = AutoRepository::new(repository_url, auth).map_err(UpdateError::Repository).and_then(
-        |repository| {
+    let https_options = https_options_or_default(https_options);
+    let res = AutoRepository::with_options(repository_url, auth, https_options)
+        .map_err(UpdateError::Repository)
+        .and_then(|repository| {
             let version = if let Some(version) = version {
                 future::ok(CleanName::new(version.to_owned()).unwrap()).boxed_local()
             } else {
@@ -118,8 +288,7 @@ pub extern "C" fn c_version_info(
                     })
                     .map_err(UpdateError::Repository),
             )
-        },
-    );
+        });
     match res {
         Ok(Some(version)) => {
             let revision = CString::new(version.revision.deref()).unwrap();
@@ -171,6 +340,41 @@ pub struct CGlobalProgression {
     pub applied_output_bytes_per_sec: f64,
 }
 
+/// Download rate limit of updates
+pub struct CBandwidthLimit {
+    limit: BandwidthLimit,
+}
+
+fn bytes_per_sec(bytes_per_sec: u64) -> Option<u64> {
+    match bytes_per_sec {
+        0 => None,
+        bytes_per_sec => Some(bytes_per_sec),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn c_bandwidth_limit_new(bytes_per_sec: u64) -> *mut CBandwidthLimit {
+    let limit = BandwidthLimit::new(self::bytes_per_sec(bytes_per_sec));
+    Box::into_raw(Box::new(CBandwidthLimit { limit }))
+}
+
+#[no_mangle]
+pub extern "C" fn c_bandwidth_limit_set(
+    bandwidth_limit: *const CBandwidthLimit,
+    bytes_per_sec: u64,
+) {
+    if let Some(bandwidth_limit) = unsafe { bandwidth_limit.as_ref() } {
+        bandwidth_limit.limit.set(self::bytes_per_sec(bytes_per_sec));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn c_bandwidth_limit_free(bandwidth_limit: *mut CBandwidthLimit) {
+    if !bandwidth_limit.is_null() {
+        drop(unsafe { Box::from_raw(bandwidth_limit) });
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn c_update_workspace(
     workspace_path: *const c_char,
@@ -180,6 +384,54 @@ pub extern "C" fn c_update_workspace(
     goal_version: *const c_char,
     progress_callback: extern "C" fn(*const c_char, *const CGlobalProgression, *mut c_void) -> u8,
     data: *mut c_void,
+) -> u8 {
+    c_update_workspace_with_options(
+        workspace_path,
+        repository_url,
+        username,
+        password,
+        ptr::null(),
+        goal_version,
+        progress_callback,
+        data,
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn c_update_workspace_with_options(
+    workspace_path: *const c_char,
+    repository_url: *const c_char,
+    username: *const c_char,
+    password: *const c_char,
+    https_options: *const CHttpsOptions,
+    goal_version: *const c_char,
+    progress_callback: extern "C" fn(*const c_char, *const CGlobalProgression, *mut c_void) -> u8,
+    data: *mut c_void,
+) -> u8 {
+    c_update_workspace_with_bandwidth_limit(
+        workspace_path,
+        repository_url,
+        username,
+        password,
+        https_options,
+        ptr::null(),
+        goal_version,
+        progress_callback,
+        data,
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn c_update_workspace_with_bandwidth_limit(
+    workspace_path: *const c_char,
+    repository_url: *const c_char,
+    username: *const c_char,
+    password: *const c_char,
+    https_options: *const CHttpsOptions,
+    bandwidth_limit: *const CBandwidthLimit,
+    goal_version: *const c_char,
+    progress_callback: extern "C" fn(*const c_char, *const CGlobalProgression, *mut c_void) -> u8,
+    data: *mut c_void,
 ) -> u8 {
     let _ = env_logger::try_init();
     let workspace_path = unsafe { CStr::from_ptr(workspace_path) }.to_str().unwrap();
@@ -197,32 +449,45 @@ pub extern "C" fn c_update_workspace(
             unsafe { CStr::from_ptr(password) }.to_str().unwrap(),
         ))
     };
-    let res = update_workspace(workspace_path, repository_url, auth, goal_version, |progress| {
-        let state = progress.borrow();
-        let progress = state.histogram.progress();
-        let speed = state.histogram.speed().progress_per_sec();
-        let cprogress = CGlobalProgression {
-            packages_start: state.downloading_package_idx,
-            packages_end: state.steps.len(),
-            downloaded_files_start: progress.downloaded_files,
-            downloaded_files_end: state.download_files,
-            downloaded_bytes_start: progress.downloaded_bytes,
-            downloaded_bytes_end: state.download_bytes,
-            applied_files_start: progress.applied_files,
-            applied_files_end: state.apply_files,
-            applied_input_bytes_start: progress.applied_input_bytes,
-            applied_input_bytes_end: state.apply_input_bytes,
-            applied_output_bytes_start: progress.applied_output_bytes,
-            applied_output_bytes_end: state.apply_output_bytes,
-            failed_files: progress.failed_files,
-            downloaded_files_per_sec: speed.downloaded_files_per_sec,
-            downloaded_bytes_per_sec: speed.downloaded_bytes_per_sec,
-            applied_files_per_sec: speed.applied_files_per_sec,
-            applied_input_bytes_per_sec: speed.applied_input_bytes_per_sec,
-            applied_output_bytes_per_sec: speed.applied_output_bytes_per_sec,
-        };
-        progress_callback(ptr::null(), &cprogress, data) != 0
-    });
+    let https_options = https_options_or_default(https_options);
+    let mut update_options = UpdateOptions::default();
+    if let Some(bandwidth_limit) = unsafe { bandwidth_limit.as_ref() } {
+        update_options.bandwidth_limit = bandwidth_limit.limit.clone();
+    }
+    let res = update_workspace(
+        workspace_path,
+        repository_url,
+        auth,
+        https_options,
+        update_options,
+        goal_version,
+        |progress| {
+            let state = progress.borrow();
+            let progress = state.histogram.progress();
+            let speed = state.histogram.speed().progress_per_sec();
+            let cprogress = CGlobalProgression {
+                packages_start: state.downloading_package_idx,
+                packages_end: state.steps.len(),
+                downloaded_files_start: progress.downloaded_files,
+                downloaded_files_end: state.download_files,
+                downloaded_bytes_start: progress.downloaded_bytes,
+                downloaded_bytes_end: state.download_bytes,
+                applied_files_start: progress.applied_files,
+                applied_files_end: state.apply_files,
+                applied_input_bytes_start: progress.applied_input_bytes,
+                applied_input_bytes_end: state.apply_input_bytes,
+                applied_output_bytes_start: progress.applied_output_bytes,
+                applied_output_bytes_end: state.apply_output_bytes,
+                failed_files: progress.failed_files,
+                downloaded_files_per_sec: speed.downloaded_files_per_sec,
+                downloaded_bytes_per_sec: speed.downloaded_bytes_per_sec,
+                applied_files_per_sec: speed.applied_files_per_sec,
+                applied_input_bytes_per_sec: speed.applied_input_bytes_per_sec,
+                applied_output_bytes_per_sec: speed.applied_output_bytes_per_sec,
+            };
+            progress_callback(ptr::null(), &cprogress, data) != 0
+        },
+    );
     if let Err(err) = &res {
         let err = CString::new(format!("{}", err)).unwrap();
         progress_callback(err.as_ptr(), ptr::null(), data);
@@ -234,6 +499,8 @@ fn update_workspace<F>(
     workspace_path: &str,
     repository_url: &str,
     auth: Option<(&str, &str)>,
+    https_options: HttpsRepositoryOptions,
+    update_options: UpdateOptions,
     goal_version: Option<&str>,
     mut progress_callback: F,
 ) -> Result<(), UpdateError>
@@ -246,21 +513,29 @@ where
         repository_url,
         goal_version.unwrap_or("latest")
     );
-    let repository = AutoRepository::new(repository_url, auth).map_err(UpdateError::Repository)?;
+    let repository = AutoRepository::with_options(repository_url, auth, https_options)
+        .map_err(UpdateError::Repository)?;
     let mut workspace =
         Workspace::open(Path::new(workspace_path)).map_err(UpdateError::LocalWorkspaceError)?;
 
-    let stream = workspace
-        .update(
-            &repository,
-            goal_version.map(|v| CleanName::new(v.to_string()).unwrap()),
-            UpdateOptions::default(),
-        )
-        .try_take_while(|progress| future::ready(Ok(progress_callback(progress.clone()))));
-    let work = stream.try_for_each(|_| async { Ok(()) });
+    let (stream, handle) = workspace.update_with_handle(
+        &repository,
+        goal_version.map(|v| CleanName::new(v.to_string()).unwrap()),
+        update_options,
+    );
+    // Stopping from the callback lets the update save its state
+    let work = stream.try_for_each(|progress| {
+        if !progress_callback(progress) {
+            handle.cancel();
+        }
+        future::ready(Ok(()))
+    });
 
     let rt = tokio::runtime::Runtime::new().unwrap();
-    rt.block_on(work)
+    match rt.block_on(work) {
+        Err(UpdateError::Cancelled) => Ok(()),
+        res => res,
+    }
 }
 
 #[repr(C)]
@@ -1,7 +1,10 @@
+mod serve;
+
 use std::fmt::Display;
 use std::io::{Read, Write};
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::{fs, io};
 
 use byte_unit::Byte;
@@ -11,9 +14,12 @@ use futures::prelude::*;
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle, WeakProgressBar};
 use log::{error, info, warn};
 use parking_lot::RwLock;
-use speedupdate::metadata::{self, CleanName, Operation};
-use speedupdate::repository::{BuildOptions, CoderOptions, PackageBuilder};
-use speedupdate::workspace::{UpdateOptions, Workspace};
+use speedupdate::metadata::{self, CleanName, HashAlgorithm, Operation, Package};
+use speedupdate::repository::{
+    BuildOptions, CoderOptions, PackageBuilder, PrunePolicy, SkipStrategy, VerifyOptions,
+};
+use speedupdate::signature::SigningKey;
+use speedupdate::workspace::{AllowList, UpdateOptions, Workspace};
 use speedupdate::Repository;
 
 struct Logger {
@@ -100,6 +106,7 @@ async fn main() {
         (author: crate_authors!("\n"))
         (about: crate_description!())
         (@arg repository: -r --repository "Repository path (defaults to current directory)")
+        (@arg signing_key: --("signing-key") +takes_value "Secret key file to sign the metadata files modified by the command with (required by signed repositories)")
         (@arg debug: -d +takes_value
             possible_value("warn")
             possible_value("info")
@@ -147,6 +154,44 @@ async fn main() {
             (about: "Unregister package")
             (@arg package_metadata_name: +required "Name of the package metadata file")
         )
+        (@subcommand skip_packages =>
+            (about: "Plan, and optionally build, patch packages between non consecutive versions")
+            (@arg skip_list: --("skip-list") +takes_value "Skip list base (i.e. 4 for patches from every 4th, 16th, ... version)")
+            (@arg hub: --hub +takes_value +multiple number_of_values(1) "Hub version, with patches to the current version and from older versions")
+            (@arg build: --build "Build and register the planned packages")
+            (@arg compressor: --compressor -c +takes_value +multiple "Compressor options (i.e. \"brotli:6\")")
+            (@arg patcher: --patcher -p +takes_value +multiple "Patcher options (i.e. \"zstd:level=3;minsize=32MB\")")
+            (@arg hash: --hash +takes_value "Build version 2 packages with this hash algorithm (sha1, sha256 or blake3)")
+            (@arg component: --component +takes_value +multiple number_of_values(1) "Optional component files, as <component>=<glob> (i.e. \"fr=lang/fr/**\")")
+            (@arg preserve: --preserve +takes_value +multiple "Keep locally modified files matching this glob pattern (i.e. \"**/*.ini\")")
+            (@arg num_threads: --("num-threads") +takes_value "Number of threads to use for building")
+            (@arg build_dir: --("build-dir") +takes_value "Directory where the build process will happen")
+            (@arg no_progress: --("no-progress") "Disable progress bars")
+        )
+        (@subcommand verify =>
+            (about: "Check the repository consistency (versions graph, packages files and data ranges)")
+            (@arg deep: --deep "Also check the data hash of every operation")
+        )
+        (@subcommand gc =>
+            (about: "Remove the files of unregistered packages")
+        )
+        (@subcommand prune =>
+            (about: "Unregister the packages that aren't needed to update to the current version and remove their files")
+            (@arg keep_last: --("keep-last") +takes_value "Keep updates from the last N versions before the current one")
+            (@arg keep: --keep +takes_value +multiple number_of_values(1) "Keep updates from this version")
+        )
+        (@subcommand serve =>
+            (about: "Serve the repository over HTTP, with range requests support, until ctrl-c is pressed")
+            (@arg bind: --bind -b +takes_value default_value("127.0.0.1:8080") "Address to listen on (i.e. \"0.0.0.0:8080\" for the LAN)")
+        )
+        (@subcommand gen_key =>
+            (about: "Generate an ed25519 key to sign repository metadata")
+            (@arg key_file: +required "File to write the secret key to")
+        )
+        (@subcommand sign =>
+            (about: "Sign current, versions, packages and packages metadata files")
+            (@arg key_file: --key -k +takes_value +required "Secret key file")
+        )
         (@subcommand build_package =>
             (about: "Build package")
             (@arg version: +required "Package output version")
@@ -155,6 +200,9 @@ async fn main() {
             (@arg register: --register +takes_value "Register the built package and its version")
             (@arg compressor: --compressor -c +takes_value +multiple "Compressor options (i.e. \"brotli:6\")")
             (@arg patcher: --patcher -p +takes_value +multiple "Patcher options (i.e. \"zstd:level=3;minsize=32MB\")")
+            (@arg hash: --hash +takes_value "Build a version 2 package with this hash algorithm (sha1, sha256 or blake3)")
+            (@arg component: --component +takes_value +multiple number_of_values(1) "Optional component files, as <component>=<glob> (i.e. \"fr=lang/fr/**\")")
+            (@arg preserve: --preserve +takes_value +multiple "Keep locally modified files matching this glob pattern (i.e. \"**/*.ini\")")
             (@arg num_threads: --("num-threads") +takes_value "Number of threads to use for building")
             (@arg build_dir: --("build-dir") +takes_value "Directory where the build process will happen")
             (@arg no_progress: --("no-progress") "Disable progress bars")
@@ -179,6 +227,9 @@ async fn main() {
     };
     eprintln!("repository: {}", repository_path);
     let mut repository = Repository::new(PathBuf::from(&repository_path));
+    if let Some(key_file) = matches.value_of("signing_key") {
+        repository.set_signing_key(Some(load_key(key_file)));
+    }
 
     match matches.subcommand() {
         ("status", Some(matches)) => do_status(matches, &mut repository).await,
@@ -196,6 +247,13 @@ async fn main() {
         ("unregister_package", Some(matches)) => {
             do_unregister_package(matches, &mut repository).await
         }
+        ("skip_packages", Some(matches)) => do_skip_packages(matches, &mut repository).await,
+        ("verify", Some(matches)) => do_verify(matches, &mut repository).await,
+        ("gc", Some(matches)) => do_gc(matches, &mut repository).await,
+        ("prune", Some(matches)) => do_prune(matches, &mut repository).await,
+        ("serve", Some(matches)) => do_serve(matches, &mut repository).await,
+        ("gen_key", Some(matches)) => do_gen_key(matches, &mut repository).await,
+        ("sign", Some(matches)) => do_sign(matches, &mut repository).await,
         ("build_package", Some(matches)) => do_build_package(matches, &mut repository).await,
         _ => unreachable!(),
     };
@@ -342,6 +400,43 @@ async fn do_log(matches: &ArgMatches<'_>, repository: &mut Repository) {
     }
 }
 
+async fn do_verify(matches: &ArgMatches<'_>, repository: &mut Repository) {
+    let options = VerifyOptions { deep: matches.is_present("deep") };
+    let inconsistencies = repository.verify(&options);
+    for inconsistency in inconsistencies.iter() {
+        error!("{}", inconsistency);
+    }
+    if !inconsistencies.is_empty() {
+        error!("{} inconsistencies found", inconsistencies.len());
+        std::process::exit(1);
+    }
+    println!("repository is consistent");
+}
+
+async fn do_gc(_matches: &ArgMatches<'_>, repository: &mut Repository) {
+    let removed = try_(repository.gc(), "remove unreferenced files");
+    println!("{} files removed", removed.len());
+}
+
+async fn do_prune(matches: &ArgMatches<'_>, repository: &mut Repository) {
+    let mut policy = PrunePolicy::default();
+    if let Some(keep_last) = matches.value_of("keep_last") {
+        policy.last_versions = try_(keep_last.parse(), "convert --keep-last to integer");
+    }
+    for version in matches.values_of("keep").into_iter().flatten() {
+        policy.versions.push(try_(
+            CleanName::new(version.to_string()),
+            "convert version to clean name (i.e. [A-Za-Z0-9_.-]+)",
+        ));
+    }
+    let removed = try_(repository.prune(&policy), "prune packages");
+    for package in removed.iter() {
+        println!("unregistered {}", package.package_metadata_name());
+    }
+    let removed = try_(repository.gc(), "remove unreferenced files");
+    println!("{} files removed", removed.len());
+}
+
 fn op_file_name(op: Option<&dyn Operation>) -> String {
     op.and_then(|op| Path::new(op.path().deref()).file_name())
         .unwrap_or_default()
@@ -349,6 +444,35 @@ fn op_file_name(op: Option<&dyn Operation>) -> String {
         .into_owned()
 }
 
+async fn do_serve(matches: &ArgMatches<'_>, repository: &mut Repository) {
+    let bind = some_(matches.value_of("bind"), "no bind address provided");
+    let addr = try_(bind.parse(), "parse bind address");
+    try_(serve::serve(repository.dir().to_path_buf(), addr).await, "serve repository");
+}
+
+async fn do_gen_key(matches: &ArgMatches<'_>, _repository: &mut Repository) {
+    let key_file = some_(matches.value_of("key_file"), "no key file provided");
+    if Path::new(key_file).exists() {
+        error!("key file {} already exists", key_file);
+        std::process::exit(1);
+    }
+    let key = try_(SigningKey::generate(), "generate key");
+    try_(fs::write(key_file, key.to_base64()), "write key file");
+    println!("public key: {}", key.public_key());
+}
+
+fn load_key(key_file: &str) -> SigningKey {
+    let key = try_(fs::read_to_string(key_file), "read key file");
+    try_(SigningKey::from_base64(&key), "load key")
+}
+
+async fn do_sign(matches: &ArgMatches<'_>, repository: &mut Repository) {
+    let key_file = some_(matches.value_of("key_file"), "no key file provided");
+    let key = load_key(key_file);
+    try_(repository.sign(&key), "sign repository");
+    println!("repository signed with public key: {}", key.public_key());
+}
+
 async fn do_build_package(matches: &ArgMatches<'_>, repository: &mut Repository) {
     let source_version = some_(matches.value_of("version"), "no version provided");
     let source_version = try_(
@@ -367,6 +491,80 @@ async fn do_build_package(matches: &ArgMatches<'_>, repository: &mut Repository)
             try_(usize::from_str_radix(num_threads, 10), "convert --num-threads to integer");
         builder.set_num_threads(num_threads);
     }
+    builder.set_options(build_options(matches));
+    if let Some(from) = matches.value_of("from") {
+        let prev_directory = builder.build_directory.join(".from");
+        let prev_version = try_(
+            CleanName::new(from.to_string()),
+            "convert from version to clean name (i.e. [A-Za-Z0-9_.-]+)",
+        );
+        checkout(matches, repository, &prev_version, &prev_directory).await;
+        builder.set_previous(prev_version, prev_directory);
+    }
+
+    build(matches, &builder).await;
+    if matches.is_present("register") {
+        try_(builder.add_to_repository(repository), "register package");
+    }
+}
+
+async fn do_skip_packages(matches: &ArgMatches<'_>, repository: &mut Repository) {
+    let strategy = match (matches.value_of("skip_list"), matches.values_of("hub")) {
+        (Some(base), None) => {
+            SkipStrategy::SkipList { base: try_(base.parse(), "convert --skip-list to integer") }
+        }
+        (None, Some(hubs)) => SkipStrategy::Hubs(
+            hubs.map(|hub| {
+                try_(
+                    CleanName::new(hub.to_string()),
+                    "convert hub version to clean name (i.e. [A-Za-Z0-9_.-]+)",
+                )
+            })
+            .collect(),
+        ),
+        _ => {
+            error!("either --skip-list or --hub is required");
+            std::process::exit(1);
+        }
+    };
+    let edges = try_(repository.plan_skip_edges(&strategy), "plan skip packages");
+    for edge in edges.iter() {
+        println!("{} -> {}", edge.from, edge.to);
+    }
+    if !matches.is_present("build") {
+        return;
+    }
+
+    let build_directory = match matches.value_of("build_dir") {
+        Some(build_directory) => PathBuf::from(build_directory),
+        None => repository.dir().join(".build"),
+    };
+    let versions_directory = build_directory.join(".versions");
+    let options = Arc::new(build_options(matches));
+    for edge in edges.iter() {
+        for version in [&edge.from, &edge.to] {
+            let directory = versions_directory.join(version);
+            if !directory.exists() {
+                checkout(matches, repository, version, &directory).await;
+            }
+        }
+        let mut builder = PackageBuilder::new(
+            build_directory.clone(),
+            edge.to.clone(),
+            versions_directory.join(&edge.to),
+        );
+        if let Some(num_threads) = matches.value_of("num_threads") {
+            builder.set_num_threads(try_(num_threads.parse(), "convert --num-threads to integer"));
+        }
+        builder.options = options.clone();
+        builder.set_previous(edge.from.clone(), versions_directory.join(&edge.from));
+        build(matches, &builder).await;
+        try_(builder.add_to_repository(repository), "register package");
+    }
+    try_(fs::remove_dir_all(&versions_directory), "remove checked out versions");
+}
+
+fn build_options(matches: &ArgMatches<'_>) -> BuildOptions {
     let mut options = BuildOptions::default();
     if let Some(compressors) = matches.values_of("compressor") {
         options.compressors = compressors
@@ -377,105 +575,121 @@ async fn do_build_package(matches: &ArgMatches<'_>, repository: &mut Repository)
         options.patchers =
             patchers.map(|s| try_(CoderOptions::from_str(s), "load patcher options")).collect();
     }
-    if let Some(from) = matches.value_of("from") {
-        let prev_directory = builder.build_directory.join(".from");
-        try_(fs::create_dir_all(&prev_directory), "create from directory");
-        let prev_version = try_(
-            CleanName::new(from.to_string()),
-            "convert from version to clean name (i.e. [A-Za-Z0-9_.-]+)",
-        );
-
-        let link = repository.link();
-        let mut workspace = Workspace::open(&prev_directory).unwrap();
-        let goal_version = Some(prev_version.clone());
-        let mut update_stream = workspace.update(&link, goal_version, UpdateOptions::default());
+    if let Some(hash) = matches.value_of("hash") {
+        options.hash = Some(try_(hash.parse::<HashAlgorithm>(), "load hash algorithm"));
+    }
+    for component in matches.values_of("component").into_iter().flatten() {
+        let (name, pattern) = some_(component.split_once('='), "component must be <name>=<glob>");
+        let name = try_(CleanName::new(name.to_string()), "convert component name to clean name");
+        let files = try_(AllowList::new([pattern]), "load component pattern");
+        options.components.push((name, files));
+    }
+    if let Some(preserve) = matches.values_of("preserve") {
+        options.preserve = try_(AllowList::new(preserve), "load preserve patterns");
+    }
+    options
+}
 
-        let state = match update_stream.next().await {
-            Some(Ok(state)) => state,
-            Some(Err(err)) => {
-                error!("update failed: {}", err);
-                std::process::exit(1)
-            }
-            None => unreachable!(),
-        };
+/// Install `version` in `directory` from the repository packages
+async fn checkout(
+    matches: &ArgMatches<'_>,
+    repository: &Repository,
+    version: &CleanName,
+    directory: &Path,
+) {
+    try_(fs::create_dir_all(directory), "create checkout directory");
+    let link = repository.link();
+    let mut workspace = Workspace::open(directory).unwrap();
+    let goal_version = Some(version.clone());
+    let mut update_stream = workspace.update(&link, goal_version, UpdateOptions::default());
+
+    let state = match update_stream.next().await {
+        Some(Ok(state)) => state,
+        Some(Err(err)) => {
+            error!("update failed: {}", err);
+            std::process::exit(1)
+        }
+        None => unreachable!(),
+    };
 
-        let state = state.borrow();
-        let progress = state.histogram.progress();
+    let state = state.borrow();
+    let progress = state.histogram.progress();
 
-        let res = if matches.is_present("no_progress") {
-            update_stream.try_for_each(|_state| future::ready(Ok(()))).await
-        } else {
-            let draw_target = ProgressDrawTarget::term(Term::buffered_stdout(), 8);
-            let m = MultiProgress::with_draw_target(draw_target);
-            const DL_TPL: &str =
+    let res = if matches.is_present("no_progress") {
+        drop(state); // drop the Ref<_>
+        update_stream.try_for_each(|_state| future::ready(Ok(()))).await
+    } else {
+        let draw_target = ProgressDrawTarget::term(Term::buffered_stdout(), 8);
+        let m = MultiProgress::with_draw_target(draw_target);
+        const DL_TPL: &str =
             "Download [{wide_bar:cyan/blue}] {bytes:>8}/{total_bytes:8} ({bytes_per_sec:>10}, {eta:4}) {msg:32}";
-            const IN_TPL: &str =
+        const IN_TPL: &str =
             "Decode   [{wide_bar:cyan/blue}] {bytes:>8}/{total_bytes:8} ({bytes_per_sec:>10}, {eta:4}) {msg:32}";
-            const OU_TPL: &str =
+        const OU_TPL: &str =
                 "Install  [{wide_bar:cyan/blue}] {bytes:>8}/{total_bytes:8} ({bytes_per_sec:>10}      ) {msg:32}";
-            let sty = ProgressStyle::default_bar().progress_chars("##-");
-
-            let dl_bytes = m.add(ProgressBar::new(state.download_bytes));
-            dl_bytes.set_style(sty.clone().template(DL_TPL));
-            dl_bytes.set_position(progress.downloaded_bytes);
-            dl_bytes.reset_eta();
-
-            let apply_input_bytes = m.add(ProgressBar::new(state.apply_input_bytes));
-            apply_input_bytes.set_style(sty.clone().template(IN_TPL));
-            apply_input_bytes.set_position(progress.applied_input_bytes);
-            apply_input_bytes.reset_eta();
-
-            let apply_output_bytes = m.add(ProgressBar::new(state.apply_output_bytes));
-            apply_output_bytes.set_style(sty.clone().template(OU_TPL));
-            apply_output_bytes.set_position(progress.applied_output_bytes);
-            apply_output_bytes.reset_eta();
-
-            LOGGER.set_progress_bar(Some(dl_bytes.clone().downgrade()));
-
-            drop(state); // drop the Ref<_>
-
-            let mp = tokio::task::spawn_blocking(move || m.join());
-
-            let res = update_stream
-                .try_for_each(|state| {
-                    let state = state.borrow();
-                    let progress = state.histogram.progress();
-                    dl_bytes.set_position(progress.downloaded_bytes);
-                    dl_bytes.set_length(state.download_bytes);
-                    dl_bytes.set_message(op_file_name(
-                        state.current_step_operation(state.downloading_operation_idx),
-                    ));
-
-                    apply_input_bytes.set_position(progress.applied_input_bytes);
-                    apply_input_bytes.set_length(state.apply_input_bytes);
-                    apply_input_bytes.set_message(op_file_name(
-                        state.current_step_operation(state.applying_operation_idx),
-                    ));
-
-                    apply_output_bytes.set_position(progress.applied_output_bytes);
-                    apply_output_bytes.set_length(state.apply_output_bytes);
-                    apply_output_bytes.set_message(format!("{:?}", state.stage));
-
-                    future::ready(Ok(()))
-                })
-                .await;
-
-            dl_bytes.finish();
-            apply_input_bytes.finish();
-            apply_output_bytes.finish();
-            let _ = mp.await;
-
-            res
-        };
+        let sty = ProgressStyle::default_bar().progress_chars("##-");
 
-        if let Err(err) = res {
-            error!("update failed: {}", err);
-            std::process::exit(1)
-        }
-        try_(workspace.remove_metadata(), "remove update metadata");
-        builder.set_previous(prev_version, prev_directory);
+        let dl_bytes = m.add(ProgressBar::new(state.download_bytes));
+        dl_bytes.set_style(sty.clone().template(DL_TPL));
+        dl_bytes.set_position(progress.downloaded_bytes);
+        dl_bytes.reset_eta();
+
+        let apply_input_bytes = m.add(ProgressBar::new(state.apply_input_bytes));
+        apply_input_bytes.set_style(sty.clone().template(IN_TPL));
+        apply_input_bytes.set_position(progress.applied_input_bytes);
+        apply_input_bytes.reset_eta();
+
+        let apply_output_bytes = m.add(ProgressBar::new(state.apply_output_bytes));
+        apply_output_bytes.set_style(sty.clone().template(OU_TPL));
+        apply_output_bytes.set_position(progress.applied_output_bytes);
+        apply_output_bytes.reset_eta();
+
+        LOGGER.set_progress_bar(Some(dl_bytes.clone().downgrade()));
+
+        drop(state); // drop the Ref<_>
+
+        let mp = tokio::task::spawn_blocking(move || m.join());
+
+        let res = update_stream
+            .try_for_each(|state| {
+                let state = state.borrow();
+                let progress = state.histogram.progress();
+                dl_bytes.set_position(progress.downloaded_bytes);
+                dl_bytes.set_length(state.download_bytes);
+                dl_bytes.set_message(op_file_name(
+                    state.current_step_operation(state.downloading_operation_idx),
+                ));
+
+                apply_input_bytes.set_position(progress.applied_input_bytes);
+                apply_input_bytes.set_length(state.apply_input_bytes);
+                apply_input_bytes.set_message(op_file_name(
+                    state.current_step_operation(state.applying_operation_idx),
+                ));
+
+                apply_output_bytes.set_position(progress.applied_output_bytes);
+                apply_output_bytes.set_length(state.apply_output_bytes);
+                apply_output_bytes.set_message(format!("{:?}", state.stage));
+
+                future::ready(Ok(()))
+            })
+            .await;
+
+        dl_bytes.finish();
+        apply_input_bytes.finish();
+        apply_output_bytes.finish();
+        let _ = mp.await;
+
+        res
+    };
+
+    if let Err(err) = res {
+        error!("update failed: {}", err);
+        std::process::exit(1)
     }
+    try_(workspace.remove_metadata(), "remove update metadata");
+}
 
+async fn build(matches: &ArgMatches<'_>, builder: &PackageBuilder) {
     let mut build_stream = builder.build();
 
     let state = match build_stream.next().await {
@@ -489,6 +703,7 @@ async fn do_build_package(matches: &ArgMatches<'_>, repository: &mut Repository)
 
     let state = state.borrow();
     let res = if matches.is_present("no_progress") {
+        drop(state); // drop the Ref<_>
         build_stream.try_for_each(|_state| future::ready(Ok(()))).await
     } else {
         let draw_target = ProgressDrawTarget::term(Term::buffered_stdout(), 8);
@@ -543,8 +758,4 @@ async fn do_build_package(matches: &ArgMatches<'_>, repository: &mut Repository)
     }
 
     info!("package `{}` built", builder.package_metadata_name());
-
-    if matches.is_present("register") {
-        try_(builder.add_to_repository(repository), "register package");
-    }
 }
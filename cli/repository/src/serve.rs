@@ -0,0 +1,468 @@
+//! HTTP server of a repository directory, with `Range` requests support
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io};
+
+use byte_unit::Byte;
+use futures::prelude::*;
+use hyper::body::Bytes;
+use hyper::header;
+use hyper::server::conn::AddrIncoming;
+use hyper::server::Builder;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{error, info};
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+#[derive(Debug, Default)]
+struct FileStats {
+    requests: u64,
+    partial_requests: u64,
+    ranges: u64,
+    bytes: u64,
+}
+
+type Stats = Arc<Mutex<BTreeMap<String, FileStats>>>;
+
+/// `Range` headers with more ranges are ignored, clients send at most 64
+const MAX_RANGES: usize = 256;
+/// Size of the reads of a multipart response body
+const READ_SIZE: u64 = 64 * 1024;
+
+enum Ranges {
+    /// No `Range` header, or one that must be ignored
+    Full,
+    Satisfiable(Vec<Range<u64>>),
+    Unsatisfiable,
+}
+
+/// Parse a `Range: bytes=...` header value for a file of `len` bytes
+///
+/// Satisfiable ranges are sorted and the overlapping or adjacent ones are
+/// coalesced.
+fn parse_ranges(value: &str, len: u64) -> Ranges {
+    let specs = match value.trim().strip_prefix("bytes=") {
+        Some(specs) if specs.split(',').count() <= MAX_RANGES => specs,
+        _ => return Ranges::Full,
+    };
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for spec in specs.split(',') {
+        let (first, last) = match spec.trim().split_once('-') {
+            Some(spec) => spec,
+            None => return Ranges::Full,
+        };
+        let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+            (Err(_), Ok(suffix)) if first.is_empty() => len.saturating_sub(suffix)..len,
+            (Ok(first), Err(_)) if last.is_empty() => first..len,
+            (Ok(first), Ok(last)) if first <= last => first..len.min(last.saturating_add(1)),
+            _ => return Ranges::Full,
+        };
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+    ranges.sort_by_key(|range| range.start);
+    let mut coalesced: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => coalesced.push(range),
+        }
+    }
+    let ranges = coalesced;
+    match ranges.is_empty() {
+        true => Ranges::Unsatisfiable,
+        false => Ranges::Satisfiable(ranges),
+    }
+}
+
+fn etag(metadata: &fs::Metadata) -> String {
+    let mtime = metadata.modified().ok().and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok());
+    format!("\"{:x}-{:x}\"", metadata.len(), mtime.unwrap_or_default().as_nanos())
+}
+
+fn file_stream(path: PathBuf, range: Range<u64>) -> impl Stream<Item = io::Result<Bytes>> {
+    async move {
+        let mut file = tokio::fs::File::open(&path).await?;
+        file.seek(SeekFrom::Start(range.start)).await?;
+        Ok(ReaderStream::new(file.take(range.end - range.start)))
+    }
+    .try_flatten_stream()
+}
+
+/// Multipart body of `parts` (part headers, range) read from a single open file
+fn multipart_stream(
+    path: PathBuf,
+    parts: Vec<(Bytes, Range<u64>)>,
+    end: Bytes,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    async move {
+        let file = tokio::fs::File::open(&path).await?;
+        let state = (file, parts.into_iter(), 0u64, Some(end));
+        Ok(stream::try_unfold(state, |(mut file, mut parts, remaining, end)| async move {
+            if remaining > 0 {
+                let mut buf = vec![0u8; remaining.min(READ_SIZE) as usize];
+                let read = file.read(&mut buf).await?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                buf.truncate(read);
+                let remaining = remaining - read as u64;
+                return Ok(Some((Bytes::from(buf), (file, parts, remaining, end))));
+            }
+            match (parts.next(), end) {
+                (Some((part_headers, range)), end) => {
+                    file.seek(SeekFrom::Start(range.start)).await?;
+                    let remaining = range.end - range.start;
+                    Ok(Some((part_headers, (file, parts, remaining, end))))
+                }
+                (None, Some(end)) => Ok(Some((end, (file, parts, 0, None)))),
+                (None, None) => Ok(None),
+            }
+        }))
+    }
+    .try_flatten_stream()
+}
+
+fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// Repository files are flat, with clean names
+fn file_path(dir: &Path, uri_path: &str) -> Option<PathBuf> {
+    let name = uri_path.strip_prefix('/')?;
+    let is_clean = name.bytes().all(|c| c.is_ascii_alphanumeric() || b"_.-".contains(&c));
+    match !name.is_empty() && !name.starts_with('.') && is_clean {
+        true => Some(dir.join(name)),
+        false => None,
+    }
+}
+
+fn respond(dir: &Path, stats: &Stats, request: &Request<Body>) -> io::Result<Response<Body>> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return Ok(status(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let path = match file_path(dir, request.uri().path()) {
+        Some(path) => path,
+        None => return Ok(status(StatusCode::NOT_FOUND)),
+    };
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(status(StatusCode::NOT_FOUND)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Ok(status(StatusCode::NOT_FOUND))
+        }
+        Err(err) => return Err(err),
+    };
+    let len = metadata.len();
+    let etag = etag(&metadata);
+
+    // a stale `If-Range` (or a date, we don't send `Last-Modified`) asks for
+    // the whole file
+    let headers = request.headers();
+    let if_range_matches = match headers.get(header::IF_RANGE) {
+        Some(if_range) => if_range.as_bytes() == etag.as_bytes(),
+        None => true,
+    };
+    let ranges = match headers.get(header::RANGE).and_then(|range| range.to_str().ok()) {
+        Some(range) if if_range_matches => parse_ranges(range, len),
+        _ => Ranges::Full,
+    };
+
+    let mut response =
+        Response::builder().header(header::ACCEPT_RANGES, "bytes").header(header::ETAG, &etag);
+    let (content_length, range_count, body) = match ranges {
+        Ranges::Full => {
+            response = response
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "application/octet-stream");
+            (len, 0, Body::wrap_stream(file_stream(path, 0..len)))
+        }
+        Ranges::Unsatisfiable => {
+            response = response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len));
+            (0, 0, Body::empty())
+        }
+        Ranges::Satisfiable(ranges) if ranges.len() == 1 => {
+            let range = ranges[0].clone();
+            response = response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .header(header::CONTENT_RANGE, content_range(&range, len));
+            (range.end - range.start, 1, Body::wrap_stream(file_stream(path, range)))
+        }
+        Ranges::Satisfiable(ranges) => {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let boundary = format!("byteranges_{:x}", nanos.as_nanos());
+            let range_count = ranges.len() as u64;
+            let parts: Vec<(Bytes, Range<u64>)> = ranges
+                .into_iter()
+                .map(|range| {
+                    let part_headers = format!(
+                        "\r\n--{}\r\n{}: application/octet-stream\r\n{}: {}\r\n\r\n",
+                        boundary,
+                        header::CONTENT_TYPE,
+                        header::CONTENT_RANGE,
+                        content_range(&range, len)
+                    );
+                    (Bytes::from(part_headers), range)
+                })
+                .collect();
+            let end = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+            let content_length = parts
+                .iter()
+                .map(|(part_headers, range)| part_headers.len() as u64 + range.end - range.start)
+                .sum::<u64>()
+                + end.len() as u64;
+            let body = multipart_stream(path, parts, end);
+            response = response.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_TYPE,
+                format!("multipart/byteranges; boundary={}", boundary),
+            );
+            (content_length, range_count, Body::wrap_stream(body))
+        }
+    };
+    let response = response.header(header::CONTENT_LENGTH, content_length);
+    let response = match request.method() {
+        &Method::HEAD => response.body(Body::empty()),
+        _ => response.body(body),
+    };
+    let response = response.map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+
+    let name = request.uri().path()[1..].to_string();
+    let mut stats = stats.lock();
+    let file_stats = stats.entry(name).or_default();
+    file_stats.requests += 1;
+    if range_count > 0 {
+        file_stats.partial_requests += 1;
+        file_stats.ranges += range_count;
+    }
+    if request.method() == Method::GET {
+        file_stats.bytes += content_length;
+    }
+    Ok(response)
+}
+
+async fn handle(
+    dir: Arc<PathBuf>,
+    stats: Stats,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match respond(&dir, &stats, &request) {
+        Ok(response) => response,
+        Err(err) => {
+            error!("{} {}: {}", request.method(), request.uri(), err);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    };
+    info!("{} {} {}", request.method(), request.uri(), response.status());
+    Ok(response)
+}
+
+/// Serve the files of `dir` with `builder` until `shutdown` completes
+async fn run(
+    dir: PathBuf,
+    builder: Builder<AddrIncoming>,
+    shutdown: impl Future<Output = ()>,
+) -> hyper::Result<Stats> {
+    let stats = Stats::default();
+    let dir = Arc::new(dir);
+    let make_service = {
+        let dir = dir.clone();
+        let stats = stats.clone();
+        make_service_fn(move |_conn| {
+            let dir = dir.clone();
+            let stats = stats.clone();
+            future::ok::<_, Infallible>(service_fn(move |request| {
+                handle(dir.clone(), stats.clone(), request)
+            }))
+        })
+    };
+    let server = builder.serve(make_service);
+    println!("serving {} on http://{}/", dir.display(), server.local_addr());
+    server.with_graceful_shutdown(shutdown).await?;
+    Ok(stats)
+}
+
+/// Serve the files of `dir` on `addr` until ctrl-c is pressed
+pub async fn serve(dir: PathBuf, addr: SocketAddr) -> hyper::Result<()> {
+    let shutdown = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    let stats = run(dir, Server::try_bind(&addr)?, shutdown).await?;
+
+    for (name, file_stats) in stats.lock().iter() {
+        println!(
+            "{}: {} requests ({} partial, {} ranges), {}",
+            name,
+            file_stats.requests,
+            file_stats.partial_requests,
+            file_stats.ranges,
+            Byte::from_bytes(file_stats.bytes.into()).get_appropriate_unit(false)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use speedupdate::link::{AutoRepository, RemoteRepository};
+    use speedupdate::metadata::CleanName;
+
+    use super::*;
+
+    fn ranges(value: &str, len: u64) -> Option<Vec<Range<u64>>> {
+        match parse_ranges(value, len) {
+            Ranges::Full => None,
+            Ranges::Satisfiable(ranges) => Some(ranges),
+            Ranges::Unsatisfiable => Some(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn range_header() {
+        assert_eq!(ranges("bytes=0-9", 100), Some(vec![0..10]));
+        // suffix and open-ended ranges
+        assert_eq!(ranges("bytes=-10", 100), Some(vec![90..100]));
+        assert_eq!(ranges("bytes=-200", 100), Some(vec![0..100]));
+        assert_eq!(ranges("bytes=90-", 100), Some(vec![90..100]));
+        assert_eq!(ranges("bytes=95-200", 100), Some(vec![95..100]));
+        // overlapping and adjacent ranges are coalesced
+        assert_eq!(ranges("bytes=0-9, 5-14", 100), Some(vec![0..15]));
+        assert_eq!(ranges("bytes=20-29,0-9,10-14", 100), Some(vec![0..15, 20..30]));
+        // too many ranges are ignored
+        let many: Vec<String> = (0..=MAX_RANGES).map(|i| format!("{}-{}", i * 2, i * 2)).collect();
+        assert_eq!(ranges(&format!("bytes={}", many.join(",")), 1000), None);
+        // unsatisfiable ranges are dropped
+        assert_eq!(ranges("bytes=0-9,100-110", 100), Some(vec![0..10]));
+        assert_eq!(ranges("bytes=100-110", 100), Some(Vec::new()));
+        assert_eq!(ranges("bytes=-0", 100), Some(Vec::new()));
+        // invalid headers are ignored
+        assert_eq!(ranges("items=0-9", 100), None);
+        assert_eq!(ranges("bytes=9-0", 100), None);
+        assert_eq!(ranges("bytes=a-b", 100), None);
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = PathBuf::from("target/tests").join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..100).collect();
+        fs::write(dir.join("package"), data).unwrap();
+        dir
+    }
+
+    async fn get(dir: &Path, headers: &[(header::HeaderName, &str)]) -> (Response<Body>, Bytes) {
+        let mut request = Request::get("/package");
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        let response = respond(dir, &Stats::default(), &request).unwrap();
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (Response::from_parts(parts, Body::empty()), body)
+    }
+
+    #[tokio::test]
+    async fn responses() {
+        let dir = test_dir("responses");
+        let header_value = |response: &Response<Body>, name| {
+            response.headers().get(name).unwrap().to_str().unwrap().to_owned()
+        };
+
+        let (response, body) = get(&dir, &[(header::RANGE, "bytes=10-19")]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header_value(&response, header::CONTENT_RANGE), "bytes 10-19/100");
+        assert_eq!(body, (10..20).collect::<Vec<u8>>());
+        let etag = header_value(&response, header::ETAG);
+
+        let (response, _) = get(&dir, &[(header::RANGE, "bytes=100-")]).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(header_value(&response, header::CONTENT_RANGE), "bytes */100");
+
+        // If-Range
+        let (response, body) =
+            get(&dir, &[(header::RANGE, "bytes=10-19"), (header::IF_RANGE, &etag)]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(body.len(), 10);
+        let (response, body) =
+            get(&dir, &[(header::RANGE, "bytes=10-19"), (header::IF_RANGE, "\"stale\"")]).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body, (0..100).collect::<Vec<u8>>());
+
+        // multipart/byteranges
+        let (response, body) = get(&dir, &[(header::RANGE, "bytes=0-1,98-")]).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = header_value(&response, header::CONTENT_TYPE);
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let mut expected = Vec::new();
+        for (range, data) in [("0-1", [0u8, 1]), ("98-99", [98, 99])] {
+            expected.extend(
+                format!(
+                    "\r\n--{}\r\ncontent-type: application/octet-stream\r\n\
+                    content-range: bytes {}/100\r\n\r\n",
+                    boundary, range
+                )
+                .into_bytes(),
+            );
+            expected.extend(data);
+        }
+        expected.extend(format!("\r\n--{}--\r\n", boundary).into_bytes());
+        assert_eq!(body, expected);
+        assert_eq!(header_value(&response, header::CONTENT_LENGTH), expected.len().to_string());
+    }
+
+    #[tokio::test]
+    async fn https_repository() {
+        let dir = test_dir("https_repository");
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let (shutdown, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(run(dir, Server::from_tcp(listener).unwrap(), async {
+            let _ = stopped.await;
+        }));
+
+        let repository = AutoRepository::new(&url, None).unwrap();
+        let name = CleanName::from_static_str("package");
+        let body = repository.package(name.clone(), 10..20).await.unwrap();
+        let body: Vec<Bytes> = body.try_collect().await.unwrap();
+        assert_eq!(body.concat(), (10..20).collect::<Vec<u8>>());
+
+        let ranges = vec![0..2, 50..60, 98..100];
+        let chunks = repository.package_ranges(name, ranges.clone()).await.unwrap().unwrap();
+        let chunks: Vec<(u64, Bytes)> = chunks.try_collect().await.unwrap();
+        let mut data = vec![0u8; 100];
+        for (offset, chunk) in chunks {
+            data[offset as usize..offset as usize + chunk.len()].copy_from_slice(&chunk);
+        }
+        for range in ranges {
+            let expected: Vec<u8> = (range.start as u8..range.end as u8).collect();
+            assert_eq!(data[range.start as usize..range.end as usize], expected[..]);
+        }
+
+        shutdown.send(()).unwrap();
+        let stats = server.await.unwrap().unwrap();
+        let stats = stats.lock();
+        let file_stats = &stats["package"];
+        assert_eq!(
+            (file_stats.requests, file_stats.partial_requests, file_stats.ranges),
+            (2, 2, 4)
+        );
+    }
+}
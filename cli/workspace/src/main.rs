@@ -1,3 +1,4 @@
+use std::collections::BTreeSet;
 use std::io::Write;
 use std::ops::Deref;
 use std::path::Path;
@@ -9,9 +10,12 @@ use futures::prelude::*;
 use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle, WeakProgressBar};
 use log::{error, warn};
 use parking_lot::RwLock;
-use speedupdate::link::{AutoRepository, RemoteRepository};
+use speedupdate::link::{AutoRepository, MirroredRepository, RemoteRepository};
 use speedupdate::metadata::{self, v1::State, CleanName, Operation};
-use speedupdate::workspace::{UpdateOptions, Workspace};
+use speedupdate::signature::PublicKey;
+use speedupdate::workspace::{
+    is_contended, AllowList, CheckOptions, GlobalProgressStream, LockMode, UpdateOptions, Workspace,
+};
 
 struct Logger {
     pb: RwLock<Option<WeakProgressBar>>,
@@ -112,12 +116,47 @@ async fn main() {
         (@subcommand update =>
             (about: "Update workspace")
             (@arg repository: +required "Repository URL")
+            (@arg mirror: --mirror +takes_value +multiple "Mirror URL of the repository")
+            (@arg trusted_key: --("trusted-key") +takes_value "Public key repository metadata must be signed with")
             (@arg to: --to +takes_value "Target revision")
             (@arg check: --check "Integrity check of all files, not just affected ones")
+            (@arg backup: --backup "Backup replaced files to allow a rollback")
+            (@arg staged: --staged "Swap new files into the workspace once every file is ready")
+            (@arg remove_extraneous: --("remove-extraneous") "Remove untracked files once the update succeeded")
+            (@arg allow: --allow +takes_value +multiple number_of_values(1) "Glob pattern of untracked paths to keep")
+            (@arg download_concurrency: --("download-concurrency") +takes_value "Number of ranges to download concurrently")
+            (@arg apply_concurrency: --("apply-concurrency") +takes_value "Number of threads applying operations")
+            (@arg limit_rate: --("limit-rate") +takes_value "Maximum download rate in bytes per second")
             (@arg no_progress: --("no-progress") "Disable progress bars")
         )
+        (@subcommand plan =>
+            (about: "Show what an update would download and apply, as JSON")
+            (@arg repository: +required "Repository URL")
+            (@arg trusted_key: --("trusted-key") +takes_value "Public key repository metadata must be signed with")
+            (@arg to: --to +takes_value "Target revision")
+            (@arg check: --check "Integrity check of all files, not just affected ones")
+            (@arg download_concurrency: --("download-concurrency") +takes_value "Number of ranges to download concurrently")
+        )
         (@subcommand check =>
             (about: "Check workspace integrity")
+            (@arg quick: --quick "Only hash the files whose size, mtime or inode changed since they were last verified")
+        )
+        (@subcommand extraneous =>
+            (about: "List files and directories the workspace doesn't track")
+            (@arg allow: --allow +takes_value +multiple number_of_values(1) "Glob pattern of untracked paths to keep")
+            (@arg remove: --remove "Remove them")
+        )
+        (@subcommand components =>
+            (about: "Show or select the optional components to install")
+            (@arg repository: "Repository URL, required to change the selection")
+            (@arg select: --select +takes_value +multiple number_of_values(1) "Component to install")
+            (@arg all: --all conflicts_with[select] "Install every component")
+            (@arg none: --none conflicts_with[select all] "Only install files of no component")
+            (@arg trusted_key: --("trusted-key") +takes_value "Public key repository metadata must be signed with")
+            (@arg no_progress: --("no-progress") "Disable progress bars")
+        )
+        (@subcommand rollback =>
+            (about: "Restore the version backed up by the last update")
         )
         (@subcommand log =>
             (about: "Show changelog")
@@ -146,8 +185,16 @@ async fn main() {
         None => std::env::current_dir().unwrap().display().to_string(),
     };
     println!("workspace: {}", workspace_path);
-    let mut workspace = match Workspace::open(&Path::new(&workspace_path)) {
+    let lock_mode = match matches.subcommand_name() {
+        Some("status") | Some("log") | Some("plan") => LockMode::Shared,
+        _ => LockMode::Exclusive,
+    };
+    let mut workspace = match Workspace::open_locked(&Path::new(&workspace_path), lock_mode) {
         Ok(workspace) => workspace,
+        Err(err) if is_contended(&err) => {
+            error!("workspace is locked by another process");
+            process::exit(1)
+        }
         Err(err) => {
             error!("unable to load workspace state: {}", err);
             process::exit(1)
@@ -158,9 +205,22 @@ async fn main() {
         ("status", Some(matches)) => do_status(matches, &mut workspace).await,
         ("log", Some(matches)) => do_log(matches, &mut workspace).await,
         ("check", Some(matches)) => do_check(matches, &mut workspace).await,
+        ("rollback", Some(_)) => do_rollback(&mut workspace),
+        ("extraneous", Some(matches)) => do_extraneous(matches, &workspace),
+        ("components", Some(matches)) => do_components(matches, &mut workspace).await,
+        ("plan", Some(matches)) => {
+            let repository = arg_repository(matches).unwrap();
+            do_plan(matches, &workspace, &repository).await
+        }
         ("update", Some(matches)) => {
             let repository = arg_repository(matches).unwrap();
-            do_update(matches, &mut workspace, &repository).await
+            match arg_mirrors(matches) {
+                Some(mirrors) => {
+                    let repository = MirroredRepository::new(repository, mirrors);
+                    do_update(matches, &mut workspace, &repository).await
+                }
+                None => do_update(matches, &mut workspace, &repository).await,
+            }
         }
         _ => unreachable!(),
     };
@@ -182,6 +242,23 @@ fn arg_repository(matches: &ArgMatches<'_>) -> Option<AutoRepository> {
     }
 }
 
+fn arg_mirrors(matches: &ArgMatches<'_>) -> Option<Vec<AutoRepository>> {
+    let urls = matches.values_of("mirror")?;
+    let mirrors = urls
+        .map(|url| {
+            println!("mirror: {}", url);
+            match AutoRepository::new(url, None) {
+                Ok(r) => r,
+                Err(err) => {
+                    error!("{}", err);
+                    process::exit(1)
+                }
+            }
+        })
+        .collect();
+    Some(mirrors)
+}
+
 async fn try_current_version(repository: &impl RemoteRepository) -> Option<metadata::Current> {
     match repository.current_version().await {
         Ok(current_version) => Some(current_version),
@@ -271,12 +348,8 @@ async fn do_status(matches: &ArgMatches<'_>, workspace: &mut Workspace) {
     }
 }
 
-async fn do_update(
-    matches: &ArgMatches<'_>,
-    workspace: &mut Workspace,
-    repository: &impl RemoteRepository,
-) {
-    let goal_version = match matches.value_of("to") {
+fn arg_goal_version(matches: &ArgMatches<'_>) -> Option<CleanName> {
+    match matches.value_of("to") {
         Some(to) => match CleanName::new(to.to_string()) {
             Ok(rev) => Some(rev),
             Err(_) => {
@@ -285,11 +358,91 @@ async fn do_update(
             }
         },
         None => None,
-    };
+    }
+}
+
+fn arg_update_options(matches: &ArgMatches<'_>) -> UpdateOptions {
     let mut update_options = UpdateOptions::default();
     update_options.check = matches.is_present("check");
-    let mut stream = workspace.update(repository, goal_version, update_options);
+    update_options.backup = matches.is_present("backup");
+    update_options.staged = matches.is_present("staged");
+    if matches.is_present("remove_extraneous") {
+        update_options.remove_extraneous = Some(arg_allow_list(matches));
+    }
+    if let Some(trusted_key) = matches.value_of("trusted_key") {
+        update_options.trusted_key = match PublicKey::from_base64(trusted_key) {
+            Ok(trusted_key) => Some(trusted_key),
+            Err(err) => {
+                error!("invalid trusted key: {}", err);
+                std::process::exit(1)
+            }
+        };
+    }
+    if let Some(download_concurrency) = matches.value_of("download_concurrency") {
+        update_options.download_concurrency = match download_concurrency.parse() {
+            Ok(download_concurrency) => download_concurrency,
+            Err(_) => {
+                error!("invalid download concurrency: {}", download_concurrency);
+                std::process::exit(1)
+            }
+        };
+    }
+    if let Some(limit_rate) = matches.value_of("limit_rate") {
+        match limit_rate.parse() {
+            Ok(limit_rate) => update_options.bandwidth_limit.set(Some(limit_rate)),
+            Err(_) => {
+                error!("invalid download rate limit: {}", limit_rate);
+                std::process::exit(1)
+            }
+        };
+    }
+    if let Some(apply_concurrency) = matches.value_of("apply_concurrency") {
+        update_options.apply_concurrency = match apply_concurrency.parse() {
+            Ok(apply_concurrency) => apply_concurrency,
+            Err(_) => {
+                error!("invalid apply concurrency: {}", apply_concurrency);
+                std::process::exit(1)
+            }
+        };
+    }
+    update_options
+}
 
+async fn do_plan(
+    matches: &ArgMatches<'_>,
+    workspace: &Workspace,
+    repository: &impl RemoteRepository,
+) {
+    let goal_version = arg_goal_version(matches);
+    let update_options = arg_update_options(matches);
+    match workspace.plan(repository, goal_version, &update_options).await {
+        Ok(plan) => println!("{}", serde_json::to_string_pretty(&plan).unwrap()),
+        Err(err) => {
+            error!("plan failed: {}", err);
+            std::process::exit(1)
+        }
+    }
+}
+
+async fn do_update(
+    matches: &ArgMatches<'_>,
+    workspace: &mut Workspace,
+    repository: &impl RemoteRepository,
+) {
+    let goal_version = arg_goal_version(matches);
+    let update_options = arg_update_options(matches);
+    let (stream, handle) = workspace.update_with_handle(repository, goal_version, update_options);
+    // ctrl-c saves the update state, the next update resumes it
+    let cancel = tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            handle.cancel();
+        }
+    });
+    run_update(matches, stream).await;
+    cancel.abort();
+}
+
+async fn run_update(matches: &ArgMatches<'_>, mut stream: GlobalProgressStream<'_>) {
     let state = match stream.next().await {
         Some(Ok(state)) => state,
         Some(Err(err)) => {
@@ -440,7 +593,8 @@ async fn do_log(matches: &ArgMatches<'_>, workspace: &mut Workspace) {
 }
 
 async fn do_check(matches: &ArgMatches<'_>, workspace: &mut Workspace) {
-    let mut stream = workspace.check();
+    let check_options = CheckOptions { quick: matches.is_present("quick") };
+    let mut stream = workspace.check_with(check_options);
     let state = match stream.next().await {
         Some(Ok(state)) => state,
         Some(Err(err)) => {
@@ -453,6 +607,7 @@ async fn do_check(matches: &ArgMatches<'_>, workspace: &mut Workspace) {
         }
     };
 
+    let shared_state = state.clone();
     let state = state.borrow();
     let progress = state.histogram.progress();
 
@@ -500,5 +655,89 @@ async fn do_check(matches: &ArgMatches<'_>, workspace: &mut Workspace) {
         error!("check failed: {}", err);
         std::process::exit(1)
     }
+    for path in shared_state.borrow().modified_files.iter() {
+        println!("MODIFIED {}", path);
+    }
     println!("CHECKED");
 }
+
+async fn do_components(matches: &ArgMatches<'_>, workspace: &mut Workspace) {
+    let components = if matches.is_present("all") {
+        None
+    } else if matches.is_present("none") {
+        Some(BTreeSet::new())
+    } else if let Some(names) = matches.values_of("select") {
+        let components = names.map(|name| match CleanName::new(name.to_string()) {
+            Ok(name) => name,
+            Err(_) => {
+                error!("invalid component: {} (must match [A-Za-Z0-9_.-]+)", name);
+                std::process::exit(1)
+            }
+        });
+        Some(components.collect())
+    } else {
+        match workspace.components() {
+            Some(components) => {
+                for component in components {
+                    println!("{}", component);
+                }
+            }
+            None => println!("all components"),
+        }
+        return;
+    };
+
+    let repository = match arg_repository(matches) {
+        Some(repository) => repository,
+        None => {
+            error!("a repository is required to change the selection");
+            std::process::exit(1)
+        }
+    };
+    let update_options = arg_update_options(matches);
+    let stream = workspace.set_components(&repository, components, update_options);
+    run_update(matches, stream).await
+}
+
+fn arg_allow_list(matches: &ArgMatches<'_>) -> AllowList {
+    match AllowList::new(matches.values_of("allow").into_iter().flatten()) {
+        Ok(allow_list) => allow_list,
+        Err(err) => {
+            error!("invalid allow pattern: {}", err);
+            std::process::exit(1)
+        }
+    }
+}
+
+fn do_extraneous(matches: &ArgMatches<'_>, workspace: &Workspace) {
+    let extraneous = match workspace.scan_extraneous(&arg_allow_list(matches)) {
+        Ok(extraneous) => extraneous,
+        Err(err) => {
+            error!("scan failed: {}", err);
+            std::process::exit(1)
+        }
+    };
+    for path in extraneous.dirs.iter() {
+        println!("{}/", path.display());
+    }
+    for path in extraneous.files.iter() {
+        println!("{}", path.display());
+    }
+    if matches.is_present("remove") {
+        if let Err(err) = workspace.remove_extraneous(&extraneous) {
+            error!("remove failed: {}", err);
+            std::process::exit(1)
+        }
+        println!("REMOVED");
+    }
+}
+
+fn do_rollback(workspace: &mut Workspace) {
+    match workspace.rollback() {
+        Ok(version) => println!("ROLLED BACK to {}", version),
+        Err(err) => {
+            error!("rollback failed: {}", err);
+            std::process::exit(1)
+        }
+    }
+}
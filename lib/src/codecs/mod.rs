@@ -30,7 +30,7 @@ pub trait Coder<W>: io::Write {
     fn finish_boxed(self: Box<Self>) -> io::Result<W>;
 }
 
-/// Coder adaptor which compute for input sha1, output sha1, count read bytes
+/// Coder adaptor which compute for input hash, output hash, count read bytes
 /// and count written bytes.
 pub struct CheckCoder<'a, W, C> {
     writer: io::CheckWriter<Box<dyn Coder<io::CheckWriter<W, C>> + 'a>, C>,
@@ -68,7 +68,7 @@ where
         writer: W,
     ) -> io::Result<Self>
     where
-        L: io::Read + io::Seek + 'a,
+        L: io::Read + io::Seek + io::FileRegion + 'a,
     {
         let output_writer = io::CheckWriter::new(writer);
         let transform_writer =
@@ -79,7 +79,7 @@ where
 
     pub fn patch_encoder<L>(patcher_options: &CoderOptions, local: L, writer: W) -> io::Result<Self>
     where
-        L: io::Read + io::Seek + 'a,
+        L: io::Read + io::Seek + io::FileRegion + 'a,
     {
         let output_writer = io::CheckWriter::new(writer);
         let transform_writer = patch_encoder(patcher_options, local, output_writer)?;
@@ -89,6 +89,13 @@ where
 }
 
 impl<W, C> CheckCoder<'_, W, C> {
+    /// Replace the input and output checks, must be called before any write
+    pub fn with_checks(mut self, input_checks: C, output_checks: C) -> Self {
+        self.writer.check = input_checks;
+        self.writer.writer.get_mut().check = output_checks;
+        self
+    }
+
     pub fn input_checks(&mut self) -> &mut C {
         &mut self.writer.check
     }
@@ -434,24 +441,40 @@ pub fn patch_encoder<'a, L, W>(
     output: W,
 ) -> Result<Box<dyn Coder<W> + 'a>, io::Error>
 where
-    L: io::Read + io::Seek + 'a,
+    L: io::Read + io::Seek + io::FileRegion + 'a,
     W: io::Write + 'a,
 {
     #[cfg(feature = "vcdiff")]
     if patcher_options.name() == "vcdiff" {
-        todo!()
+        let window_size = patcher_options.get_size(&["window", "window_size"], 16 * 1024 * 1024)?;
+        let block_size = patcher_options.get_u32_range(&["block", "block_size"], 16, 4..=4096)?;
+        return Ok(BoxCoderDirect::boxed(vcdiff::EncoderWriter::new(
+            local,
+            output,
+            window_size as usize,
+            block_size as usize,
+        )?));
     }
 
     #[cfg(feature = "zstd")]
     if patcher_options.name() == "zstd" {
-        let mut local = local;
         let level = patcher_options.get_u32_range(&["", "level"], 3, 1..=21)?;
-        let mut buf = Vec::new();
-        local.read_to_end(&mut buf)?;
-        return Ok(BoxCoderDirect::boxed(zstd::Encoder::with_dictionary(
+        let window_log = match patcher_options.get(&["window", "window_log"]) {
+            // patches of large files then require up to 2GiB to be applied
+            Some("auto") => None,
+            _ => Some(patcher_options.get_u32_range(
+                &["window", "window_log"],
+                zstd::WINDOW_LOG_DEFAULT,
+                zstd::WINDOW_LOG_MIN..=zstd::WINDOW_LOG_MAX,
+            )?),
+        };
+        let long = patcher_options.get_bool(&["long"], 1)?;
+        return Ok(BoxCoderDirect::boxed(zstd::PatchEncoder::new(
+            &local,
             output,
             level as i32,
-            &buf,
+            window_log,
+            long,
         )?));
     }
 
@@ -469,7 +492,7 @@ pub fn patch_decoder<'a, L, W>(
     output: W,
 ) -> Result<Box<dyn Coder<W> + 'a>, io::Error>
 where
-    L: io::Read + io::Seek + 'a,
+    L: io::Read + io::Seek + io::FileRegion + 'a,
     W: io::Write + io::ReadSlice + 'a,
 {
     #[cfg(feature = "vcdiff")]
@@ -482,10 +505,7 @@ where
 
     #[cfg(feature = "zstd")]
     if patcher_name == "zstd" {
-        let mut local = local;
-        let mut buf = Vec::new();
-        local.read_to_end(&mut buf)?;
-        return Ok(BoxCoderDirect::boxed(zstd::Decoder::with_dictionary(output, &buf)?));
+        return Ok(BoxCoderDirect::boxed(zstd::PatchDecoder::new(&local, output)?));
     }
 
     if patcher_name == "raw" {
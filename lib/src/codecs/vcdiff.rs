@@ -82,3 +82,402 @@ where
         Ok(())
     }
 }
+
+/// VCDIFF header magic (RFC 3284 section 4.1), version 0
+const VCD_MAGIC: [u8; 4] = [0xD6, 0xC3, 0xC4, 0x00];
+/// Window indicator bit: the window copies from the source file
+const VCD_SOURCE: u8 = 0x01;
+/// Default code table: `ADD` with size in the instructions section
+const VCD_ADD: u8 = 1;
+/// Default code table: first `COPY` instruction (mode 0, size in the instructions section)
+const VCD_COPY: u8 = 19;
+/// Address mode: absolute address
+const VCD_SELF: u8 = 0;
+/// Address mode: address relative to the current position
+const VCD_HERE: u8 = 1;
+
+/// Minimum match length worth a `COPY` instruction
+const MIN_MATCH: usize = 4;
+
+fn varint_len(mut value: u64) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+fn write_varint(out: &mut Vec<u8>, value: u64) {
+    let mut buf = [0u8; 10];
+    let mut i = buf.len() - 1;
+    buf[i] = (value & 0x7F) as u8;
+    let mut value = value >> 7;
+    while value > 0 {
+        i -= 1;
+        buf[i] = 0x80 | (value & 0x7F) as u8;
+        value >>= 7;
+    }
+    out.extend_from_slice(&buf[i..]);
+}
+
+/// Rolling hash over `block_size` bytes (Rabin-Karp)
+struct RollingHash {
+    /// `BASE ^ (block_size - 1)`
+    remove_factor: u64,
+}
+
+impl RollingHash {
+    const BASE: u64 = 0x100000001b3;
+
+    fn new(block_size: usize) -> Self {
+        let mut remove_factor = 1u64;
+        for _ in 1..block_size {
+            remove_factor = remove_factor.wrapping_mul(Self::BASE);
+        }
+        Self { remove_factor }
+    }
+
+    fn hash(&self, block: &[u8]) -> u64 {
+        block.iter().fold(0u64, |h, &b| h.wrapping_mul(Self::BASE).wrapping_add(b as u64 + 1))
+    }
+
+    fn roll(&self, hash: u64, old: u8, new: u8) -> u64 {
+        hash.wrapping_sub((old as u64 + 1).wrapping_mul(self.remove_factor))
+            .wrapping_mul(Self::BASE)
+            .wrapping_add(new as u64 + 1)
+    }
+}
+
+/// Position table indexed by block hash
+struct BlockTable {
+    mask: u64,
+    positions: Vec<u64>,
+}
+
+impl BlockTable {
+    const EMPTY: u64 = u64::MAX;
+
+    fn new(blocks: usize) -> Self {
+        let len = blocks.max(1).next_power_of_two();
+        Self { mask: len as u64 - 1, positions: vec![Self::EMPTY; len] }
+    }
+
+    /// Insert `pos`, replacing any previous position with the same hash slot
+    fn insert(&mut self, hash: u64, pos: u64) {
+        self.positions[(hash & self.mask) as usize] = pos;
+    }
+
+    /// Insert `pos` only if the hash slot is empty
+    ///
+    /// Keeping the oldest position gives longer non overlapping copies on
+    /// repetitive data.
+    fn insert_first(&mut self, hash: u64, pos: u64) {
+        let slot = &mut self.positions[(hash & self.mask) as usize];
+        if *slot == Self::EMPTY {
+            *slot = pos;
+        }
+    }
+
+    fn get(&self, hash: u64) -> Option<u64> {
+        match self.positions[(hash & self.mask) as usize] {
+            Self::EMPTY => None,
+            pos => Some(pos),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.positions.iter_mut().for_each(|p| *p = Self::EMPTY);
+    }
+}
+
+/// Delta encoding sections of one target window
+#[derive(Default)]
+struct WindowSections {
+    data: Vec<u8>,
+    instructions: Vec<u8>,
+    addresses: Vec<u8>,
+}
+
+impl WindowSections {
+    fn add(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let size = bytes.len();
+        if size <= 17 {
+            self.instructions.push(VCD_ADD + size as u8);
+        } else {
+            self.instructions.push(VCD_ADD);
+            write_varint(&mut self.instructions, size as u64);
+        }
+        self.data.extend_from_slice(bytes);
+    }
+
+    fn copy(&mut self, size: usize, addr: u64, here: u64) {
+        let here_addr = here - addr;
+        let (mode, addr) = if varint_len(here_addr) < varint_len(addr) {
+            (VCD_HERE, here_addr)
+        } else {
+            (VCD_SELF, addr)
+        };
+        let code = VCD_COPY + 16 * mode;
+        if (MIN_MATCH..=18).contains(&size) {
+            self.instructions.push(code + (size - 3) as u8);
+        } else {
+            self.instructions.push(code);
+            write_varint(&mut self.instructions, size as u64);
+        }
+        write_varint(&mut self.addresses, addr);
+    }
+}
+
+/// VCDIFF (RFC 3284) encoder
+///
+/// The whole `original` content is loaded in memory and indexed by blocks of
+/// `block_size` bytes. Target data is buffered and encoded by windows of
+/// `window_size` bytes, each window can copy from the whole original content
+/// and from itself.
+///
+/// Only the default code table is used and no secondary compression is done,
+/// so the output can be decoded by any conforming decoder.
+pub struct EncoderWriter<W: io::Write> {
+    output: W,
+    source: Vec<u8>,
+    source_table: BlockTable,
+    target_table: BlockTable,
+    hasher: RollingHash,
+    block_size: usize,
+    window: Vec<u8>,
+    window_size: usize,
+    header_written: bool,
+}
+
+impl<W: io::Write> EncoderWriter<W> {
+    pub fn new<R: io::Read>(
+        mut original: R,
+        output: W,
+        window_size: usize,
+        block_size: usize,
+    ) -> io::Result<Self> {
+        let block_size = block_size.max(MIN_MATCH);
+        let window_size = window_size.max(block_size);
+        let mut source = Vec::new();
+        original.read_to_end(&mut source)?;
+
+        let hasher = RollingHash::new(block_size);
+        let mut source_table = BlockTable::new(source.len() / block_size);
+        for (idx, block) in source.chunks_exact(block_size).enumerate() {
+            source_table.insert(hasher.hash(block), (idx * block_size) as u64);
+        }
+
+        Ok(Self {
+            output,
+            source,
+            source_table,
+            target_table: BlockTable::new(window_size / block_size * 4),
+            hasher,
+            block_size,
+            window: Vec::with_capacity(window_size),
+            window_size,
+            header_written: false,
+        })
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.output.write_all(&VCD_MAGIC)?;
+            self.output.write_all(&[0])?; // Hdr_Indicator: no secondary compressor, no code table
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    /// Length of the match between `target[pos..]` and `candidate[cpos..]`
+    fn match_len(candidate: &[u8], cpos: usize, target: &[u8], pos: usize) -> usize {
+        candidate[cpos..].iter().zip(&target[pos..]).take_while(|(a, b)| a == b).count()
+    }
+
+    /// Find the best copy for `window[pos..]`, extending backward up to `min_pos`
+    ///
+    /// Returns `(target_start, size, address)`
+    fn find_copy(&self, hash: u64, pos: usize, min_pos: usize) -> Option<(usize, usize, u64)> {
+        let window = &self.window;
+        let source_len = self.source.len() as u64;
+        let mut best: Option<(usize, usize, u64)> = None;
+        let mut consider = |candidate: &[u8], cpos: usize, base: u64| {
+            let forward = Self::match_len(candidate, cpos, window, pos);
+            if forward < self.block_size {
+                return;
+            }
+            let backward = candidate[..cpos]
+                .iter()
+                .rev()
+                .zip(window[min_pos..pos].iter().rev())
+                .take_while(|(a, b)| a == b)
+                .count();
+            let size = backward + forward;
+            let is_better = match best {
+                Some((_, best_size, _)) => size > best_size,
+                None => true,
+            };
+            if is_better {
+                best = Some((pos - backward, size, base + (cpos - backward) as u64));
+            }
+        };
+
+        if let Some(spos) = self.source_table.get(hash) {
+            consider(&self.source, spos as usize, 0);
+        }
+        if let Some(tpos) = self.target_table.get(hash) {
+            // Only non overlapping copies from the already encoded part of the window
+            let tpos = tpos as usize;
+            consider(&window[..pos], tpos, source_len);
+        }
+        best
+    }
+
+    /// Encode and write the buffered target window
+    fn encode_window(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        if self.window.is_empty() {
+            return Ok(());
+        }
+
+        let mut sections = WindowSections::default();
+        let source_len = self.source.len() as u64;
+        let block_size = self.block_size;
+        let len = self.window.len();
+        let mut add_start = 0;
+        let mut pos = 0;
+        let mut hash = None;
+        self.target_table.clear();
+        while pos + block_size <= len {
+            let h = match hash {
+                Some(h) => h,
+                None => self.hasher.hash(&self.window[pos..pos + block_size]),
+            };
+            match self.find_copy(h, pos, add_start) {
+                Some((start, size, addr)) => {
+                    sections.add(&self.window[add_start..start]);
+                    sections.copy(size, addr, source_len + start as u64);
+                    pos = start + size;
+                    add_start = pos;
+                    hash = None;
+                }
+                None => {
+                    self.target_table.insert_first(h, pos as u64);
+                    if pos + block_size < len {
+                        hash = Some(self.hasher.roll(
+                            h,
+                            self.window[pos],
+                            self.window[pos + block_size],
+                        ));
+                    }
+                    pos += 1;
+                }
+            }
+        }
+        sections.add(&self.window[add_start..]);
+
+        let WindowSections { data, instructions, addresses } = sections;
+        let mut header = Vec::new();
+        if source_len > 0 {
+            header.push(VCD_SOURCE);
+            write_varint(&mut header, source_len);
+            write_varint(&mut header, 0);
+        } else {
+            header.push(0);
+        }
+        let mut delta = Vec::new();
+        write_varint(&mut delta, len as u64);
+        delta.push(0); // Delta_Indicator: no secondary compression
+        write_varint(&mut delta, data.len() as u64);
+        write_varint(&mut delta, instructions.len() as u64);
+        write_varint(&mut delta, addresses.len() as u64);
+        let delta_len = delta.len() + data.len() + instructions.len() + addresses.len();
+        write_varint(&mut header, delta_len as u64);
+
+        self.output.write_all(&header)?;
+        self.output.write_all(&delta)?;
+        self.output.write_all(&data)?;
+        self.output.write_all(&instructions)?;
+        self.output.write_all(&addresses)?;
+        self.window.clear();
+        Ok(())
+    }
+}
+
+impl<W: io::Write> super::Coder<W> for EncoderWriter<W> {
+    fn get_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.encode_window()?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+
+    fn finish_boxed(self: Box<Self>) -> io::Result<W> {
+        self.finish()
+    }
+}
+
+impl<W: io::Write> io::Write for EncoderWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.window_size - self.window.len());
+        self.window.extend_from_slice(&buf[..len]);
+        if self.window.len() == self.window_size {
+            self.encode_window()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codecs::Coder;
+    use crate::io::Write;
+    use crate::tests::{pseudo_random, Bytes};
+
+    fn roundtrip(original: &[u8], target: &[u8], window_size: usize) -> usize {
+        let mut encoder = EncoderWriter::new(original, Vec::new(), window_size, 16).unwrap();
+        for chunk in target.chunks(1000) {
+            encoder.write_all(chunk).unwrap();
+        }
+        let delta = encoder.finish().unwrap();
+
+        let mut decoder = DecoderWriter::new(
+            io::Cursor::new(original),
+            io::Cursor::new(Vec::new()),
+            io::BUFFER_SIZE,
+        );
+        decoder.write_all(&delta).unwrap();
+        let decoded = decoder.finish().unwrap().into_inner();
+        assert_eq!(Bytes(&decoded), Bytes(target));
+        delta.len()
+    }
+
+    #[test]
+    fn encoder_roundtrip() {
+        let original = pseudo_random(200_000, 1);
+        let mut target = original.clone();
+        target[1000..1010].copy_from_slice(b"0123456789");
+        target.drain(50_000..50_500);
+        target.splice(150_000..150_000, pseudo_random(3000, 2));
+
+        for &window_size in &[4096, 64 * 1024, 1024 * 1024] {
+            let delta_len = roundtrip(&original, &target, window_size);
+            assert!(delta_len < 20_000, "delta_len = {}", delta_len);
+        }
+        roundtrip(&[], &b"abc".repeat(1000), 1024);
+        roundtrip(&original, &[], 1024);
+        roundtrip(b"short", b"tiny", 1024);
+    }
+}
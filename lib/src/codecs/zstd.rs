@@ -1,7 +1,15 @@
+use std::convert::TryFrom;
 use std::io::{self, Write};
 
+use memmap2::{Mmap, MmapOptions};
 pub use zstd::stream::write::Decoder;
 pub use zstd::stream::write::Encoder;
+use zstd::zstd_safe::{
+    self, zstd_sys::ZSTD_EndDirective as EndDirective, CCtx, CParameter, DCtx, DParameter,
+    InBuffer, OutBuffer,
+};
+
+use crate::io::FileRegion;
 
 impl<W: Write> super::Coder<W> for Decoder<'static, W> {
     fn get_mut(&mut self) -> &mut W {
@@ -30,3 +38,319 @@ impl<W: Write> super::Coder<W> for Encoder<'static, W> {
         self.finish()
     }
 }
+
+/// Maximum window log supported by zstd on this platform
+#[cfg(target_pointer_width = "64")]
+pub const WINDOW_LOG_MAX: u32 = 31;
+#[cfg(not(target_pointer_width = "64"))]
+pub const WINDOW_LOG_MAX: u32 = 30;
+
+/// Minimum window log supported by zstd
+pub const WINDOW_LOG_MIN: u32 = 10;
+
+/// Window log of `zstd --long`, it bounds the memory required to build and
+/// apply a patch to ~128MiB
+pub const WINDOW_LOG_DEFAULT: u32 = 27;
+
+/// Smallest window log covering a reference of `len` bytes and the data that
+/// follows it, up to `WINDOW_LOG_MAX`
+fn auto_window_log(len: u64) -> u32 {
+    let bits = 64 - len.leading_zeros() + 1;
+    bits.clamp(WINDOW_LOG_MIN, WINDOW_LOG_MAX)
+}
+
+fn map_error_code(code: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, zstd_safe::get_error_name(code))
+}
+
+/// Memory mapped reference content (the local file) of a patch.
+///
+/// Nothing is loaded in memory, the OS pages in the parts of the reference that
+/// are actually matched.
+struct Reference {
+    mmap: Option<Mmap>,
+}
+
+impl Reference {
+    fn map<L: FileRegion>(local: &L) -> io::Result<Self> {
+        let (file, offset, size) = local.file_region()?;
+        if size == 0 {
+            // zero sized mapping aren't supported
+            return Ok(Self { mmap: None });
+        }
+        let len = usize::try_from(size)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "reference is too large"))?;
+        // SAFETY: the mapping is only read by zstd through the pointer given to
+        // `ref_prefix`, no Rust code reads it. The local file is a packager input
+        // while building, or a workspace file the locked update is about to
+        // replace while applying. If something else still writes it meanwhile,
+        // the output is wrong and rejected by the final hash check of the
+        // operation. Copying it instead would read the whole file even if few
+        // parts of it are matched.
+        let mmap = unsafe { MmapOptions::new().offset(offset).len(len).map(file)? };
+        Ok(Self { mmap: Some(mmap) })
+    }
+
+    fn len(&self) -> u64 {
+        self.mmap.as_ref().map_or(0, |mmap| mmap.len() as u64)
+    }
+
+    /// Returns the reference content with an unbounded lifetime.
+    ///
+    /// # Safety
+    ///
+    /// The returned slice must not outlive `self`, the memory mapping address is
+    /// stable even if `self` is moved.
+    unsafe fn prefix(&self) -> Option<&'static [u8]> {
+        self.mmap.as_ref().map(|mmap| std::slice::from_raw_parts(mmap.as_ptr(), mmap.len()))
+    }
+}
+
+/// Streaming zstd patch encoder (equivalent of `zstd --long --patch-from`).
+///
+/// The local file is memory mapped and referenced as a prefix, with long
+/// distance matching enabled, so the memory usage is bounded by the window
+/// size instead of the local file size.
+pub struct PatchEncoder<W> {
+    // `context` references `_reference` memory, it must be dropped first
+    context: CCtx<'static>,
+    _reference: Reference,
+    buffer: Vec<u8>,
+    writer: W,
+}
+
+impl<W: Write> PatchEncoder<W> {
+    /// Creates a new patch encoder
+    ///
+    /// If `window_log` is `None`, the window is large enough to cover the
+    /// whole local file up to `WINDOW_LOG_MAX`, so matches are found anywhere
+    /// in huge files. Building and applying the patch then requires as much
+    /// memory (up to 2GiB), this is opt-in with the `window=auto` patcher
+    /// option, `WINDOW_LOG_DEFAULT` is used otherwise.
+    pub fn new<L: FileRegion>(
+        local: &L,
+        writer: W,
+        level: i32,
+        window_log: Option<u32>,
+        long_distance_matching: bool,
+    ) -> io::Result<Self> {
+        let reference = Reference::map(local)?;
+        let window_log = window_log.unwrap_or_else(|| auto_window_log(reference.len()));
+
+        let mut context = CCtx::create();
+        context.set_parameter(CParameter::CompressionLevel(level)).map_err(map_error_code)?;
+        context.set_parameter(CParameter::WindowLog(window_log)).map_err(map_error_code)?;
+        context
+            .set_parameter(CParameter::EnableLongDistanceMatching(long_distance_matching))
+            .map_err(map_error_code)?;
+        // SAFETY: `reference` is owned by the encoder and outlives `context`
+        if let Some(prefix) = unsafe { reference.prefix() } {
+            context.ref_prefix(prefix).map_err(map_error_code)?;
+        }
+
+        Ok(Self {
+            context,
+            _reference: reference,
+            buffer: Vec::with_capacity(CCtx::out_size()),
+            writer,
+        })
+    }
+
+    fn compress(&mut self, input: &mut InBuffer, end_op: EndDirective) -> io::Result<usize> {
+        self.buffer.clear();
+        let mut output = OutBuffer::around(&mut self.buffer);
+        let remaining =
+            self.context.compress_stream2(&mut output, input, end_op).map_err(map_error_code)?;
+        self.writer.write_all(&self.buffer)?;
+        Ok(remaining)
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        while self.compress(&mut InBuffer::around(&[]), EndDirective::ZSTD_e_end)? > 0 {}
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for PatchEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut input = InBuffer::around(buf);
+        while input.pos() < buf.len() {
+            self.compress(&mut input, EndDirective::ZSTD_e_continue)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while self.compress(&mut InBuffer::around(&[]), EndDirective::ZSTD_e_flush)? > 0 {}
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> super::Coder<W> for PatchEncoder<W> {
+    fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.end()?;
+        Ok(self.writer)
+    }
+
+    fn finish_boxed(self: Box<Self>) -> io::Result<W> {
+        self.finish()
+    }
+}
+
+/// Streaming zstd patch decoder.
+///
+/// The local file is memory mapped and referenced as a prefix, the memory usage
+/// is bounded by the window size the patch was encoded with.
+///
+/// Patches encoded with the local file as a raw content dictionary (the
+/// previous zstd patcher) are decoded the same way.
+pub struct PatchDecoder<W> {
+    // `context` references `_reference` memory, it must be dropped first
+    context: DCtx<'static>,
+    _reference: Reference,
+    buffer: Vec<u8>,
+    writer: W,
+    /// Last hint of `decompress_stream`, `0` once the frame is complete
+    remaining: usize,
+}
+
+impl<W: Write> PatchDecoder<W> {
+    pub fn new<L: FileRegion>(local: &L, writer: W) -> io::Result<Self> {
+        let reference = Reference::map(local)?;
+
+        let mut context = DCtx::create();
+        context.set_parameter(DParameter::WindowLogMax(WINDOW_LOG_MAX)).map_err(map_error_code)?;
+        // SAFETY: `reference` is owned by the decoder and outlives `context`
+        if let Some(prefix) = unsafe { reference.prefix() } {
+            context.ref_prefix(prefix).map_err(map_error_code)?;
+        }
+
+        Ok(Self {
+            context,
+            _reference: reference,
+            buffer: Vec::with_capacity(DCtx::out_size()),
+            writer,
+            remaining: 0,
+        })
+    }
+
+    /// Decompress as much as possible of `input`, returns `true` if the output
+    /// buffer was filled (more data might be pending).
+    fn decompress(&mut self, input: &mut InBuffer) -> io::Result<bool> {
+        self.buffer.clear();
+        let mut output = OutBuffer::around(&mut self.buffer);
+        let remaining =
+            self.context.decompress_stream(&mut output, input).map_err(map_error_code)?;
+        // without input, a complete frame hints at the header of the next one
+        if !input.src.is_empty() || remaining == 0 {
+            self.remaining = remaining;
+        }
+        self.writer.write_all(&self.buffer)?;
+        Ok(self.buffer.len() == self.buffer.capacity())
+    }
+}
+
+impl<W: Write> Write for PatchDecoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut input = InBuffer::around(buf);
+        while self.decompress(&mut input)? || input.pos() < buf.len() {}
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while self.decompress(&mut InBuffer::around(&[]))? {}
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> super::Coder<W> for PatchDecoder<W> {
+    fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        while self.decompress(&mut InBuffer::around(&[]))? {}
+        if self.remaining > 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated zstd frame"));
+        }
+        Ok(self.writer)
+    }
+
+    fn finish_boxed(self: Box<Self>) -> io::Result<W> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::codecs::Coder;
+    use crate::io::Slice;
+    use crate::tests::{pseudo_random, tmp_dir, Bytes};
+
+    fn decode<L: FileRegion>(local: &L, patch: &[u8]) -> Vec<u8> {
+        let mut decoder = PatchDecoder::new(local, Vec::new()).unwrap();
+        for chunk in patch.chunks(1000) {
+            decoder.write_all(chunk).unwrap();
+        }
+        decoder.finish().unwrap()
+    }
+
+    #[test]
+    fn patch_roundtrip() {
+        let dir = tmp_dir("zstd_patch_roundtrip");
+        let original = pseudo_random(1024 * 1024, 42);
+        let mut target = original.clone();
+        target[1000..2000].copy_from_slice(&pseudo_random(1000, 7));
+        target.extend_from_slice(&original[..4096]);
+
+        let local_path = dir.join("local");
+        fs::write(&local_path, [&b"header"[..], &original, &b"footer"[..]].concat()).unwrap();
+        let local =
+            Slice::new(fs::File::open(&local_path).unwrap(), 6, original.len() as u64).unwrap();
+
+        let mut encoder = PatchEncoder::new(&local, Vec::new(), 3, None, true).unwrap();
+        for chunk in target.chunks(1000) {
+            encoder.write_all(chunk).unwrap();
+        }
+        let patch = encoder.finish().unwrap();
+        assert!(patch.len() < 16 * 1024, "patch is too large: {}", patch.len());
+        assert_eq!(Bytes(&decode(&local, &patch)), Bytes(&target));
+
+        // truncated patch
+        let mut decoder = PatchDecoder::new(&local, Vec::new()).unwrap();
+        decoder.write_all(&patch[..patch.len() - 4]).unwrap();
+        let err = decoder.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // patches built with the local file as dictionary are still supported
+        let mut encoder = Encoder::with_dictionary(Vec::new(), 3, &original).unwrap();
+        encoder.write_all(&target).unwrap();
+        let patch = encoder.finish().unwrap();
+        assert_eq!(Bytes(&decode(&local, &patch)), Bytes(&target));
+
+        // empty local file
+        let empty_path = dir.join("empty");
+        fs::write(&empty_path, b"").unwrap();
+        let empty = fs::File::open(&empty_path).unwrap();
+        let mut encoder = PatchEncoder::new(&empty, Vec::new(), 3, Some(20), false).unwrap();
+        encoder.write_all(&target).unwrap();
+        let patch = encoder.finish().unwrap();
+        assert_eq!(Bytes(&decode(&empty, &patch)), Bytes(&target));
+    }
+
+    #[test]
+    fn patch_window_log() {
+        assert_eq!(auto_window_log(0), WINDOW_LOG_MIN);
+        assert_eq!(auto_window_log(1 << 20), 22);
+        // references larger than the default window are covered
+        assert_eq!(auto_window_log(3 << 30), WINDOW_LOG_MAX);
+        assert!(auto_window_log(1 << 28) > WINDOW_LOG_DEFAULT);
+    }
+}
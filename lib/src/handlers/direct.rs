@@ -1,9 +1,12 @@
 use std::{fs, path::PathBuf};
 
 use super::{Applier, CheckApplier, HandlerContext, WriteApplier};
+use tracing::info;
+
 use crate::codecs::CheckCoder;
 use crate::io;
 use crate::metadata;
+use crate::workspace::preserve;
 
 pub struct Handler<'a> {
     ctx: HandlerContext<'a>,
@@ -13,6 +16,22 @@ impl<'a> Handler<'a> {
     pub fn new(ctx: HandlerContext<'a>) -> Self {
         Self { ctx }
     }
+
+    /// The workspace file has a preserve policy and matches neither its
+    /// previous version nor `hashes`
+    fn is_locally_modified(
+        &self,
+        common: &metadata::v2::Common,
+        hashes: &[&metadata::Hash],
+    ) -> io::Result<bool> {
+        let path = &common.path;
+        if common.preserve.is_none() || self.ctx.local_path(path) != self.ctx.workspace_path(path) {
+            return Ok(false);
+        }
+        let mut hashes = hashes.to_vec();
+        hashes.extend(self.ctx.pristine_hashes.get(path));
+        preserve::is_modified(&self.ctx.workspace_path(path), &hashes)
+    }
 }
 
 impl<'a> super::ApplyHandler for Handler<'a> {
@@ -23,65 +42,101 @@ impl<'a> super::ApplyHandler for Handler<'a> {
         false
     }
 
-    fn add(&mut self, op: &metadata::v1::Add) -> io::Result<Option<Box<dyn Applier>>> {
+    fn add(&mut self, op: &metadata::v2::Add) -> io::Result<Option<Box<dyn Applier>>> {
         let tmp_path = self.ctx.tmp_operation_path();
-        let final_path = self.ctx.final_path(&op.common.path);
+        let mut final_path = self.ctx.create_final_path(&op.common.path)?;
+        let mut backup_path = self.ctx.backup_path(&op.common.path);
+        if self.is_locally_modified(&op.common, &[&op.final_hash])? {
+            final_path = preserve::new_path(&final_path);
+            backup_path = None;
+            info!("{} is locally modified, write {:?}", op.common.path, final_path);
+        }
         let tmp_file = fs::OpenOptions::new().write(true).create(true).open(&tmp_path)?;
         io::set_exe_permission(&tmp_file, op.common.exe)?;
-        let decoder = CheckCoder::decoder(&op.data_compression, tmp_file)?;
+        let decoder = CheckCoder::decoder(&op.data_compression, tmp_file)?.with_checks(
+            io::CheckHashSize::new(op.data_hash.algorithm()),
+            io::CheckHashSize::new(op.final_hash.algorithm()),
+        );
         let applier = WriteApplier {
             data_size_expected: op.data_size,
-            data_sha1_expected: op.data_sha1.clone(),
+            data_hash_expected: op.data_hash.clone(),
             final_size_expected: op.final_size,
-            final_sha1_expected: op.final_sha1.clone(),
+            final_hash_expected: op.final_hash.clone(),
             final_path,
+            backup_path,
             tmp_path,
             decoder,
         };
         Ok(Some(Box::new(applier)))
     }
 
-    fn patch(&mut self, op: &metadata::v1::Patch) -> io::Result<Option<Box<dyn Applier>>> {
-        let final_path = self.ctx.final_path(&op.common.path);
-        let current_local_size = fs::metadata(&final_path).map(|m| m.len())?;
+    fn patch(&mut self, op: &metadata::v2::Patch) -> io::Result<Option<Box<dyn Applier>>> {
+        let mut final_path = self.ctx.create_final_path(&op.common.path)?;
+        let mut local_path = self.ctx.local_path(&op.common.path);
+        let mut backup_path = self.ctx.backup_path(&op.common.path);
+        if self.is_locally_modified(&op.common, &[&op.local_hash])? {
+            // patch the previous `.new` file, if it is still pristine
+            let new_path = preserve::new_path(&local_path);
+            if !new_path.exists() || preserve::is_modified(&new_path, &[&op.local_hash])? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "locally modified, without a pristine version to patch",
+                ));
+            }
+            info!("{} is locally modified, patch {:?}", op.common.path, new_path);
+            local_path = new_path;
+            final_path = preserve::new_path(&final_path);
+            backup_path = None;
+        }
+        let current_local_size = fs::metadata(&local_path).map(|m| m.len())?;
 
         io::assert_eq(current_local_size, op.local_size, "local size")?;
 
-        let local_file = fs::OpenOptions::new().read(true).write(true).open(&final_path)?;
+        let local_file = fs::OpenOptions::new().read(true).write(true).open(&local_path)?;
         let tmp_path = self.ctx.tmp_operation_path();
         let tmp_file =
             fs::OpenOptions::new().write(true).read(true).create(true).open(&tmp_path)?;
         io::set_exe_permission(&tmp_file, op.common.exe)?;
         let decoder =
-            CheckCoder::patch_decoder(&op.data_compression, &op.patch_type, local_file, tmp_file)?;
+            CheckCoder::patch_decoder(&op.data_compression, &op.patch_type, local_file, tmp_file)?
+                .with_checks(
+                    io::CheckHashSize::new(op.data_hash.algorithm()),
+                    io::CheckHashSize::new(op.final_hash.algorithm()),
+                );
         let applier = WriteApplier {
             data_size_expected: op.data_size,
-            data_sha1_expected: op.data_sha1.clone(),
+            data_hash_expected: op.data_hash.clone(),
             final_size_expected: op.final_size,
-            final_sha1_expected: op.final_sha1.clone(),
+            final_hash_expected: op.final_hash.clone(),
             final_path,
+            backup_path,
             tmp_path,
             decoder,
         };
         Ok(Some(Box::new(applier)))
     }
 
-    fn check(&mut self, op: &metadata::v1::Check) -> io::Result<Option<Box<dyn Applier>>> {
+    fn check(&mut self, op: &metadata::v2::Check) -> io::Result<Option<Box<dyn Applier>>> {
         if !self.ctx.update_options.check {
             return Ok(None);
         }
 
-        let path = self.ctx.final_path(&op.common.path);
+        let preserve = op.common.preserve.is_some();
+        let path = self.ctx.local_path(&op.common.path);
         let file = fs::OpenOptions::new().read(true).open(&path)?;
         let size = file.metadata()?.len();
-        io::assert_eq(size, op.local_size, "local size")?;
+        io::assert_eq(size, op.local_size, "local size").map_err(|err| match preserve {
+            true => preserve::locally_modified(err),
+            false => err,
+        })?;
         io::set_exe_permission(&file, op.common.exe)?;
-        let applier = CheckApplier::new(op.local_size, op.local_sha1.clone(), file);
+        let applier =
+            CheckApplier::new(op.local_size, op.local_hash.clone(), file).with_preserve(preserve);
         Ok(Some(Box::new(applier)))
     }
 
-    fn rm(&mut self, op: &metadata::v1::Rm) -> io::Result<Option<Box<dyn Applier>>> {
-        io::remove_file(self.ctx.final_path(&op.path))?;
+    fn rm(&mut self, op: &metadata::v2::Rm) -> io::Result<Option<Box<dyn Applier>>> {
+        self.ctx.remove_final_file(&op.path)?;
         Ok(None)
     }
 
@@ -99,7 +154,7 @@ impl<'a> super::ApplyHandler for Handler<'a> {
     }
 
     fn rmdir(&mut self, path: &metadata::CleanPath) -> io::Result<Option<Box<dyn Applier>>> {
-        if let Err(err) = fs::remove_dir(self.ctx.final_path(path)) {
+        if let Err(err) = self.ctx.remove_final_dir(path) {
             if err.kind() != io::ErrorKind::NotFound {
                 self.ctx.warn_fs(&format!("unable to remove directory {}", path), err)?;
             }
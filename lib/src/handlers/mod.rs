@@ -3,13 +3,14 @@ mod sliced;
 
 use std::fs;
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub use direct::Handler as DefaultHandler;
 use tracing::warn;
 
 use crate::metadata::{self, Operation};
-use crate::workspace::{UpdateOptions, WorkspaceFileManager};
+use crate::workspace::preserve::{self, PristineHashes};
+use crate::workspace::{backup, stage, UpdateOptions, WorkspaceFileManager};
 use crate::{codecs, io};
 
 #[derive(Clone)]
@@ -18,11 +19,48 @@ pub struct HandlerContext<'a> {
     pub package_name: &'a str,
     pub operation_idx: usize,
     pub update_options: &'a UpdateOptions,
+    /// Where replaced and removed files are moved, if backups are enabled
+    pub(crate) backup_dir: Option<&'a Path>,
+    /// Where new files are built, if the update is staged
+    pub(crate) stage_dir: Option<&'a Path>,
+    /// Previous hashes of the files with a preserve policy
+    pub(crate) pristine_hashes: &'a PristineHashes,
 }
 
 impl<'a> HandlerContext<'a> {
+    /// Path of the new version of `path`
     pub fn final_path(&self, path: &metadata::CleanPath) -> PathBuf {
-        self.file_manager.dir().join(&path)
+        match self.stage_dir {
+            Some(stage_dir) => stage::file_path(stage_dir, path),
+            None => self.file_manager.dir().join(&path),
+        }
+    }
+
+    /// Path of the new version of `path`, with its parent directories
+    ///
+    /// The stage only contains the directories of staged files, existing
+    /// workspace directories aren't created by operations.
+    pub fn create_final_path(&self, path: &metadata::CleanPath) -> io::Result<PathBuf> {
+        let final_path = self.final_path(path);
+        if let (Some(_), Some(parent)) = (self.stage_dir, final_path.parent()) {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(final_path)
+    }
+
+    /// Path of the current version of `path`
+    pub fn local_path(&self, path: &metadata::CleanPath) -> PathBuf {
+        match self.stage_dir {
+            Some(stage_dir) if stage::file_path(stage_dir, path).exists() => {
+                stage::file_path(stage_dir, path)
+            }
+            _ => self.workspace_path(path),
+        }
+    }
+
+    /// Path of `path` in the workspace
+    pub fn workspace_path(&self, path: &metadata::CleanPath) -> PathBuf {
+        self.file_manager.dir().join(path)
     }
 
     pub fn tmp_operation_path(&self) -> PathBuf {
@@ -33,6 +71,36 @@ impl<'a> HandlerContext<'a> {
         self.file_manager.download_operation_path(self.package_name, self.operation_idx)
     }
 
+    /// Where the current version of `path` is backed up when replaced
+    ///
+    /// Staged files are backed up when swapped into the workspace.
+    pub fn backup_path(&self, path: &metadata::CleanPath) -> Option<PathBuf> {
+        match (self.backup_dir, self.stage_dir) {
+            (Some(backup_dir), None) => Some(backup::file_path(backup_dir, path)),
+            _ => None,
+        }
+    }
+
+    /// Remove `path` from the workspace, or move it to the backup directory
+    pub fn remove_final_file(&self, path: &metadata::CleanPath) -> io::Result<()> {
+        match self.stage_dir {
+            Some(stage_dir) => stage::remove_file(stage_dir, path, &self.operation_name()),
+            None => remove_final_file(&self.final_path(path), self.backup_path(path).as_deref()),
+        }
+    }
+
+    /// Remove the directory `path` from the workspace
+    pub fn remove_final_dir(&self, path: &metadata::CleanPath) -> io::Result<()> {
+        match self.stage_dir {
+            Some(stage_dir) => stage::remove_dir(stage_dir, path, &self.operation_name()),
+            None => fs::remove_dir(self.final_path(path)),
+        }
+    }
+
+    fn operation_name(&self) -> String {
+        format!("{}-{}", self.package_name, self.operation_idx)
+    }
+
     fn warn_meta(&self, msg: &str) -> io::Result<()> {
         if self.update_options.strict_meta {
             Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
@@ -72,10 +140,10 @@ pub trait ApplyHandler {
     fn download_operation_path(&self) -> PathBuf;
     fn try_still_compatible(&mut self, path: &metadata::CleanPath, operation_idx: usize) -> bool;
 
-    fn add(&mut self, op: &metadata::v1::Add) -> io::Result<Option<Box<dyn Applier + '_>>>;
-    fn patch(&mut self, op: &metadata::v1::Patch) -> io::Result<Option<Box<dyn Applier + '_>>>;
-    fn check(&mut self, op: &metadata::v1::Check) -> io::Result<Option<Box<dyn Applier + '_>>>;
-    fn rm(&mut self, op: &metadata::v1::Rm) -> io::Result<Option<Box<dyn Applier + '_>>>;
+    fn add(&mut self, op: &metadata::v2::Add) -> io::Result<Option<Box<dyn Applier + '_>>>;
+    fn patch(&mut self, op: &metadata::v2::Patch) -> io::Result<Option<Box<dyn Applier + '_>>>;
+    fn check(&mut self, op: &metadata::v2::Check) -> io::Result<Option<Box<dyn Applier + '_>>>;
+    fn rm(&mut self, op: &metadata::v2::Rm) -> io::Result<Option<Box<dyn Applier + '_>>>;
     fn mkdir(&mut self, path: &metadata::CleanPath) -> io::Result<Option<Box<dyn Applier + '_>>>;
     fn rmdir(&mut self, path: &metadata::CleanPath) -> io::Result<Option<Box<dyn Applier + '_>>>;
     fn finalize(self: Box<Self>) -> io::Result<Option<Box<dyn Applier>>>;
@@ -126,15 +194,23 @@ pub trait Applier {
     fn commit(self: Box<Self>) -> io::Result<()>;
 }
 
+fn remove_final_file(final_path: &Path, backup_path: Option<&Path>) -> io::Result<()> {
+    match backup_path {
+        Some(backup_path) => backup::backup_file(final_path, backup_path),
+        None => io::remove_file(final_path),
+    }
+}
+
 /// Simple write Applier
 struct WriteApplier<'a, W> {
     data_size_expected: u64,
-    data_sha1_expected: metadata::Sha1Hash,
+    data_hash_expected: metadata::Hash,
     final_size_expected: u64,
-    final_sha1_expected: metadata::Sha1Hash,
+    final_hash_expected: metadata::Hash,
     final_path: PathBuf,
+    backup_path: Option<PathBuf>,
     tmp_path: PathBuf,
-    decoder: codecs::CheckCoder<'a, W, io::CheckSha1Size>,
+    decoder: codecs::CheckCoder<'a, W, io::CheckHashSize>,
 }
 
 impl<W: io::Write + io::Seek + io::Read> Applier for WriteApplier<'_, W> {
@@ -160,18 +236,18 @@ impl<W: io::Write + io::Seek + io::Read> Applier for WriteApplier<'_, W> {
         self.decoder.flush()?;
 
         let input_checks = self.decoder.input_checks();
-        let data_sha1 = input_checks.sha1();
-        io::assert_eq(&data_sha1, &self.data_sha1_expected, "data sha1")?;
+        let data_hash = input_checks.hash();
+        io::assert_eq(&data_hash, &self.data_hash_expected, "data hash")?;
         let data_size = input_checks.bytes;
         io::assert_eq(data_size, self.data_size_expected, "data size")?;
 
         let mut output_checks = self.decoder.finish()?.check;
-        let final_sha1 = output_checks.sha1();
-        io::assert_eq(&final_sha1, &self.final_sha1_expected, "final sha1")?;
+        let final_hash = output_checks.hash();
+        io::assert_eq(&final_hash, &self.final_hash_expected, "final hash")?;
         let final_size = output_checks.bytes;
         io::assert_eq(final_size, self.final_size_expected, "final size")?;
 
-        io::remove_file(&self.final_path)?;
+        remove_final_file(&self.final_path, self.backup_path.as_deref())?;
         fs::rename(&self.tmp_path, &self.final_path)?;
         Ok(())
     }
@@ -180,16 +256,30 @@ impl<W: io::Write + io::Seek + io::Read> Applier for WriteApplier<'_, W> {
 /// Simple write Applier
 pub struct CheckApplier<R> {
     final_size_expected: u64,
-    final_sha1_expected: metadata::Sha1Hash,
-    r: io::CheckReader<R, io::CheckSha1Size>,
+    final_hash_expected: metadata::Hash,
+    r: io::CheckReader<R, io::CheckHashSize>,
+    preserve: bool,
 }
 
 impl<R> CheckApplier<R> {
-    pub fn new(final_size: u64, final_sha1: metadata::Sha1Hash, r: R) -> Self {
+    pub fn new(final_size: u64, final_hash: metadata::Hash, r: R) -> Self {
         Self {
             final_size_expected: final_size,
-            final_sha1_expected: final_sha1,
-            r: io::CheckReader::new(r),
+            r: io::CheckReader::with_algorithm(r, final_hash.algorithm()),
+            final_hash_expected: final_hash,
+            preserve: false,
+        }
+    }
+
+    /// Mismatches are reported as local modifications
+    pub(crate) fn with_preserve(self, preserve: bool) -> Self {
+        Self { preserve, ..self }
+    }
+
+    fn mismatch(&self, err: io::Error) -> io::Error {
+        match self.preserve {
+            true => preserve::locally_modified(err),
+            false => err,
         }
     }
 }
@@ -210,32 +300,31 @@ impl<R: io::Read> Applier for CheckApplier<R> {
     fn check_bytes(&mut self, buf: &mut [u8]) -> io::Result<u64> {
         let read = self.r.read(buf)?;
         if read == 0 {
-            return Err(io::Error::new(
+            return Err(self.mismatch(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!(
                     "final size mismatch, found: {}, expected: {}",
                     self.r.read_bytes(),
                     self.final_size_expected
                 ),
-            ));
+            )));
         }
 
         Ok(read as u64)
     }
 
     fn commit(mut self: Box<Self>) -> io::Result<()> {
-        io::assert_eq(self.r.read_bytes(), self.final_size_expected, "final size")?;
-        io::assert_eq(&self.r.sha1(), &self.final_sha1_expected, "final sha1")?;
-
-        Ok(())
+        io::assert_eq(self.r.read_bytes(), self.final_size_expected, "final size")
+            .and_then(|()| io::assert_eq(&self.r.hash(), &self.final_hash_expected, "final hash"))
+            .map_err(|err| self.mismatch(err))
     }
 }
 
-impl ApplyOperation for metadata::v1::Operation {
+impl ApplyOperation for metadata::v2::Operation {
     fn apply_handler<'a>(&self, ctx: HandlerContext<'a>) -> io::Result<Box<dyn ApplyHandler + 'a>> {
         if let Some(handler_name) = self.slice_handler() {
             if handler_name.as_str() == "sliced" {
-                let handler = sliced::Handler::from_v1_operation(ctx, self)?;
+                let handler = sliced::Handler::from_operation(ctx, self)?;
                 return Ok(Box::new(handler));
             }
 
@@ -253,12 +342,12 @@ impl ApplyOperation for metadata::v1::Operation {
         handler: &'a mut dyn ApplyHandler,
     ) -> io::Result<Option<Box<dyn Applier + 'a>>> {
         match self {
-            metadata::v1::Operation::Add(op) => handler.add(op),
-            metadata::v1::Operation::Patch(op) => handler.patch(op),
-            metadata::v1::Operation::Check(op) => handler.check(op),
-            metadata::v1::Operation::MkDir { path, .. } => handler.mkdir(path),
-            metadata::v1::Operation::RmDir { path, .. } => handler.rmdir(path),
-            metadata::v1::Operation::Rm(op) => handler.rm(op),
+            metadata::v2::Operation::Add(op) => handler.add(op),
+            metadata::v2::Operation::Patch(op) => handler.patch(op),
+            metadata::v2::Operation::Check(op) => handler.check(op),
+            metadata::v2::Operation::MkDir { path, .. } => handler.mkdir(path),
+            metadata::v2::Operation::RmDir { path, .. } => handler.rmdir(path),
+            metadata::v2::Operation::Rm(op) => handler.rm(op),
         }
     }
 }
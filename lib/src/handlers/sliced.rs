@@ -27,9 +27,9 @@ use crate::io::{self, Read, Write};
 use crate::metadata::{self, Operation};
 
 pub enum HandlerMode {
-    Add { tmp_file: io::CheckWriter<File, io::CheckSha1Size> },
-    Patch { local_file: File, tmp_file: io::CheckWriter<File, io::CheckSha1Size> },
-    Check { local_file: io::CheckReader<File, io::CheckSha1Size> },
+    Add { tmp_file: io::CheckWriter<File, io::CheckHashSize> },
+    Patch { local_file: File, tmp_file: io::CheckWriter<File, io::CheckHashSize> },
+    Check { local_file: io::CheckReader<File, io::CheckHashSize> },
 }
 
 impl fmt::Debug for HandlerMode {
@@ -44,10 +44,10 @@ impl fmt::Debug for HandlerMode {
 
 struct SliceWriteApplier<'a, W> {
     data_size_expected: u64,
-    data_sha1_expected: metadata::Sha1Hash,
+    data_hash_expected: metadata::Hash,
     final_size_expected: u64,
-    final_sha1_expected: metadata::Sha1Hash,
-    decoder: codecs::CheckCoder<'a, &'a mut W, io::CheckSha1Size>,
+    final_hash_expected: metadata::Hash,
+    decoder: codecs::CheckCoder<'a, &'a mut W, io::CheckHashSize>,
 }
 
 impl<'a, W> super::Applier for SliceWriteApplier<'a, W>
@@ -76,14 +76,14 @@ where
         self.decoder.flush()?;
 
         let input_checks = self.decoder.input_checks();
-        let data_sha1 = input_checks.sha1();
-        io::assert_eq(&data_sha1, &self.data_sha1_expected, "data sha1")?;
+        let data_hash = input_checks.hash();
+        io::assert_eq(&data_hash, &self.data_hash_expected, "data hash")?;
         let data_size = input_checks.bytes;
         io::assert_eq(data_size, self.data_size_expected, "data size")?;
 
         let mut output_checks = self.decoder.finish()?.check;
-        let final_sha1 = output_checks.sha1();
-        io::assert_eq(&final_sha1, &self.final_sha1_expected, "final sha1")?;
+        let final_hash = output_checks.hash();
+        io::assert_eq(&final_hash, &self.final_hash_expected, "final hash")?;
         let final_size = output_checks.bytes;
         io::assert_eq(final_size, self.final_size_expected, "final size")?;
 
@@ -93,12 +93,12 @@ where
 
 struct SliceCopyApplier<R, W> {
     size_expected: u64,
-    sha1_expected: metadata::Sha1Hash,
+    hash_expected: metadata::Hash,
     reader: R,
     writer: W,
 }
 
-impl<R, W> super::Applier for SliceCopyApplier<io::CheckReader<R, io::CheckSha1Size>, W>
+impl<R, W> super::Applier for SliceCopyApplier<io::CheckReader<R, io::CheckHashSize>, W>
 where
     R: io::Read,
     W: io::Write,
@@ -131,8 +131,8 @@ where
     }
 
     fn commit(mut self: Box<Self>) -> io::Result<()> {
-        let data_sha1 = self.reader.check.sha1();
-        io::assert_eq(&data_sha1, &self.sha1_expected, "copy sha1")?;
+        let data_hash = self.reader.check.hash();
+        io::assert_eq(&data_hash, &self.hash_expected, "copy hash")?;
         let data_size = self.reader.check.bytes;
         io::assert_eq(data_size, self.size_expected, "copy size")?;
         Ok(())
@@ -143,41 +143,50 @@ pub struct Handler<'a> {
     ctx: HandlerContext<'a>,
     path: metadata::CleanPath,
     final_size_expected: u64,
-    final_sha1_expected: metadata::Sha1Hash,
+    final_hash_expected: metadata::Hash,
     mode: HandlerMode,
 }
 
 impl<'a> Handler<'a> {
-    pub fn from_v1_operation(
+    pub fn from_operation(
         ctx: HandlerContext<'a>,
-        op: &metadata::v1::Operation,
+        op: &metadata::v2::Operation,
     ) -> io::Result<Self> {
         let path = op.path();
-        let (mode, final_size_expected, final_sha1_expected) = match op {
-            metadata::v1::Operation::Add(op) => (
+        let (mode, final_size_expected, final_hash_expected) = match op {
+            metadata::v2::Operation::Add(op) => (
                 HandlerMode::Add {
-                    tmp_file: io::CheckWriter::new(fs::File::create(ctx.tmp_operation_path())?),
+                    tmp_file: io::CheckWriter::with_algorithm(
+                        fs::File::create(ctx.tmp_operation_path())?,
+                        op.final_hash.algorithm(),
+                    ),
                 },
                 op.final_size,
-                op.final_sha1.clone(),
+                op.final_hash.clone(),
             ),
-            metadata::v1::Operation::Patch(op) => (
+            metadata::v2::Operation::Patch(op) => (
                 HandlerMode::Patch {
-                    tmp_file: io::CheckWriter::new(fs::File::create(ctx.tmp_operation_path())?),
+                    tmp_file: io::CheckWriter::with_algorithm(
+                        fs::File::create(ctx.tmp_operation_path())?,
+                        op.final_hash.algorithm(),
+                    ),
                     local_file: fs::OpenOptions::new()
                         .read(true)
                         .write(true)
-                        .open(ctx.final_path(path))?,
+                        .open(ctx.local_path(path))?,
                 },
                 op.final_size,
-                op.final_sha1.clone(),
+                op.final_hash.clone(),
             ),
-            metadata::v1::Operation::Check(op) => (
+            metadata::v2::Operation::Check(op) => (
                 HandlerMode::Check {
-                    local_file: io::CheckReader::new(fs::File::open(ctx.final_path(path))?),
+                    local_file: io::CheckReader::with_algorithm(
+                        fs::File::open(ctx.local_path(path))?,
+                        op.local_hash.algorithm(),
+                    ),
                 },
                 op.local_size,
-                op.local_sha1.clone(),
+                op.local_hash.clone(),
             ),
             _ => {
                 return Err(io::Error::new(
@@ -186,7 +195,7 @@ impl<'a> Handler<'a> {
                 ))
             }
         };
-        Ok(Self { ctx, path: path.clone(), mode, final_size_expected, final_sha1_expected })
+        Ok(Self { ctx, path: path.clone(), mode, final_size_expected, final_hash_expected })
     }
 }
 
@@ -200,7 +209,7 @@ impl<'a> super::ApplyHandler for Handler<'a> {
         &self.path == path
     }
 
-    fn add(&mut self, op: &metadata::v1::Add) -> io::Result<Option<Box<dyn Applier + '_>>> {
+    fn add(&mut self, op: &metadata::v2::Add) -> io::Result<Option<Box<dyn Applier + '_>>> {
         let slice = match &op.common.slice {
             None => return Ok(None),
             Some(slice) => slice,
@@ -208,12 +217,15 @@ impl<'a> super::ApplyHandler for Handler<'a> {
 
         match &mut self.mode {
             HandlerMode::Add { tmp_file } | HandlerMode::Patch { tmp_file, .. } => {
-                let decoder = CheckCoder::decoder(&op.data_compression, tmp_file)?;
+                let decoder = CheckCoder::decoder(&op.data_compression, tmp_file)?.with_checks(
+                    io::CheckHashSize::new(op.data_hash.algorithm()),
+                    io::CheckHashSize::new(op.final_hash.algorithm()),
+                );
                 let applier = SliceWriteApplier {
                     data_size_expected: op.data_size,
-                    data_sha1_expected: op.data_sha1.clone(),
+                    data_hash_expected: op.data_hash.clone(),
                     final_size_expected: op.final_size,
-                    final_sha1_expected: op.final_sha1.clone(),
+                    final_hash_expected: op.final_hash.clone(),
                     decoder,
                 };
                 Ok(Some(Box::new(applier)))
@@ -228,7 +240,7 @@ impl<'a> super::ApplyHandler for Handler<'a> {
         }
     }
 
-    fn patch(&mut self, op: &metadata::v1::Patch) -> io::Result<Option<Box<dyn Applier + '_>>> {
+    fn patch(&mut self, op: &metadata::v2::Patch) -> io::Result<Option<Box<dyn Applier + '_>>> {
         let slice = match &op.common.slice {
             None => return Ok(None),
             Some(slice) => slice,
@@ -242,12 +254,16 @@ impl<'a> super::ApplyHandler for Handler<'a> {
                     &op.patch_type,
                     local_slice,
                     tmp_file,
-                )?;
+                )?
+                .with_checks(
+                    io::CheckHashSize::new(op.data_hash.algorithm()),
+                    io::CheckHashSize::new(op.final_hash.algorithm()),
+                );
                 let applier = SliceWriteApplier {
                     data_size_expected: op.data_size,
-                    data_sha1_expected: op.data_sha1.clone(),
+                    data_hash_expected: op.data_hash.clone(),
                     final_size_expected: op.final_size,
-                    final_sha1_expected: op.final_sha1.clone(),
+                    final_hash_expected: op.final_hash.clone(),
                     decoder,
                 };
                 Ok(Some(Box::new(applier)))
@@ -269,7 +285,7 @@ impl<'a> super::ApplyHandler for Handler<'a> {
         }
     }
 
-    fn check(&mut self, op: &metadata::v1::Check) -> io::Result<Option<Box<dyn Applier + '_>>> {
+    fn check(&mut self, op: &metadata::v2::Check) -> io::Result<Option<Box<dyn Applier + '_>>> {
         let slice = match &op.common.slice {
             None => return Ok(None), // Check integrity at finalize step
             Some(slice) => slice,
@@ -291,8 +307,8 @@ impl<'a> super::ApplyHandler for Handler<'a> {
                 let local_slice = io::Slice::new(local_file, op.local_offset, op.local_size)?;
                 let applier = SliceCopyApplier {
                     size_expected: op.local_size,
-                    sha1_expected: op.local_sha1.clone(),
-                    reader: io::CheckReader::new(local_slice),
+                    hash_expected: op.local_hash.clone(),
+                    reader: io::CheckReader::with_algorithm(local_slice, op.local_hash.algorithm()),
                     writer: tmp_file,
                 };
                 Ok(Some(Box::new(applier)))
@@ -300,13 +316,13 @@ impl<'a> super::ApplyHandler for Handler<'a> {
             HandlerMode::Check { local_file } => {
                 io::assert_eq(local_file.check.bytes, op.local_offset, "slice local offset")?;
                 let local_slice = local_file.take(op.local_size);
-                let applier = CheckApplier::new(op.local_size, op.local_sha1.clone(), local_slice);
+                let applier = CheckApplier::new(op.local_size, op.local_hash.clone(), local_slice);
                 Ok(Some(Box::new(applier)))
             }
         }
     }
 
-    fn rm(&mut self, op: &metadata::v1::Rm) -> io::Result<Option<Box<dyn Applier>>> {
+    fn rm(&mut self, op: &metadata::v2::Rm) -> io::Result<Option<Box<dyn Applier>>> {
         if op.slice.is_none() {
             self.ctx.warn_meta(&format!(
                 "rm {} is not a valid sliced operation without slice",
@@ -332,11 +348,11 @@ impl<'a> super::ApplyHandler for Handler<'a> {
                 let mut output_checks = tmp_file.check;
                 let final_size = output_checks.bytes;
                 io::assert_eq(final_size, self.final_size_expected, "file size")?;
-                let final_sha1 = output_checks.sha1();
-                io::assert_eq(&final_sha1, &self.final_sha1_expected, "file sha1")?;
+                let final_hash = output_checks.hash();
+                io::assert_eq(&final_hash, &self.final_hash_expected, "file hash")?;
 
-                let final_path = self.ctx.final_path(&self.path);
-                io::remove_file(&final_path)?;
+                let final_path = self.ctx.create_final_path(&self.path)?;
+                super::remove_final_file(&final_path, self.ctx.backup_path(&self.path).as_deref())?;
                 fs::rename(&self.ctx.tmp_operation_path(), &final_path)?;
 
                 Ok(None)
@@ -344,8 +360,8 @@ impl<'a> super::ApplyHandler for Handler<'a> {
             HandlerMode::Check { mut local_file } => {
                 let local_size = local_file.check.bytes;
                 io::assert_eq(local_size, self.final_size_expected, "file size")?;
-                let local_sha1 = local_file.check.sha1();
-                io::assert_eq(&local_sha1, &self.final_sha1_expected, "file sha1")?;
+                let local_hash = local_file.check.hash();
+                io::assert_eq(&local_hash, &self.final_hash_expected, "file hash")?;
 
                 Ok(None)
             }
@@ -4,8 +4,9 @@ use std::path::Path;
 use std::{fmt, fs};
 
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 
-use crate::metadata::Sha1Hash;
+use crate::metadata::{Hash, HashAlgorithm};
 
 /// Buffer size to use in the whole library
 pub const BUFFER_SIZE: usize = 128 * 1024;
@@ -32,6 +33,25 @@ impl<T: Read + Seek> ReadSlice for T {
     }
 }
 
+/// The FileRegion trait exposes the file region backing a reader, so codecs
+/// can memory map it instead of loading it in memory.
+pub trait FileRegion {
+    /// Returns the backing file, the offset and the size of the region.
+    fn file_region(&self) -> Result<(&fs::File, u64, u64)>;
+}
+
+impl FileRegion for fs::File {
+    fn file_region(&self) -> Result<(&fs::File, u64, u64)> {
+        Ok((self, 0, self.metadata()?.len()))
+    }
+}
+
+impl<T: FileRegion> FileRegion for &mut T {
+    fn file_region(&self) -> Result<(&fs::File, u64, u64)> {
+        (**self).file_region()
+    }
+}
+
 pub trait Check {
     fn check(&mut self, buf: &[u8]);
 }
@@ -48,22 +68,83 @@ impl Check for CheckSize {
     }
 }
 
-#[derive(Default)]
-pub struct CheckSha1Size {
-    pub sha1: Sha1,
+/// Incremental hasher of one of the supported hash algorithms
+#[derive(Clone)]
+pub enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            Hasher::Sha1(_) => HashAlgorithm::Sha1,
+            Hasher::Sha256(_) => HashAlgorithm::Sha256,
+            Hasher::Blake3(_) => HashAlgorithm::Blake3,
+        }
+    }
+
+    #[inline]
+    pub fn update(&mut self, buf: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(buf),
+            Hasher::Sha256(hasher) => hasher.update(buf),
+            Hasher::Blake3(hasher) => {
+                hasher.update(buf);
+            }
+        }
+    }
+
+    pub fn finalize_reset(&mut self) -> Hash {
+        let hash = match self {
+            Hasher::Sha1(hasher) => hasher.finalize_reset().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize_reset().to_vec(),
+            Hasher::Blake3(hasher) => {
+                let hash = hasher.finalize().as_bytes().to_vec();
+                hasher.reset();
+                hash
+            }
+        };
+        Hash::new(self.algorithm(), hash).expect("hasher output to match its algorithm")
+    }
+}
+
+/// Compute the hash and count bytes, sha1 by default
+#[derive(Clone)]
+pub struct CheckHashSize {
+    pub hasher: Hasher,
     pub bytes: u64,
 }
 
-impl CheckSha1Size {
-    pub fn sha1(&mut self) -> Sha1Hash {
-        Sha1Hash::new(self.sha1.finalize_reset().into())
+impl CheckHashSize {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        Self { hasher: Hasher::new(algorithm), bytes: 0 }
+    }
+
+    pub fn hash(&mut self) -> Hash {
+        self.hasher.finalize_reset()
     }
 }
 
-impl Check for CheckSha1Size {
+impl Default for CheckHashSize {
+    fn default() -> Self {
+        Self::new(HashAlgorithm::Sha1)
+    }
+}
+
+impl Check for CheckHashSize {
     #[inline]
     fn check(&mut self, buf: &[u8]) {
-        self.sha1.update(buf);
+        self.hasher.update(buf);
         self.bytes += buf.len() as u64;
     }
 }
@@ -74,19 +155,17 @@ pub struct CheckReader<R, C> {
     pub check: C,
 }
 
-impl<R, C: Default> CheckReader<R, C> {
-    pub fn new(reader: R) -> Self {
-        CheckReader { reader, check: C::default() }
+impl<R> CheckReader<R, CheckHashSize> {
+    pub fn with_algorithm(reader: R, algorithm: HashAlgorithm) -> Self {
+        CheckReader { reader, check: CheckHashSize::new(algorithm) }
     }
-}
 
-impl<R> CheckReader<R, CheckSha1Size> {
     pub fn read_bytes(&self) -> u64 {
         self.check.bytes
     }
 
-    pub fn sha1(&mut self) -> Sha1Hash {
-        self.check.sha1()
+    pub fn hash(&mut self) -> Hash {
+        self.check.hash()
     }
 }
 
@@ -121,6 +200,12 @@ impl<W, C: Default> CheckWriter<W, C> {
     }
 }
 
+impl<W> CheckWriter<W, CheckHashSize> {
+    pub fn with_algorithm(writer: W, algorithm: HashAlgorithm) -> Self {
+        CheckWriter { writer, check: CheckHashSize::new(algorithm) }
+    }
+}
+
 impl<T, C> Write for CheckWriter<T, C>
 where
     T: Write,
@@ -269,6 +354,13 @@ impl<T: Seek> Seek for Slice<T> {
     }
 }
 
+impl<T: FileRegion> FileRegion for Slice<T> {
+    fn file_region(&self) -> Result<(&fs::File, u64, u64)> {
+        let (file, offset, _) = self.inner.file_region()?;
+        Ok((file, offset + self.offset, self.size))
+    }
+}
+
 impl<T: Read> Read for Slice<T> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         // Don't call into inner reader at all at EOF because it may still block
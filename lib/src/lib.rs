@@ -5,6 +5,7 @@ mod io;
 pub mod link;
 pub mod metadata;
 pub mod repository;
+pub mod signature;
 mod sync;
 pub mod workspace;
 
@@ -116,6 +117,18 @@ pub mod tests {
         path
     }
 
+    /// `len` deterministic bytes that don't compress (xorshift of `seed`)
+    pub fn pseudo_random(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
     pub fn data(name: &str) -> PathBuf {
         let path = PathBuf::from("tests/data").join(name);
         path
@@ -0,0 +1,270 @@
+//! Responses to multi-range requests (`Range: bytes=a-b,c-d,...`)
+//!
+//! Servers answer with a `multipart/byteranges` body, or with a single range
+//! if they coalesced the requested ranges. Both are turned into chunks tagged
+//! with their offset in the file.
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use bytes::{Buf, Bytes, BytesMut};
+use futures::prelude::*;
+
+use super::{RepositoryError, RepositoryStream};
+
+/// Maximum size of the headers of a part
+const MAX_PART_HEADERS_SIZE: usize = 16 * 1024;
+
+fn invalid(reason: &str) -> RepositoryError {
+    RepositoryError::InvalidRanges { reason: reason.to_owned() }
+}
+
+/// Value of the `Range` header requesting `ranges`, `None` if they are all
+/// empty
+///
+/// Empty ranges can't be written in a `Range` header, they are skipped.
+pub(super) fn range_header(ranges: &[Range<u64>]) -> Option<String> {
+    let specs: Vec<String> = ranges
+        .iter()
+        .filter(|range| !range.is_empty())
+        .map(|range| format!("{}-{}", range.start, range.end - 1))
+        .collect();
+    match specs.is_empty() {
+        true => None,
+        false => Some(format!("bytes={}", specs.join(","))),
+    }
+}
+
+/// Boundary of a `multipart/byteranges` content type
+pub(super) fn boundary(content_type: &str) -> Option<&str> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/byteranges") {
+        return None;
+    }
+    params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        match name.trim().eq_ignore_ascii_case("boundary") {
+            true => Some(value.trim().trim_matches('"')),
+            false => None,
+        }
+    })
+}
+
+/// Range of a `Content-Range: bytes a-b/len` header value
+pub(super) fn content_range(value: &str) -> Option<Range<u64>> {
+    let (range, _len) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let (first, last): (u64, u64) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+    match first <= last {
+        true => Some(first..last + 1),
+        false => None,
+    }
+}
+
+/// Chunks of a single range body starting at `offset`
+pub(super) fn single_part(
+    body: RepositoryStream<Bytes>,
+    offset: u64,
+) -> RepositoryStream<(u64, Bytes)> {
+    let mut offset = offset;
+    body.map_ok(move |chunk| {
+        let chunk_offset = offset;
+        offset += chunk.len() as u64;
+        (chunk_offset, chunk)
+    })
+    .boxed_local()
+}
+
+struct Parts {
+    body: RepositoryStream<Bytes>,
+    delimiter: Vec<u8>,
+    /// Received bytes not parsed yet
+    pending: Bytes,
+    /// Remaining range of the current part body
+    part: Option<Range<u64>>,
+}
+
+impl Parts {
+    async fn fill(&mut self) -> Result<(), RepositoryError> {
+        match self.body.try_next().await? {
+            Some(chunk) => {
+                self.pending = chunk;
+                Ok(())
+            }
+            None => Err(invalid("truncated multipart/byteranges body")),
+        }
+    }
+
+    /// Parse the next part headers, returns `None` after the last part
+    async fn next_part(&mut self) -> Result<Option<Range<u64>>, RepositoryError> {
+        let mut headers = BytesMut::new();
+        loop {
+            headers.extend_from_slice(&self.pending);
+            self.pending = Bytes::new();
+            if let Some(start) = find(&headers, &self.delimiter) {
+                let after = &headers[start + self.delimiter.len()..];
+                if after.starts_with(b"--") {
+                    return Ok(None);
+                }
+                if let Some(end) = find(after, b"\r\n\r\n") {
+                    let part_headers = String::from_utf8_lossy(&after[..end]);
+                    let range = part_headers
+                        .lines()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-range"))
+                        .and_then(|(_, value)| content_range(value))
+                        .ok_or_else(|| invalid("part without a valid content-range"))?;
+                    headers.advance(start + self.delimiter.len() + end + 4);
+                    self.pending = headers.freeze();
+                    return Ok(Some(range));
+                }
+            }
+            if headers.len() > MAX_PART_HEADERS_SIZE {
+                return Err(invalid("multipart/byteranges part headers are too big"));
+            }
+            self.fill().await?;
+        }
+    }
+
+    async fn next_chunk(&mut self) -> Result<Option<(u64, Bytes)>, RepositoryError> {
+        loop {
+            let part = match self.part.clone() {
+                Some(part) if part.start < part.end => part,
+                _ => match self.next_part().await? {
+                    Some(part) => {
+                        self.part = Some(part);
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+            };
+            if self.pending.is_empty() {
+                self.fill().await?;
+            }
+            let len = self.pending.len().min((part.end - part.start) as usize);
+            self.part = Some(part.start + len as u64..part.end);
+            return Ok(Some((part.start, self.pending.split_to(len))));
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Chunks of the parts of a `multipart/byteranges` body
+pub(super) fn multipart(
+    body: RepositoryStream<Bytes>,
+    boundary: &str,
+) -> RepositoryStream<(u64, Bytes)> {
+    let parts = Parts {
+        body,
+        delimiter: format!("--{}", boundary).into_bytes(),
+        pending: Bytes::new(),
+        part: None,
+    };
+    stream::try_unfold(parts, |mut parts| async move {
+        Ok(parts.next_chunk().await?.map(|chunk| (chunk, parts)))
+    })
+    .boxed_local()
+}
+
+/// Requested ranges, the next expected byte is `ranges[0].start`
+struct Selection {
+    ranges: VecDeque<Range<u64>>,
+}
+
+impl Selection {
+    /// Requested pieces of `chunk`
+    fn select(
+        &mut self,
+        mut offset: u64,
+        mut chunk: Bytes,
+    ) -> Result<Vec<(u64, Bytes)>, RepositoryError> {
+        let mut selected = Vec::new();
+        while let Some(range) = self.ranges.front_mut() {
+            let chunk_end = offset + chunk.len() as u64;
+            if chunk_end <= range.start {
+                // not requested or already received
+                break;
+            }
+            if offset > range.start {
+                return Err(invalid("requested bytes are missing"));
+            }
+            chunk.advance((range.start - offset) as usize);
+            let len = chunk.len().min((range.end - range.start) as usize);
+            selected.push((range.start, chunk.split_to(len)));
+            offset = range.start + len as u64;
+            range.start = offset;
+            if range.start == range.end {
+                self.ranges.pop_front();
+            }
+        }
+        Ok(selected)
+    }
+}
+
+/// Keep the bytes of `ranges` (in order) from `chunks`
+///
+/// Servers may coalesce ranges or send more bytes than requested.
+pub(super) fn select(
+    chunks: RepositoryStream<(u64, Bytes)>,
+    ranges: Vec<Range<u64>>,
+) -> RepositoryStream<(u64, Bytes)> {
+    let selection = Selection { ranges: ranges.into() };
+    stream::try_unfold(
+        (chunks, selection, VecDeque::new()),
+        |(mut chunks, mut selection, mut selected)| async move {
+            loop {
+                if let Some(chunk) = selected.pop_front() {
+                    return Ok(Some((chunk, (chunks, selection, selected))));
+                }
+                if selection.ranges.is_empty() {
+                    return Ok(None);
+                }
+                match chunks.try_next().await? {
+                    Some((offset, chunk)) => selected.extend(selection.select(offset, chunk)?),
+                    None => return Err(invalid("requested bytes are missing")),
+                }
+            }
+        },
+    )
+    .boxed_local()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multipart_byteranges() {
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        assert_eq!(boundary("multipart/byteranges; boundary=\"3d6b\""), Some("3d6b"));
+        assert_eq!(boundary("application/octet-stream"), None);
+        assert_eq!(range_header(&[0..10, 15..15, 20..21]).unwrap(), "bytes=0-9,20-20");
+        assert_eq!(range_header(&[3..3, 7..7]), None);
+
+        let body = "\r\n--3d6b\r\nContent-Type: application/octet-stream\r\n\
+            Content-Range: bytes 0-3/100\r\n\r\n0123\r\n--3d6b\r\n\
+            Content-Range: bytes 10-15/100\r\n\r\nabcdef\r\n--3d6b--\r\n";
+        // split the body in small chunks to cross every boundary
+        let chunks: Vec<Result<Bytes, RepositoryError>> =
+            body.as_bytes().chunks(5).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect();
+        let parts = multipart(stream::iter(chunks).boxed_local(), "3d6b");
+        let selected = select(parts, vec![1..3, 10..11, 13..16]);
+        let selected: Vec<(u64, Bytes)> = rt.block_on(selected.try_collect()).unwrap();
+        let mut data: Vec<(u64, Vec<u8>)> = Vec::new();
+        for (offset, chunk) in selected {
+            match data.last_mut() {
+                Some((start, last)) if *start + last.len() as u64 == offset => last.extend(chunk),
+                _ => data.push((offset, chunk.to_vec())),
+            }
+        }
+        assert_eq!(data, vec![(1, b"12".to_vec()), (10, b"a".to_vec()), (13, b"def".to_vec())]);
+
+        // a coalesced single range missing requested bytes
+        let body = stream::iter(vec![Ok(Bytes::from_static(b"0123"))]).boxed_local();
+        let selected = select(single_part(body, 0), vec![1..2, 6..8]);
+        let res: Result<Vec<_>, _> = rt.block_on(selected.try_collect());
+        assert!(matches!(res, Err(RepositoryError::InvalidRanges { .. })));
+    }
+}
@@ -7,16 +7,23 @@ use futures::prelude::*;
 use serde_json;
 use tokio::io::AsyncSeekExt;
 
-use crate::link::{RemoteRepository, RepositoryError, RepositoryStream};
+use crate::link::{verify_signature, RemoteRepository, RepositoryError, RepositoryStream};
 use crate::metadata;
+use crate::signature::{signature_filename, PublicKey};
 
 pub struct FileRepository {
     dir: PathBuf,
+    trusted_key: Option<PublicKey>,
 }
 
 impl FileRepository {
     pub fn new(dir: PathBuf) -> FileRepository {
-        FileRepository { dir }
+        FileRepository { dir, trusted_key: None }
+    }
+
+    /// Require metadata files to be signed by `trusted_key`
+    pub fn set_trusted_key(&mut self, trusted_key: Option<PublicKey>) {
+        self.trusted_key = trusted_key;
     }
 
     async fn get<T>(&self, file_name: &str) -> Result<T, RepositoryError>
@@ -24,7 +31,11 @@ impl FileRepository {
         T: for<'de> serde::Deserialize<'de>,
     {
         let path = self.dir.join(&file_name);
-        let raw = tokio::fs::read(&path).await.map_err(|err| RepositoryError::file(&path, err))?;
+        let raw = self.file(file_name).await?;
+        if let Some(trusted_key) = &self.trusted_key {
+            let signature = self.file(&signature_filename(file_name)).await;
+            verify_signature(trusted_key, file_name, &raw, signature)?;
+        }
         let decoded =
             serde_json::from_slice::<T>(&raw).map_err(|err| RepositoryError::json(&path, err))?;
         Ok(decoded)
@@ -33,6 +44,12 @@ impl FileRepository {
 
 #[async_trait]
 impl RemoteRepository for FileRepository {
+    async fn file(&self, name: &str) -> Result<Bytes, RepositoryError> {
+        let path = self.dir.join(name);
+        let raw = tokio::fs::read(&path).await.map_err(|err| RepositoryError::file(&path, err))?;
+        Ok(Bytes::from(raw))
+    }
+
     async fn current_version(&self) -> Result<metadata::Current, RepositoryError> {
         self.get(metadata::Current::filename()).await
     }
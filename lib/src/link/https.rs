@@ -1,47 +1,245 @@
 use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::prelude::*;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tracing::warn;
 
-use crate::link::{RemoteRepository, RepositoryError, RepositoryStream};
+use super::byteranges;
+use crate::link::{fetch_json, RemoteRepository, RepositoryError, RepositoryStream};
 use crate::metadata;
+use crate::signature::PublicKey;
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
+/// Credentials added to every request
+#[derive(Debug, Clone)]
+pub enum Token {
+    /// Sent as an `Authorization: Bearer <token>` header
+    Bearer(String),
+    /// Appended to the query string of urls (i.e. `Expires=...&Signature=...`)
+    Query(String),
+}
+
+/// Returns the token of the next request, it's called before every request
+/// so expired tokens can be refreshed
+pub type TokenProvider = Arc<dyn Fn() -> Option<Token> + Send + Sync>;
+
+/// HTTP client options of [`HttpsRepository`]
+#[derive(Clone, Default)]
+pub struct HttpsRepositoryOptions {
+    proxy: Option<String>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    headers: Vec<(String, String)>,
+    token_provider: Option<TokenProvider>,
+    user_agent: Option<String>,
+    root_certificates: Vec<Vec<u8>>,
+}
+
+impl HttpsRepositoryOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send every request through the proxy `url` (`http://`, `https://` or
+    /// `socks5://`), instead of the one of the environment
+    pub fn proxy(mut self, url: impl Into<String>) -> Self {
+        self.proxy = Some(url.into());
+        self
+    }
+
+    /// Maximum duration to establish a connection
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Maximum duration to wait for the response headers or the next body
+    /// chunk, long downloads aren't limited as long as bytes are received
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Add the header `name: value` to every request
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Authenticate every request with the token `provider` returns
+    pub fn token_provider<F>(mut self, provider: F) -> Self
+    where
+        F: Fn() -> Option<Token> + Send + Sync + 'static,
+    {
+        self.token_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Replace the default `speedupdate/<version>` user agent
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Trust the root certificates of `certificate` (PEM bundle or DER), in
+    /// addition to the system ones
+    pub fn root_certificate(mut self, certificate: impl Into<Vec<u8>>) -> Self {
+        self.root_certificates.push(certificate.into());
+        self
+    }
+
+    fn client(&self) -> Result<reqwest::Client, RepositoryError> {
+        let user_agent = self.user_agent.as_deref().unwrap_or(APP_USER_AGENT);
+        let mut builder = reqwest::Client::builder().user_agent(user_agent);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.headers.iter() {
+            let invalid = || RepositoryError::InvalidOption {
+                reason: format!("invalid header {}: {}", name, value),
+            };
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+            let value = HeaderValue::from_str(value).map_err(|_| invalid())?;
+            headers.append(name, value);
+        }
+        builder = builder.default_headers(headers);
+        for certificate in self.root_certificates.iter() {
+            let certificates = match certificate.starts_with(b"-----BEGIN") {
+                true => reqwest::Certificate::from_pem_bundle(certificate)?,
+                false => vec![reqwest::Certificate::from_der(certificate)?],
+            };
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        Ok(builder.build()?)
+    }
+}
+
 pub struct HttpsRepository {
     client: reqwest::Client,
     remote_url: reqwest::Url,
+    trusted_key: Option<PublicKey>,
+    read_timeout: Option<Duration>,
+    token_provider: Option<TokenProvider>,
+    /// The server ignored a multi-range request
+    single_range: AtomicBool,
 }
 
 impl HttpsRepository {
     pub fn new(remote_url: reqwest::Url) -> Result<Self, RepositoryError> {
-        let client = reqwest::Client::builder().user_agent(APP_USER_AGENT).build()?;
-        Ok(HttpsRepository { client, remote_url })
+        Self::with_options(remote_url, HttpsRepositoryOptions::default())
+    }
+
+    pub fn with_options(
+        remote_url: reqwest::Url,
+        options: HttpsRepositoryOptions,
+    ) -> Result<Self, RepositoryError> {
+        Ok(HttpsRepository {
+            client: options.client()?,
+            remote_url,
+            trusted_key: None,
+            read_timeout: options.read_timeout,
+            token_provider: options.token_provider,
+            single_range: AtomicBool::new(false),
+        })
+    }
+
+    /// Require metadata files to be signed by `trusted_key`
+    pub fn set_trusted_key(&mut self, trusted_key: Option<PublicKey>) {
+        self.trusted_key = trusted_key;
     }
 
     fn get(&self, slice: &str) -> Result<reqwest::RequestBuilder, RepositoryError> {
-        let url = self
+        let mut url = self
             .remote_url
             .join(slice)
             .map_err(|err| RepositoryError::InvalidUrl { reason: err.to_string() })?;
+        let token = self.token_provider.as_ref().and_then(|provider| provider());
+        if let Some(Token::Query(query)) = &token {
+            let query = match url.query() {
+                Some(url_query) => format!("{}&{}", url_query, query),
+                None => query.clone(),
+            };
+            url.set_query(Some(&query));
+        }
         let builder = self.client.get(url);
-        Ok(builder)
+        Ok(match token {
+            Some(Token::Bearer(token)) => builder.bearer_auth(token),
+            _ => builder,
+        })
+    }
+
+    /// Wait for `future` at most the read timeout
+    async fn timeout<T>(
+        &self,
+        future: impl Future<Output = reqwest::Result<T>>,
+    ) -> Result<T, RepositoryError> {
+        let res = match self.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, future)
+                .await
+                .map_err(|_| RepositoryError::HttpsReadTimeout(timeout))?,
+            None => future.await,
+        };
+        Ok(res?)
+    }
+
+    async fn send(
+        &self,
+        builder: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, RepositoryError> {
+        let request = builder.build()?;
+        let response = self.timeout(self.client.execute(request)).await?;
+        Ok(response.error_for_status()?)
+    }
+
+    /// Body of `response`, failing if no chunk is received within the read
+    /// timeout
+    fn body(&self, response: reqwest::Response) -> RepositoryStream<Bytes> {
+        let body = response.bytes_stream().err_into::<RepositoryError>().boxed_local();
+        let timeout = match self.read_timeout {
+            Some(timeout) => timeout,
+            None => return body,
+        };
+        stream::try_unfold(body, move |mut body| async move {
+            match tokio::time::timeout(timeout, body.try_next()).await {
+                Ok(chunk) => Ok(chunk?.map(|chunk| (chunk, body))),
+                Err(_) => Err(RepositoryError::HttpsReadTimeout(timeout)),
+            }
+        })
+        .boxed_local()
     }
 
     async fn get_json<T>(&self, slice: &str) -> Result<T, RepositoryError>
     where
         T: for<'de> serde::Deserialize<'de>,
     {
-        let request = self.get(slice)?.build()?;
-        let response = self.client.execute(request).await?.error_for_status()?;
-        let json = response.json().await?;
-        Ok(json)
+        if self.trusted_key.is_none() {
+            let response = self.send(self.get(slice)?).await?;
+            let json = self.timeout(response.json()).await?;
+            return Ok(json);
+        }
+        fetch_json(self, slice, self.trusted_key.as_ref()).await
     }
 }
 
 #[async_trait]
 impl RemoteRepository for HttpsRepository {
+    async fn file(&self, name: &str) -> Result<Bytes, RepositoryError> {
+        let response = self.send(self.get(name)?).await?;
+        self.timeout(response.bytes()).await
+    }
+
     async fn current_version(&self) -> Result<metadata::Current, RepositoryError> {
         self.get_json(metadata::Current::filename()).await
     }
@@ -66,17 +264,64 @@ impl RemoteRepository for HttpsRepository {
         package_name: metadata::CleanName,
         range: Range<u64>,
     ) -> Result<RepositoryStream<Bytes>, RepositoryError> {
-        let request = self
-            .get(&package_name)?
-            .header(reqwest::header::RANGE, format!("bytes={}-{}", range.start, range.end))
-            .build()?;
+        let range_header = match byteranges::range_header(&[range]) {
+            Some(range_header) => range_header,
+            None => return Ok(stream::empty().boxed_local()),
+        };
+        let request = self.get(&package_name)?.header(reqwest::header::RANGE, range_header);
+
+        let response = self.send(request).await?;
+
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Err(RepositoryError::HttpsNotPartialContent(response.status()));
+        }
 
-        let response = self.client.execute(request).await?.error_for_status()?;
+        Ok(self.body(response))
+    }
 
+    async fn package_ranges(
+        &self,
+        package_name: metadata::CleanName,
+        ranges: Vec<Range<u64>>,
+    ) -> Result<Option<RepositoryStream<(u64, Bytes)>>, RepositoryError> {
+        if self.single_range.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let ranges: Vec<_> = ranges.into_iter().filter(|range| !range.is_empty()).collect();
+        let range_header = match byteranges::range_header(&ranges) {
+            Some(range_header) => range_header,
+            None => return Ok(Some(stream::empty().boxed_local())),
+        };
+        let request = self.get(&package_name)?.header(reqwest::header::RANGE, range_header);
+
+        let response = self.send(request).await?;
+
+        if response.status() == reqwest::StatusCode::OK {
+            warn!("{} doesn't support multi-range requests", self.remote_url);
+            self.single_range.store(true, Ordering::Relaxed);
+            return Ok(None);
+        }
         if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
             return Err(RepositoryError::HttpsNotPartialContent(response.status()));
         }
 
-        Ok(response.bytes_stream().err_into::<RepositoryError>().boxed_local())
+        let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok());
+        let boundary = header(reqwest::header::CONTENT_TYPE)
+            .and_then(byteranges::boundary)
+            .map(|boundary| boundary.to_owned());
+        let content_range =
+            header(reqwest::header::CONTENT_RANGE).and_then(byteranges::content_range);
+        let body = self.body(response);
+        let chunks = match (boundary, content_range) {
+            (Some(boundary), _) => byteranges::multipart(body, &boundary),
+            // the server coalesced the ranges
+            (None, Some(content_range)) => byteranges::single_part(body, content_range.start),
+            (None, None) => {
+                return Err(RepositoryError::InvalidRanges {
+                    reason: "partial content without content-range".to_owned(),
+                })
+            }
+        };
+        Ok(Some(byteranges::select(chunks, ranges)))
     }
 }
@@ -0,0 +1,539 @@
+use std::future::Future;
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::prelude::*;
+use parking_lot::Mutex;
+use tracing::warn;
+
+use crate::link::{RemoteRepository, RepositoryError, RepositoryStream};
+use crate::metadata;
+use crate::signature::signature_filename;
+
+/// Minimum number of bytes a download must transfer to measure the throughput
+const MIN_MEASURED_BYTES: u64 = 64 * 1024;
+
+/// Weight of a new throughput measure in the mirror average
+const THROUGHPUT_SMOOTHING: f64 = 0.3;
+
+/// Delay after which a mirror failure is forgiven
+const FAILURE_DECAY: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct MirrorStats {
+    /// Average throughput in bytes/s, `None` until measured
+    throughput: Option<f64>,
+    /// Number of running downloads
+    in_flight: usize,
+    /// Number of consecutive failures, see [`failures`](Self::failures)
+    failures: u32,
+    last_failure: Option<Instant>,
+}
+
+impl MirrorStats {
+    /// Number of consecutive failures, minus one per `FAILURE_DECAY` since the
+    /// last one so that a mirror isn't ignored forever
+    fn failures(&self) -> u32 {
+        let forgiven = match self.last_failure {
+            Some(last_failure) => last_failure.elapsed().as_secs() / FAILURE_DECAY.as_secs(),
+            None => 0,
+        };
+        self.failures.saturating_sub(forgiven.min(u32::MAX as u64) as u32)
+    }
+
+    fn failed(&mut self) {
+        self.failures = self.failures() + 1;
+        self.last_failure = Some(Instant::now());
+    }
+
+    /// Expected throughput of a new download on this mirror
+    fn score(&self) -> f64 {
+        let failures = self.failures();
+        let throughput = match (self.throughput, failures) {
+            (Some(throughput), _) => throughput,
+            // try unmeasured mirrors first
+            (None, 0) => f64::MAX,
+            (None, _) => 0.0,
+        };
+        throughput / (self.in_flight + 1) as f64 / 2f64.powi(failures.min(16) as i32)
+    }
+
+    fn measure(&mut self, bytes: u64, elapsed: f64) {
+        if bytes >= MIN_MEASURED_BYTES && elapsed > 0.0 {
+            let sample = bytes as f64 / elapsed;
+            self.throughput = Some(match self.throughput {
+                Some(throughput) => {
+                    throughput * (1.0 - THROUGHPUT_SMOOTHING) + sample * THROUGHPUT_SMOOTHING
+                }
+                None => sample,
+            });
+        }
+    }
+}
+
+struct Mirror<R> {
+    repository: R,
+    stats: Arc<Mutex<MirrorStats>>,
+}
+
+/// Remote repository over multiple mirrors of the same repository
+///
+/// Metadata requests fail over to the next mirror on errors, package ranges
+/// are spread across mirrors according to their measured throughput.
+///
+/// The `current` version always comes from the primary mirror, so a stale
+/// mirror cannot roll clients back.
+pub struct MirroredRepository<R> {
+    mirrors: Vec<Mirror<R>>,
+}
+
+impl<R> MirroredRepository<R>
+where
+    R: RemoteRepository + Send + Sync,
+{
+    pub fn new(primary: R, mirrors: Vec<R>) -> Self {
+        Self {
+            mirrors: std::iter::once(primary)
+                .chain(mirrors)
+                .map(|repository| Mirror { repository, stats: Arc::default() })
+                .collect(),
+        }
+    }
+
+    pub fn primary(&self) -> &R {
+        &self.mirrors[0].repository
+    }
+
+    /// Call `request` on each mirror, starting from the primary, until one
+    /// succeed
+    async fn failover<'a, T, F, Fut>(&'a self, request: F) -> Result<T, RepositoryError>
+    where
+        F: Fn(&'a R) -> Fut,
+        Fut: Future<Output = Result<T, RepositoryError>>,
+    {
+        let mut last_err = None;
+        for (idx, mirror) in self.mirrors.iter().enumerate() {
+            match request(&mirror.repository).await {
+                Ok(res) => return Ok(res),
+                Err(err) => {
+                    warn!("mirror #{} failed: {}", idx, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("at least the primary mirror"))
+    }
+
+    /// Mirror indexes sorted by expected throughput
+    fn package_order(&self) -> Vec<usize> {
+        let mut scores: Vec<(usize, f64)> =
+            self.mirrors.iter().map(|mirror| mirror.stats.lock().score()).enumerate().collect();
+        scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        scores.into_iter().map(|(idx, _)| idx).collect()
+    }
+}
+
+#[async_trait]
+impl<R> RemoteRepository for MirroredRepository<R>
+where
+    R: RemoteRepository + Send + Sync,
+{
+    async fn file(&self, name: &str) -> Result<Bytes, RepositoryError> {
+        let current = metadata::Current::filename();
+        if name == current || name == signature_filename(current) {
+            return self.primary().file(name).await;
+        }
+        self.failover(|repository| repository.file(name)).await
+    }
+
+    async fn current_version(&self) -> Result<metadata::Current, RepositoryError> {
+        self.primary().current_version().await
+    }
+
+    async fn versions(&self) -> Result<metadata::Versions, RepositoryError> {
+        self.failover(|repository| repository.versions()).await
+    }
+
+    async fn packages(&self) -> Result<metadata::Packages, RepositoryError> {
+        self.failover(|repository| repository.packages()).await
+    }
+
+    async fn package_metadata(
+        &self,
+        package_name: metadata::CleanName,
+    ) -> Result<metadata::PackageMetadata, RepositoryError> {
+        self.failover(|repository| repository.package_metadata(package_name.clone())).await
+    }
+
+    async fn package(
+        &self,
+        package_name: metadata::CleanName,
+        range: Range<u64>,
+    ) -> Result<RepositoryStream<Bytes>, RepositoryError> {
+        let mut last_err = None;
+        for idx in self.package_order() {
+            let mirror = &self.mirrors[idx];
+            let in_flight = InFlight::new(mirror.stats.clone());
+            match mirror.repository.package(package_name.clone(), range.clone()).await {
+                Ok(stream) => {
+                    let expected = range.end - range.start;
+                    return Ok(MeasuredStream::new(stream, in_flight, expected).boxed_local());
+                }
+                Err(err) => {
+                    warn!("mirror #{} failed to download {}: {}", idx, package_name, err);
+                    in_flight.failed();
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("at least the primary mirror"))
+    }
+
+    async fn package_ranges(
+        &self,
+        package_name: metadata::CleanName,
+        ranges: Vec<Range<u64>>,
+    ) -> Result<Option<RepositoryStream<(u64, Bytes)>>, RepositoryError> {
+        let mut last_err = None;
+        for idx in self.package_order() {
+            let mirror = &self.mirrors[idx];
+            let in_flight = InFlight::new(mirror.stats.clone());
+            match mirror.repository.package_ranges(package_name.clone(), ranges.clone()).await {
+                Ok(Some(stream)) => {
+                    let expected = ranges.iter().map(|range| range.end - range.start).sum();
+                    return Ok(Some(
+                        MeasuredStream::new(stream, in_flight, expected).boxed_local(),
+                    ));
+                }
+                // try the next mirror, ranges are fetched one by one if none can
+                Ok(None) => {}
+                Err(err) => {
+                    warn!("mirror #{} failed to download {}: {}", idx, package_name, err);
+                    in_flight.failed();
+                    last_err = Some(err);
+                }
+            }
+        }
+        match last_err {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Running download on a mirror
+struct InFlight {
+    stats: Arc<Mutex<MirrorStats>>,
+    started: Instant,
+}
+
+impl InFlight {
+    fn new(stats: Arc<Mutex<MirrorStats>>) -> Self {
+        stats.lock().in_flight += 1;
+        Self { stats, started: Instant::now() }
+    }
+
+    fn failed(&self) {
+        self.stats.lock().failed();
+    }
+
+    fn succeeded(&self) {
+        self.stats.lock().failures = 0;
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.stats.lock().in_flight -= 1;
+    }
+}
+
+/// Package chunk, with its offset for multi-range downloads
+trait Chunk {
+    fn len(&self) -> usize;
+}
+
+impl Chunk for Bytes {
+    fn len(&self) -> usize {
+        Bytes::len(self)
+    }
+}
+
+impl Chunk for (u64, Bytes) {
+    fn len(&self) -> usize {
+        self.1.len()
+    }
+}
+
+/// Package stream measuring the mirror throughput
+///
+/// Only the time spent waiting for the mirror is measured, not the time the
+/// consumer takes to poll the next chunk. The throughput is measured once the
+/// expected bytes are received, or when the stream is dropped before.
+struct MeasuredStream<T> {
+    inner: RepositoryStream<T>,
+    in_flight: InFlight,
+    bytes: u64,
+    expected: u64,
+    /// Time spent waiting for the mirror
+    elapsed: Duration,
+    /// Start of the current wait for a chunk
+    polled: Option<Instant>,
+    measured: bool,
+}
+
+impl<T> MeasuredStream<T> {
+    fn new(inner: RepositoryStream<T>, in_flight: InFlight, expected: u64) -> Self {
+        let elapsed = in_flight.started.elapsed();
+        Self { inner, in_flight, bytes: 0, expected, elapsed, polled: None, measured: false }
+    }
+
+    fn measure(&mut self) {
+        if !self.measured {
+            self.measured = true;
+            self.in_flight.stats.lock().measure(self.bytes, self.elapsed.as_secs_f64());
+        }
+    }
+}
+
+impl<T: Chunk> Stream for MeasuredStream<T> {
+    type Item = Result<T, RepositoryError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = *self.polled.get_or_insert_with(Instant::now);
+        let res = self.inner.as_mut().poll_next(cx);
+        if res.is_ready() {
+            self.elapsed += polled.elapsed();
+            self.polled = None;
+        }
+        match &res {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.bytes += chunk.len() as u64;
+                if self.bytes >= self.expected {
+                    self.measure();
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.in_flight.failed(),
+            Poll::Ready(None) => {
+                self.in_flight.succeeded();
+                self.measure();
+            }
+            Poll::Pending => {}
+        }
+        res
+    }
+}
+
+impl<T> Drop for MeasuredStream<T> {
+    fn drop(&mut self) {
+        // the consumer may stop before the end of the stream
+        self.measure();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::link::{FileRepository, HttpsRepository};
+    use crate::metadata::CleanName;
+
+    /// HTTP server answering multi-range requests with a multipart body, or
+    /// with the whole `data` if `multi_range` is false
+    ///
+    /// Returns the server url and its number of requests.
+    async fn http_server(
+        data: Arc<Vec<u8>>,
+        multi_range: bool,
+    ) -> (reqwest::Url, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap()).parse().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let ranges = String::from_utf8_lossy(&request).lines().find_map(|line| {
+                    let line = line.to_ascii_lowercase();
+                    line.strip_prefix("range: bytes=").map(str::to_owned)
+                });
+                let (head, body) = match ranges {
+                    Some(ranges) if multi_range => {
+                        let mut body = Vec::new();
+                        for spec in ranges.split(',') {
+                            let (first, last) = spec.split_once('-').unwrap();
+                            let (first, last): (usize, usize) =
+                                (first.parse().unwrap(), last.parse().unwrap());
+                            body.extend_from_slice(
+                                format!(
+                                    "\r\n--b\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                                    first,
+                                    last,
+                                    data.len()
+                                )
+                                .as_bytes(),
+                            );
+                            body.extend_from_slice(&data[first..=last]);
+                        }
+                        body.extend_from_slice(b"\r\n--b--\r\n");
+                        let head = "206 Partial Content\r\n\
+                            Content-Type: multipart/byteranges; boundary=b";
+                        (head, body)
+                    }
+                    _ => ("200 OK", data.to_vec()),
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    head,
+                    body.len()
+                );
+                let _ = socket.write_all(head.as_bytes()).await;
+                let _ = socket.write_all(&body).await;
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn mirrored_failover() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let primary = crate::tests::tmp_dir("mirrored_failover_primary");
+        let mirror = crate::tests::tmp_dir("mirrored_failover_mirror");
+        let package: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        fs::write(mirror.join("package"), &package).unwrap();
+        fs::write(
+            mirror.join("current"),
+            r#"{"version":"1","current":{"revision":"1","description":""}}"#,
+        )
+        .unwrap();
+
+        let repository = MirroredRepository::new(
+            FileRepository::new(primary.clone()),
+            vec![FileRepository::new(mirror)],
+        );
+        let package_name = CleanName::from_static_str("package");
+        let download = |range: Range<u64>| {
+            rt.block_on(async {
+                let stream = repository.package(package_name.clone(), range).await.unwrap();
+                stream
+                    .try_fold(Vec::new(), |mut data, chunk| async move {
+                        data.extend_from_slice(&chunk);
+                        Ok(data)
+                    })
+                    .await
+                    .unwrap()
+            })
+        };
+
+        // the primary doesn't have the package, the mirror has it
+        assert_eq!(&download(0..100_000)[..100_000], &package[..100_000]);
+        assert_eq!(repository.mirrors[0].stats.lock().failures, 1);
+        assert!(repository.mirrors[1].stats.lock().throughput.is_some());
+        // the measured mirror is now preferred
+        assert_eq!(repository.package_order(), vec![1, 0]);
+
+        // `current` only comes from the primary
+        assert!(rt.block_on(repository.current_version()).is_err());
+        fs::write(
+            primary.join("current"),
+            r#"{"version":"1","current":{"revision":"2","description":""}}"#,
+        )
+        .unwrap();
+        let current = rt.block_on(repository.current_version()).unwrap();
+        assert_eq!(current.version(), &CleanName::from_static_str("2"));
+    }
+
+    #[test]
+    fn mirrored_measure() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let dir = crate::tests::tmp_dir("mirrored_measure");
+        fs::write(dir.join("package"), vec![0u8; 200_000]).unwrap();
+        let repository = MirroredRepository::new(FileRepository::new(dir), vec![]);
+
+        // the consumer is slow and stops before the end of the range
+        rt.block_on(async {
+            let package_name = CleanName::from_static_str("package");
+            let mut stream = repository.package(package_name, 0..200_000).await.unwrap();
+            let mut bytes = 0;
+            while bytes < MIN_MEASURED_BYTES {
+                bytes += stream.try_next().await.unwrap().unwrap().len() as u64;
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        let mut stats = repository.mirrors[0].stats.lock();
+        // waiting for the consumer isn't measured, it takes more than 100ms
+        assert!(stats.throughput.unwrap() > MIN_MEASURED_BYTES as f64 / 0.1);
+
+        // failures are forgiven over time
+        stats.failed();
+        stats.failed();
+        assert_eq!(stats.failures(), 2);
+        stats.last_failure = Instant::now().checked_sub(FAILURE_DECAY);
+        assert_eq!(stats.failures(), 1);
+    }
+
+    #[test]
+    fn mirrored_package_ranges() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let package: Arc<Vec<u8>> = Arc::new((0..1000u32).map(|i| i as u8).collect());
+        let (primary_url, primary_requests) = rt.block_on(http_server(package.clone(), false));
+        let (mirror_url, mirror_requests) = rt.block_on(http_server(package.clone(), true));
+
+        let mut repository = MirroredRepository::new(
+            HttpsRepository::new(primary_url).unwrap(),
+            vec![HttpsRepository::new(mirror_url).unwrap()],
+        );
+        let package_name = CleanName::from_static_str("package");
+        let download = |ranges: Vec<Range<u64>>| {
+            rt.block_on(async {
+                let stream = repository
+                    .package_ranges(package_name.clone(), ranges)
+                    .await
+                    .unwrap()
+                    .expect("a mirror supports multi-range requests");
+                let chunks: Vec<(u64, Bytes)> = stream.try_collect().await.unwrap();
+                let mut data = Vec::new();
+                for (offset, chunk) in chunks {
+                    assert_eq!(chunk, package[offset as usize..offset as usize + chunk.len()]);
+                    data.extend_from_slice(&chunk);
+                }
+                data
+            })
+        };
+
+        // the primary answers the whole package, the mirror the ranges
+        assert_eq!(
+            download(vec![10..20, 500..600]),
+            [&package[10..20], &package[500..600]].concat()
+        );
+        assert_eq!(primary_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(mirror_requests.load(Ordering::Relaxed), 1);
+        // the primary isn't asked for multiple ranges anymore
+        assert_eq!(download(vec![0..1, 999..1000]), vec![0, 231]);
+        assert_eq!(primary_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(mirror_requests.load(Ordering::Relaxed), 2);
+
+        // ranges are fetched one by one if no mirror supports them
+        let single = MirroredRepository::new(repository.mirrors.remove(0).repository, vec![]);
+        let res = rt.block_on(single.package_ranges(package_name.clone(), vec![0..1, 2..3]));
+        assert!(res.unwrap().is_none());
+    }
+}
@@ -1,6 +1,8 @@
 //! Link to remote repository
+mod byteranges;
 mod file;
 mod https;
+mod mirrored;
 
 use std::{
     fmt,
@@ -11,19 +13,44 @@ use std::{
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::Stream;
+use futures::{Future, Stream};
 
 pub use self::file::FileRepository;
-pub use self::https::HttpsRepository;
+pub use self::https::{HttpsRepository, HttpsRepositoryOptions, Token, TokenProvider};
+pub use self::mirrored::MirroredRepository;
 use crate::metadata;
+use crate::signature::{signature_filename, PublicKey, SignatureError};
 
 #[derive(Debug)]
 pub enum RepositoryError {
-    File { path: PathBuf, err: std::io::Error },
+    File {
+        path: PathBuf,
+        err: std::io::Error,
+    },
     Https(reqwest::Error),
     HttpsNotPartialContent(reqwest::StatusCode),
-    Json { path: PathBuf, err: serde_json::Error },
-    InvalidUrl { reason: String },
+    HttpsReadTimeout(std::time::Duration),
+    Json {
+        path: PathBuf,
+        err: serde_json::Error,
+    },
+    InvalidUrl {
+        reason: String,
+    },
+    InvalidOption {
+        reason: String,
+    },
+    Signature {
+        name: String,
+        err: SignatureError,
+    },
+    /// The response to a ranges request doesn't contain the requested bytes
+    InvalidRanges {
+        reason: String,
+    },
+    Unsupported {
+        operation: &'static str,
+    },
 }
 
 impl RepositoryError {
@@ -34,6 +61,42 @@ impl RepositoryError {
     pub fn json(path: &Path, err: serde_json::Error) -> Self {
         RepositoryError::Json { path: path.to_owned(), err }
     }
+
+    /// Returns `true` if the error is likely temporary (timeout, connection
+    /// reset, server overloaded, ...) and the request is worth retrying
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RepositoryError::File { err, .. } => matches!(
+                err.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::WouldBlock
+            ),
+            RepositoryError::Https(err) => match err.status() {
+                Some(status) => {
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                }
+                // `is_request` errors come from the request itself, they fail again
+                None => err.is_timeout() || err.is_connect() || err.is_body(),
+            },
+            RepositoryError::HttpsReadTimeout(_) | RepositoryError::InvalidRanges { .. } => true,
+            RepositoryError::HttpsNotPartialContent(_)
+            | RepositoryError::Json { .. }
+            | RepositoryError::InvalidUrl { .. }
+            | RepositoryError::InvalidOption { .. }
+            | RepositoryError::Signature { .. }
+            | RepositoryError::Unsupported { .. } => false,
+        }
+    }
+
+    /// Returns `true` if the requested file doesn't exist in the repository
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            RepositoryError::File { err, .. } => err.kind() == std::io::ErrorKind::NotFound,
+            RepositoryError::Https(err) => err.status() == Some(reqwest::StatusCode::NOT_FOUND),
+            _ => false,
+        }
+    }
 }
 
 impl From<reqwest::Error> for RepositoryError {
@@ -50,12 +113,27 @@ impl fmt::Display for RepositoryError {
             RepositoryError::HttpsNotPartialContent(status) => {
                 write!(f, "HTTP status server not partial content ({})", status)
             }
+            RepositoryError::HttpsReadTimeout(timeout) => {
+                write!(f, "HTTP read timed out after {:?}", timeout)
+            }
             RepositoryError::Json { path, err } => {
                 write!(f, "metadata  {:?} error: {}", path, err)
             }
             RepositoryError::InvalidUrl { reason } => {
                 write!(f, "invalid repository url: {}", reason)
             }
+            RepositoryError::InvalidOption { reason } => {
+                write!(f, "invalid repository option: {}", reason)
+            }
+            RepositoryError::Signature { name, err } => {
+                write!(f, "metadata {} signature error: {}", name, err)
+            }
+            RepositoryError::InvalidRanges { reason } => {
+                write!(f, "invalid ranges response: {}", reason)
+            }
+            RepositoryError::Unsupported { operation } => {
+                write!(f, "repository doesn't support {}", operation)
+            }
         }
     }
 }
@@ -64,8 +142,92 @@ impl std::error::Error for RepositoryError {}
 
 pub type RepositoryStream<Item> = Pin<Box<dyn Stream<Item = Result<Item, RepositoryError>>>>;
 
+/// Verify the signature of the metadata file `name` with `trusted_key`
+///
+/// `signature` is the result of the fetch of the detached signature file.
+/// Returns the time the signature was made.
+pub fn verify_signature(
+    trusted_key: &PublicKey,
+    name: &str,
+    data: &[u8],
+    signature: Result<Bytes, RepositoryError>,
+) -> Result<u64, RepositoryError> {
+    let err = match signature {
+        Ok(signature) => match trusted_key.verify(data, &signature) {
+            Ok(signed_at) => return Ok(signed_at),
+            Err(err) => err,
+        },
+        Err(err) if err.is_not_found() => SignatureError::Unsigned,
+        Err(err) => return Err(err),
+    };
+    Err(RepositoryError::Signature { name: name.to_owned(), err })
+}
+
+/// Fetch and decode the metadata file `name`
+///
+/// If `trusted_key` is set, the file detached signature is verified first.
+pub async fn fetch_json<R, T>(
+    repository: &R,
+    name: &str,
+    trusted_key: Option<&PublicKey>,
+) -> Result<T, RepositoryError>
+where
+    R: RemoteRepository + ?Sized,
+    T: for<'de> serde::Deserialize<'de>,
+{
+    match trusted_key {
+        Some(trusted_key) => Ok(fetch_signed_json(repository, name, trusted_key).await?.0),
+        None => {
+            let raw = repository.file(name).await?;
+            serde_json::from_slice(&raw).map_err(|err| RepositoryError::json(Path::new(name), err))
+        }
+    }
+}
+
+/// Fetch and decode the metadata file `name` signed by `trusted_key`
+///
+/// Returns the file with the time of its signature.
+pub async fn fetch_signed_json<R, T>(
+    repository: &R,
+    name: &str,
+    trusted_key: &PublicKey,
+) -> Result<(T, u64), RepositoryError>
+where
+    R: RemoteRepository + ?Sized,
+    T: for<'de> serde::Deserialize<'de>,
+{
+    let raw = repository.file(name).await?;
+    let signature = repository.file(&signature_filename(name)).await;
+    let signed_at = verify_signature(trusted_key, name, &raw, signature)?;
+    let decoded =
+        serde_json::from_slice(&raw).map_err(|err| RepositoryError::json(Path::new(name), err))?;
+    Ok((decoded, signed_at))
+}
+
+/// Future of the default methods of [`RemoteRepository`]
+///
+/// `#[async_trait]` default methods borrow `self` in a `Send` future, which
+/// requires `Self: Sync` from every generic caller. Default methods are
+/// written with the signature `#[async_trait]` gives to implementations and
+/// return futures that don't borrow `self`.
+type DefaultFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, RepositoryError>> + Send + 'a>>;
+
 #[async_trait]
 pub trait RemoteRepository {
+    /// Fetch the raw content of the file `name` (metadata or signature)
+    ///
+    /// Repositories that don't implement it can't be used with a trusted key.
+    fn file<'life0, 'life1, 'async_trait>(
+        &'life0 self,
+        _name: &'life1 str,
+    ) -> DefaultFuture<'async_trait, Bytes>
+    where
+        'life0: 'async_trait,
+        'life1: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async { Err(RepositoryError::Unsupported { operation: "raw file fetching" }) })
+    }
     async fn current_version(&self) -> Result<metadata::Current, RepositoryError>;
     async fn versions(&self) -> Result<metadata::Versions, RepositoryError>;
     async fn packages(&self) -> Result<metadata::Packages, RepositoryError>;
@@ -78,6 +240,22 @@ pub trait RemoteRepository {
         package_name: metadata::CleanName,
         range: Range<u64>,
     ) -> Result<RepositoryStream<Bytes>, RepositoryError>;
+    /// Fetch several ranges of a package with a single request
+    ///
+    /// Chunks are tagged with their offset in the package and cover exactly
+    /// `ranges`, in order. Returns `None` if the repository can't, ranges
+    /// must then be fetched one by one with [`RemoteRepository::package`].
+    fn package_ranges<'life0, 'async_trait>(
+        &'life0 self,
+        _package_name: metadata::CleanName,
+        _ranges: Vec<Range<u64>>,
+    ) -> DefaultFuture<'async_trait, Option<RepositoryStream<(u64, Bytes)>>>
+    where
+        'life0: 'async_trait,
+        Self: 'async_trait,
+    {
+        Box::pin(async { Ok(None) })
+    }
 }
 
 pub enum AutoRepository {
@@ -87,6 +265,16 @@ pub enum AutoRepository {
 
 impl AutoRepository {
     pub fn new(repository_url: &str, auth: Option<(&str, &str)>) -> Result<Self, RepositoryError> {
+        Self::with_options(repository_url, auth, HttpsRepositoryOptions::default())
+    }
+
+    /// Same as [`AutoRepository::new`], HTTP(S) repositories are created with
+    /// `https_options`
+    pub fn with_options(
+        repository_url: &str,
+        auth: Option<(&str, &str)>,
+        https_options: HttpsRepositoryOptions,
+    ) -> Result<Self, RepositoryError> {
         if repository_url.starts_with("https://") || repository_url.starts_with("http://") {
             let mut remote_url = reqwest::Url::parse(repository_url)
                 .map_err(|err| RepositoryError::InvalidUrl { reason: err.to_string() })?;
@@ -94,7 +282,8 @@ impl AutoRepository {
                 let _ = remote_url.set_username(username);
                 let _ = remote_url.set_password(Some(password));
             }
-            return Ok(AutoRepository::Https(https::HttpsRepository::new(remote_url)?));
+            let repository = https::HttpsRepository::with_options(remote_url, https_options)?;
+            return Ok(AutoRepository::Https(repository));
         }
 
         if repository_url.starts_with("file://") {
@@ -104,10 +293,24 @@ impl AutoRepository {
 
         Err(RepositoryError::InvalidUrl { reason: format!("unsupported scheme") })
     }
+
+    /// Require metadata files to be signed by `trusted_key`
+    pub fn set_trusted_key(&mut self, trusted_key: Option<PublicKey>) {
+        match self {
+            AutoRepository::Https(r) => r.set_trusted_key(trusted_key),
+            AutoRepository::File(r) => r.set_trusted_key(trusted_key),
+        }
+    }
 }
 
 #[async_trait]
 impl RemoteRepository for AutoRepository {
+    async fn file(&self, name: &str) -> Result<Bytes, RepositoryError> {
+        match self {
+            AutoRepository::Https(r) => r.file(name).await,
+            AutoRepository::File(r) => r.file(name).await,
+        }
+    }
     async fn current_version(&self) -> Result<metadata::Current, RepositoryError> {
         match self {
             AutoRepository::Https(r) => r.current_version().await,
@@ -145,4 +348,14 @@ impl RemoteRepository for AutoRepository {
             AutoRepository::File(r) => r.package(package_name, range).await,
         }
     }
+    async fn package_ranges(
+        &self,
+        package_name: metadata::CleanName,
+        ranges: Vec<Range<u64>>,
+    ) -> Result<Option<RepositoryStream<(u64, Bytes)>>, RepositoryError> {
+        match self {
+            AutoRepository::Https(r) => r.package_ranges(package_name, ranges).await,
+            AutoRepository::File(r) => r.package_ranges(package_name, ranges).await,
+        }
+    }
 }
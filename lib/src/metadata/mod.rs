@@ -1,12 +1,12 @@
 //! Workspace and Repository metadata definition, serde, ...
 mod dijkstra;
 pub mod v1;
+pub mod v2;
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt;
 use std::ops::{Deref, Range};
 use std::path::Path;
-use std::slice;
 use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
@@ -34,13 +34,19 @@ pub trait Package {
 }
 
 /// Operation type (add, patch, check, ...)
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum OperationKind {
+    #[serde(rename = "add")]
     Add,
+    #[serde(rename = "patch")]
     Patch,
+    #[serde(rename = "check")]
     Check,
+    #[serde(rename = "rm")]
     Rm,
+    #[serde(rename = "mkdir")]
     MkDir,
+    #[serde(rename = "rmdir")]
     RmDir,
 }
 
@@ -50,6 +56,9 @@ pub trait Operation {
     fn path(&self) -> &CleanPath;
     fn slice(&self) -> Option<&CleanPath>;
     fn slice_handler(&self) -> Option<&CleanName>;
+    /// Optional components the file belongs to, empty if the file is always
+    /// installed
+    fn components(&self) -> &[CleanName];
 
     fn range(&self) -> Option<Range<u64>>;
     fn check_size(&self) -> u64;
@@ -212,6 +221,160 @@ impl<'de> serde::Deserialize<'de> for Sha1Hash {
     }
 }
 
+/// Hash algorithm of a package (sha1 for version 1 packages)
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    #[serde(rename = "sha1")]
+    Sha1,
+    #[serde(rename = "sha256")]
+    Sha256,
+    #[serde(rename = "blake3")]
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    fn hash_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha1 => 20,
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 32,
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = &'static str;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            _ => Err("unsupported hash algorithm"),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A hash and its algorithm, formatted as `algorithm:hex`
+#[derive(Clone, Eq, PartialEq)]
+pub struct Hash {
+    algorithm: HashAlgorithm,
+    hash: Vec<u8>,
+}
+
+impl Hash {
+    /// Returns `None` if `hash` length doesn't match `algorithm` output
+    pub fn new(algorithm: HashAlgorithm, hash: Vec<u8>) -> Option<Self> {
+        if hash.len() == algorithm.hash_len() {
+            Some(Self { algorithm, hash })
+        } else {
+            None
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.hash
+    }
+
+    pub fn to_sha1(&self) -> Option<Sha1Hash> {
+        match self.algorithm {
+            HashAlgorithm::Sha1 => {
+                let mut hash = [0u8; 20];
+                hash.copy_from_slice(&self.hash);
+                Some(Sha1Hash::new(hash))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<Sha1Hash> for Hash {
+    fn from(hash: Sha1Hash) -> Self {
+        Self { algorithm: HashAlgorithm::Sha1, hash: hash.hash.to_vec() }
+    }
+}
+
+impl FromStr for Hash {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn val(c: u8) -> Result<u8, &'static str> {
+            match c {
+                b'A'..=b'F' => Ok(c - b'A' + 10),
+                b'a'..=b'f' => Ok(c - b'a' + 10),
+                b'0'..=b'9' => Ok(c - b'0'),
+                _ => Err("invalid hex char"),
+            }
+        }
+
+        let mut parts = s.splitn(2, ':');
+        let algorithm = HashAlgorithm::from_str(parts.next().unwrap_or_default())?;
+        let hex = parts.next().ok_or("missing hash algorithm prefix")?.as_bytes();
+        if hex.len() != algorithm.hash_len() * 2 {
+            return Err("invalid string length");
+        }
+
+        let mut hash = vec![0u8; algorithm.hash_len()];
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = val(hex[2 * i])? << 4 | val(hex[2 * i + 1])?;
+        }
+        Ok(Self { algorithm, hash })
+    }
+}
+
+impl fmt::Debug for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:", self.algorithm)?;
+        for i in self.hash.iter() {
+            write!(f, "{:02x}", i)?;
+        }
+        Ok(())
+    }
+}
+
+impl serde::Serialize for Hash {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Hash {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Self::from_str(&s)
+            .map_err(|err| serde::de::Error::invalid_value(serde::de::Unexpected::Str(&s), &err))
+    }
+}
+
 /// A clean relative path (no '..' or '.' component, '/' separator only)
 #[derive(Debug, Clone, Serialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
 #[serde(transparent)]
@@ -280,7 +443,7 @@ impl<'de> serde::Deserialize<'de> for CleanPath {
 }
 
 /// A clean name (i.e  `[A-Za-Z0-9_.-]+`)
-#[derive(Debug, Clone, Serialize, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Serialize, Eq, PartialEq, Ord, PartialOrd, Hash)]
 #[serde(transparent)]
 pub struct CleanName {
     name: String,
@@ -431,44 +594,101 @@ impl Packages {
 pub enum PackageMetadata {
     #[serde(rename = "1")]
     V1 { package: v1::Package, operations: Vec<v1::Operation> },
+    #[serde(rename = "2")]
+    V2 { package: v2::Package, operations: Vec<v2::Operation> },
 }
 
 impl Package for PackageMetadata {
     fn from(&self) -> Option<&CleanName> {
         match self {
-            &PackageMetadata::V1 { ref package, .. } => package.from(),
+            PackageMetadata::V1 { package, .. } => package.from(),
+            PackageMetadata::V2 { package, .. } => package.from(),
         }
     }
     fn to(&self) -> &CleanName {
         match self {
-            &PackageMetadata::V1 { ref package, .. } => package.to(),
+            PackageMetadata::V1 { package, .. } => package.to(),
+            PackageMetadata::V2 { package, .. } => package.to(),
         }
     }
     fn size(&self) -> u64 {
         match self {
-            &PackageMetadata::V1 { ref package, .. } => package.size(),
+            PackageMetadata::V1 { package, .. } => package.size(),
+            PackageMetadata::V2 { package, .. } => package.size(),
         }
     }
     fn package_data_name(&self) -> CleanName {
         match self {
-            &PackageMetadata::V1 { ref package, .. } => package.package_data_name(),
+            PackageMetadata::V1 { package, .. } => package.package_data_name(),
+            PackageMetadata::V2 { package, .. } => package.package_data_name(),
         }
     }
     fn package_metadata_name(&self) -> CleanName {
         match self {
-            &PackageMetadata::V1 { ref package, .. } => package.package_metadata_name(),
+            PackageMetadata::V1 { package, .. } => package.package_metadata_name(),
+            PackageMetadata::V2 { package, .. } => package.package_metadata_name(),
         }
     }
 }
 
 impl PackageMetadata {
-    pub(crate) fn iter(&self) -> slice::Iter<v1::Operation> {
+    pub fn iter(&self) -> Box<dyn Iterator<Item = &dyn Operation> + '_> {
+        match self {
+            PackageMetadata::V1 { operations, .. } => {
+                Box::new(operations.iter().map(|o| o as &dyn Operation))
+            }
+            PackageMetadata::V2 { operations, .. } => {
+                Box::new(operations.iter().map(|o| o as &dyn Operation))
+            }
+        }
+    }
+
+    /// Operations upgraded to version 2
+    pub(crate) fn operations(&self) -> Vec<v2::Operation> {
+        match self {
+            PackageMetadata::V1 { operations, .. } => {
+                operations.iter().cloned().map(v2::Operation::from).collect()
+            }
+            PackageMetadata::V2 { operations, .. } => operations.clone(),
+        }
+    }
+
+    /// Checks of the workspace state once this package is applied, files of
+    /// components not in `selection` are skipped
+    pub(crate) fn checks(&self, selection: Option<&BTreeSet<CleanName>>) -> WorkspaceChecks {
         match self {
-            &PackageMetadata::V1 { ref operations, .. } => operations.iter(),
+            PackageMetadata::V1 { operations, .. } => WorkspaceChecks::V1 {
+                operations: operations
+                    .iter()
+                    .filter(|o| is_selected(o.components(), selection))
+                    .filter_map(|o| o.as_check_operation())
+                    .collect(),
+            },
+            PackageMetadata::V2 { operations, .. } => WorkspaceChecks::V2 {
+                operations: operations
+                    .iter()
+                    .filter(|o| is_selected(o.components(), selection))
+                    .filter_map(|o| o.as_check_operation())
+                    .collect(),
+            },
         }
     }
 }
 
+/// A file of `components` is installed if `selection` contains one of them
+///
+/// Files without components are always installed, a `None` selection selects
+/// every component.
+pub fn is_selected(components: &[CleanName], selection: Option<&BTreeSet<CleanName>>) -> bool {
+    match selection {
+        Some(selection) => {
+            components.is_empty()
+                || components.iter().any(|component| selection.contains(component))
+        }
+        None => true,
+    }
+}
+
 /// Find the shortest path accross packages
 ///
 /// Returns [`Some(Vec<P>)`] if a path between `start` and `goal` exists
@@ -615,13 +835,123 @@ impl Operation for v1::Operation {
             }
         }
     }
+
+    fn components(&self) -> &[CleanName] {
+        match self {
+            v1::Operation::Add(v1::Add { common, .. })
+            | v1::Operation::Patch(v1::Patch { common, .. })
+            | v1::Operation::Check(v1::Check { common, .. }) => &common.components,
+            v1::Operation::Rm(_) | v1::Operation::MkDir { .. } | v1::Operation::RmDir { .. } => &[],
+        }
+    }
+}
+
+impl Operation for v2::Operation {
+    fn kind(&self) -> OperationKind {
+        match self {
+            v2::Operation::Add(_) => OperationKind::Add,
+            v2::Operation::Patch(_) => OperationKind::Patch,
+            v2::Operation::Check(_) => OperationKind::Check,
+            v2::Operation::Rm(_) => OperationKind::Rm,
+            v2::Operation::MkDir { .. } => OperationKind::MkDir,
+            v2::Operation::RmDir { .. } => OperationKind::RmDir,
+        }
+    }
+    fn check_size(&self) -> u64 {
+        match self {
+            v2::Operation::Check(op) => op.local_size,
+            _ => 0,
+        }
+    }
+    fn data_size(&self) -> u64 {
+        match self {
+            v2::Operation::Add(op) => op.data_size,
+            v2::Operation::Patch(op) => op.data_size,
+            _ => 0,
+        }
+    }
+    fn final_size(&self) -> u64 {
+        match self {
+            v2::Operation::Add(op) => op.final_size,
+            v2::Operation::Patch(op) => op.final_size,
+            _ => 0,
+        }
+    }
+    fn range(&self) -> Option<Range<u64>> {
+        match self {
+            v2::Operation::Add(v2::Add { data_offset, data_size, .. })
+            | v2::Operation::Patch(v2::Patch { data_offset, data_size, .. }) => {
+                Some(Range { start: *data_offset, end: *data_offset + *data_size })
+            }
+            _ => None,
+        }
+    }
+
+    fn set_data_offset(&mut self, offset: u64) {
+        match self {
+            v2::Operation::Add(v2::Add { data_offset, .. })
+            | v2::Operation::Patch(v2::Patch { data_offset, .. }) => *data_offset = offset,
+            _ => {}
+        }
+    }
+
+    fn path(&self) -> &CleanPath {
+        match self {
+            v2::Operation::Add(v2::Add { common, .. })
+            | v2::Operation::Patch(v2::Patch { common, .. })
+            | v2::Operation::Check(v2::Check { common, .. }) => &common.path,
+            v2::Operation::MkDir { path, .. }
+            | v2::Operation::RmDir { path, .. }
+            | v2::Operation::Rm(v2::Rm { path, .. }) => path,
+        }
+    }
+
+    fn slice(&self) -> Option<&CleanPath> {
+        match self {
+            v2::Operation::Add(v2::Add { common, .. })
+            | v2::Operation::Patch(v2::Patch { common, .. })
+            | v2::Operation::Check(v2::Check { common, .. }) => common.slice.as_ref(),
+            v2::Operation::Rm(v2::Rm { slice, .. }) => slice.as_ref(),
+            v2::Operation::MkDir { .. } | v2::Operation::RmDir { .. } => None,
+        }
+    }
+
+    fn slice_handler(&self) -> Option<&CleanName> {
+        match self {
+            v2::Operation::Add(v2::Add { common, .. })
+            | v2::Operation::Patch(v2::Patch { common, .. })
+            | v2::Operation::Check(v2::Check { common, .. }) => common.slice_handler.as_ref(),
+            v2::Operation::Rm(_) | v2::Operation::MkDir { .. } | v2::Operation::RmDir { .. } => {
+                None
+            }
+        }
+    }
+
+    fn components(&self) -> &[CleanName] {
+        match self {
+            v2::Operation::Add(v2::Add { common, .. })
+            | v2::Operation::Patch(v2::Patch { common, .. })
+            | v2::Operation::Check(v2::Check { common, .. }) => &common.components,
+            v2::Operation::Rm(_) | v2::Operation::MkDir { .. } | v2::Operation::RmDir { .. } => &[],
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "version")]
 pub enum WorkspaceState {
     #[serde(rename = "1")]
-    V1 { state: v1::State },
+    V1 {
+        state: v1::State,
+        /// Optional components to install, `None` selects every component
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        components: Option<BTreeSet<CleanName>>,
+        /// Time the last trusted repository `current` file was signed at
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        current_signed_at: Option<u64>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -629,12 +959,29 @@ pub enum WorkspaceState {
 pub enum WorkspaceChecks {
     #[serde(rename = "1")]
     V1 { operations: Vec<v1::Operation> },
+    #[serde(rename = "2")]
+    V2 { operations: Vec<v2::Operation> },
 }
 
 impl WorkspaceChecks {
-    pub(crate) fn iter(&self) -> slice::Iter<v1::Operation> {
+    pub fn iter(&self) -> Box<dyn Iterator<Item = &dyn Operation> + '_> {
         match self {
-            &WorkspaceChecks::V1 { ref operations, .. } => operations.iter(),
+            WorkspaceChecks::V1 { operations } => {
+                Box::new(operations.iter().map(|o| o as &dyn Operation))
+            }
+            WorkspaceChecks::V2 { operations } => {
+                Box::new(operations.iter().map(|o| o as &dyn Operation))
+            }
+        }
+    }
+
+    /// Operations upgraded to version 2
+    pub(crate) fn operations(&self) -> Vec<v2::Operation> {
+        match self {
+            WorkspaceChecks::V1 { operations } => {
+                operations.iter().cloned().map(v2::Operation::from).collect()
+            }
+            WorkspaceChecks::V2 { operations } => operations.clone(),
         }
     }
 }
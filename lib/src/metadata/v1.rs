@@ -1,4 +1,5 @@
 //! Version 1 metadata definition
+use std::collections::BTreeSet;
 use std::fmt;
 
 use serde::{Deserialize, Serialize};
@@ -74,6 +75,24 @@ pub struct Common {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub slice_handler: Option<CleanName>,
+    /// How local modifications of this file are handled
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preserve: Option<Preserve>,
+    /// Optional components (language pack, ...) the file belongs to, the file
+    /// is always installed if empty
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub components: Vec<CleanName>,
+}
+
+/// Policy of files the user is expected to modify (configuration, ...)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preserve {
+    /// A locally modified file is kept, its new version is written beside it
+    /// with a `.new` extension
+    #[serde(rename = "if-modified")]
+    IfModified,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -262,6 +281,11 @@ pub struct StateUpdating {
     pub to: CleanName,
     pub(crate) available: UpdatePosition,
     pub(crate) applied: UpdatePosition,
+    /// Operations after `applied` that are already applied, workers finish
+    /// operations out of order
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) applied_ahead: BTreeSet<usize>,
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub failures: Vec<Failure>,
@@ -279,6 +303,7 @@ impl StateUpdating {
             to,
             available: UpdatePosition::default(),
             applied: UpdatePosition::default(),
+            applied_ahead: BTreeSet::new(),
             failures,
             previous_failures: Vec::default(),
             check_only: false,
@@ -290,6 +315,7 @@ impl StateUpdating {
         self.to = other.to;
         self.available = other.available;
         self.applied = other.applied;
+        self.applied_ahead = other.applied_ahead;
         self.check_only = other.check_only;
         if !other.failures.is_empty() || !other.previous_failures.is_empty() {
             self.failures.extend(other.failures);
@@ -302,6 +328,24 @@ impl StateUpdating {
     pub(crate) fn clear_progress(&mut self) {
         self.available = UpdatePosition::default();
         self.applied = UpdatePosition::default();
+        self.applied_ahead.clear();
+    }
+
+    /// Every operation before `operation_idx` and `applied_operations` are
+    /// applied
+    pub(crate) fn set_applied(
+        &mut self,
+        operation_idx: usize,
+        applied_operations: impl IntoIterator<Item = usize>,
+    ) {
+        self.applied.operation_idx = operation_idx;
+        self.applied_ahead.extend(applied_operations);
+        self.applied_ahead = self.applied_ahead.split_off(&operation_idx);
+    }
+
+    /// If the operation at `operation_idx` of the current package is applied
+    pub(crate) fn is_applied(&self, operation_idx: usize) -> bool {
+        operation_idx < self.applied.operation_idx || self.applied_ahead.contains(&operation_idx)
     }
 
     pub(crate) fn dedup_failures(&mut self) {
@@ -0,0 +1,264 @@
+//! Version 2 metadata definition
+//!
+//! Same layout as version 1, but every hash is prefixed by its algorithm
+//! (i.e. `sha256:<hex>`), so packages are no longer bound to sha1.
+use serde::{Deserialize, Serialize};
+
+pub use super::v1::{Common, Package, Preserve, Rm};
+use super::{u64_str, v1, CleanName, CleanPath, Hash};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Add {
+    #[serde(flatten)]
+    pub common: Common,
+
+    #[serde(rename = "dataOffset")]
+    #[serde(with = "u64_str")]
+    pub data_offset: u64,
+    #[serde(rename = "dataSize")]
+    #[serde(with = "u64_str")]
+    pub data_size: u64,
+    #[serde(rename = "dataHash")]
+    pub data_hash: Hash,
+    #[serde(rename = "dataCompression")]
+    pub data_compression: CleanName,
+
+    #[serde(rename = "finalOffset")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "u64_str::is_zero")]
+    #[serde(with = "u64_str")]
+    pub final_offset: u64,
+    #[serde(rename = "finalSize")]
+    #[serde(with = "u64_str")]
+    pub final_size: u64,
+    #[serde(rename = "finalHash")]
+    pub final_hash: Hash,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Patch {
+    #[serde(flatten)]
+    pub common: Common,
+
+    #[serde(rename = "dataOffset")]
+    #[serde(with = "u64_str")]
+    pub data_offset: u64,
+    #[serde(rename = "dataSize")]
+    #[serde(with = "u64_str")]
+    pub data_size: u64,
+    #[serde(rename = "dataHash")]
+    pub data_hash: Hash,
+    #[serde(rename = "dataCompression")]
+    pub data_compression: CleanName,
+
+    #[serde(rename = "patchType")]
+    pub patch_type: CleanName,
+
+    #[serde(rename = "localOffset")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "u64_str::is_zero")]
+    #[serde(with = "u64_str")]
+    pub local_offset: u64,
+    #[serde(rename = "localSize")]
+    #[serde(with = "u64_str")]
+    pub local_size: u64,
+    #[serde(rename = "localHash")]
+    pub local_hash: Hash,
+
+    #[serde(rename = "finalOffset")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "u64_str::is_zero")]
+    #[serde(with = "u64_str")]
+    pub final_offset: u64,
+    #[serde(rename = "finalSize")]
+    #[serde(with = "u64_str")]
+    pub final_size: u64,
+    #[serde(rename = "finalHash")]
+    pub final_hash: Hash,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Check {
+    #[serde(flatten)]
+    pub common: Common,
+
+    #[serde(rename = "localOffset")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "u64_str::is_zero")]
+    #[serde(with = "u64_str")]
+    pub local_offset: u64,
+    #[serde(rename = "localSize")]
+    #[serde(with = "u64_str")]
+    pub local_size: u64,
+    #[serde(rename = "localHash")]
+    pub local_hash: Hash,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum Operation {
+    #[serde(rename = "add")]
+    Add(Add),
+    #[serde(rename = "patch")]
+    Patch(Patch),
+    #[serde(rename = "check")]
+    Check(Check),
+    #[serde(rename = "rm")]
+    Rm(Rm),
+    #[serde(rename = "mkdir")]
+    MkDir { path: CleanPath },
+    #[serde(rename = "rmdir")]
+    RmDir { path: CleanPath },
+}
+
+impl Operation {
+    /// Common information of file operations
+    pub fn common(&self) -> Option<&Common> {
+        match self {
+            Operation::Add(Add { common, .. })
+            | Operation::Patch(Patch { common, .. })
+            | Operation::Check(Check { common, .. }) => Some(common),
+            Operation::Rm(_) | Operation::MkDir { .. } | Operation::RmDir { .. } => None,
+        }
+    }
+
+    pub fn as_check_operation(&self) -> Option<Operation> {
+        match self {
+            Operation::Add(Add { common, final_offset, final_size, final_hash, .. })
+            | Operation::Patch(Patch { common, final_offset, final_size, final_hash, .. }) => {
+                Some(Operation::Check(Check {
+                    common: common.clone(),
+                    local_offset: *final_offset,
+                    local_size: *final_size,
+                    local_hash: final_hash.clone(),
+                }))
+            }
+            Operation::Check { .. } | Operation::MkDir { .. } => Some(self.clone()),
+            Operation::RmDir { .. } | Operation::Rm { .. } => None,
+        }
+    }
+
+    /// Version 1 operation, if every hash of this operation is a sha1 hash
+    pub fn to_v1(&self) -> Option<v1::Operation> {
+        Some(match self.clone() {
+            Operation::Add(op) => v1::Operation::Add(v1::Add {
+                common: op.common,
+                data_offset: op.data_offset,
+                data_size: op.data_size,
+                data_sha1: op.data_hash.to_sha1()?,
+                data_compression: op.data_compression,
+                final_offset: op.final_offset,
+                final_size: op.final_size,
+                final_sha1: op.final_hash.to_sha1()?,
+            }),
+            Operation::Patch(op) => v1::Operation::Patch(v1::Patch {
+                common: op.common,
+                data_offset: op.data_offset,
+                data_size: op.data_size,
+                data_sha1: op.data_hash.to_sha1()?,
+                data_compression: op.data_compression,
+                patch_type: op.patch_type,
+                local_offset: op.local_offset,
+                local_size: op.local_size,
+                local_sha1: op.local_hash.to_sha1()?,
+                final_offset: op.final_offset,
+                final_size: op.final_size,
+                final_sha1: op.final_hash.to_sha1()?,
+            }),
+            Operation::Check(op) => v1::Operation::Check(v1::Check {
+                common: op.common,
+                local_offset: op.local_offset,
+                local_size: op.local_size,
+                local_sha1: op.local_hash.to_sha1()?,
+            }),
+            Operation::Rm(op) => v1::Operation::Rm(op),
+            Operation::MkDir { path } => v1::Operation::MkDir { path },
+            Operation::RmDir { path } => v1::Operation::RmDir { path },
+        })
+    }
+}
+
+impl From<v1::Operation> for Operation {
+    fn from(op: v1::Operation) -> Self {
+        match op {
+            v1::Operation::Add(op) => Operation::Add(Add {
+                common: op.common,
+                data_offset: op.data_offset,
+                data_size: op.data_size,
+                data_hash: op.data_sha1.into(),
+                data_compression: op.data_compression,
+                final_offset: op.final_offset,
+                final_size: op.final_size,
+                final_hash: op.final_sha1.into(),
+            }),
+            v1::Operation::Patch(op) => Operation::Patch(Patch {
+                common: op.common,
+                data_offset: op.data_offset,
+                data_size: op.data_size,
+                data_hash: op.data_sha1.into(),
+                data_compression: op.data_compression,
+                patch_type: op.patch_type,
+                local_offset: op.local_offset,
+                local_size: op.local_size,
+                local_hash: op.local_sha1.into(),
+                final_offset: op.final_offset,
+                final_size: op.final_size,
+                final_hash: op.final_sha1.into(),
+            }),
+            v1::Operation::Check(op) => Operation::Check(Check {
+                common: op.common,
+                local_offset: op.local_offset,
+                local_size: op.local_size,
+                local_hash: op.local_sha1.into(),
+            }),
+            v1::Operation::Rm(op) => Operation::Rm(op),
+            v1::Operation::MkDir { path } => Operation::MkDir { path },
+            v1::Operation::RmDir { path } => Operation::RmDir { path },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::io::{self, Check};
+    use crate::metadata::HashAlgorithm;
+
+    #[test]
+    fn hashes() {
+        let digest = |algorithm| {
+            let mut checks = io::CheckHashSize::new(algorithm);
+            checks.check(b"abc");
+            checks.hash().to_string()
+        };
+        assert_eq!(digest(HashAlgorithm::Sha1), "sha1:a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(
+            digest(HashAlgorithm::Sha256),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            digest(HashAlgorithm::Blake3),
+            "blake3:6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert!(Hash::from_str("sha256:a9993e364706816aba3e25717850c26c9cd0d89d").is_err());
+        assert!(Hash::from_str("a9993e364706816aba3e25717850c26c9cd0d89d").is_err());
+
+        let v1: v1::Operation = serde_json::from_str(
+            r#"{"type":"check","path":"a","localSize":"3","localSha1":"a9993e364706816aba3e25717850c26c9cd0d89d"}"#,
+        )
+        .unwrap();
+        let v2 = Operation::from(v1);
+        let json = serde_json::to_string(&v2).unwrap();
+        assert!(json.contains(r#""localHash":"sha1:a9993e364706816aba3e25717850c26c9cd0d89d""#));
+        assert!(v2.to_v1().is_some());
+
+        let v2: Operation = serde_json::from_str(&json.replace(
+            "sha1:a9993e364706816aba3e25717850c26c9cd0d89d",
+            &digest(HashAlgorithm::Blake3),
+        ))
+        .unwrap();
+        assert!(v2.to_v1().is_none());
+    }
+}
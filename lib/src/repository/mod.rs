@@ -8,6 +8,10 @@
 //! - `$package_name.metadata`: a JSON file with precise informations about a package
 //!    and how to apply it.
 //! - `$package_name`: a binary file containing package update operations data.
+//! - `$file_name.sig`: detached ed25519 signature of the `$file_name` JSON file.
+//!
+//! Once signed, metadata files can only be modified with the signing key,
+//! they are signed again as soon as they are written.
 //!
 //! ## Safety
 //!
@@ -15,27 +19,75 @@
 //! (i.e. renaming of existing file) of  repository known files (i.e. `current`,
 //! `versions` and `packages`).
 mod packager;
+mod planner;
 pub mod progress;
+mod prune;
+mod verify;
 
-use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{cmp, fs};
 
 use serde::Serialize;
 use serde_json;
 
 pub use self::packager::{BuildError, BuildOptions, PackageBuilder};
+pub use self::planner::{SkipEdge, SkipStrategy};
+pub use self::prune::PrunePolicy;
+pub use self::verify::{Inconsistency, VerifyOptions};
 pub use crate::codecs::CoderOptions;
 use crate::metadata::{self, CleanName, PackageMetadata, Packages, Versions};
+use crate::signature::{self, signature_filename, SigningKey};
 use crate::{io, link};
 
 /// Manage a repository (get/set current version, add/rm package, ...)
 pub struct Repository {
     dir: PathBuf,
+    signing_key: Option<SigningKey>,
 }
 
 impl Repository {
     pub fn new(dir: PathBuf) -> Repository {
-        Repository { dir }
+        Repository { dir, signing_key: None }
+    }
+
+    /// Sign the metadata files modified from now on with `signing_key`
+    ///
+    /// Without a key, modifications of a signed repository are refused.
+    pub fn set_signing_key(&mut self, signing_key: Option<SigningKey>) {
+        self.signing_key = signing_key;
+    }
+
+    /// `true` if the repository metadata files are signed
+    pub fn is_signed(&self) -> bool {
+        self.dir.join(signature_filename(metadata::Packages::filename())).exists()
+    }
+
+    /// Fails if the repository is signed and no signing key is set
+    pub(crate) fn check_writable(&self) -> io::Result<()> {
+        if self.signing_key.is_none() && self.is_signed() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the repository is signed, a signing key is required to modify it",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Atomically write the metadata file `file_name` and sign it if a
+    /// signing key is set
+    ///
+    /// Clients fail to verify the file until its signature is written too.
+    pub(crate) fn write_json<T>(&self, file_name: &str, value: &T) -> io::Result<()>
+    where
+        T: Serialize,
+    {
+        self.check_writable()?;
+        io::atomic_write_json(self.dir.join(file_name), value)?;
+        match &self.signing_key {
+            Some(key) => self.sign_file(key, file_name),
+            None => Ok(()),
+        }
     }
 
     pub fn link(&self) -> link::FileRepository {
@@ -65,8 +117,9 @@ impl Repository {
 
     /// Set repository current version
     ///
-    /// Fails if the request version isn't in the list of known versions or
-    /// if the atomic rename of `current` fails
+    /// Fails if the request version isn't in the list of known versions, if
+    /// the repository is signed without a signing key set or if the atomic
+    /// rename of `current` fails
     pub fn set_current_version(&mut self, version: &CleanName) -> io::Result<()> {
         let version: metadata::Current = match self.versions()? {
             Versions::V1 { versions } => versions
@@ -78,8 +131,7 @@ impl Repository {
             io::ErrorKind::InvalidInput,
             format!("version {} doesn't exists", version),
         ))?;
-        io::atomic_write_json(&self.dir.join(metadata::Current::filename()), &version)?;
-        Ok(())
+        self.write_json(metadata::Current::filename(), &version)
     }
 
     pub fn versions(&self) -> io::Result<metadata::Versions> {
@@ -89,7 +141,8 @@ impl Repository {
 
     /// Register or update version
     ///
-    /// Fails if the atomic rename of `versions` fails.
+    /// Fails if the repository is signed without a signing key set or if the
+    /// atomic rename of `versions` fails.
     pub fn register_version(&self, version: &dyn metadata::Version) -> io::Result<()> {
         let versions = match self.versions()? {
             Versions::V1 { versions } => versions
@@ -102,13 +155,13 @@ impl Repository {
                 .collect(),
         };
         let versions = Versions::V1 { versions };
-        io::atomic_write_json(&self.dir.join(metadata::Versions::filename()), &versions)?;
-        Ok(())
+        self.write_json(metadata::Versions::filename(), &versions)
     }
 
     /// Remove version to repository
     ///
-    /// Fails if the atomic rename of `versions` fails.
+    /// Fails if the repository is signed without a signing key set or if the
+    /// atomic rename of `versions` fails.
     pub fn unregister_version(&self, revision: &CleanName) -> io::Result<()> {
         let versions = match self.versions()? {
             Versions::V1 { versions } => {
@@ -116,8 +169,7 @@ impl Repository {
             }
         };
         let versions = Versions::V1 { versions };
-        io::atomic_write_json(&self.dir.join(metadata::Versions::filename()), &versions)?;
-        Ok(())
+        self.write_json(metadata::Versions::filename(), &versions)
     }
 
     pub fn packages(&self) -> io::Result<metadata::Packages> {
@@ -135,33 +187,115 @@ impl Repository {
 
     /// Register or update package to repository
     ///
-    /// Fails if the atomic rename of `packages` fails.
+    /// The package metadata file is signed too if a signing key is set. Fails
+    /// if the repository is signed without a signing key set or if the atomic
+    /// rename of `packages` fails.
     pub fn register_package(&self, package_metadata_name: &str) -> io::Result<()> {
+        self.check_writable()?;
         let packages = match (self.package_metadata(package_metadata_name)?, self.packages()?) {
-            (PackageMetadata::V1 { package, .. }, Packages::V1 { packages }) => packages
+            (PackageMetadata::V1 { package, .. }, Packages::V1 { packages })
+            | (PackageMetadata::V2 { package, .. }, Packages::V1 { packages }) => packages
                 .into_iter()
                 .filter(|p| p != &package)
                 .chain(std::iter::once(package.clone()))
                 .collect(),
         };
+        if let Some(key) = &self.signing_key {
+            self.sign_file(key, package_metadata_name)?;
+        }
         let packages = Packages::V1 { packages };
-        io::atomic_write_json(&self.dir.join(metadata::Versions::filename()), &packages)?;
-        Ok(())
+        self.write_json(metadata::Packages::filename(), &packages)
     }
 
     /// Unregister package to repository
     ///
-    /// Fails if the atomic rename of `packages` fails.
+    /// Fails if the repository is signed without a signing key set or if the
+    /// atomic rename of `packages` fails.
     pub fn unregister_package(&self, package_metadata_name: &str) -> io::Result<()> {
         let packages = match (self.package_metadata(package_metadata_name)?, self.packages()?) {
-            (PackageMetadata::V1 { package, .. }, Packages::V1 { packages }) => {
+            (PackageMetadata::V1 { package, .. }, Packages::V1 { packages })
+            | (PackageMetadata::V2 { package, .. }, Packages::V1 { packages }) => {
                 packages.into_iter().filter(|p| p != &package).collect()
             }
         };
         let packages = Packages::V1 { packages };
-        io::atomic_write_json(&self.dir.join(metadata::Versions::filename()), &packages)?;
+        self.write_json(metadata::Packages::filename(), &packages)
+    }
+
+    /// Write the detached signature file of `file_name`
+    ///
+    /// The signature time is the current time, or just after the time of the
+    /// previous signature if the clock went backward, so workspaces never take
+    /// the new file for a replayed one. Fails if the atomic rename of the
+    /// signature file fails.
+    pub fn sign_file(&self, key: &SigningKey, file_name: &str) -> io::Result<()> {
+        let data = fs::read(self.dir.join(file_name))?;
+        let path = self.dir.join(signature_filename(file_name));
+        let tmp_path = self.dir.join(signature_filename(file_name) + ".tmp");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let signed_at = match fs::read(&path).ok().and_then(|sig| signature::signed_at(&sig)) {
+            Some(previous) => cmp::max(now, previous + 1),
+            None => now,
+        };
+        fs::write(&tmp_path, key.sign(&data, signed_at))?;
+        let res = io::atomic_rename(&tmp_path, &path);
+        if res.is_err() {
+            let _ = io::remove_file(&tmp_path);
+        }
+        res
+    }
+
+    /// Sign `current`, `versions`, `packages` and every registered package
+    /// metadata files
+    pub fn sign(&self, key: &SigningKey) -> io::Result<()> {
+        if self.dir.join(metadata::Current::filename()).exists() {
+            self.sign_file(key, metadata::Current::filename())?;
+        }
+        self.sign_file(key, metadata::Versions::filename())?;
+        self.sign_file(key, metadata::Packages::filename())?;
+        for package in self.packages()?.iter() {
+            self.sign_file(key, &package.package_metadata_name())?;
+        }
         Ok(())
     }
+
+    /// Remove the package files (data, metadata and signatures) of packages
+    /// that aren't registered
+    ///
+    /// Returns the names of the removed files. Must not run while a package
+    /// is added to the repository.
+    pub fn gc(&self) -> io::Result<Vec<String>> {
+        prune::gc(self)
+    }
+
+    /// Unregister the packages that aren't needed to update to the current
+    /// version from an empty workspace or from the versions of `policy`
+    ///
+    /// Fails without modifying `packages` if a version would be left without
+    /// an update path or if the repository is signed without a signing key
+    /// set. Returns the unregistered packages, their files are removed by
+    /// [`gc`](Self::gc).
+    pub fn prune(&self, policy: &PrunePolicy) -> io::Result<Vec<metadata::v1::Package>> {
+        prune::prune(self, policy)
+    }
+
+    /// Patch packages to build, between versions up to the current one, so
+    /// that workspaces of old versions have short update paths
+    ///
+    /// Packages already registered aren't planned again.
+    pub fn plan_skip_edges(&self, strategy: &SkipStrategy) -> io::Result<Vec<SkipEdge>> {
+        planner::plan_skip_edges(self, strategy)
+    }
+
+    /// Check the repository consistency
+    ///
+    /// Every version must be reachable from an empty workspace, and every
+    /// registered package must have its metadata and data files, with the
+    /// data of each operation inside the data file. Returns the
+    /// inconsistencies found, the repository is consistent if there is none.
+    pub fn verify(&self, options: &VerifyOptions) -> Vec<Inconsistency> {
+        verify::verify(self, options)
+    }
 }
 
 fn create_if_missing<T>(path: &Path, value: &T) -> io::Result<()>
@@ -205,4 +339,38 @@ mod tests {
         let build_stream = builder.build();
         rt.block_on(build_stream.try_for_each(|_| async { Ok(()) })).unwrap();
     }
+
+    #[test]
+    fn signed_writes() {
+        let dir = crate::tests::tmp_dir("repository_signed_writes");
+        let mut repository = Repository::new(dir.clone());
+        repository.init().unwrap();
+        let version = |revision: &'static str| metadata::v1::Version {
+            revision: CleanName::from_static_str(revision),
+            description: String::new(),
+        };
+        repository.register_version(&version("v1")).unwrap();
+        let key = SigningKey::generate().unwrap();
+        let public_key = key.public_key();
+        repository.sign(&key).unwrap();
+        assert!(repository.is_signed());
+
+        // a signed repository can't be modified without the key
+        let err = repository.register_version(&version("v2")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(repository.versions().unwrap().iter().count(), 1);
+
+        repository.set_signing_key(Some(key));
+        repository.register_version(&version("v2")).unwrap();
+        repository.set_current_version(&CleanName::from_static_str("v1")).unwrap();
+        let read_signed_at = |name: &str| {
+            let data = fs::read(dir.join(name)).unwrap();
+            let signature = fs::read(dir.join(signature_filename(name))).unwrap();
+            public_key.verify(&data, &signature).unwrap()
+        };
+        read_signed_at(metadata::Versions::filename());
+        let v1_signed_at = read_signed_at(metadata::Current::filename());
+        repository.set_current_version(&CleanName::from_static_str("v2")).unwrap();
+        assert!(read_signed_at(metadata::Current::filename()) > v1_signed_at);
+    }
 }
@@ -12,8 +12,9 @@ use tracing::{debug, error, instrument, span, Level};
 
 use super::progress::{BuildProgress, BuildStage, BuildWorkerProgress, SharedBuildProgress};
 use crate::codecs::{CheckCoder, CoderOptions};
-use crate::metadata::{self, CleanName, CleanPath, Operation, Package, Sha1Hash};
+use crate::metadata::{self, CleanName, CleanPath, Hash, HashAlgorithm, Operation, Package};
 use crate::sync::watch_progress;
+use crate::workspace::AllowList;
 use crate::{io, Repository};
 
 /// Build a new repository package
@@ -90,6 +91,12 @@ impl PackageBuilder {
         }
     }
 
+    /// Build a patch package from `prev_version`, whose files are in
+    /// `prev_directory`
+    ///
+    /// `prev_version` doesn't have to be the version before the source
+    /// version, a patch from an older version is a skip package (see
+    /// [`Repository::plan_skip_edges`]).
     pub fn set_previous(&mut self, prev_version: CleanName, prev_directory: PathBuf) {
         self.previous = Some((prev_version, prev_directory));
     }
@@ -119,6 +126,7 @@ impl PackageBuilder {
         let repo_data_path = repository.dir.join(&package_data_name);
         let repo_metadata_path = repository.dir.join(&package_metadata_name);
 
+        repository.check_writable()?;
         io::assert_is_file_eq(&built_data_path, true, "built data file")?;
         io::assert_is_file_eq(&built_metadata_path, true, "built metadata file")?;
         io::assert_is_file_eq(&repo_data_path, false, "repository data file")?;
@@ -285,8 +293,18 @@ impl PackageBuilder {
                 .flush()
                 .map_err(|err| BuildError::PackageCreateError { path: path(), err })?;
 
-            let package_metadata_v1 =
-                metadata::PackageMetadata::V1 { package: package_v1.clone(), operations };
+            let package_metadata = match options.hash {
+                None => metadata::PackageMetadata::V1 {
+                    package: package_v1.clone(),
+                    operations: operations
+                        .iter()
+                        .map(|o| o.to_v1().expect("sha1 hashes without hash option"))
+                        .collect(),
+                },
+                Some(_) => {
+                    metadata::PackageMetadata::V2 { package: package_v1.clone(), operations }
+                }
+            };
 
             {
                 let path = || metadata_path.display().to_string().into_boxed_str();
@@ -296,7 +314,7 @@ impl PackageBuilder {
                     .create_new(true)
                     .open(&metadata_path)
                     .map_err(meta_err)?;
-                serde_json::to_writer_pretty(&mut metadata_file, &package_metadata_v1)
+                serde_json::to_writer_pretty(&mut metadata_file, &package_metadata)
                     .map_err(|err| meta_err(err.into()))?;
                 metadata_file.flush().map_err(meta_err)?;
             }
@@ -435,16 +453,16 @@ fn ordered_dir_list(
 }
 
 struct BuiltOperation {
-    pub operation: metadata::v1::Operation,
+    pub operation: metadata::v2::Operation,
     pub data_path: Option<PathBuf>,
 }
 
 impl BuiltOperation {
-    fn no_data(operation: metadata::v1::Operation) -> Self {
+    fn no_data(operation: metadata::v2::Operation) -> Self {
         Self { operation, data_path: None }
     }
 
-    fn with_data(data_path: PathBuf, operation: metadata::v1::Operation) -> Self {
+    fn with_data(data_path: PathBuf, operation: metadata::v2::Operation) -> Self {
         Self { operation, data_path: Some(data_path) }
     }
 }
@@ -452,7 +470,27 @@ impl BuiltOperation {
 /// Build package options (compressors, patchers, ...)
 pub struct BuildOptions {
     pub compressors: Vec<CoderOptions>,
+    /// Patchers tried for each modified file
+    ///
+    /// zstd patches find matches within a 128MiB window by default, applying
+    /// them requires as much memory. The `zstd:window=auto` patcher covers
+    /// whole previous files up to 2GiB, at the cost of as much memory on
+    /// workspaces.
     pub patchers: Vec<CoderOptions>,
+    /// Hash algorithm of a version 2 package
+    ///
+    /// Default to `None`, i.e. a version 1 package with sha1 hashes.
+    pub hash: Option<HashAlgorithm>,
+    /// Files the user is expected to modify (configuration, ...), locally
+    /// modified versions are kept by updates
+    ///
+    /// Default to no files.
+    pub preserve: AllowList,
+    /// Optional components (language packs, ...) and the files they contain,
+    /// files of no component are always installed
+    ///
+    /// Default to no components.
+    pub components: Vec<(CleanName, AllowList)>,
 }
 
 impl BuildOptions {
@@ -460,6 +498,28 @@ impl BuildOptions {
         Self {
             compressors: vec![CoderOptions::new("raw".to_string())],
             patchers: vec![CoderOptions::new("raw".to_string())],
+            hash: None,
+            preserve: AllowList::default(),
+            components: Vec::new(),
+        }
+    }
+
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash.unwrap_or(HashAlgorithm::Sha1)
+    }
+
+    fn components(&self, path: &CleanPath) -> Vec<CleanName> {
+        self.components
+            .iter()
+            .filter(|(_, files)| files.allows(path.as_str()))
+            .map(|(component, _)| component.clone())
+            .collect()
+    }
+
+    fn preserve(&self, path: &CleanPath) -> Option<metadata::v2::Preserve> {
+        match self.preserve.allows(path.as_str()) {
+            true => Some(metadata::v2::Preserve::IfModified),
+            false => None,
         }
     }
 }
@@ -474,11 +534,15 @@ impl Default for BuildOptions {
                 CoderOptions::new("zstd".to_string()),
                 CoderOptions::new("raw".to_string()),
             ],
+            // vcdiff loads the whole previous file in memory, it is opt-in
             patchers: vec![
                 #[cfg(feature = "zstd")]
                 CoderOptions::new("zstd".to_string()),
                 CoderOptions::new("raw".to_string()),
             ],
+            hash: None,
+            preserve: AllowList::default(),
+            components: Vec::new(),
         }
     }
 }
@@ -561,7 +625,7 @@ impl BuildTaskBuilder {
             if pre_t.is_file() && !src_t.is_file() {
                 let path = path.to_owned();
                 self.push(&format!("rm {}", path), move |_| {
-                    Ok(BuiltOperation::no_data(metadata::v1::Operation::Rm(metadata::v1::Rm {
+                    Ok(BuiltOperation::no_data(metadata::v2::Operation::Rm(metadata::v2::Rm {
                         path,
                         slice: None,
                     })))
@@ -570,7 +634,7 @@ impl BuildTaskBuilder {
             if src_t.is_dir() && !pre_t.is_dir() {
                 let path = path.to_owned();
                 self.push(&format!("mkdir {}", path), move |_| {
-                    Ok(BuiltOperation::no_data(metadata::v1::Operation::MkDir { path }))
+                    Ok(BuiltOperation::no_data(metadata::v2::Operation::MkDir { path }))
                 });
             }
             if src_t.is_file() && !pre_t.is_file() {
@@ -578,11 +642,13 @@ impl BuildTaskBuilder {
                 let path = path.to_owned();
                 let src_path = src.expect("src is_file").join(&filename);
                 let tmp_path = tmp_dir.join(format!("task_{}", self.tasks.len()));
-                let common = metadata::v1::Common {
+                let common = metadata::v2::Common {
                     path: path.clone(),
                     slice: None,
                     exe: src_t.is_exe(),
                     slice_handler: None,
+                    preserve: options.preserve(&path),
+                    components: options.components(&path),
                 };
                 for src_slice in slices(options, common, src_path, tmp_path)? {
                     self.push(
@@ -597,11 +663,13 @@ impl BuildTaskBuilder {
                 let src_path = src.expect("src is_file").join(&filename);
                 let pre_path = pre.expect("pre is_file").join(&filename);
                 let tmp_path = tmp_dir.join(format!("task_{}", self.tasks.len()));
-                let common = metadata::v1::Common {
+                let common = metadata::v2::Common {
                     path: path.clone(),
                     slice: None,
                     exe: src_t.is_exe(),
                     slice_handler: None,
+                    preserve: options.preserve(&path),
+                    components: options.components(&path),
                 };
                 let pre_slices = slices(options, common.clone(), pre_path, tmp_path.clone())?;
                 for src_slice in slices(options, common, src_path, tmp_path)? {
@@ -662,7 +730,7 @@ impl BuildTaskBuilder {
             if pre_t.is_dir() && !src_t.is_dir() {
                 let path = path.to_owned();
                 self.push(&format!("rmdir {}", path), |_| {
-                    Ok(BuiltOperation::no_data(metadata::v1::Operation::RmDir { path }))
+                    Ok(BuiltOperation::no_data(metadata::v2::Operation::RmDir { path }))
                 });
             }
         }
@@ -675,16 +743,16 @@ struct Encoded<'a> {
     encoder_options: &'a CoderOptions,
     path: PathBuf,
     data_size: u64,
-    data_sha1: Sha1Hash,
+    data_hash: Hash,
     final_size: u64,
-    final_sha1: Sha1Hash,
+    final_hash: Hash,
 }
 
 #[instrument(skip(ctx, encoders_options, mk_encoder))]
 fn best_encoder<'a>(
     ctx: &mut BuildTaskCtx,
     encoders_options: &'a [CoderOptions],
-    mk_encoder: impl Fn(&CoderOptions, fs::File) -> io::Result<CheckCoder<fs::File, io::CheckSha1Size>>,
+    mk_encoder: impl Fn(&CoderOptions, fs::File) -> io::Result<CheckCoder<fs::File, io::CheckHashSize>>,
     src_slice: &Slice,
 ) -> io::Result<Encoded<'a>> {
     let mut best: Option<Encoded<'a>> = None;
@@ -699,7 +767,11 @@ fn best_encoder<'a>(
         enc_path.push(format!(".{}", encoder_options.name()));
         let mut src_file = src_slice.open()?;
         let enc_file = fs::File::create(&enc_path)?;
-        let mut encoder = mk_encoder(encoder_options, enc_file)?;
+        let hash_algorithm = ctx.options.hash_algorithm();
+        let mut encoder = mk_encoder(encoder_options, enc_file)?.with_checks(
+            io::CheckHashSize::new(hash_algorithm),
+            io::CheckHashSize::new(hash_algorithm),
+        );
         let mut buffer = [0u8; io::BUFFER_SIZE];
         loop {
             let read = src_file.read(&mut buffer)?;
@@ -713,10 +785,10 @@ fn best_encoder<'a>(
         encoder.flush()?;
         let input_checks = encoder.input_checks();
         let final_size = input_checks.bytes;
-        let final_sha1 = input_checks.sha1();
+        let final_hash = input_checks.hash();
         let mut output_checks = encoder.finish()?.check;
         let data_size = output_checks.bytes;
-        let data_sha1 = output_checks.sha1();
+        let data_hash = output_checks.hash();
 
         let ratio = (data_size * 100) / final_size;
 
@@ -724,9 +796,9 @@ fn best_encoder<'a>(
             path: PathBuf::from(&enc_path),
             encoder_options,
             data_size,
-            data_sha1,
+            data_hash,
             final_size,
-            final_sha1,
+            final_hash,
         };
 
         io::assert_eq(encoded.final_size, src_slice.size, "src file size")?;
@@ -761,7 +833,7 @@ fn best_encoder<'a>(
 
 #[derive(Debug, Clone)]
 struct Slice {
-    common: metadata::v1::Common,
+    common: metadata::v2::Common,
     src_path: PathBuf,
     tmp_path: PathBuf,
     offset: u64,
@@ -776,7 +848,7 @@ impl Slice {
 
 fn slices(
     _options: &BuildOptions,
-    common: metadata::v1::Common,
+    common: metadata::v2::Common,
     src_path: PathBuf,
     tmp_path: PathBuf,
 ) -> io::Result<Vec<Slice>> {
@@ -792,7 +864,7 @@ fn slices(
 
 #[cfg(feature = "ue4pak")]
 fn ue4pak_slices(
-    mut common: metadata::v1::Common,
+    mut common: metadata::v2::Common,
     src_path: PathBuf,
     tmp_path: PathBuf,
 ) -> io::Result<Vec<Slice>> {
@@ -811,7 +883,7 @@ fn ue4pak_slices(
     let mut cuts = Vec::new();
 
     let new_cut = |path: &str, offset: u64| {
-        let slice = CleanPath::new(Sha1Hash::digest(path.as_bytes()).to_string())
+        let slice = CleanPath::new(metadata::Sha1Hash::digest(path.as_bytes()).to_string())
             .expect("sha1 is cleanpath valid");
         (offset, slice)
     };
@@ -838,7 +910,7 @@ fn ue4pak_slices(
     let mut prev = it.next().unwrap();
     for cut in it {
         let slice = Slice {
-            common: metadata::v1::Common { slice: Some(prev.1), ..common.clone() },
+            common: metadata::v2::Common { slice: Some(prev.1), ..common.clone() },
             src_path: src_path.clone(),
             tmp_path: tmp_path.clone(),
             offset: prev.0,
@@ -848,7 +920,7 @@ fn ue4pak_slices(
         prev = cut;
     }
     let slice = Slice {
-        common: metadata::v1::Common { slice: Some(prev.1), ..common.clone() },
+        common: metadata::v2::Common { slice: Some(prev.1), ..common.clone() },
         src_path,
         tmp_path,
         offset: prev.0,
@@ -868,16 +940,16 @@ fn add_file(ctx: &mut BuildTaskCtx, src_slice: Slice) -> Result<BuiltOperation,
         |encoder_options, enc_file| CheckCoder::encoder(encoder_options, enc_file),
         &src_slice,
     )?;
-    let op = metadata::v1::Operation::Add(metadata::v1::Add {
+    let op = metadata::v2::Operation::Add(metadata::v2::Add {
         common: src_slice.common,
         data_offset: 0,
         data_size: best_compressor.data_size,
-        data_sha1: best_compressor.data_sha1,
+        data_hash: best_compressor.data_hash,
         data_compression: CleanName::new(best_compressor.encoder_options.name().to_string())
             .expect("supported encoder name to be clean"),
         final_offset: 0,
         final_size: best_compressor.final_size,
-        final_sha1: best_compressor.final_sha1,
+        final_hash: best_compressor.final_hash,
     });
 
     Ok(BuiltOperation::with_data(best_compressor.path, op))
@@ -891,7 +963,7 @@ fn patch_file(
     let options = ctx.options.clone();
     let mut are_equals = src_slice.size == pre_slice.size;
 
-    let mut pre_file = io::CheckReader::new(pre_slice.open()?);
+    let mut pre_file = io::CheckReader::with_algorithm(pre_slice.open()?, options.hash_algorithm());
     let mut pre_buffer = [0u8; BUFFER_SIZE];
     if are_equals {
         // same len, let's check if content is the same
@@ -907,12 +979,12 @@ fn patch_file(
         }
         if are_equals {
             // same content
-            return Ok(BuiltOperation::no_data(metadata::v1::Operation::Check(
-                metadata::v1::Check {
+            return Ok(BuiltOperation::no_data(metadata::v2::Operation::Check(
+                metadata::v2::Check {
                     common: src_slice.common,
                     local_offset: 0,
                     local_size: pre_file.read_bytes(),
-                    local_sha1: pre_file.sha1(),
+                    local_hash: pre_file.hash(),
                 },
             )));
         }
@@ -923,7 +995,7 @@ fn patch_file(
             break;
         }
     }
-    let pre_sha1 = pre_file.sha1();
+    let pre_hash = pre_file.hash();
     io::assert_eq(pre_file.read_bytes(), pre_slice.size, "pre file size")?;
     drop(pre_file);
 
@@ -943,11 +1015,13 @@ fn patch_file(
         &options.compressors,
         |encoder_options, enc_file| CheckCoder::encoder(encoder_options, enc_file),
         &Slice {
-            common: metadata::v1::Common {
+            common: metadata::v2::Common {
                 path: CleanPath::from_static_str("unreachable"),
                 slice: None,
                 exe: false,
                 slice_handler: None,
+                preserve: None,
+                components: Vec::new(),
             },
             src_path: best_patcher.path.clone(),
             tmp_path: best_patcher.path.clone(),
@@ -957,33 +1031,33 @@ fn patch_file(
     )?;
     let op = if best_patcher.encoder_options.name() == "raw" {
         // i.e. patch is bigger than file
-        metadata::v1::Operation::Add(metadata::v1::Add {
+        metadata::v2::Operation::Add(metadata::v2::Add {
             common: src_slice.common,
             data_offset: 0,
             data_size: best_compressor.data_size,
-            data_sha1: best_compressor.data_sha1,
+            data_hash: best_compressor.data_hash,
             data_compression: CleanName::new(best_patcher.encoder_options.name().to_string())
                 .expect("supported encoder name to be clean"),
             final_offset: 0,
             final_size: best_patcher.final_size,
-            final_sha1: best_patcher.final_sha1,
+            final_hash: best_patcher.final_hash,
         })
     } else {
-        metadata::v1::Operation::Patch(metadata::v1::Patch {
+        metadata::v2::Operation::Patch(metadata::v2::Patch {
             common: src_slice.common,
             data_offset: 0,
             data_size: best_compressor.data_size,
-            data_sha1: best_compressor.data_sha1,
+            data_hash: best_compressor.data_hash,
             data_compression: CleanName::new(best_compressor.encoder_options.name().to_string())
                 .expect("supported encoder name to be clean"),
             patch_type: CleanName::new(best_patcher.encoder_options.name().to_string())
                 .expect("supported encoder name to be clean"),
             local_offset: 0,
             local_size: pre_slice.size,
-            local_sha1: pre_sha1,
+            local_hash: pre_hash,
             final_offset: 0,
             final_size: best_patcher.final_size,
-            final_sha1: best_patcher.final_sha1,
+            final_hash: best_patcher.final_hash,
         })
     };
 
@@ -0,0 +1,131 @@
+//! Planning of skip packages, direct patches between non consecutive
+//! versions that shorten update paths to the current version
+use super::Repository;
+use crate::io;
+use crate::metadata::{CleanName, Package};
+
+/// How skip packages are chosen
+#[derive(Debug, Clone)]
+pub enum SkipStrategy {
+    /// Patches from every `base^k`-th version to the next one, for every
+    /// level `k >= 1`, any version is at most `base - 1` patches away from
+    /// the next level
+    SkipList { base: usize },
+    /// Patches from each hub version to the current one, and from every
+    /// other version to the next hub
+    Hubs(Vec<CleanName>),
+}
+
+/// A patch package to build
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkipEdge {
+    pub from: CleanName,
+    pub to: CleanName,
+}
+
+/// Skip edges between `revisions` (oldest first, the last one is the current
+/// version) that `packages` doesn't already provide
+fn plan<P: Package>(
+    revisions: &[&CleanName],
+    packages: &[P],
+    strategy: &SkipStrategy,
+) -> Vec<SkipEdge> {
+    let last = match revisions.len().checked_sub(1) {
+        Some(last) => last,
+        None => return Vec::new(),
+    };
+    let mut edges: Vec<(usize, usize)> = Vec::new();
+    match strategy {
+        SkipStrategy::SkipList { base } => {
+            let base = (*base).max(2);
+            let mut step = base;
+            while step <= last {
+                edges.extend((0..=last - step).step_by(step).map(|from| (from, from + step)));
+                step = match step.checked_mul(base) {
+                    Some(step) => step,
+                    None => break,
+                };
+            }
+        }
+        SkipStrategy::Hubs(hubs) => {
+            let mut hubs: Vec<usize> = hubs
+                .iter()
+                .filter_map(|hub| revisions.iter().position(|&revision| revision == hub))
+                .filter(|&hub| hub < last)
+                .collect();
+            hubs.sort_unstable();
+            hubs.dedup();
+            hubs.push(last);
+            let mut next_hub = hubs.iter().peekable();
+            for from in 0..last {
+                while next_hub.next_if(|&&hub| hub <= from).is_some() {}
+                let to = *next_hub.peek().expect("the current version to be a hub");
+                edges.push((from, *to));
+                if hubs.contains(&from) {
+                    edges.push((from, last));
+                }
+            }
+        }
+    }
+    edges.sort_unstable();
+    edges.dedup();
+    edges
+        .into_iter()
+        .map(|(from, to)| SkipEdge { from: revisions[from].clone(), to: revisions[to].clone() })
+        .filter(|edge| !packages.iter().any(|p| p.from() == Some(&edge.from) && p.to() == &edge.to))
+        .collect()
+}
+
+pub(super) fn plan_skip_edges(
+    repository: &Repository,
+    strategy: &SkipStrategy,
+) -> io::Result<Vec<SkipEdge>> {
+    let current = repository.current_version()?.version().clone();
+    let versions = repository.versions()?;
+    let revisions: Vec<&CleanName> = versions.iter().map(|v| v.revision()).collect();
+    let current_idx =
+        revisions.iter().position(|&revision| revision == &current).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("current version {} doesn't exists", current),
+            )
+        })?;
+    let packages = repository.packages()?;
+    Ok(plan(&revisions[..=current_idx], packages.as_slice(), strategy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata;
+
+    #[test]
+    fn plan_skip_edges() {
+        let revisions: Vec<CleanName> =
+            (0..10).map(|i| CleanName::new(format!("v{}", i)).unwrap()).collect();
+        let revisions: Vec<&CleanName> = revisions.iter().collect();
+        let packages: Vec<metadata::v1::Package> = (0..9)
+            .map(|i| metadata::v1::Package {
+                from: Some(revisions[i].clone()),
+                to: revisions[i + 1].clone(),
+                size: 10,
+            })
+            .collect();
+        let edges = |strategy| -> Vec<String> {
+            plan(&revisions, &packages, &strategy)
+                .into_iter()
+                .map(|edge| format!("{}>{}", edge.from, edge.to))
+                .collect()
+        };
+
+        assert_eq!(
+            edges(SkipStrategy::SkipList { base: 3 }),
+            vec!["v0>v3", "v0>v9", "v3>v6", "v6>v9"]
+        );
+        let hubs = vec![CleanName::from_static_str("v5")];
+        assert_eq!(
+            edges(SkipStrategy::Hubs(hubs)),
+            vec!["v0>v5", "v1>v5", "v2>v5", "v3>v5", "v5>v9", "v6>v9", "v7>v9"]
+        );
+    }
+}
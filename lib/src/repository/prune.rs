@@ -0,0 +1,218 @@
+//! Removal of the packages and files a repository doesn't need anymore
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+
+use tracing::info;
+
+use super::Repository;
+use crate::io;
+use crate::metadata::{self, CleanName, Package, Packages};
+use crate::signature::signature_filename;
+
+/// Versions workspaces are expected to update from
+#[derive(Debug, Clone, Default)]
+pub struct PrunePolicy {
+    /// Keep the updates from the last `n` versions released before the current
+    /// one, in the order given by the patch packages
+    pub last_versions: usize,
+    /// Keep the updates from these versions
+    pub versions: Vec<CleanName>,
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Package files (`complete_*`, `patch*`) names
+fn is_package_file(name: &str) -> bool {
+    name.starts_with("complete_") || name.starts_with("patch")
+}
+
+/// `revisions` in release order
+///
+/// A patch package from `a` to `b` means `a` was released before `b`, versions
+/// that no patch orders keep their order in `versions` (i.e. registration
+/// order) and cycles (i.e. downgrade patches) are broken in that order too.
+fn release_order<'a>(
+    revisions: &[&'a CleanName],
+    packages: &[metadata::v1::Package],
+) -> Vec<&'a CleanName> {
+    let idx: HashMap<&CleanName, usize> =
+        revisions.iter().enumerate().map(|(i, &revision)| (revision, i)).collect();
+    let mut next = vec![Vec::new(); revisions.len()];
+    let mut previous_count = vec![0usize; revisions.len()];
+    for package in packages {
+        let from = package.from().and_then(|from| idx.get(from));
+        if let (Some(&from), Some(&to)) = (from, idx.get(package.to())) {
+            if from != to {
+                next[from].push(to);
+                previous_count[to] += 1;
+            }
+        }
+    }
+
+    let mut ordered = vec![false; revisions.len()];
+    let mut ready: BTreeSet<usize> =
+        (0..revisions.len()).filter(|&i| previous_count[i] == 0).collect();
+    let mut order = Vec::with_capacity(revisions.len());
+    while order.len() < revisions.len() {
+        let i = match ready.iter().next() {
+            Some(&i) => i,
+            None => (0..revisions.len()).find(|&i| !ordered[i]).unwrap(),
+        };
+        ready.remove(&i);
+        ordered[i] = true;
+        order.push(revisions[i]);
+        for &j in next[i].iter() {
+            previous_count[j] = previous_count[j].saturating_sub(1);
+            if previous_count[j] == 0 && !ordered[j] {
+                ready.insert(j);
+            }
+        }
+    }
+    order
+}
+
+pub(super) fn gc(repository: &Repository) -> io::Result<Vec<String>> {
+    let mut referenced = BTreeSet::new();
+    for package in repository.packages()?.iter() {
+        for name in [package.package_data_name(), package.package_metadata_name()] {
+            referenced.insert(signature_filename(&name));
+            referenced.insert(name.to_string());
+        }
+    }
+
+    let mut removed = Vec::new();
+    for entry in fs::read_dir(repository.dir())? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if !is_package_file(&name) || referenced.contains(&name) || !entry.file_type()?.is_file() {
+            continue;
+        }
+        info!("remove unreferenced file {}", name);
+        io::remove_file(entry.path())?;
+        removed.push(name);
+    }
+    removed.sort();
+    Ok(removed)
+}
+
+pub(super) fn prune(
+    repository: &Repository,
+    policy: &PrunePolicy,
+) -> io::Result<Vec<metadata::v1::Package>> {
+    let current = repository.current_version()?.version().clone();
+    let versions = repository.versions()?;
+    let packages = repository.packages()?;
+    let packages = packages.as_slice();
+    let revisions: Vec<&CleanName> = versions.iter().map(|v| v.revision()).collect();
+    let revisions = release_order(&revisions, packages);
+    let current_idx = revisions
+        .iter()
+        .position(|&revision| revision == &current)
+        .ok_or_else(|| invalid_input(format!("current version {} doesn't exists", current)))?;
+    let mut starts: Vec<Option<&CleanName>> = vec![None];
+    starts.extend(
+        revisions[current_idx.saturating_sub(policy.last_versions)..current_idx]
+            .iter()
+            .map(|&revision| Some(revision)),
+    );
+    for version in policy.versions.iter() {
+        if !revisions.contains(&version) {
+            return Err(invalid_input(format!("version {} doesn't exists", version)));
+        }
+        starts.push(Some(version));
+    }
+
+    let mut kept = BTreeSet::new();
+    for start in starts.into_iter().filter(|&start| start != Some(&current)) {
+        let path = metadata::shortest_path(start, &current, packages).ok_or_else(|| {
+            let start = start.map_or_else(|| "an empty workspace".to_owned(), |s| s.to_string());
+            invalid_input(format!("no packages lead from {} to {}", start, current))
+        })?;
+        kept.extend(path.into_iter().map(|package| package.package_metadata_name()));
+    }
+    let (kept, removed): (Vec<_>, Vec<_>) = packages
+        .iter()
+        .cloned()
+        .partition(|package| kept.contains(&package.package_metadata_name()));
+
+    // Every version must still be able to update, even if it's with the
+    // complete package
+    for &revision in revisions.iter().filter(|&&revision| revision != &current) {
+        if metadata::shortest_path(Some(revision), &current, &kept).is_none() {
+            return Err(invalid_input(format!("version {} would have no update path", revision)));
+        }
+    }
+
+    for package in removed.iter() {
+        info!("unregister package {}", package.package_metadata_name());
+    }
+    let packages = Packages::V1 { packages: kept };
+    repository.write_json(Packages::filename(), &packages)?;
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prune_and_gc() {
+        let dir = crate::tests::tmp_dir("prune_and_gc");
+        let mut repository = Repository::new(dir.clone());
+        repository.init().unwrap();
+        let mut packages = Vec::new();
+        let graph = [("", "v1"), ("v1", "v2"), ("", "v3"), ("v2", "v3"), ("v1", "v3")];
+        for (from, to) in graph {
+            let revision = CleanName::new(to.to_string()).unwrap();
+            let version = metadata::v1::Version { revision, description: String::new() };
+            repository.register_version(&version).unwrap();
+            let package: metadata::v1::Package = serde_json::from_value(serde_json::json!({
+                "from": from, "to": to, "size": if from.is_empty() { "1000" } else { "10" },
+            }))
+            .unwrap();
+            fs::write(dir.join(package.package_data_name()), b"").unwrap();
+            fs::write(dir.join(package.package_metadata_name()), b"").unwrap();
+            packages.push(package);
+        }
+        fs::write(dir.join("patchv0_v1"), b"").unwrap();
+        fs::write(dir.join("release.key"), b"").unwrap();
+        let packages = Packages::V1 { packages };
+        io::atomic_write_json(dir.join(Packages::filename()), &packages).unwrap();
+        repository.set_current_version(&CleanName::from_static_str("v3")).unwrap();
+        // re-registering v2 moves it after v3 in `versions`
+        let v2 = metadata::v1::Version {
+            revision: CleanName::from_static_str("v2"),
+            description: "v2".to_owned(),
+        };
+        repository.register_version(&v2).unwrap();
+
+        let policy = PrunePolicy { last_versions: 1, versions: Vec::new() };
+        let removed = repository.prune(&policy).unwrap();
+        let removed: Vec<_> =
+            removed.iter().map(|p| p.package_metadata_name().to_string()).collect();
+        assert_eq!(
+            removed,
+            vec!["complete_v1.metadata", "patchv1_v2.metadata", "patchv1_v3.metadata"]
+        );
+
+        let removed = repository.gc().unwrap();
+        assert_eq!(
+            removed,
+            vec![
+                "complete_v1",
+                "complete_v1.metadata",
+                "patchv0_v1",
+                "patchv1_v2",
+                "patchv1_v2.metadata",
+                "patchv1_v3",
+                "patchv1_v3.metadata",
+            ]
+        );
+        assert!(dir.join("release.key").exists() && dir.join("patchv2_v3").exists());
+    }
+}
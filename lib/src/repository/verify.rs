@@ -0,0 +1,295 @@
+//! Consistency checks of a repository (see [`Repository::verify`])
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+
+use super::Repository;
+use crate::io::{self, Read, Seek};
+use crate::metadata::{self, CleanName, CleanPath, Hash, Operation, Package, PackageMetadata};
+
+/// Verify options
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    /// Hash the data of every operation and compare it to its data hash
+    pub deep: bool,
+}
+
+/// Inconsistency found by [`Repository::verify`]
+#[derive(Debug)]
+pub enum Inconsistency {
+    /// A repository file is missing or invalid
+    UnreadableFile { file: CleanName, err: io::Error },
+    /// `current` isn't listed by `versions`
+    UnknownCurrentVersion { version: CleanName },
+    /// A package goes from or to a version `versions` doesn't list
+    UnknownVersion { package: CleanName, version: CleanName },
+    /// No packages lead from an empty workspace to this version
+    UnreachableVersion { version: CleanName },
+    /// The package described by the metadata file isn't the registered one
+    PackageMismatch { package: CleanName },
+    /// The size of the data file isn't the package size
+    DataSizeMismatch { package: CleanName, expected: u64, found: u64 },
+    /// The data of an operation lies outside the data file
+    OutOfRange { package: CleanName, path: CleanPath, range: Range<u64> },
+    /// The data of an operation doesn't match its data hash
+    DataHashMismatch { package: CleanName, path: CleanPath, expected: Hash, found: Hash },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inconsistency::UnreadableFile { file, err } => {
+                write!(f, "unable to read {}: {}", file, err)
+            }
+            Inconsistency::UnknownCurrentVersion { version } => {
+                write!(f, "current version {} isn't a registered version", version)
+            }
+            Inconsistency::UnknownVersion { package, version } => {
+                write!(f, "package {} references unknown version {}", package, version)
+            }
+            Inconsistency::UnreachableVersion { version } => {
+                write!(f, "no packages lead to version {}", version)
+            }
+            Inconsistency::PackageMismatch { package } => {
+                write!(f, "package {} doesn't match its metadata file", package)
+            }
+            Inconsistency::DataSizeMismatch { package, expected, found } => write!(
+                f,
+                "package {} data size mismatch (expected = {}, found = {})",
+                package, expected, found
+            ),
+            Inconsistency::OutOfRange { package, path, range } => write!(
+                f,
+                "package {} data of {} ({}..{}) is out of the data file",
+                package, path, range.start, range.end
+            ),
+            Inconsistency::DataHashMismatch { package, path, expected, found } => write!(
+                f,
+                "package {} data hash of {} mismatch (expected = {}, found = {})",
+                package, path, expected, found
+            ),
+        }
+    }
+}
+
+fn file_name(name: &'static str) -> CleanName {
+    CleanName::from_static_str(name)
+}
+
+/// Data hash of every operation with data
+fn data_hashes(package_metadata: &PackageMetadata) -> Vec<(CleanPath, Range<u64>, Hash)> {
+    package_metadata
+        .operations()
+        .into_iter()
+        .filter_map(|o| {
+            let range = o.range()?;
+            match o {
+                metadata::v2::Operation::Add(op) => Some((op.common.path, range, op.data_hash)),
+                metadata::v2::Operation::Patch(op) => Some((op.common.path, range, op.data_hash)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn hash_range(file: &mut fs::File, range: &Range<u64>, expected: &Hash) -> io::Result<Hash> {
+    file.seek(io::SeekFrom::Start(range.start))?;
+    let mut reader = io::CheckReader::with_algorithm(
+        Read::take(&mut *file, range.end - range.start),
+        expected.algorithm(),
+    );
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    Ok(reader.hash())
+}
+
+fn verify_package(
+    repository: &Repository,
+    package: &metadata::v1::Package,
+    options: &VerifyOptions,
+    inconsistencies: &mut Vec<Inconsistency>,
+) {
+    let package_metadata_name = package.package_metadata_name();
+    let package_data_name = package.package_data_name();
+    let package_metadata = match repository.package_metadata(&package_metadata_name) {
+        Ok(package_metadata) => package_metadata,
+        Err(err) => {
+            inconsistencies
+                .push(Inconsistency::UnreadableFile { file: package_metadata_name, err });
+            return;
+        }
+    };
+    match &package_metadata {
+        PackageMetadata::V1 { package: metadata_package, .. }
+        | PackageMetadata::V2 { package: metadata_package, .. } => {
+            if metadata_package != package {
+                inconsistencies.push(Inconsistency::PackageMismatch {
+                    package: package_metadata_name.clone(),
+                });
+            }
+        }
+    }
+
+    let data_path = repository.dir.join(&package_data_name);
+    let mut file = match fs::File::open(&data_path).and_then(|file| Ok((file.metadata()?, file))) {
+        Ok((data_metadata, file)) => {
+            if data_metadata.len() != package.size {
+                inconsistencies.push(Inconsistency::DataSizeMismatch {
+                    package: package_data_name.clone(),
+                    expected: package.size,
+                    found: data_metadata.len(),
+                });
+            }
+            Some((data_metadata.len(), file))
+        }
+        Err(err) => {
+            inconsistencies
+                .push(Inconsistency::UnreadableFile { file: package_data_name.clone(), err });
+            None
+        }
+    };
+
+    for (path, range, expected) in data_hashes(&package_metadata) {
+        let (data_len, file) = match &mut file {
+            Some((data_len, file)) => (*data_len, file),
+            None => return,
+        };
+        if range.end > data_len.min(package.size) {
+            inconsistencies.push(Inconsistency::OutOfRange {
+                package: package_data_name.clone(),
+                path,
+                range,
+            });
+            continue;
+        }
+        if !options.deep {
+            continue;
+        }
+        match hash_range(file, &range, &expected) {
+            Ok(found) if found == expected => {}
+            Ok(found) => inconsistencies.push(Inconsistency::DataHashMismatch {
+                package: package_data_name.clone(),
+                path,
+                expected,
+                found,
+            }),
+            Err(err) => {
+                inconsistencies
+                    .push(Inconsistency::UnreadableFile { file: package_data_name.clone(), err });
+                return;
+            }
+        }
+    }
+}
+
+pub(super) fn verify(repository: &Repository, options: &VerifyOptions) -> Vec<Inconsistency> {
+    let mut inconsistencies = Vec::new();
+    let versions: BTreeSet<CleanName> = match repository.versions() {
+        Ok(versions) => versions.iter().map(|v| v.revision().clone()).collect(),
+        Err(err) => {
+            let file = file_name(metadata::Versions::filename());
+            inconsistencies.push(Inconsistency::UnreadableFile { file, err });
+            BTreeSet::new()
+        }
+    };
+    let packages = match repository.packages() {
+        Ok(packages) => packages,
+        Err(err) => {
+            let file = file_name(metadata::Packages::filename());
+            inconsistencies.push(Inconsistency::UnreadableFile { file, err });
+            metadata::Packages::V1 { packages: Vec::new() }
+        }
+    };
+    // `current` is created by the first `set_current_version`
+    let current = match repository.current_version() {
+        Ok(current) => Some(current.version().clone()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => {
+            let file = file_name(metadata::Current::filename());
+            inconsistencies.push(Inconsistency::UnreadableFile { file, err });
+            None
+        }
+    };
+
+    let mut reachables = versions.clone();
+    if let Some(version) = current.filter(|version| !versions.contains(version)) {
+        inconsistencies.push(Inconsistency::UnknownCurrentVersion { version: version.clone() });
+        reachables.insert(version);
+    }
+    for package in packages.iter() {
+        for version in package.from().into_iter().chain(Some(package.to())) {
+            if !versions.contains(version) {
+                inconsistencies.push(Inconsistency::UnknownVersion {
+                    package: package.package_metadata_name(),
+                    version: version.clone(),
+                });
+            }
+        }
+    }
+    for version in reachables {
+        if metadata::shortest_path(None, &version, packages.as_slice()).is_none() {
+            inconsistencies.push(Inconsistency::UnreachableVersion { version });
+        }
+    }
+
+    for package in packages.as_slice() {
+        verify_package(repository, package, options, &mut inconsistencies);
+    }
+    inconsistencies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_repository() {
+        let dir = crate::tests::tmp_dir("verify_repository");
+        let mut repository = Repository::new(dir.clone());
+        repository.init().unwrap();
+        let v1 = CleanName::from_static_str("v1");
+        let version = metadata::v1::Version { revision: v1.clone(), description: String::new() };
+        repository.register_version(&version).unwrap();
+        repository.set_current_version(&v1).unwrap();
+
+        let package_metadata: PackageMetadata = serde_json::from_value(serde_json::json!({
+            "version": "2",
+            "package": { "from": "", "to": "v1", "size": "5" },
+            "operations": [{
+                "type": "add", "path": "a.txt", "dataOffset": "0", "dataSize": "5",
+                "dataHash": "sha1:aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d",
+                "dataCompression": "raw", "finalSize": "5",
+                "finalHash": "sha1:aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d",
+            }],
+        }))
+        .unwrap();
+        let package_metadata_name = package_metadata.package_metadata_name();
+        fs::write(dir.join(package_metadata.package_data_name()), b"hello").unwrap();
+        let file = fs::File::create(dir.join(&package_metadata_name)).unwrap();
+        serde_json::to_writer(file, &package_metadata).unwrap();
+        repository.register_package(&package_metadata_name).unwrap();
+        let deep = VerifyOptions { deep: true };
+        assert!(repository.verify(&deep).is_empty());
+
+        fs::write(dir.join(package_metadata.package_data_name()), b"hellO").unwrap();
+        assert!(repository.verify(&VerifyOptions::default()).is_empty());
+        let inconsistencies = repository.verify(&deep);
+        assert!(matches!(inconsistencies[..], [Inconsistency::DataHashMismatch { .. }]));
+
+        fs::write(dir.join(package_metadata.package_data_name()), b"hel").unwrap();
+        let v2 = metadata::v1::Version {
+            revision: CleanName::from_static_str("v2"),
+            description: String::new(),
+        };
+        repository.register_version(&v2).unwrap();
+        let inconsistencies = repository.verify(&deep);
+        assert!(matches!(
+            inconsistencies[..],
+            [
+                Inconsistency::UnreachableVersion { .. },
+                Inconsistency::DataSizeMismatch { expected: 5, found: 3, .. },
+                Inconsistency::OutOfRange { .. },
+            ]
+        ));
+    }
+}
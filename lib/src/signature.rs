@@ -0,0 +1,207 @@
+//! Ed25519 detached signatures of repository metadata files.
+//!
+//! `current`, `versions`, `packages` and `*.metadata` files are signed by the
+//! repository owner, the signature is stored base64 encoded in a `.sig` file
+//! next to the signed file. Operations embed the sha1 of the package data, so
+//! signing the metadata also authenticates the data.
+//!
+//! The signature also covers the time it was made, which is written before it
+//! in the `.sig` file and only grows for a given file. Workspaces remember the
+//! time of the last `current` file they trusted and refuse older ones, so an
+//! outdated `current` can't be replayed to downgrade them.
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use ed25519_dalek::Signer;
+
+/// Name of the detached signature file of `file_name`
+pub fn signature_filename(file_name: &str) -> String {
+    format!("{}.sig", file_name)
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+    Base64(base64::DecodeError),
+    Invalid(ed25519_dalek::SignatureError),
+    Random(getrandom::Error),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyError::Base64(err) => write!(f, "invalid key encoding: {}", err),
+            KeyError::Invalid(err) => write!(f, "invalid key: {}", err),
+            KeyError::Random(err) => write!(f, "unable to generate key: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+#[derive(Debug)]
+pub enum SignatureError {
+    /// The detached signature file doesn't exist
+    Unsigned,
+    /// The detached signature file isn't a valid signature
+    Malformed,
+    /// The signature doesn't match the content or the trusted key
+    Mismatch,
+    /// The signature is older than the one of the file already trusted
+    Replayed,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::Unsigned => write!(f, "signature is missing"),
+            SignatureError::Malformed => write!(f, "signature is malformed"),
+            SignatureError::Mismatch => write!(f, "signature doesn't match"),
+            SignatureError::Replayed => write!(f, "signature is older than the trusted one"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Time of the signature and the base64 encoded signature of the content of a
+/// detached signature file
+fn parse(signature: &[u8]) -> Option<(u64, &str)> {
+    let (signed_at, signature) = std::str::from_utf8(signature).ok()?.trim().split_once(' ')?;
+    Some((signed_at.parse().ok()?, signature))
+}
+
+/// Time the content of a detached signature file claims it was signed at
+///
+/// The signature itself isn't verified.
+pub fn signed_at(signature: &[u8]) -> Option<u64> {
+    parse(signature).map(|(signed_at, _)| signed_at)
+}
+
+/// Message actually signed, the signature time comes first so it can't be
+/// confused with the data
+fn signed_message(data: &[u8], signed_at: u64) -> Vec<u8> {
+    let mut message = Vec::with_capacity(8 + data.len());
+    message.extend_from_slice(&signed_at.to_be_bytes());
+    message.extend_from_slice(data);
+    message
+}
+
+/// Secret key used to sign repository metadata files
+pub struct SigningKey {
+    keypair: ed25519_dalek::Keypair,
+}
+
+impl SigningKey {
+    /// Generate a new random key
+    pub fn generate() -> Result<Self, KeyError> {
+        let mut seed = [0u8; ed25519_dalek::SECRET_KEY_LENGTH];
+        getrandom::getrandom(&mut seed).map_err(KeyError::Random)?;
+        Self::from_bytes(&seed)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, KeyError> {
+        let secret = ed25519_dalek::SecretKey::from_bytes(bytes).map_err(KeyError::Invalid)?;
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        Ok(Self { keypair: ed25519_dalek::Keypair { secret, public } })
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Self, KeyError> {
+        Self::from_bytes(&base64::decode(encoded.trim()).map_err(KeyError::Base64)?)
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.keypair.secret.as_bytes())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.keypair.public.to_bytes())
+    }
+
+    /// Returns the content of the detached signature file of `data`, signed
+    /// at `signed_at` (seconds since the unix epoch)
+    pub fn sign(&self, data: &[u8], signed_at: u64) -> String {
+        let signature = self.keypair.sign(&signed_message(data, signed_at));
+        format!("{} {}", signed_at, base64::encode(signature.to_bytes()))
+    }
+}
+
+/// Public key trusted to sign repository metadata files
+///
+/// Only the compressed form is kept, the decompressed point is 6 times larger
+/// and this key is embedded in update options.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublicKey([u8; ed25519_dalek::PUBLIC_KEY_LENGTH]);
+
+impl PublicKey {
+    pub fn from_base64(encoded: &str) -> Result<Self, KeyError> {
+        let bytes = base64::decode(encoded.trim()).map_err(KeyError::Base64)?;
+        let key = ed25519_dalek::PublicKey::from_bytes(&bytes).map_err(KeyError::Invalid)?;
+        Ok(Self(key.to_bytes()))
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.0)
+    }
+
+    /// Verify `data` against the content of its detached signature file
+    ///
+    /// Returns the time the signature was made.
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> Result<u64, SignatureError> {
+        let (signed_at, signature) = parse(signature).ok_or(SignatureError::Malformed)?;
+        let signature = base64::decode(signature)
+            .ok()
+            .and_then(|signature| ed25519_dalek::Signature::try_from(&signature[..]).ok())
+            .ok_or(SignatureError::Malformed)?;
+        ed25519_dalek::PublicKey::from_bytes(&self.0)
+            .and_then(|key| key.verify_strict(&signed_message(data, signed_at), &signature))
+            .map_err(|_| SignatureError::Mismatch)?;
+        Ok(signed_at)
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_base64(s)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_base64())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_verify() {
+        let key = SigningKey::generate().unwrap();
+        let key = SigningKey::from_base64(&key.to_base64()).unwrap();
+        let public_key: PublicKey = key.public_key().to_string().parse().unwrap();
+        let signature = key.sign(b"{\"version\":\"1\"}", 1000);
+
+        assert_eq!(public_key.verify(b"{\"version\":\"1\"}", signature.as_bytes()).unwrap(), 1000);
+        assert_eq!(signed_at(signature.as_bytes()), Some(1000));
+        // the signature time is signed too
+        let replaced = signature.replacen("1000", "2000", 1);
+        assert!(matches!(
+            public_key.verify(b"{\"version\":\"1\"}", replaced.as_bytes()),
+            Err(SignatureError::Mismatch)
+        ));
+        assert!(matches!(
+            public_key.verify(b"{\"version\":\"2\"}", signature.as_bytes()),
+            Err(SignatureError::Mismatch)
+        ));
+        assert!(matches!(
+            public_key.verify(b"{\"version\":\"1\"}", b"not a signature"),
+            Err(SignatureError::Malformed)
+        ));
+        let other_key = SigningKey::generate().unwrap().public_key();
+        assert!(other_key.verify(b"{\"version\":\"1\"}", signature.as_bytes()).is_err());
+    }
+}
@@ -2,8 +2,10 @@ use std::collections::VecDeque;
 use std::fmt;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
+use std::ops::Range;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::task::{Context, Poll};
 use std::thread;
 use std::{cmp, pin::Pin};
@@ -11,10 +13,12 @@ use std::{cmp, pin::Pin};
 use futures::{prelude::*, task::AtomicWaker};
 use tracing::{debug, info, warn};
 
+use super::handle::UpdateHandle;
+use super::preserve::{self, PristineHashes};
 use super::updater::UpdateOptions;
 use crate::handlers::{ApplyHandler, ApplyOperation, HandlerContext};
 use crate::io;
-use crate::metadata::{self, v1, Operation};
+use crate::metadata::{self, v2, Operation};
 use crate::workspace::{UpdatePosition, WorkspaceFileManager};
 
 type Item = Result<ApplyPackageProgression, ApplyError>;
@@ -26,6 +30,11 @@ pub enum ApplyError {
         slice: Option<metadata::CleanPath>,
         cause: std::io::Error,
     },
+    /// The file differs from the version of the workspace, but its preserve
+    /// policy keeps it
+    LocallyModified {
+        path: metadata::CleanPath,
+    },
     Cancelled,
     PoisonError,
 }
@@ -39,6 +48,7 @@ impl fmt::Display for ApplyError {
             ApplyError::OperationFailed { path, cause, slice: None } => {
                 write!(f, "operation {} failed: {}", path, cause)
             }
+            ApplyError::LocallyModified { path } => write!(f, "{} is locally modified", path),
             ApplyError::Cancelled => write!(f, "download abort"),
             ApplyError::PoisonError => write!(f, "mutex poison error"),
         }
@@ -68,6 +78,7 @@ pub fn notify(mutex: &Mutex<(VecDeque<Item>, AtomicWaker)>, value: Item) {
         let push = match (data.0.front_mut(), &value) {
             (Some(&mut Ok(ref mut cur_pos)), &Ok(ref new_pos)) => {
                 cur_pos.operation_idx = new_pos.operation_idx;
+                cur_pos.applied_operations.extend(&new_pos.applied_operations);
                 cur_pos.delta_input_bytes += new_pos.delta_input_bytes;
                 cur_pos.delta_output_bytes += new_pos.delta_output_bytes;
                 false
@@ -97,6 +108,21 @@ impl AvailableForApply {
         Self { shared: Arc::new((Mutex::new((ApplyState::Continue, available)), Condvar::new())) }
     }
 
+    fn cancel(&self) {
+        let &(ref lock, ref cvar) = &*self.shared;
+        if let Ok(mut started) = lock.lock() {
+            (*started).0 = ApplyState::Cancel;
+        }
+        cvar.notify_all();
+    }
+
+    fn is_cancelled(&self) -> bool {
+        match self.shared.0.lock() {
+            Ok(started) => matches!(started.0, ApplyState::Cancel),
+            Err(_) => true,
+        }
+    }
+
     fn wait_until<F>(&self, until: F) -> Result<UpdatePosition, InternalApplyError>
     where
         F: Fn(&UpdatePosition) -> bool,
@@ -125,6 +151,7 @@ pub struct ApplyStream {
     done: Arc<AtomicUsize>,
     i_available: AvailableForApply,
     o_applied: Arc<Mutex<(VecDeque<Item>, AtomicWaker)>>,
+    handle: UpdateHandle,
 }
 
 impl ApplyStream {
@@ -132,14 +159,19 @@ impl ApplyStream {
         let &(ref lock, ref cvar) = &*self.i_available.shared;
         let mut started = lock.lock().unwrap();
         (*started).1 = value;
-        cvar.notify_one();
+        cvar.notify_all();
     }
 
+    /// Stop the workers, paused ones included
     pub fn cancel(&self) {
-        let &(ref lock, ref cvar) = &*self.i_available.shared;
-        let mut started = lock.lock().unwrap();
-        (*started).0 = ApplyState::Cancel;
-        cvar.notify_one();
+        self.i_available.cancel();
+        self.handle.wake_waiters();
+    }
+}
+
+impl Drop for ApplyStream {
+    fn drop(&mut self) {
+        self.cancel();
     }
 }
 
@@ -171,211 +203,646 @@ impl Stream for ApplyStream {
 }
 
 pub struct ApplyPackageProgression {
+    /// Every operation before this index is applied
     pub operation_idx: usize,
+    /// Operations applied since the last progression, maybe after
+    /// `operation_idx` as operations finish out of order
+    pub applied_operations: Vec<usize>,
     pub delta_applied_files: usize,
     pub delta_input_bytes: u64,
     pub delta_output_bytes: u64,
 }
 
-pub(crate) fn apply_package(
+/// Consecutive operations applied by the same worker
+///
+/// Operations on the same path are never split across jobs, so sliced
+/// handlers keep their state through `try_still_compatible`.
+struct Job {
+    /// Positions in the package operations
+    operations: Range<usize>,
+    path: metadata::CleanPath,
+    /// Directory operations wait for every previous job and block next ones
+    barrier: bool,
+}
+
+fn jobs(operations: &[(usize, Arc<v2::Operation>)]) -> Vec<Job> {
+    let mut jobs: Vec<Job> = Vec::new();
+    for (pos, (_, operation)) in operations.iter().enumerate() {
+        let barrier =
+            matches!(**operation, v2::Operation::MkDir { .. } | v2::Operation::RmDir { .. });
+        match jobs.last_mut() {
+            Some(job) if !barrier && !job.barrier && &job.path == operation.path() => {
+                job.operations.end = pos + 1;
+            }
+            _ => {
+                jobs.push(Job { operations: pos..pos + 1, path: operation.path().clone(), barrier })
+            }
+        }
+    }
+    jobs
+}
+
+#[derive(Default)]
+struct Schedule {
+    /// Next job to be taken by a worker
+    next_job: usize,
+    /// First job not done yet
+    first_pending_job: usize,
+    jobs_done: Vec<bool>,
+    operations_done: Vec<bool>,
+    /// Number of leading operations done, this is what is reported as applied
+    /// so resuming an update never skips an operation
+    applied: usize,
+    cancelled: bool,
+}
+
+/// Workers applying the operations of a package
+struct Pool {
     update_options: UpdateOptions,
     file_manager: WorkspaceFileManager,
-    package_name: &metadata::CleanName,
-    operations: Vec<(usize, Arc<v1::Operation>)>,
-    i_available: AvailableForApply,
-) -> ApplyStream {
-    let done = Arc::new(AtomicUsize::new(0));
-    let o_applied = Arc::new(Mutex::new((VecDeque::new(), AtomicWaker::new())));
-    let t_done = done.clone();
-    let t_applied = o_applied.clone();
-    let t_available = i_available.clone();
-    let package_name = package_name.to_string();
-    thread::spawn(move || -> () {
-        let terr_applied = t_applied.clone();
-        let mut applied_data = UpdatePosition::new();
+    package_name: String,
+    backup_dir: Option<PathBuf>,
+    stage_dir: Option<PathBuf>,
+    pristine_hashes: Arc<PristineHashes>,
+    operations: Vec<(usize, Arc<v2::Operation>)>,
+    jobs: Vec<Job>,
+    schedule: Mutex<Schedule>,
+    scheduled: Condvar,
+    available: AvailableForApply,
+    handle: UpdateHandle,
+    applied: Arc<Mutex<(VecDeque<Item>, AtomicWaker)>>,
+    running_workers: AtomicUsize,
+    done: Arc<AtomicUsize>,
+}
+
+impl Pool {
+    fn lock_schedule(&self) -> Result<MutexGuard<'_, Schedule>, InternalApplyError> {
+        self.schedule.lock().map_err(|_| InternalApplyError::PoisonError)
+    }
+
+    fn can_start(&self, schedule: &Schedule, job_idx: usize) -> bool {
+        let job = &self.jobs[job_idx];
+        (schedule.first_pending_job..job_idx).all(|idx| {
+            let other = &self.jobs[idx];
+            schedule.jobs_done[idx] || !(job.barrier || other.barrier || job.path == other.path)
+        })
+    }
+
+    /// Take the next job and wait until every job it depends on is done
+    fn next_job(&self) -> Result<Option<usize>, InternalApplyError> {
+        let mut schedule = self.lock_schedule()?;
+        if schedule.next_job >= self.jobs.len() {
+            return Ok(None);
+        }
+        let job_idx = schedule.next_job;
+        schedule.next_job += 1;
+        loop {
+            if schedule.cancelled {
+                return Err(InternalApplyError::Cancelled);
+            }
+            if self.can_start(&schedule, job_idx) {
+                return Ok(Some(job_idx));
+            }
+            schedule =
+                self.scheduled.wait(schedule).map_err(|_| InternalApplyError::PoisonError)?;
+        }
+    }
+
+    fn job_done(&self, job_idx: usize) -> Result<(), InternalApplyError> {
+        let mut schedule = self.lock_schedule()?;
+        schedule.jobs_done[job_idx] = true;
+        while schedule.first_pending_job < self.jobs.len()
+            && schedule.jobs_done[schedule.first_pending_job]
+        {
+            schedule.first_pending_job += 1;
+        }
+        self.scheduled.notify_all();
+        Ok(())
+    }
+
+    /// Operation index before which every operation is applied
+    fn applied_operation_idx(&self, schedule: &Schedule) -> usize {
+        match schedule.applied {
+            0 => self.operations.first().map_or(0, |&(idx, _)| idx),
+            applied => self.operations[applied - 1].0 + 1,
+        }
+    }
+
+    fn progress(
+        &self,
+        delta_input_bytes: u64,
+        delta_output_bytes: u64,
+    ) -> Result<(), InternalApplyError> {
+        let schedule = self.lock_schedule()?;
+        notify(
+            &self.applied,
+            Ok(ApplyPackageProgression {
+                operation_idx: self.applied_operation_idx(&schedule),
+                applied_operations: Vec::new(),
+                delta_applied_files: 0,
+                delta_input_bytes,
+                delta_output_bytes,
+            }),
+        );
+        Ok(())
+    }
+
+    fn operation_done(
+        &self,
+        pos: usize,
+        res: Result<(), ApplyError>,
+    ) -> Result<(), InternalApplyError> {
+        let mut schedule = self.lock_schedule()?;
+        schedule.operations_done[pos] = true;
+        while schedule.applied < self.operations.len() && schedule.operations_done[schedule.applied]
+        {
+            schedule.applied += 1;
+        }
+        let delta_applied_files = match res {
+            Ok(()) => 1,
+            Err(err) => {
+                notify(&self.applied, Err(err));
+                0
+            }
+        };
+        notify(
+            &self.applied,
+            Ok(ApplyPackageProgression {
+                operation_idx: self.applied_operation_idx(&schedule),
+                applied_operations: vec![self.operations[pos].0],
+                delta_applied_files,
+                delta_input_bytes: 0,
+                delta_output_bytes: 0,
+            }),
+        );
+        Ok(())
+    }
+
+    /// Block while the update is paused
+    ///
+    /// Only called between the steps of an operation, a cancelled update
+    /// never leaves an operation half committed.
+    fn wait_resumed(&self) -> Result<(), InternalApplyError> {
+        match self.handle.wait_resumed(|| self.available.is_cancelled()) {
+            true => Ok(()),
+            false => Err(InternalApplyError::Cancelled),
+        }
+    }
+
+    /// Stop every worker
+    fn abort(&self, err: ApplyError) {
+        if let Ok(mut schedule) = self.schedule.lock() {
+            schedule.cancelled = true;
+        }
+        self.scheduled.notify_all();
+        self.available.cancel();
+        self.handle.wake_waiters();
+        notify(&self.applied, Err(err));
+    }
+
+    fn work(&self) {
+        loop {
+            let res = match self.next_job() {
+                Ok(Some(job_idx)) => self.apply_job(job_idx),
+                Ok(None) => break,
+                Err(err) => Err(err),
+            };
+            match res {
+                Ok(()) => {}
+                Err(InternalApplyError::Cancelled) => {
+                    self.abort(ApplyError::Cancelled);
+                    break;
+                }
+                Err(_) => {
+                    self.abort(ApplyError::PoisonError);
+                    break;
+                }
+            }
+        }
+        if self.running_workers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.done.store(1, Ordering::Relaxed);
+            notify_end(&self.applied);
+            debug!("end apply");
+        }
+    }
+
+    fn apply_job(&self, job_idx: usize) -> Result<(), InternalApplyError> {
         let base_ctx = HandlerContext {
-            file_manager: &file_manager,
-            package_name: &package_name,
+            file_manager: &self.file_manager,
+            package_name: &self.package_name,
             operation_idx: 0,
-            update_options: &update_options,
+            update_options: &self.update_options,
+            backup_dir: self.backup_dir.as_deref(),
+            stage_dir: self.stage_dir.as_deref(),
+            pristine_hashes: &self.pristine_hashes,
         };
-        let mut maybe_handler: Option<Box<dyn ApplyHandler>> = None;
-        let mut apply_operation = move |operation_idx, operation: &v1::Operation| {
-            applied_data.operation_idx = operation_idx;
-            applied_data.byte_idx = 0;
-
-            let ctx = HandlerContext { operation_idx, ..base_ctx.clone() };
-            let mut handler = match maybe_handler.take() {
-                None => operation.apply_handler(ctx)?,
-                Some(mut handler) => {
-                    if handler.try_still_compatible(operation.path(), operation_idx) {
-                        handler
-                    } else {
-                        operation.apply_handler(ctx)?
-                    }
+        let mut maybe_handler = None;
+        for pos in self.jobs[job_idx].operations.clone() {
+            let (operation_idx, operation) = &self.operations[pos];
+            let res = match self.apply_operation(
+                &base_ctx,
+                &mut maybe_handler,
+                *operation_idx,
+                operation,
+            ) {
+                Ok(()) => Ok(()),
+                Err(InternalApplyError::IoError(io_err))
+                    if preserve::is_locally_modified(&io_err) =>
+                {
+                    Err(ApplyError::LocallyModified { path: operation.path().clone() })
                 }
+                Err(InternalApplyError::IoError(io_err)) => Err(ApplyError::OperationFailed {
+                    path: operation.path().clone(),
+                    slice: operation.slice().cloned(),
+                    cause: io_err,
+                }),
+                Err(err) => return Err(err),
             };
+            self.operation_done(pos, res)?;
+        }
+        self.job_done(job_idx)
+    }
 
-            let data_file_path = handler.download_operation_path();
-            let mut maybe_applier = operation.begin_apply(&mut *handler).map_err(|err| {
-                warn!(
-                    "begin apply operation#{} {} failed: {}",
-                    operation_idx,
-                    operation.path(),
-                    err
-                );
-                err
-            })?;
-            debug!("begin apply operation#{} {}", operation_idx, operation.path());
-            if let Some(mut applier) = maybe_applier.take() {
-                let mut buffer = [0u8; io::BUFFER_SIZE];
-
-                // Wait until there is a least a few bytes available in the current package before
-                // opening the file
-                t_available.wait_until(|available| applied_data < *available)?;
-
-                let mut total_output_bytes = 0;
-                let expected_input_bytes = applier.expected_input_bytes();
-                let mut remaining = expected_input_bytes;
-                if remaining > 0 {
-                    info!("apply data_file_path {:?} for {}", data_file_path, &operation.path());
-                    let mut data_file =
-                        OpenOptions::new().read(true).open(&data_file_path).map_err(|err| {
-                            warn!(
-                                "apply operation#{} {} failed: unable to open data file ({})",
-                                operation_idx,
-                                operation.path(),
-                                err
-                            );
-                            err
-                        })?;
-                    while remaining > 0 {
-                        let available =
-                            t_available.wait_until(|available| applied_data < *available)?;
-                        let available = if available.operation_idx == applied_data.operation_idx {
-                            available.byte_idx - applied_data.byte_idx
-                        } else {
-                            remaining
-                        };
-
-                        let max_read = cmp::min(available, buffer.len() as u64) as usize;
-                        let read = data_file
-                            .read(&mut buffer[0..max_read])
-                            .and_then(|read| {
-                                if read > 0 {
-                                    Ok(read)
-                                } else {
-                                    Err(io::Error::new(io::ErrorKind::InvalidData, "EOF"))
-                                }
-                            })
-                            .map_err(|err| {
-                                warn!(
-                                    "apply operation#{} {} failed: unable to read data file ({})",
-                                    operation_idx,
-                                    operation.path(),
-                                    err
-                                );
-                                err
-                            })?;
-                        let new_total_output_bytes =
-                            applier.apply_input_bytes(&buffer[0..read]).map_err(|err| {
-                                warn!(
-                                    "apply operation#{} {} failed: unable to write final file ({})",
-                                    operation_idx,
-                                    operation.path(),
-                                    err
-                                );
-                                err
-                            })?;
-                        let delta_input_bytes = read as u64;
-                        applied_data.byte_idx += delta_input_bytes;
-                        remaining -= delta_input_bytes;
-
-                        let delta_output_bytes = new_total_output_bytes - total_output_bytes;
-                        notify(
-                            &t_applied,
-                            Ok(ApplyPackageProgression {
-                                operation_idx: applied_data.operation_idx,
-                                delta_applied_files: 0,
-                                delta_input_bytes,
-                                delta_output_bytes,
-                            }),
-                        );
-                        total_output_bytes = new_total_output_bytes;
-                    }
+    fn apply_operation<'a>(
+        &self,
+        base_ctx: &HandlerContext<'a>,
+        maybe_handler: &mut Option<Box<dyn ApplyHandler + 'a>>,
+        operation_idx: usize,
+        operation: &v2::Operation,
+    ) -> Result<(), InternalApplyError> {
+        self.wait_resumed()?;
+        let mut applied_data = UpdatePosition { operation_idx, byte_idx: 0 };
+
+        let ctx = HandlerContext { operation_idx, ..base_ctx.clone() };
+        let mut handler = match maybe_handler.take() {
+            None => operation.apply_handler(ctx)?,
+            Some(mut handler) => {
+                if handler.try_still_compatible(operation.path(), operation_idx) {
+                    handler
+                } else {
+                    operation.apply_handler(ctx)?
                 }
+            }
+        };
 
-                let mut remaining = applier.expected_check_bytes();
-                while remaining > 0 {
-                    let delta_bytes = applier.check_bytes(&mut buffer).map_err(|err| {
+        let data_file_path = handler.download_operation_path();
+        let mut maybe_applier = operation.begin_apply(&mut *handler).map_err(|err| {
+            warn!("begin apply operation#{} {} failed: {}", operation_idx, operation.path(), err);
+            err
+        })?;
+        debug!("begin apply operation#{} {}", operation_idx, operation.path());
+        if let Some(mut applier) = maybe_applier.take() {
+            let mut buffer = [0u8; io::BUFFER_SIZE];
+
+            // Wait until there is a least a few bytes available in the current package before
+            // opening the file
+            self.available.wait_until(|available| applied_data < *available)?;
+
+            let mut total_output_bytes = 0;
+            let expected_input_bytes = applier.expected_input_bytes();
+            let mut remaining = expected_input_bytes;
+            if remaining > 0 {
+                info!("apply data_file_path {:?} for {}", data_file_path, &operation.path());
+                let mut data_file =
+                    OpenOptions::new().read(true).open(&data_file_path).map_err(|err| {
                         warn!(
-                            "apply operation#{} {} failed: unable to check final file ({})",
+                            "apply operation#{} {} failed: unable to open data file ({})",
                             operation_idx,
                             operation.path(),
                             err
                         );
                         err
                     })?;
-                    remaining -= delta_bytes;
-                    notify(
-                        &t_applied,
-                        Ok(ApplyPackageProgression {
-                            operation_idx: applied_data.operation_idx,
-                            delta_applied_files: 0,
-                            delta_input_bytes: delta_bytes,
-                            delta_output_bytes: delta_bytes,
-                        }),
-                    );
+                while remaining > 0 {
+                    self.wait_resumed()?;
+                    let available =
+                        self.available.wait_until(|available| applied_data < *available)?;
+                    let available = if available.operation_idx == applied_data.operation_idx {
+                        available.byte_idx - applied_data.byte_idx
+                    } else {
+                        remaining
+                    };
+
+                    let max_read = cmp::min(available, buffer.len() as u64) as usize;
+                    let read = data_file
+                        .read(&mut buffer[0..max_read])
+                        .and_then(|read| {
+                            if read > 0 {
+                                Ok(read)
+                            } else {
+                                Err(io::Error::new(io::ErrorKind::InvalidData, "EOF"))
+                            }
+                        })
+                        .map_err(|err| {
+                            warn!(
+                                "apply operation#{} {} failed: unable to read data file ({})",
+                                operation_idx,
+                                operation.path(),
+                                err
+                            );
+                            err
+                        })?;
+                    let new_total_output_bytes =
+                        applier.apply_input_bytes(&buffer[0..read]).map_err(|err| {
+                            warn!(
+                                "apply operation#{} {} failed: unable to write final file ({})",
+                                operation_idx,
+                                operation.path(),
+                                err
+                            );
+                            err
+                        })?;
+                    let delta_input_bytes = read as u64;
+                    applied_data.byte_idx += delta_input_bytes;
+                    remaining -= delta_input_bytes;
+
+                    let delta_output_bytes = new_total_output_bytes - total_output_bytes;
+                    self.progress(delta_input_bytes, delta_output_bytes)?;
+                    total_output_bytes = new_total_output_bytes;
                 }
+            }
 
-                applier.commit().map_err(|err| {
+            let mut remaining = applier.expected_check_bytes();
+            while remaining > 0 {
+                self.wait_resumed()?;
+                let delta_bytes = applier.check_bytes(&mut buffer).map_err(|err| {
                     warn!(
-                        "apply operation#{} {} failed: unable to commit changes ({})",
+                        "apply operation#{} {} failed: unable to check final file ({})",
                         operation_idx,
                         operation.path(),
                         err
                     );
                     err
                 })?;
+                remaining -= delta_bytes;
+                self.progress(delta_bytes, delta_bytes)?;
+            }
 
-                if expected_input_bytes > 0 {
-                    io::remove_file(&data_file_path)?;
-                }
+            applier.commit().map_err(|err| {
+                warn!(
+                    "apply operation#{} {} failed: unable to commit changes ({})",
+                    operation_idx,
+                    operation.path(),
+                    err
+                );
+                err
+            })?;
+
+            if expected_input_bytes > 0 {
+                io::remove_file(&data_file_path)?;
             }
-            drop(maybe_applier);
-            applied_data.operation_idx += 1;
-            applied_data.byte_idx = 0;
-            notify(
-                &t_applied,
-                Ok(ApplyPackageProgression {
-                    operation_idx: applied_data.operation_idx,
-                    delta_applied_files: 1,
-                    delta_input_bytes: 0,
-                    delta_output_bytes: 0,
-                }),
-            );
-            maybe_handler = Some(handler);
-            Ok(())
+        }
+        drop(maybe_applier);
+        *maybe_handler = Some(handler);
+        Ok(())
+    }
+}
+
+/// Apply `operations` on a pool of `UpdateOptions::apply_concurrency` threads
+///
+/// Operations on different paths are applied in parallel, directory operations
+/// and operations on the same path are applied in order.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn apply_package(
+    update_options: UpdateOptions,
+    file_manager: WorkspaceFileManager,
+    package_name: &metadata::CleanName,
+    backup_dir: Option<PathBuf>,
+    stage_dir: Option<PathBuf>,
+    pristine_hashes: Arc<PristineHashes>,
+    operations: Vec<(usize, Arc<v2::Operation>)>,
+    i_available: AvailableForApply,
+    handle: UpdateHandle,
+) -> ApplyStream {
+    let done = Arc::new(AtomicUsize::new(0));
+    let o_applied = Arc::new(Mutex::new((VecDeque::new(), AtomicWaker::new())));
+    let jobs = jobs(&operations);
+    let workers = cmp::max(1, cmp::min(update_options.apply_concurrency, jobs.len()));
+    let schedule = Schedule {
+        jobs_done: vec![false; jobs.len()],
+        operations_done: vec![false; operations.len()],
+        ..Schedule::default()
+    };
+    let pool = Arc::new(Pool {
+        update_options,
+        file_manager,
+        package_name: package_name.to_string(),
+        backup_dir,
+        stage_dir,
+        pristine_hashes,
+        operations,
+        jobs,
+        schedule: Mutex::new(schedule),
+        scheduled: Condvar::new(),
+        available: i_available.clone(),
+        handle: handle.clone(),
+        applied: o_applied.clone(),
+        running_workers: AtomicUsize::new(workers),
+        done: done.clone(),
+    });
+    for _ in 0..workers {
+        let pool = pool.clone();
+        thread::spawn(move || pool.work());
+    }
+
+    ApplyStream { done, o_applied, i_available, handle }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::metadata::v1::StateUpdating;
+    use crate::metadata::CleanName;
+
+    #[test]
+    fn apply_jobs() {
+        let operations: Vec<(usize, Arc<v2::Operation>)> =
+            serde_json::from_str::<Vec<v2::Operation>>(
+                r#"[
+                {"type":"mkdir","path":"a"},
+                {"type":"rm","path":"a/b"},
+                {"type":"rm","path":"a/c","slice":"0"},
+                {"type":"rm","path":"a/c","slice":"1"},
+                {"type":"rm","path":"a/d"},
+                {"type":"rmdir","path":"e"},
+                {"type":"rm","path":"a/b"}
+            ]"#,
+            )
+            .unwrap()
+            .into_iter()
+            .map(Arc::new)
+            .enumerate()
+            .collect();
+        let jobs = jobs(&operations);
+        let ranges: Vec<_> = jobs.iter().map(|job| job.operations.clone()).collect();
+        assert_eq!(ranges, vec![0..1, 1..2, 2..4, 4..5, 5..6, 6..7]);
+        assert_eq!(
+            jobs.iter().map(|job| job.barrier).collect::<Vec<_>>(),
+            vec![true, false, false, false, true, false]
+        );
+    }
+
+    #[test]
+    fn resume_out_of_order() {
+        let dir = crate::tests::tmp_dir("apply_resume_out_of_order");
+        let file_manager = WorkspaceFileManager { dir: dir.clone() };
+        file_manager.create_update_dirs().unwrap();
+        let operations: Vec<(usize, Arc<v2::Operation>)> =
+            serde_json::from_value::<Vec<v2::Operation>>(serde_json::json!([
+                {
+                    "type": "add", "path": "a",
+                    "dataOffset": "0", "dataSize": "3", "dataCompression": "raw",
+                    "dataHash": "sha1:a9993e364706816aba3e25717850c26c9cd0d89d",
+                    "finalSize": "3", "finalHash": "sha1:a9993e364706816aba3e25717850c26c9cd0d89d",
+                },
+                { "type": "rm", "path": "b" },
+            ]))
+            .unwrap()
+            .into_iter()
+            .map(Arc::new)
+            .enumerate()
+            .collect();
+        let package_name = CleanName::from_static_str("package");
+        let data_path = file_manager.download_operation_path(&package_name, 0);
+        let apply = |operations, available| {
+            apply_package(
+                UpdateOptions { apply_concurrency: 2, ..UpdateOptions::default() },
+                file_manager.clone(),
+                &package_name,
+                None,
+                None,
+                Arc::new(PristineHashes::default()),
+                operations,
+                AvailableForApply::new(available),
+                UpdateHandle::new(),
+            )
         };
+        let mut state = StateUpdating::new(None, CleanName::from_static_str("v1"), Vec::new());
+        let apply_progress = |state: &mut StateUpdating, res| match res {
+            Ok(ApplyPackageProgression { operation_idx, applied_operations, .. }) => {
+                state.set_applied(operation_idx, applied_operations)
+            }
+            Err(ApplyError::Cancelled) => {}
+            Err(err) => panic!("{}", err),
+        };
+
+        // "b" is removed while "a" waits for the rest of its data
+        fs::write(dir.join("b"), "b").unwrap();
+        fs::write(&data_path, "a").unwrap();
+        let mut stream =
+            apply(operations.clone(), UpdatePosition { operation_idx: 0, byte_idx: 1 });
+        while !state.is_applied(1) {
+            apply_progress(&mut state, block_on(stream.next()).unwrap());
+        }
+        stream.cancel();
+        while let Some(res) = block_on(stream.next()) {
+            apply_progress(&mut state, res);
+        }
+        assert_eq!(state.applied.operation_idx, 0);
+        assert!(!dir.join("b").exists());
+
+        // resuming doesn't remove "b" again
+        fs::write(dir.join("b"), "b").unwrap();
+        fs::write(&data_path, "abc").unwrap();
+        let operations =
+            operations.into_iter().filter(|&(idx, _)| !state.is_applied(idx)).collect();
+        let mut stream = apply(operations, UpdatePosition { operation_idx: 2, byte_idx: 0 });
+        while let Some(res) = block_on(stream.next()) {
+            apply_progress(&mut state, res);
+        }
+        assert!(state.is_applied(0) && state.is_applied(1));
+        assert_eq!(fs::read(dir.join("a")).unwrap(), b"abc");
+        assert!(dir.join("b").exists());
+    }
 
-        for &(idx, ref operation) in operations.iter() {
-            if let Err(err) = apply_operation(idx, operation) {
-                let err = match err {
-                    InternalApplyError::IoError(io_err) => ApplyError::OperationFailed {
-                        path: operation.path().clone(),
-                        slice: operation.slice().cloned(),
-                        cause: io_err,
-                    },
-                    InternalApplyError::Cancelled => ApplyError::Cancelled,
-                    InternalApplyError::PoisonError => ApplyError::PoisonError,
-                };
-                notify(&terr_applied, Err(err));
+    #[test]
+    fn drop_paused() {
+        let dir = crate::tests::tmp_dir("apply_drop_paused");
+        let file_manager = WorkspaceFileManager { dir };
+        file_manager.create_update_dirs().unwrap();
+        let operations: Vec<(usize, Arc<v2::Operation>)> =
+            serde_json::from_value::<Vec<v2::Operation>>(serde_json::json!([
+                { "type": "rm", "path": "a" },
+                { "type": "rm", "path": "b" },
+            ]))
+            .unwrap()
+            .into_iter()
+            .map(Arc::new)
+            .enumerate()
+            .collect();
+        let handle = UpdateHandle::new();
+        handle.pause();
+        // the workers are the only other owners of the pristine hashes
+        let pristine_hashes = Arc::new(PristineHashes::default());
+        let stream = apply_package(
+            UpdateOptions { apply_concurrency: 2, ..UpdateOptions::default() },
+            file_manager,
+            &CleanName::from_static_str("package"),
+            None,
+            None,
+            pristine_hashes.clone(),
+            operations,
+            AvailableForApply::new(UpdatePosition { operation_idx: 2, byte_idx: 0 }),
+            handle.clone(),
+        );
+        drop(stream);
+        for _ in 0..100 {
+            if Arc::strong_count(&pristine_hashes) == 1 {
+                break;
             }
+            std::thread::sleep(std::time::Duration::from_millis(10));
         }
-        t_done.store(1, Ordering::Relaxed);
-        notify_end(&terr_applied);
-        debug!("end apply");
-    });
+        assert_eq!(Arc::strong_count(&pristine_hashes), 1);
+        assert!(handle.is_paused());
+    }
+
+    #[test]
+    fn staged_existing_dir() {
+        let dir = crate::tests::tmp_dir("apply_staged_existing_dir");
+        let file_manager = WorkspaceFileManager { dir: dir.clone() };
+        file_manager.create_update_dirs().unwrap();
+        fs::create_dir(dir.join("d")).unwrap();
+        fs::write(dir.join("d/a"), "a").unwrap();
+        // "d" already exists, the package has no mkdir for it
+        let operations: Vec<(usize, Arc<v2::Operation>)> =
+            serde_json::from_value::<Vec<v2::Operation>>(serde_json::json!([{
+                "type": "add", "path": "d/a",
+                "dataOffset": "0", "dataSize": "3", "dataCompression": "raw",
+                "dataHash": "sha1:a9993e364706816aba3e25717850c26c9cd0d89d",
+                "finalSize": "3", "finalHash": "sha1:a9993e364706816aba3e25717850c26c9cd0d89d",
+            }]))
+            .unwrap()
+            .into_iter()
+            .map(Arc::new)
+            .enumerate()
+            .collect();
+        let package_name = CleanName::from_static_str("package");
+        fs::write(file_manager.download_operation_path(&package_name, 0), "abc").unwrap();
+        let stage_dir =
+            super::super::stage::prepare(&file_manager, &metadata::v1::State::New, true)
+                .unwrap()
+                .unwrap();
 
-    ApplyStream { done, o_applied, i_available }
+        let stream = apply_package(
+            UpdateOptions::default(),
+            file_manager.clone(),
+            &package_name,
+            None,
+            Some(stage_dir),
+            Arc::new(PristineHashes::default()),
+            operations,
+            AvailableForApply::new(UpdatePosition { operation_idx: 1, byte_idx: 0 }),
+            UpdateHandle::new(),
+        );
+        for res in block_on(stream.collect::<Vec<_>>()) {
+            res.unwrap();
+        }
+        assert_eq!(fs::read(dir.join("d/a")).unwrap(), b"a");
+        super::super::stage::swap(&file_manager, None, &CleanName::from_static_str("v2")).unwrap();
+        assert_eq!(fs::read(dir.join("d/a")).unwrap(), b"abc");
+    }
 }
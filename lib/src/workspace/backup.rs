@@ -0,0 +1,281 @@
+//! Backup of files replaced by an update and rollback to the backed up version
+//!
+//! Backups are stored in `.update/backup/<version>`, with the `check.json` of
+//! `<version>` and every file the update replaced or removed under `files/`.
+//! Only the first copy of a file is kept, so the backup always matches
+//! `<version>` even if an update goes through multiple packages.
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tracing::{info, warn};
+
+use super::{is_contended, Workspace, WorkspaceFileManager};
+use crate::io;
+use crate::metadata::{self, CleanName, CleanPath, OperationKind};
+
+#[derive(Debug)]
+pub enum RollbackError {
+    /// There is no backup to restore
+    NoBackup,
+    /// Another process is using the workspace
+    WorkspaceLocked,
+    LocalBackupError(io::Error),
+    LocalStateError(io::Error),
+}
+
+impl fmt::Display for RollbackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RollbackError::NoBackup => write!(f, "no backup of a previous version"),
+            RollbackError::WorkspaceLocked => write!(f, "workspace is locked by another process"),
+            RollbackError::LocalBackupError(err) => write!(f, "local backup error: {}", err),
+            RollbackError::LocalStateError(err) => write!(f, "local state.json error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RollbackError {}
+
+fn files_dir(backup_dir: &Path) -> PathBuf {
+    backup_dir.join("files")
+}
+
+fn checks_path(backup_dir: &Path) -> PathBuf {
+    backup_dir.join("check.json")
+}
+
+/// Version and directory of the current backup
+pub(super) fn current(
+    file_manager: &WorkspaceFileManager,
+) -> io::Result<Option<(CleanName, PathBuf)>> {
+    let entries = match fs::read_dir(file_manager.backup_dir()) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let version = entry.file_name().to_str().and_then(|name| CleanName::new(name.into()).ok());
+        if let Some(version) = version {
+            return Ok(Some((version, entry.path())));
+        }
+    }
+    Ok(None)
+}
+
+/// Directory where files replaced by the update starting from `state` are
+/// backed up
+///
+/// Updates starting from a stable version replace the previous backup, other
+/// updates (resume, repair, ...) keep filling the current one.
+pub(super) fn prepare(
+    file_manager: &WorkspaceFileManager,
+    state: &metadata::v1::State,
+) -> io::Result<Option<PathBuf>> {
+    let current = current(file_manager)?;
+    let version = match state {
+        metadata::v1::State::Stable { version } => version,
+        _ => return Ok(current.map(|(_, dir)| dir)),
+    };
+    if let Some((current_version, dir)) = current {
+        if &current_version == version {
+            return Ok(Some(dir));
+        }
+    }
+
+    let backup_dir = file_manager.backup_dir();
+    super::ignore_not_found(fs::remove_dir_all(&backup_dir))?;
+    let dir = backup_dir.join(version.as_str());
+    fs::create_dir_all(files_dir(&dir))?;
+    if let Err(err) = fs::copy(file_manager.check_path(), checks_path(&dir)) {
+        warn!("unable to backup {}: {}", version, err);
+        fs::remove_dir_all(&backup_dir)?;
+        return Ok(None);
+    }
+    info!("backup {} to {:?}", version, dir);
+    Ok(Some(dir))
+}
+
+/// Path where `path` is backed up in `backup_dir`
+pub(crate) fn file_path(backup_dir: &Path, path: &CleanPath) -> PathBuf {
+    files_dir(backup_dir).join(path)
+}
+
+/// Move `final_path` to `backup_path`, unless a copy is already backed up
+pub(crate) fn backup_file(final_path: &Path, backup_path: &Path) -> io::Result<()> {
+    if backup_path.exists() {
+        return io::remove_file(final_path);
+    }
+    if let Some(parent) = backup_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(final_path, backup_path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+fn list_files(dir: &Path, prefix: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = prefix.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn paths(checks: &metadata::WorkspaceChecks) -> (BTreeSet<&CleanPath>, BTreeSet<&CleanPath>) {
+    let mut files = BTreeSet::new();
+    let mut dirs = BTreeSet::new();
+    for operation in checks.iter() {
+        if operation.kind() == OperationKind::MkDir {
+            dirs.insert(operation.path());
+        } else {
+            files.insert(operation.path());
+        }
+    }
+    (files, dirs)
+}
+
+pub(super) fn rollback(workspace: &mut Workspace) -> Result<CleanName, RollbackError> {
+    let _lock = workspace.lock_exclusive().map_err(|err| match is_contended(&err) {
+        true => RollbackError::WorkspaceLocked,
+        false => RollbackError::LocalBackupError(err),
+    })?;
+    let file_manager = workspace.file_manager();
+    let (version, dir) = current(&file_manager)
+        .map_err(RollbackError::LocalBackupError)?
+        .ok_or(RollbackError::NoBackup)?;
+    let previous_checks: metadata::WorkspaceChecks = fs::File::open(checks_path(&dir))
+        .and_then(|file| serde_json::from_reader(file).map_err(io::Error::from))
+        .map_err(RollbackError::LocalBackupError)?;
+    let current_checks = match file_manager.read_checks() {
+        Ok(checks) => Some(checks),
+        Err(err) => {
+            warn!("unable to load current checks: {}", err);
+            None
+        }
+    };
+    info!("rollback to {}", version);
+
+    let (files, dirs) = paths(&previous_checks);
+    let workspace_dir = file_manager.dir();
+    let res = (|| -> io::Result<()> {
+        // remove what the update added
+        let mut added_dirs = Vec::new();
+        if let Some(current_checks) = &current_checks {
+            let (current_files, current_dirs) = paths(current_checks);
+            for path in current_files.difference(&files) {
+                io::remove_file(workspace_dir.join(path))?;
+            }
+            added_dirs.extend(current_dirs.difference(&dirs).map(|path| workspace_dir.join(path)));
+        }
+
+        // restore what the update replaced or removed
+        for path in dirs.iter() {
+            fs::create_dir_all(workspace_dir.join(path))?;
+        }
+        let mut backed_up = Vec::new();
+        list_files(&files_dir(&dir), Path::new(""), &mut backed_up)?;
+        for path in backed_up {
+            let final_path = workspace_dir.join(&path);
+            match path.to_str().and_then(|path| CleanPath::new(path.into()).ok()) {
+                Some(clean_path) if files.contains(&clean_path) => {
+                    if let Some(parent) = final_path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    io::remove_file(&final_path)?;
+                    fs::rename(files_dir(&dir).join(&path), &final_path)?;
+                }
+                _ => {}
+            }
+        }
+
+        // deepest directories first
+        added_dirs.sort();
+        for path in added_dirs.iter().rev() {
+            if let Err(err) = fs::remove_dir(path) {
+                warn!("unable to remove directory {:?}: {}", path, err);
+            }
+        }
+
+        file_manager.create_update_dirs()?;
+        file_manager.clear_download_dir()?;
+        file_manager.clear_tmp_dir()?;
+        file_manager.write_checks(&previous_checks)
+    })();
+    res.map_err(RollbackError::LocalBackupError)?;
+
+    workspace
+        .set_state(metadata::v1::State::Stable { version: version.clone() })
+        .map_err(RollbackError::LocalStateError)?;
+    fs::remove_dir_all(file_manager.backup_dir()).map_err(RollbackError::LocalBackupError)?;
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checks(files: &[&str]) -> metadata::WorkspaceChecks {
+        let operations = files
+            .iter()
+            .map(|path| {
+                serde_json::from_value(serde_json::json!({
+                    "type": if path.ends_with('/') { "mkdir" } else { "rm" },
+                    "path": path.trim_end_matches('/'),
+                }))
+                .unwrap()
+            })
+            .collect();
+        metadata::WorkspaceChecks::V2 { operations }
+    }
+
+    #[test]
+    fn rollback_restores_backup() {
+        let dir = crate::tests::tmp_dir("rollback_restores_backup");
+        let mut workspace = Workspace::open(&dir).unwrap();
+        let file_manager = workspace.file_manager();
+        file_manager.create_update_dirs().unwrap();
+        file_manager.write_checks(&checks(&["a", "b"])).unwrap();
+        let v1 = CleanName::new("v1".into()).unwrap();
+        workspace.set_state(metadata::v1::State::Stable { version: v1.clone() }).unwrap();
+        fs::write(dir.join("a"), b"a1").unwrap();
+        fs::write(dir.join("b"), b"b1").unwrap();
+
+        // update to v2: replace a, remove b, add d/c
+        let backup_dir = prepare(&file_manager, workspace.state()).unwrap().unwrap();
+        let a = CleanPath::new("a".into()).unwrap();
+        let b = CleanPath::new("b".into()).unwrap();
+        backup_file(&dir.join("a"), &file_path(&backup_dir, &a)).unwrap();
+        fs::write(dir.join("a"), b"a2").unwrap();
+        backup_file(&dir.join("b"), &file_path(&backup_dir, &b)).unwrap();
+        // only the first copy is kept
+        fs::write(dir.join("a"), b"a3").unwrap();
+        backup_file(&dir.join("a"), &file_path(&backup_dir, &a)).unwrap();
+        fs::write(dir.join("a"), b"a3").unwrap();
+        fs::create_dir(dir.join("d")).unwrap();
+        fs::write(dir.join("d/c"), b"c2").unwrap();
+        file_manager.write_checks(&checks(&["a", "d/", "d/c"])).unwrap();
+        let v2 = CleanName::new("v2".into()).unwrap();
+        workspace.set_state(metadata::v1::State::Stable { version: v2 }).unwrap();
+
+        assert_eq!(workspace.rollback().unwrap(), v1);
+        assert_eq!(fs::read(dir.join("a")).unwrap(), b"a1");
+        assert_eq!(fs::read(dir.join("b")).unwrap(), b"b1");
+        assert!(!dir.join("d").exists());
+        assert!(
+            matches!(workspace.state(), metadata::v1::State::Stable { version } if version == &v1)
+        );
+        assert!(matches!(workspace.rollback(), Err(RollbackError::NoBackup)));
+    }
+}
@@ -0,0 +1,134 @@
+//! Download rate limiting
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Maximum delay before a throttled download checks the limit again, so
+/// raising or lifting it takes effect quickly
+const MAX_THROTTLE_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, `0` means unlimited
+    rate: u64,
+    /// Bytes that can be downloaded right away, negative when downloads
+    /// went ahead of the rate
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        // allows bursts of up to a second of data
+        let rate = self.rate as f64;
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+    }
+}
+
+/// Limit of the download rate shared by every range of an update
+///
+/// Clones share the same limit, it can be changed while an update runs.
+#[derive(Debug, Clone)]
+pub struct BandwidthLimit {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl BandwidthLimit {
+    /// Limit downloads to `bytes_per_sec`, `None` means unlimited
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        let bucket = Bucket { rate: 0, tokens: 0., last_refill: Instant::now() };
+        let limit = Self { bucket: Arc::new(Mutex::new(bucket)) };
+        limit.set(bytes_per_sec);
+        limit
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None)
+    }
+
+    /// Current limit in bytes per second, `None` means unlimited
+    pub fn get(&self) -> Option<u64> {
+        match self.bucket.lock().rate {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+
+    /// Change the limit, ongoing downloads are affected immediately
+    pub fn set(&self, bytes_per_sec: Option<u64>) {
+        self.set_at(bytes_per_sec, Instant::now())
+    }
+
+    fn set_at(&self, bytes_per_sec: Option<u64>, now: Instant) {
+        let mut bucket = self.bucket.lock();
+        bucket.refill(now);
+        bucket.rate = bytes_per_sec.unwrap_or(0);
+        if bucket.rate == 0 {
+            bucket.tokens = 0.;
+        }
+    }
+
+    /// Account for `len` downloaded bytes
+    pub(super) fn consume(&self, len: usize) {
+        let mut bucket = self.bucket.lock();
+        if bucket.rate > 0 {
+            bucket.tokens -= len as f64;
+        }
+    }
+
+    /// Duration to wait before downloading more bytes, `None` if downloads
+    /// can go on
+    pub(super) fn delay(&self) -> Option<Duration> {
+        self.delay_at(Instant::now())
+    }
+
+    fn delay_at(&self, now: Instant) -> Option<Duration> {
+        let mut bucket = self.bucket.lock();
+        if bucket.rate == 0 {
+            return None;
+        }
+        bucket.refill(now);
+        if bucket.tokens >= 0. {
+            return None;
+        }
+        let delay = Duration::from_secs_f64(-bucket.tokens / bucket.rate as f64);
+        Some(delay.min(MAX_THROTTLE_DELAY))
+    }
+}
+
+impl Default for BandwidthLimit {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bandwidth_limit() {
+        let limit = BandwidthLimit::unlimited();
+        limit.consume(1 << 20);
+        assert_eq!(limit.delay(), None);
+
+        let now = Instant::now();
+        let millis = |delay: Option<Duration>| (delay.unwrap().as_secs_f64() * 1000.).round();
+        let shared = limit.clone();
+        shared.set_at(Some(1000), now);
+        assert_eq!(limit.get(), Some(1000));
+        limit.consume(2000);
+        assert_eq!(limit.delay_at(now), Some(MAX_THROTTLE_DELAY));
+        // the 2000 bytes ahead of the rate take 20ms at the new rate
+        shared.set_at(Some(100_000), now);
+        assert_eq!(millis(limit.delay_at(now)), 20.);
+        assert_eq!(millis(limit.delay_at(now + Duration::from_millis(5))), 15.);
+        assert_eq!(limit.delay_at(now + Duration::from_millis(20)), None);
+
+        shared.set(None);
+        assert_eq!(limit.delay(), None);
+    }
+}
@@ -4,14 +4,19 @@ use std::mem;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use futures::prelude::*;
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 
 use super::apply::{apply_package, ApplyError, AvailableForApply};
+use super::handle::UpdateHandle;
+use super::preserve::PristineHashes;
 use super::progress::{CheckProgression, SharedCheckProgress};
+use super::stat_cache::{FileStat, StatCache};
 use super::UpdateOptions;
-use super::{UpdatePosition, Workspace};
+use super::{is_contended, UpdatePosition, Workspace};
+use crate::metadata::Operation;
 use crate::{io, metadata};
 
 pub type GlobalCheckStream<'a> =
@@ -23,7 +28,11 @@ pub enum CheckError {
     LocalStateError(io::Error),
     LocalCheckError(io::Error),
     LocalWorkspaceError(io::Error),
-    Failed { files: usize },
+    /// Another process is using the workspace
+    WorkspaceLocked,
+    Failed {
+        files: usize,
+    },
     PoisonError,
 }
 
@@ -34,41 +43,91 @@ impl fmt::Display for CheckError {
             CheckError::LocalStateError(err) => write!(f, "local state.json error: {}", err),
             CheckError::LocalCheckError(err) => write!(f, "local check.json error:: {}", err),
             CheckError::LocalWorkspaceError(err) => write!(f, "local workspace error: {}", err),
+            CheckError::WorkspaceLocked => write!(f, "workspace is locked by another process"),
             CheckError::Failed { files } => write!(f, "check failed for {} files", files),
             CheckError::PoisonError => write!(f, "internal error: mutex poisonned"),
         }
     }
 }
 
+/// Check options
+#[derive(Debug, Clone, Default)]
+pub struct CheckOptions {
+    /// Only hash the files whose size, mtime or inode changed since they were
+    /// last verified
+    ///
+    /// Modifications that keep the size, mtime and inode of a file, like
+    /// silent disk corruption, go unnoticed. Default to `false`.
+    pub quick: bool,
+}
+
 pub(crate) async fn check<'a>(
     workspace: &mut Workspace,
+    check_options: CheckOptions,
 ) -> Result<impl Stream<Item = Result<SharedCheckProgress, CheckError>> + '_, CheckError> {
     if matches!(workspace.state(), metadata::v1::State::New) {
         return Err(CheckError::NewWorkspace);
     }
+    let lock = workspace.lock_exclusive().map_err(|err| match is_contended(&err) {
+        true => CheckError::WorkspaceLocked,
+        false => CheckError::LocalWorkspaceError(err),
+    })?;
 
     let file_manager = workspace.file_manager();
     let checks = file_manager.read_checks().map_err(CheckError::LocalCheckError)?;
 
-    // Build list of operations to do
-    let operations: Vec<(usize, Arc<metadata::v1::Operation>)> = checks
+    // Build list of operations to do, skipping files that didn't change since
+    // they were last verified
+    let mut stat_cache = StatCache::load(&file_manager);
+    let stat_time = SystemTime::now();
+    let mut stats = Vec::new();
+    let mut skipped = CheckProgression::default();
+    let operations: Vec<(usize, Arc<metadata::v2::Operation>)> = checks
+        .operations()
         .iter()
         .enumerate()
-        .filter_map(|(idx, o)| o.as_check_operation().map(|o| (idx, Arc::new(o))))
+        .filter_map(|(idx, o)| o.as_check_operation().map(|o| (idx, o)))
+        .filter(|(_, o)| {
+            let hash = match o {
+                metadata::v2::Operation::Check(check) => &check.local_hash,
+                _ => return true,
+            };
+            match FileStat::of(&file_manager.dir().join(o.path())) {
+                Ok(stat) if check_options.quick && stat_cache.is_fresh(o.path(), hash, &stat) => {
+                    skipped.checked_files += 1;
+                    skipped.checked_bytes += o.check_size();
+                    false
+                }
+                Ok(stat) => {
+                    stats.push((o.path().clone(), hash.clone(), stat));
+                    true
+                }
+                Err(_) => true, // reported by the check itself
+            }
+        })
+        .map(|(idx, o)| (idx, Arc::new(o)))
         .collect();
+    debug!("{} files unchanged since their last check", skipped.checked_files);
+    let operation_count = checks.operations().len();
     let global_progression_n = SharedCheckProgress::new(Arc::new(checks));
+    global_progression_n.borrow_mut().histogram.inc(skipped);
     let global_progression_c = global_progression_n.clone();
     let package_name = metadata::CleanName::from_static_str("local");
     let i_available =
-        AvailableForApply::new(UpdatePosition { operation_idx: operations.len(), byte_idx: 0 });
+        AvailableForApply::new(UpdatePosition { operation_idx: operation_count, byte_idx: 0 });
+    let file_manager_c = file_manager.clone();
     let failures_n: Rc<RefCell<Vec<metadata::v1::Failure>>> = Default::default();
     let failures_c = failures_n.clone();
     let check_stream = apply_package(
         UpdateOptions { check: true, ..UpdateOptions::default() },
         file_manager,
         &package_name,
+        None,
+        None,
+        Arc::new(PristineHashes::default()),
         operations,
         i_available,
+        UpdateHandle::new(),
     )
     .map(move |res| {
         let mut delta = CheckProgression::default();
@@ -85,6 +144,10 @@ pub(crate) async fn check<'a>(
                 };
                 failures_n.borrow_mut().push(failure);
             }
+            Err(ApplyError::LocallyModified { path }) => {
+                info!("{} is locally modified", path);
+                global_progression_n.borrow_mut().modified_files.push(path);
+            }
             Err(ApplyError::Cancelled) => {}
             Err(ApplyError::PoisonError) => return Err(CheckError::PoisonError),
         }
@@ -94,7 +157,21 @@ pub(crate) async fn check<'a>(
 
     let commit_stream = future::lazy(move |_| {
         debug!("end check package");
+        let _lock = lock;
         let failures = mem::take(&mut *failures_c.borrow_mut());
+        let modified_files = &global_progression_c.borrow().modified_files;
+        for (path, hash, stat) in stats {
+            // modified files are checked again to be reported again
+            match failures.iter().any(|failure| failure.path() == &path)
+                || modified_files.contains(&path)
+            {
+                true => stat_cache.remove(&path),
+                false => stat_cache.insert(path, hash, stat, stat_time),
+            }
+        }
+        if let Err(err) = stat_cache.write(&file_manager_c) {
+            warn!("unable to write check cache: {}", err);
+        }
         let state = workspace.state_mut();
         let res = match state {
             metadata::v1::State::Stable { version } if !failures.is_empty() => {
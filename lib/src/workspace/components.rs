@@ -0,0 +1,211 @@
+//! Optional components (language packs, HD textures, ...) of a workspace
+//!
+//! Files tagged with components are only installed if one of their components
+//! is selected, the selection is saved in `state.json`.
+use std::collections::BTreeSet;
+use std::fs;
+
+use futures::prelude::*;
+use tracing::{info, warn};
+
+use super::handle::UpdateHandle;
+use super::updater::{self, GlobalProgressStream, UpdateError, UpdateOptions};
+use super::{is_contended, Workspace};
+use crate::io;
+use crate::link::RemoteRepository;
+use crate::metadata::v1::{Failure, State};
+use crate::metadata::{self, CleanName, CleanPath, OperationKind};
+
+/// Parent directories of `path`, deepest first
+fn parent_dirs(path: &CleanPath) -> impl Iterator<Item = &str> {
+    let path = path.as_str();
+    path.rmatch_indices('/').map(move |(idx, _)| &path[..idx])
+}
+
+/// Files to download, paths to remove and directories left empty when the
+/// selection changes from `previous` to `components`
+fn changes(
+    package_metadata: &metadata::PackageMetadata,
+    previous: Option<&BTreeSet<CleanName>>,
+    components: Option<&BTreeSet<CleanName>>,
+) -> (Vec<Failure>, BTreeSet<CleanPath>, BTreeSet<String>) {
+    let mut added = Vec::new();
+    let mut removed = BTreeSet::new();
+    let mut kept_dirs = BTreeSet::new();
+    for operation in package_metadata.iter() {
+        if metadata::is_selected(operation.components(), components) {
+            kept_dirs.extend(parent_dirs(operation.path()));
+            if operation.kind() == OperationKind::MkDir {
+                kept_dirs.insert(operation.path().as_str());
+            }
+        }
+        match operation.kind() {
+            OperationKind::Add | OperationKind::Patch | OperationKind::Check => {}
+            OperationKind::Rm | OperationKind::MkDir | OperationKind::RmDir => continue,
+        }
+        let was_selected = metadata::is_selected(operation.components(), previous);
+        let is_selected = metadata::is_selected(operation.components(), components);
+        match (was_selected, is_selected) {
+            (false, true) => added.push(match operation.slice() {
+                Some(slice) => {
+                    Failure::Slice { path: operation.path().clone(), slice: slice.clone() }
+                }
+                None => Failure::Path { path: operation.path().clone() },
+            }),
+            (true, false) => {
+                removed.insert(operation.path().clone());
+            }
+            _ => {}
+        }
+    }
+    let removed_dirs = removed
+        .iter()
+        .flat_map(parent_dirs)
+        .filter(|dir| !kept_dirs.contains(dir))
+        .map(str::to_owned)
+        .collect();
+    (added, removed, removed_dirs)
+}
+
+pub(super) async fn select<'a, R>(
+    workspace: &'a mut Workspace,
+    repository: &'a R,
+    components: Option<BTreeSet<CleanName>>,
+    update_options: Box<UpdateOptions>,
+) -> Result<GlobalProgressStream<'a>, UpdateError>
+where
+    R: RemoteRepository,
+{
+    let lock = workspace.lock_exclusive().map_err(|err| match is_contended(&err) {
+        true => UpdateError::WorkspaceLocked,
+        false => UpdateError::LocalWorkspaceError(err),
+    })?;
+    if let Err(err) = workspace.reload_state_from_fs() {
+        warn!("unable to load current workspace state: {}", err);
+    };
+
+    let (version, mut failures) = match workspace.state() {
+        State::Stable { version } => (version.clone(), Vec::new()),
+        State::Corrupted { version, failures } => (version.clone(), failures.clone()),
+        State::New | State::Updating(_) => {
+            // applied by the next update
+            *workspace.components_mut() = components;
+            workspace.write_state().map_err(UpdateError::LocalStateError)?;
+            return Ok(stream::empty().boxed_local());
+        }
+    };
+
+    // The last package to the current version lists every file of the version
+    let maybe_path = updater::update_path(
+        State::New,
+        repository,
+        &version,
+        false,
+        update_options.trusted_key.as_ref(),
+    )
+    .await?;
+    let package_metadata = match maybe_path.and_then(|(path, _)| path.last().cloned()) {
+        Some(package_metadata) => package_metadata,
+        None => return Err(UpdateError::NoPath),
+    };
+
+    let (added, removed, removed_dirs) =
+        changes(&package_metadata, workspace.components(), components.as_ref());
+    let file_manager = workspace.file_manager();
+    for path in removed {
+        info!("remove {}, its components are not selected", path);
+        io::remove_file(file_manager.dir().join(&path))
+            .map_err(UpdateError::LocalWorkspaceError)?;
+    }
+    // Deepest first, directories with files of their own are kept
+    for dir in removed_dirs.iter().rev() {
+        let path = file_manager.dir().join(dir);
+        let is_empty = fs::read_dir(&path).is_ok_and(|mut entries| entries.next().is_none());
+        if is_empty {
+            info!("remove empty directory {}", dir);
+            fs::remove_dir(&path).map_err(UpdateError::LocalWorkspaceError)?;
+        }
+    }
+    file_manager
+        .write_checks(&package_metadata.checks(components.as_ref()))
+        .map_err(UpdateError::LocalCheckError)?;
+
+    // New files are repaired by the update
+    *workspace.components_mut() = components;
+    let res = if added.is_empty() {
+        workspace.write_state()
+    } else {
+        failures.extend(added);
+        failures.sort();
+        failures.dedup();
+        workspace.set_state(State::Corrupted { version: version.clone(), failures })
+    };
+    res.map_err(UpdateError::LocalStateError)?;
+
+    // The lock is held until the repair is done, so that no other process
+    // updates the workspace in between
+    let stream = updater::update(
+        workspace,
+        repository,
+        Some(version),
+        update_options,
+        UpdateHandle::new(),
+        lock,
+    )
+    .await?;
+    Ok(stream.boxed_local())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn component_changes() {
+        let package_metadata: metadata::PackageMetadata = serde_json::from_value(serde_json::json!({
+            "version": "2",
+            "package": { "from": "", "to": "v1", "size": "0" },
+            "operations": [
+                { "type": "mkdir", "path": "lang" },
+                {
+                    "type": "check", "path": "base.bin", "localSize": "0",
+                    "localHash": "sha1:da39a3ee5e6b4b0d3255bfef95601890afd80709",
+                },
+                {
+                    "type": "check", "path": "lang/fr.pak", "components": ["fr"], "localSize": "0",
+                    "localHash": "sha1:da39a3ee5e6b4b0d3255bfef95601890afd80709",
+                },
+                {
+                    "type": "check", "path": "lang/de.pak", "components": ["de"], "localSize": "0",
+                    "localHash": "sha1:da39a3ee5e6b4b0d3255bfef95601890afd80709",
+                },
+                {
+                    "type": "check", "path": "hd/textures/all.pak", "components": ["hd"],
+                    "localSize": "0", "localHash": "sha1:da39a3ee5e6b4b0d3255bfef95601890afd80709",
+                },
+            ],
+        }))
+        .unwrap();
+        let selection = |names: &[&str]| {
+            names.iter().map(|name| CleanName::new(name.to_string()).unwrap()).collect()
+        };
+        let fr: BTreeSet<CleanName> = selection(&["fr"]);
+        let de: BTreeSet<CleanName> = selection(&["de"]);
+
+        let (added, removed, removed_dirs) = changes(&package_metadata, Some(&fr), Some(&de));
+        let added: Vec<_> = added.iter().map(|failure| failure.path().as_str()).collect();
+        let removed: Vec<_> = removed.iter().map(|path| path.as_str()).collect();
+        assert_eq!((added, removed), (vec!["lang/de.pak"], vec!["lang/fr.pak"]));
+        assert!(removed_dirs.is_empty());
+
+        let (added, removed, removed_dirs) = changes(&package_metadata, None, Some(&fr));
+        assert!(added.is_empty());
+        assert_eq!(removed.len(), 2);
+        // `lang` is created by a selected operation
+        assert_eq!(removed_dirs.into_iter().collect::<Vec<_>>(), vec!["hd", "hd/textures"]);
+
+        let checks = package_metadata.checks(Some(&fr));
+        let paths: Vec<_> = checks.iter().map(|o| o.path().as_str()).collect();
+        assert_eq!(paths, vec!["lang", "base.bin", "lang/fr.pak"]);
+    }
+}
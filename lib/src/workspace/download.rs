@@ -1,16 +1,22 @@
+use std::collections::VecDeque;
 use std::fs::OpenOptions;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::io::Write;
 use std::ops::{Deref, Range};
+use std::rc::Rc;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::{cmp, pin::Pin};
 
+use bytes::Bytes;
+use futures::future::LocalBoxFuture;
 use futures::prelude::*;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
-use super::updater::UpdateError;
-use crate::link::RemoteRepository;
+use super::bandwidth::BandwidthLimit;
+use super::updater::{RetryPolicy, UpdateError, UpdateOptions};
+use crate::link::{RemoteRepository, RepositoryError};
 use crate::metadata::{self, Operation};
 use crate::workspace::{UpdatePosition, WorkspaceFileManager};
 
@@ -41,6 +47,343 @@ where
     ranges
 }
 
+/// Maximum size of a range when downloading concurrently.
+///
+/// Bigger ranges are split so they can be spread over concurrent requests, it
+/// also bounds the memory used to buffer ranges ahead of the written one.
+const CONCURRENT_RANGE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Split ranges bigger than `max_size`
+fn split_ranges(ranges: Vec<Range<u64>>, max_size: u64) -> Vec<Range<u64>> {
+    let mut splitted = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        let mut start = range.start;
+        while range.end - start > max_size {
+            splitted.push(Range { start, end: start + max_size });
+            start += max_size;
+        }
+        splitted.push(Range { start, end: range.end });
+    }
+    splitted
+}
+
+/// Ranges downloaded for `operations`, starting `offset` bytes into the first
+/// one
+pub(super) fn download_ranges<'a, L, I>(
+    operations: L,
+    offset: u64,
+    concurrency: usize,
+) -> Vec<Range<u64>>
+where
+    L: Iterator<Item = &'a I>,
+    I: Operation + 'a,
+{
+    let ranges = ranges(operations, offset, 500 * 1024);
+    if concurrency > 1 {
+        split_ranges(ranges, CONCURRENT_RANGE_SIZE)
+    } else {
+        ranges
+    }
+}
+
+/// Maximum number of ranges fetched by a single request
+const MAX_REQUEST_RANGES: usize = 64;
+
+/// Bytes to receive after a failed attempt before attempts are counted from
+/// the start again, so that a connection failing after a few bytes every time
+/// still runs out of attempts
+const RETRY_PROGRESS: u64 = 1024 * 1024;
+
+/// Group consecutive ranges into requests of at most `MAX_REQUEST_RANGES`
+/// ranges and `max_size` bytes
+fn batch_ranges(ranges: Vec<Range<u64>>, max_size: u64) -> Vec<Vec<Range<u64>>> {
+    let mut batches: Vec<Vec<Range<u64>>> = Vec::new();
+    let mut batch_size = 0;
+    for range in ranges {
+        let size = range.end - range.start;
+        match batches.last_mut() {
+            Some(batch) if batch.len() < MAX_REQUEST_RANGES && batch_size + size <= max_size => {
+                batch_size += size;
+                batch.push(range);
+            }
+            _ => {
+                batch_size = size;
+                batches.push(vec![range]);
+            }
+        }
+    }
+    batches
+}
+
+type RangesStream<'a> = Pin<Box<dyn Stream<Item = Result<(u64, Bytes), RepositoryError>> + 'a>>;
+type RangesRequest<'a> = LocalBoxFuture<'a, Result<RangesStream<'a>, RepositoryError>>;
+
+/// Chunks of `ranges`, fetched one by one
+fn package_ranges_one_by_one<'a, R>(
+    repository: &'a R,
+    package_name: metadata::CleanName,
+    ranges: Vec<Range<u64>>,
+) -> RangesStream<'a>
+where
+    R: RemoteRepository,
+{
+    stream::iter(ranges)
+        .then(move |range| {
+            let package_name = package_name.clone();
+            async move {
+                let body = repository.package(package_name, range.clone()).await?;
+                // repositories are allowed to send more bytes than requested
+                Ok::<_, RepositoryError>(stream::try_unfold(
+                    (body, range),
+                    |(mut body, mut range)| async move {
+                        if range.start == range.end {
+                            return Ok(None);
+                        }
+                        Ok(body.try_next().await?.map(|mut chunk: Bytes| {
+                            chunk.truncate(
+                                cmp::min(chunk.len() as u64, range.end - range.start) as usize
+                            );
+                            let offset = range.start;
+                            range.start += chunk.len() as u64;
+                            ((offset, chunk), (body, range))
+                        }))
+                    },
+                ))
+            }
+        })
+        .try_flatten()
+        .boxed_local()
+}
+
+/// Download of a batch of ranges, chunks received ahead of time are buffered
+///
+/// Failed requests are retried according to the retry policy, resuming after
+/// the last received byte. Attempts are counted until [`RETRY_PROGRESS`]
+/// bytes are received in a row. The body isn't polled while the bandwidth
+/// limit is exceeded.
+struct RangeDownload<'a> {
+    /// Ranges not received yet
+    ranges: VecDeque<Range<u64>>,
+    attempts: u32,
+    /// Bytes received since the last failed attempt
+    progress: u64,
+    request_ranges: Rc<dyn Fn(Vec<Range<u64>>) -> RangesRequest<'a> + 'a>,
+    retry: RetryPolicy,
+    backoff: Option<Pin<Box<tokio::time::Sleep>>>,
+    bandwidth_limit: BandwidthLimit,
+    throttle: Option<Pin<Box<tokio::time::Sleep>>>,
+    request: Option<RangesRequest<'a>>,
+    body: Option<RangesStream<'a>>,
+    chunks: VecDeque<Result<(u64, Bytes), RepositoryError>>,
+    buffered: u64,
+    finished: bool,
+}
+
+impl<'a> RangeDownload<'a> {
+    fn new(
+        ranges: Vec<Range<u64>>,
+        request_ranges: Rc<dyn Fn(Vec<Range<u64>>) -> RangesRequest<'a> + 'a>,
+        retry: RetryPolicy,
+        bandwidth_limit: BandwidthLimit,
+    ) -> Self {
+        Self {
+            request: Some(request_ranges(ranges.clone())),
+            ranges: ranges.into(),
+            attempts: 1,
+            progress: 0,
+            request_ranges,
+            retry,
+            backoff: None,
+            bandwidth_limit,
+            throttle: None,
+            body: None,
+            chunks: VecDeque::new(),
+            buffered: 0,
+            finished: false,
+        }
+    }
+
+    /// Schedule a new attempt if `err` is retryable, otherwise returns `err`
+    fn retry(&mut self, err: RepositoryError) -> Result<(), RepositoryError> {
+        self.request = None;
+        self.body = None;
+        if self.attempts >= self.retry.max_attempts || !(self.retry.retryable)(&err) {
+            self.finished = true;
+            return Err(err);
+        }
+        let backoff = self.retry.backoff(self.attempts);
+        warn!(
+            "download [{}, {}) failed (attempt {}/{}): {}, retrying in {:?}",
+            self.ranges.front().map_or(0, |range| range.start),
+            self.ranges.back().map_or(0, |range| range.end),
+            self.attempts,
+            self.retry.max_attempts,
+            err,
+            backoff
+        );
+        self.attempts += 1;
+        self.progress = 0;
+        self.backoff = Some(Box::pin(tokio::time::sleep(backoff)));
+        Ok(())
+    }
+
+    /// Check `chunk` is the next expected one
+    fn receive(&mut self, offset: u64, mut chunk: Bytes) -> Result<Bytes, RepositoryError> {
+        self.bandwidth_limit.consume(chunk.len());
+        let range = match self.ranges.front_mut() {
+            Some(range) if range.start == offset => range,
+            _ => {
+                return Err(RepositoryError::InvalidRanges {
+                    reason: format!("unexpected chunk at offset {}", offset),
+                })
+            }
+        };
+        chunk.truncate(cmp::min(chunk.len() as u64, range.end - range.start) as usize);
+        range.start += chunk.len() as u64;
+        if range.start == range.end {
+            self.ranges.pop_front();
+        }
+        if self.ranges.is_empty() {
+            self.body = None;
+            self.finished = true;
+        }
+        self.progress += chunk.len() as u64;
+        if self.progress >= RETRY_PROGRESS {
+            self.attempts = 1;
+        }
+        Ok(chunk)
+    }
+
+    fn poll_network(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(u64, Bytes), RepositoryError>>> {
+        loop {
+            if let Some(backoff) = &mut self.backoff {
+                if backoff.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.backoff = None;
+                self.request = Some((self.request_ranges)(self.ranges.iter().cloned().collect()));
+            }
+            if let Some(request) = &mut self.request {
+                match request.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(body)) => {
+                        self.request = None;
+                        self.body = Some(body);
+                    }
+                    Poll::Ready(Err(err)) => match self.retry(err) {
+                        Ok(()) => continue,
+                        Err(err) => return Poll::Ready(Some(Err(err))),
+                    },
+                }
+            }
+            let body = match &mut self.body {
+                Some(body) => body,
+                None => return Poll::Ready(None),
+            };
+            loop {
+                if let Some(throttle) = &mut self.throttle {
+                    if throttle.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    self.throttle = None;
+                }
+                match self.bandwidth_limit.delay() {
+                    Some(delay) => self.throttle = Some(Box::pin(tokio::time::sleep(delay))),
+                    None => break,
+                }
+            }
+            let err = match body.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok((offset, chunk)))) => match self.receive(offset, chunk) {
+                    Ok(chunk) => return Poll::Ready(Some(Ok((offset, chunk)))),
+                    Err(err) => err,
+                },
+                Poll::Ready(Some(Err(err))) => err,
+                Poll::Ready(None) => RepositoryError::InvalidRanges {
+                    reason: format!("{} ranges are missing", self.ranges.len()),
+                },
+                Poll::Pending => return Poll::Pending,
+            };
+            match self.retry(err) {
+                Ok(()) => continue,
+                Err(err) => return Poll::Ready(Some(Err(err))),
+            }
+        }
+    }
+
+    /// Buffer chunks until `limit` bytes are buffered
+    fn prefetch(&mut self, cx: &mut Context<'_>, limit: u64) {
+        while !self.finished && self.buffered < limit {
+            match self.poll_network(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    self.buffered += chunk.as_ref().map_or(0, |(_, chunk)| chunk.len() as u64);
+                    self.chunks.push_back(chunk);
+                }
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+    }
+
+    fn poll_chunk(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<(u64, Bytes), RepositoryError>>> {
+        if let Some(chunk) = self.chunks.pop_front() {
+            self.buffered -= chunk.as_ref().map_or(0, |(_, chunk)| chunk.len() as u64);
+            return Poll::Ready(Some(chunk));
+        }
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        self.poll_network(cx)
+    }
+}
+
+/// Download up to `concurrency` batches of ranges at the same time and yield
+/// their chunks in ranges order
+struct ConcurrentRanges<'a> {
+    ranges: Box<dyn Iterator<Item = RangeDownload<'a>> + 'a>,
+    downloads: VecDeque<RangeDownload<'a>>,
+    concurrency: usize,
+}
+
+impl<'a> Stream for ConcurrentRanges<'a> {
+    type Item = Result<(u64, Bytes), UpdateError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            while this.downloads.len() < this.concurrency {
+                match this.ranges.next() {
+                    Some(download) => this.downloads.push_back(download),
+                    None => break,
+                }
+            }
+
+            for download in this.downloads.iter_mut().skip(1) {
+                download.prefetch(cx, CONCURRENT_RANGE_SIZE);
+            }
+
+            let head = match this.downloads.front_mut() {
+                Some(head) => head,
+                None => return Poll::Ready(None),
+            };
+            match head.poll_chunk(cx) {
+                Poll::Ready(Some(Ok(chunk))) => return Poll::Ready(Some(Ok(chunk))),
+                Poll::Ready(Some(Err(err))) => {
+                    return Poll::Ready(Some(Err(UpdateError::Download(err))))
+                }
+                Poll::Ready(None) => {
+                    this.downloads.pop_front();
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
 pub struct DownloadPackageProgression {
     pub(super) available: UpdatePosition,
     pub delta_downloaded_files: usize,
@@ -59,14 +402,19 @@ pub(super) fn download_package<'a, R, O>(
     package_name: &metadata::CleanName,
     operations: Vec<(usize, Arc<O>)>,
     start_position: UpdatePosition,
+    update_options: &UpdateOptions,
 ) -> DownloadStream<'a>
 where
     R: RemoteRepository,
     O: Operation + 'a,
 {
     // 1. Compute the list of ranges to download in the requested package
-    let ranges =
-        ranges(operations.iter().map(|&(_, ref o)| o.deref()), start_position.byte_idx, 500 * 1024);
+    let concurrency = update_options.download_concurrency;
+    let ranges = download_ranges(
+        operations.iter().map(|&(_, ref o)| o.deref()),
+        start_position.byte_idx,
+        concurrency,
+    );
     let mut end_position = start_position.clone();
     if let Some(&(last_op_idx, _)) = operations.last() {
         end_position.operation_idx = last_op_idx + 1;
@@ -89,17 +437,38 @@ where
 
     // 2. Starts downloading ranges
     // -> TryStream< (range_start: u64, Bytes) >
+    // Scattered ranges are fetched with multi-range requests when the
+    // repository supports them
     let package_name_r = package_name.clone();
-    let download_ranges = stream::iter(ranges.into_iter().map(move |range| {
-        let range_start = range.start;
-        repository.package(package_name_r.clone(), range).map_err(UpdateError::Download).map_ok(
-            move |chunks| {
-                chunks.map_ok(move |chunk| (range_start, chunk)).map_err(UpdateError::Download)
-            },
-        )
-    }))
-    .then(|fut| fut)
-    .try_flatten();
+    let request_ranges: Rc<dyn Fn(Vec<Range<u64>>) -> RangesRequest<'a> + 'a> =
+        Rc::new(move |ranges| {
+            let package_name = package_name_r.clone();
+            async move {
+                if ranges.len() > 1 {
+                    let chunks = repository.package_ranges(package_name.clone(), ranges.clone());
+                    if let Some(chunks) = chunks.await? {
+                        return Ok(chunks as RangesStream<'a>);
+                    }
+                }
+                Ok(package_ranges_one_by_one(repository, package_name, ranges))
+            }
+            .boxed_local()
+        });
+    let retry = update_options.retry.clone();
+    let bandwidth_limit = update_options.bandwidth_limit.clone();
+    let max_batch_size = if concurrency > 1 { CONCURRENT_RANGE_SIZE } else { u64::MAX };
+    let download_ranges = ConcurrentRanges {
+        ranges: Box::new(batch_ranges(ranges, max_batch_size).into_iter().map(move |ranges| {
+            RangeDownload::new(
+                ranges,
+                request_ranges.clone(),
+                retry.clone(),
+                bandwidth_limit.clone(),
+            )
+        })),
+        downloads: VecDeque::new(),
+        concurrency: cmp::max(concurrency, 1),
+    };
 
     // 3. Write downloaded ranges chunks
     // -> TryStream< UpdatePosition >
@@ -144,6 +513,7 @@ where
                             let ignore_len =
                                 cmp::min(bytes.len() as u64, range.start - pos) as usize;
                             bytes = &bytes[ignore_len..];
+                            pos += ignore_len as u64;
                         }
                         let remaining = (range.end - pos) as usize;
                         let cur_len = cmp::min(bytes.len(), remaining);
@@ -188,3 +558,86 @@ where
 
     write_ranges.chain(done_stream).boxed_local()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn range_download_ends_early() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let data: Bytes = (0..100u8).collect::<Vec<u8>>().into();
+        let requests = Rc::new(Cell::new(Vec::new()));
+        // the first body ends after 10 bytes, the following ones are complete
+        let request_ranges: Rc<dyn Fn(Vec<Range<u64>>) -> RangesRequest<'static>> = {
+            let requests = requests.clone();
+            let data = data.clone();
+            Rc::new(move |ranges: Vec<Range<u64>>| {
+                let mut requested = requests.take();
+                requested.push(ranges.clone());
+                let short = requested.len() == 1;
+                requests.set(requested);
+                let chunks: Vec<_> = if short {
+                    vec![Ok((ranges[0].start, data.slice(0..10)))]
+                } else {
+                    ranges
+                        .into_iter()
+                        .map(|range| {
+                            Ok((range.start, data.slice(range.start as usize..range.end as usize)))
+                        })
+                        .collect()
+                };
+                future::ready(Ok(stream::iter(chunks).boxed_local() as RangesStream<'static>))
+                    .boxed_local()
+            })
+        };
+        let retry =
+            RetryPolicy { initial_backoff: Duration::from_millis(1), ..RetryPolicy::default() };
+        let download = |retry: RetryPolicy| {
+            let mut download = RangeDownload::new(
+                vec![0..50, 60..100],
+                request_ranges.clone(),
+                retry,
+                BandwidthLimit::unlimited(),
+            );
+            rt.block_on(stream::poll_fn(|cx| download.poll_chunk(cx)).collect::<Vec<_>>())
+        };
+
+        let mut received = Vec::new();
+        for chunk in download(retry.clone()) {
+            let (offset, chunk) = chunk.unwrap();
+            assert_eq!(chunk, data.slice(offset as usize..offset as usize + chunk.len()));
+            received.extend_from_slice(&chunk);
+        }
+        assert_eq!(received, [&data[0..50], &data[60..100]].concat());
+        // the retry resumes after the last received byte
+        assert_eq!(requests.take(), vec![vec![0..50, 60..100], vec![10..50, 60..100]]);
+
+        // without retries the missing ranges are reported
+        let chunks = download(RetryPolicy::never());
+        assert!(matches!(chunks[0], Ok((0, ref chunk)) if chunk.len() == 10));
+        assert!(matches!(chunks[1], Err(RepositoryError::InvalidRanges { .. })));
+        assert_eq!(chunks.len(), 2);
+
+        // bodies ending after a few bytes every time run out of attempts
+        let always_short: Rc<dyn Fn(Vec<Range<u64>>) -> RangesRequest<'static>> =
+            Rc::new(move |ranges: Vec<Range<u64>>| {
+                let chunk = data.slice(ranges[0].start as usize..ranges[0].start as usize + 10);
+                let chunks = vec![Ok((ranges[0].start, chunk))];
+                future::ready(Ok(stream::iter(chunks).boxed_local() as RangesStream<'static>))
+                    .boxed_local()
+            });
+        let mut download = RangeDownload::new(
+            vec![0..50, 60..100],
+            always_short,
+            retry,
+            BandwidthLimit::unlimited(),
+        );
+        let chunks = rt.block_on(stream::poll_fn(|cx| download.poll_chunk(cx)).collect::<Vec<_>>());
+        assert_eq!(chunks.iter().filter(|chunk| chunk.is_ok()).count(), 5);
+        assert!(matches!(chunks.last(), Some(Err(RepositoryError::InvalidRanges { .. }))));
+    }
+}
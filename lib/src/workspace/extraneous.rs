@@ -0,0 +1,226 @@
+//! Files and directories of the workspace that `check.json` doesn't list
+//! (left by the user, old installers, ...)
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tracing::info;
+
+use super::WorkspaceFileManager;
+use crate::io;
+use crate::metadata::{self, OperationKind};
+
+/// Glob patterns of paths to keep even if the workspace doesn't track them
+/// (saves, user settings, ...)
+///
+/// Patterns are matched against paths relative to the workspace, with `/`
+/// separators. `*` doesn't match `/`, `**` matches any number of directories
+/// (i.e. `saves/**`, `**/*.ini`). Matching directories are kept with their
+/// content.
+#[derive(Debug, Clone, Default)]
+pub struct AllowList {
+    patterns: Vec<glob::Pattern>,
+}
+
+impl AllowList {
+    pub fn new<I, S>(patterns: I) -> Result<Self, glob::PatternError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let patterns = patterns
+            .into_iter()
+            .map(|pattern| glob::Pattern::new(pattern.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { patterns })
+    }
+
+    pub(crate) fn allows(&self, path: &str) -> bool {
+        let options = glob::MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        self.patterns.iter().any(|pattern| pattern.matches_with(path, options))
+    }
+}
+
+/// Untracked paths, relative to the workspace
+#[derive(Debug, Clone, Default)]
+pub struct ExtraneousPaths {
+    pub files: Vec<PathBuf>,
+    /// Directories without any tracked or allowed path, their content isn't
+    /// listed
+    pub dirs: Vec<PathBuf>,
+}
+
+impl ExtraneousPaths {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.dirs.is_empty()
+    }
+}
+
+struct Tracked<'a> {
+    files: BTreeSet<&'a str>,
+    dirs: BTreeSet<&'a str>,
+    /// Files with a preserve policy, their new version may be kept beside them
+    preserved: BTreeSet<String>,
+}
+
+impl<'a> Tracked<'a> {
+    fn new(checks: &'a metadata::WorkspaceChecks) -> Self {
+        let mut files = BTreeSet::new();
+        let mut dirs = BTreeSet::new();
+        for operation in checks.iter() {
+            let path = operation.path().as_str();
+            if operation.kind() == OperationKind::MkDir {
+                dirs.insert(path);
+            } else {
+                files.insert(path);
+            }
+            let mut parent = path;
+            while let Some(idx) = parent.rfind('/') {
+                parent = &parent[..idx];
+                dirs.insert(parent);
+            }
+        }
+        let preserved = checks
+            .operations()
+            .into_iter()
+            .filter_map(|o| o.common().filter(|common| common.preserve.is_some()).cloned())
+            .map(|common| common.path.as_str().to_owned())
+            .collect();
+        Self { files, dirs, preserved }
+    }
+
+    fn tracks_file(&self, path: &str) -> bool {
+        self.files.contains(path)
+            || matches!(path.strip_suffix(".new"), Some(path) if self.preserved.contains(path))
+    }
+}
+
+/// Walk `dir` and returns `true` if nothing in it is tracked or allowed
+fn walk(
+    dir: &Path,
+    prefix: &str,
+    tracked: &Tracked,
+    allow_list: &AllowList,
+    extraneous: &mut ExtraneousPaths,
+) -> io::Result<bool> {
+    let mut untracked = true;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                // tracked paths are valid utf-8
+                let path = Path::new(prefix).join(&name);
+                match entry.file_type()?.is_dir() {
+                    true => extraneous.dirs.push(path),
+                    false => extraneous.files.push(path),
+                }
+                continue;
+            }
+        };
+        let path = match prefix {
+            "" => name.to_owned(),
+            _ => format!("{}/{}", prefix, name),
+        };
+        if (prefix.is_empty() && name == ".update") || allow_list.allows(&path) {
+            untracked = false;
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            let mut sub_extraneous = ExtraneousPaths::default();
+            let sub_untracked =
+                walk(&entry.path(), &path, tracked, allow_list, &mut sub_extraneous)?;
+            if sub_untracked && !tracked.dirs.contains(path.as_str()) {
+                extraneous.dirs.push(PathBuf::from(path));
+            } else {
+                untracked = false;
+                extraneous.files.append(&mut sub_extraneous.files);
+                extraneous.dirs.append(&mut sub_extraneous.dirs);
+            }
+        } else if tracked.tracks_file(&path) {
+            untracked = false;
+        } else {
+            extraneous.files.push(PathBuf::from(path));
+        }
+    }
+    Ok(untracked)
+}
+
+pub(super) fn scan(
+    file_manager: &WorkspaceFileManager,
+    allow_list: &AllowList,
+) -> io::Result<ExtraneousPaths> {
+    let checks = file_manager.read_checks()?;
+    let tracked = Tracked::new(&checks);
+    let mut extraneous = ExtraneousPaths::default();
+    walk(file_manager.dir(), "", &tracked, allow_list, &mut extraneous)?;
+    Ok(extraneous)
+}
+
+pub(super) fn remove(
+    file_manager: &WorkspaceFileManager,
+    extraneous: &ExtraneousPaths,
+) -> io::Result<()> {
+    let workspace_dir = file_manager.dir();
+    for path in extraneous.files.iter() {
+        info!("remove extraneous file {:?}", path);
+        io::remove_file(workspace_dir.join(path))?;
+    }
+    for path in extraneous.dirs.iter() {
+        info!("remove extraneous directory {:?}", path);
+        super::ignore_not_found(fs::remove_dir_all(workspace_dir.join(path)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    #[test]
+    fn scan_extraneous() {
+        let dir = crate::tests::tmp_dir("scan_extraneous");
+        let workspace = Workspace::open(&dir).unwrap();
+        let file_manager = workspace.file_manager();
+        file_manager.create_update_dirs().unwrap();
+        let operations = serde_json::from_value(serde_json::json!([
+            { "type": "rm", "path": "a" },
+            {
+                "type": "check", "path": "d/b", "preserve": "if-modified", "localSize": "0",
+                "localHash": "sha1:da39a3ee5e6b4b0d3255bfef95601890afd80709",
+            },
+        ]))
+        .unwrap();
+        file_manager.write_checks(&metadata::WorkspaceChecks::V2 { operations }).unwrap();
+        for path in
+            &["a", "a.new", "x.dll", "d/b", "d/b.new", "d/y", "e/z", "saves/s1", "f/g/settings.ini"]
+        {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+
+        let allow_list = AllowList::new(["saves", "**/*.ini"]).unwrap();
+        let extraneous = workspace.scan_extraneous(&allow_list).unwrap();
+        let mut files = extraneous.files.clone();
+        files.sort();
+        // only preserved files have a tracked new version
+        assert_eq!(
+            files,
+            vec![PathBuf::from("a.new"), PathBuf::from("d/y"), PathBuf::from("x.dll")]
+        );
+        assert_eq!(extraneous.dirs, vec![PathBuf::from("e")]);
+
+        remove(&file_manager, &extraneous).unwrap();
+        assert!(workspace.scan_extraneous(&allow_list).unwrap().is_empty());
+        assert!(dir.join("a").exists() && dir.join("saves/s1").exists());
+        assert!(dir.join("f/g/settings.ini").exists() && dir.join("d/b.new").exists());
+    }
+}
@@ -0,0 +1,136 @@
+//! Control of a running update
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::task::AtomicWaker;
+use parking_lot::{Condvar, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    Running,
+    Paused,
+    Cancelled,
+}
+
+#[derive(Debug)]
+struct Shared {
+    control: Mutex<Control>,
+    /// Apply threads waiting for the update to be resumed
+    resumed: Condvar,
+    /// Update stream waiting for the update to be resumed
+    waker: AtomicWaker,
+}
+
+/// Handle to pause, resume or cancel an update
+///
+/// A paused update keeps its in-flight downloads and stops applying
+/// operations once their current step is done. Download bodies aren't polled
+/// while paused, so a long pause usually ends with a read timeout or a closed
+/// connection: the range is then retried from its last received byte, like
+/// any transient download error, and nothing already written is downloaded
+/// again. A cancelled update waits for the operations being committed, saves
+/// the workspace state and ends with
+/// [`UpdateError::Cancelled`](super::UpdateError::Cancelled). Clones control
+/// the same update.
+#[derive(Debug, Clone)]
+pub struct UpdateHandle {
+    shared: Arc<Shared>,
+}
+
+impl UpdateHandle {
+    pub(crate) fn new() -> Self {
+        let shared = Shared {
+            control: Mutex::new(Control::Running),
+            resumed: Condvar::new(),
+            waker: AtomicWaker::new(),
+        };
+        Self { shared: Arc::new(shared) }
+    }
+
+    fn set(&self, f: impl FnOnce(Control) -> Control) {
+        let mut control = self.shared.control.lock();
+        *control = f(*control);
+        drop(control);
+        self.shared.resumed.notify_all();
+        self.shared.waker.wake();
+    }
+
+    /// Stop downloading and applying until [`resume`](Self::resume) is called
+    pub fn pause(&self) {
+        self.set(|control| match control {
+            Control::Running => Control::Paused,
+            control => control,
+        });
+    }
+
+    pub fn resume(&self) {
+        self.set(|control| match control {
+            Control::Paused => Control::Running,
+            control => control,
+        });
+    }
+
+    /// Stop the update, a paused update is cancelled too
+    pub fn cancel(&self) {
+        self.set(|_| Control::Cancelled);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.shared.control.lock() == Control::Paused
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.shared.control.lock() == Control::Cancelled
+    }
+
+    /// Block while the update is paused, `false` if it is cancelled
+    ///
+    /// `stopped` is checked on every [`wake_waiters`](Self::wake_waiters) so
+    /// waiters also leave when only their part of the update is dropped.
+    pub(super) fn wait_resumed(&self, stopped: impl Fn() -> bool) -> bool {
+        let mut control = self.shared.control.lock();
+        while *control == Control::Paused && !stopped() {
+            self.shared.resumed.wait(&mut control);
+        }
+        *control == Control::Running && !stopped()
+    }
+
+    /// Wake the threads blocked in [`wait_resumed`](Self::wait_resumed)
+    pub(super) fn wake_waiters(&self) {
+        let _control = self.shared.control.lock();
+        self.shared.resumed.notify_all();
+    }
+
+    /// Ready while the update isn't paused, with `false` if it is cancelled
+    pub(super) fn poll_resumed(&self, cx: &mut Context<'_>) -> Poll<bool> {
+        self.shared.waker.register(cx.waker());
+        match *self.shared.control.lock() {
+            Control::Running => Poll::Ready(true),
+            Control::Paused => Poll::Pending,
+            Control::Cancelled => Poll::Ready(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_handle() {
+        let handle = UpdateHandle::new();
+        let shared = handle.clone();
+        shared.pause();
+        assert!(handle.is_paused());
+        let waiter = std::thread::spawn(move || handle.wait_resumed(|| false));
+        shared.resume();
+        assert!(waiter.join().unwrap());
+
+        shared.pause();
+        shared.cancel();
+        assert!(shared.is_cancelled());
+        // a cancelled update can't be resumed
+        shared.resume();
+        assert!(shared.is_cancelled() && !shared.wait_resumed(|| false));
+    }
+}
@@ -0,0 +1,106 @@
+//! Cross-process advisory lock of a workspace (`.update/lock`)
+//!
+//! Updates, checks and rollbacks hold the lock exclusively, read-only
+//! queries can share it. Locks are released when dropped, or when the
+//! process exits.
+use std::fs::{self, OpenOptions};
+
+use fs2::FileExt;
+
+use super::WorkspaceFileManager;
+use crate::io;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LockMode {
+    /// Read-only access, any number of processes can share the lock
+    Shared,
+    /// Read-write access (update, check, ...)
+    Exclusive,
+}
+
+/// Advisory lock of a workspace, released when dropped
+#[derive(Debug)]
+pub struct WorkspaceLock {
+    file: fs::File,
+    mode: LockMode,
+}
+
+impl WorkspaceLock {
+    /// Try to lock the workspace, without waiting for other processes
+    ///
+    /// Fails with an error matching [`is_contended`] if another process
+    /// holds a conflicting lock.
+    pub(super) fn try_lock(
+        file_manager: &WorkspaceFileManager,
+        mode: LockMode,
+    ) -> io::Result<Self> {
+        fs::create_dir_all(file_manager.metadata_dir())?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(file_manager.lock_path())?;
+        // `std::fs::File` has inherent methods with the same names
+        match mode {
+            LockMode::Shared => FileExt::try_lock_shared(&file)?,
+            LockMode::Exclusive => FileExt::try_lock_exclusive(&file)?,
+        }
+        Ok(Self { file, mode })
+    }
+
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+}
+
+impl Drop for WorkspaceLock {
+    fn drop(&mut self) {
+        let _ignore_err = FileExt::unlock(&self.file);
+    }
+}
+
+/// `err` was returned because another process holds the lock
+pub fn is_contended(err: &io::Error) -> bool {
+    err.raw_os_error().is_some() && err.raw_os_error() == fs2::lock_contended_error().raw_os_error()
+}
+
+/// Exclusive lock held during a workspace modification
+///
+/// `held` is the lock the workspace was opened with, a new lock is only
+/// taken if there is none.
+pub(super) fn exclusive(
+    file_manager: &WorkspaceFileManager,
+    held: Option<&WorkspaceLock>,
+) -> io::Result<Option<WorkspaceLock>> {
+    match held.map(WorkspaceLock::mode) {
+        Some(LockMode::Exclusive) => Ok(None),
+        Some(LockMode::Shared) => Err(fs2::lock_contended_error()),
+        None => WorkspaceLock::try_lock(file_manager, LockMode::Exclusive).map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    #[test]
+    fn lock_modes() {
+        let dir = crate::tests::tmp_dir("lock_modes");
+        let shared = Workspace::open_locked(&dir, LockMode::Shared).unwrap();
+        let other = Workspace::open_locked(&dir, LockMode::Shared).unwrap();
+        let err = Workspace::open_locked(&dir, LockMode::Exclusive).err().unwrap();
+        assert!(is_contended(&err));
+        assert!(is_contended(&shared.lock_exclusive().unwrap_err()));
+        drop((shared, other));
+
+        let workspace = Workspace::open_locked(&dir, LockMode::Exclusive).unwrap();
+        assert!(workspace.lock_exclusive().unwrap().is_none());
+        assert!(is_contended(&Workspace::open_locked(&dir, LockMode::Shared).err().unwrap()));
+        let unlocked = Workspace::open(&dir).unwrap();
+        assert!(is_contended(&unlocked.lock_exclusive().unwrap_err()));
+        drop(workspace);
+        assert!(unlocked.lock_exclusive().unwrap().is_some());
+    }
+}
@@ -1,10 +1,21 @@
 //! Tools to manage a workspace (update, check, status, ...)
 mod apply;
+pub(crate) mod backup;
+mod bandwidth;
 mod check;
+mod components;
 mod download;
+mod extraneous;
+mod handle;
+mod lock;
+mod plan;
+pub(crate) mod preserve;
 pub mod progress;
+pub(crate) mod stage;
+mod stat_cache;
 mod updater;
 
+use std::collections::BTreeSet;
 use std::fs;
 use std::path::Path;
 use std::path::PathBuf;
@@ -13,9 +24,16 @@ use futures::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json;
 
-pub use self::check::CheckError;
+pub use self::backup::RollbackError;
+pub use self::bandwidth::BandwidthLimit;
 pub use self::check::GlobalCheckStream;
+pub use self::check::{CheckError, CheckOptions};
+pub use self::extraneous::{AllowList, ExtraneousPaths};
+pub use self::handle::UpdateHandle;
+pub use self::lock::{is_contended, LockMode, WorkspaceLock};
+pub use self::plan::{OperationPlan, PackagePlan, UpdatePlan};
 pub use self::updater::GlobalProgressStream;
+pub use self::updater::RetryPolicy;
 pub use self::updater::UpdateError;
 pub use self::updater::UpdateOptions;
 use crate::io;
@@ -79,6 +97,14 @@ impl WorkspaceFileManager {
         self.metadata_dir().join("state.json")
     }
 
+    pub fn lock_path(&self) -> PathBuf {
+        self.metadata_dir().join("lock")
+    }
+
+    pub fn stat_cache_path(&self) -> PathBuf {
+        self.metadata_dir().join("stat_cache.json")
+    }
+
     pub fn check_path(&self) -> PathBuf {
         self.metadata_dir().join("check.json")
     }
@@ -91,6 +117,14 @@ impl WorkspaceFileManager {
         self.metadata_dir().join("dl")
     }
 
+    pub fn backup_dir(&self) -> PathBuf {
+        self.metadata_dir().join("backup")
+    }
+
+    pub fn stage_dir(&self) -> PathBuf {
+        self.metadata_dir().join("stage")
+    }
+
     pub fn download_operation_path(&self, package_name: &str, operation_idx: usize) -> PathBuf {
         self.download_dir().join(format!("{}-{}.data", package_name, operation_idx))
     }
@@ -113,32 +147,104 @@ impl WorkspaceFileManager {
 pub struct Workspace {
     file_manager: WorkspaceFileManager,
     state: metadata::WorkspaceState,
+    lock: Option<WorkspaceLock>,
 }
 impl Workspace {
     /// Open workspace
+    ///
+    /// The workspace is not locked, updates and checks still lock it for their
+    /// duration.
     pub fn open(dir: &Path) -> io::Result<Workspace> {
         let mut workspace = Workspace {
             file_manager: WorkspaceFileManager { dir: dir.to_owned() },
-            state: metadata::WorkspaceState::V1 { state: metadata::v1::State::New },
+            state: metadata::WorkspaceState::V1 {
+                state: metadata::v1::State::New,
+                components: None,
+                current_signed_at: None,
+            },
+            lock: None,
         };
         workspace.reload_state_from_fs()?;
         Ok(workspace)
     }
 
+    /// Open workspace and lock it until the workspace is dropped
+    ///
+    /// Fails with an error matching [`is_contended`] if another process holds
+    /// a conflicting lock. A shared lock only allows read-only queries, like
+    /// [`Workspace::state`]: updates and checks fail with a `WorkspaceLocked`
+    /// error.
+    pub fn open_locked(dir: &Path, mode: LockMode) -> io::Result<Workspace> {
+        let file_manager = WorkspaceFileManager { dir: dir.to_owned() };
+        let lock = WorkspaceLock::try_lock(&file_manager, mode)?;
+        let mut workspace = Workspace {
+            file_manager,
+            state: metadata::WorkspaceState::V1 {
+                state: metadata::v1::State::New,
+                components: None,
+                current_signed_at: None,
+            },
+            lock: Some(lock),
+        };
+        workspace.reload_state_from_fs()?;
+        Ok(workspace)
+    }
+
+    /// Mode of the lock the workspace was opened with
+    pub fn lock_mode(&self) -> Option<LockMode> {
+        self.lock.as_ref().map(WorkspaceLock::mode)
+    }
+
+    /// Exclusive lock to hold while modifying the workspace, `None` if the
+    /// workspace was opened with one
+    pub(crate) fn lock_exclusive(&self) -> io::Result<Option<WorkspaceLock>> {
+        self::lock::exclusive(&self.file_manager, self.lock.as_ref())
+    }
+
     /// Cached workspace state
     pub fn state(&self) -> &metadata::v1::State {
         match &self.state {
-            metadata::WorkspaceState::V1 { state } => state,
+            metadata::WorkspaceState::V1 { state, .. } => state,
         }
     }
 
     /// Cached workspace state
     fn state_mut(&mut self) -> &mut metadata::v1::State {
         match &mut self.state {
-            metadata::WorkspaceState::V1 { state } => state,
+            metadata::WorkspaceState::V1 { state, .. } => state,
+        }
+    }
+
+    /// Optional components the workspace installs, `None` if it installs
+    /// every component
+    pub fn components(&self) -> Option<&BTreeSet<CleanName>> {
+        match &self.state {
+            metadata::WorkspaceState::V1 { components, .. } => components.as_ref(),
+        }
+    }
+
+    fn components_mut(&mut self) -> &mut Option<BTreeSet<CleanName>> {
+        match &mut self.state {
+            metadata::WorkspaceState::V1 { components, .. } => components,
         }
     }
 
+    /// Time the last trusted repository `current` file was signed at
+    pub(crate) fn current_signed_at(&self) -> Option<u64> {
+        match &self.state {
+            metadata::WorkspaceState::V1 { current_signed_at, .. } => *current_signed_at,
+        }
+    }
+
+    pub(crate) fn set_current_signed_at(&mut self, signed_at: u64) -> io::Result<()> {
+        match &mut self.state {
+            metadata::WorkspaceState::V1 { current_signed_at, .. } => {
+                *current_signed_at = Some(signed_at)
+            }
+        }
+        self.write_state()
+    }
+
     /// Reload cached workspace state from filesystem
     pub fn reload_state_from_fs(&mut self) -> io::Result<()> {
         let file = fs::File::open(self.file_manager.state_path()).map(|file| Some(file)).or_else(
@@ -157,6 +263,7 @@ impl Workspace {
     pub fn clear_update_state(&mut self) -> io::Result<()> {
         self.file_manager.clear_download_dir()?;
         self.file_manager.clear_tmp_dir()?;
+        ignore_not_found(fs::remove_dir_all(self.file_manager.stage_dir()))?;
         match self.state_mut() {
             metadata::v1::State::New
             | metadata::v1::State::Stable { .. }
@@ -175,7 +282,7 @@ impl Workspace {
     }
 
     pub(crate) fn set_state(&mut self, state: metadata::v1::State) -> io::Result<()> {
-        self.state = metadata::WorkspaceState::V1 { state };
+        *self.state_mut() = state;
         self.write_state()
     }
 
@@ -197,13 +304,102 @@ impl Workspace {
     where
         R: RemoteRepository,
     {
-        self::updater::update(self, repository, goal_version, update_options)
+        self.update_with_handle(repository, goal_version, update_options).0
+    }
+
+    /// Like [`Workspace::update`], with a handle to pause, resume or cancel
+    /// the update while the stream is polled
+    pub fn update_with_handle<'a, R>(
+        &'a mut self,
+        repository: &'a R,
+        goal_version: Option<CleanName>,
+        update_options: UpdateOptions,
+    ) -> (GlobalProgressStream<'a>, UpdateHandle)
+    where
+        R: RemoteRepository,
+    {
+        let handle = UpdateHandle::new();
+        let stream = self::updater::update(
+            self,
+            repository,
+            goal_version,
+            Box::new(update_options),
+            handle.clone(),
+            None,
+        )
+        .try_flatten_stream()
+        .boxed_local();
+        (stream, handle)
+    }
+
+    /// Select the optional components to install, `None` selects every
+    /// component
+    ///
+    /// Files of components that are no longer selected are removed with the
+    /// directories they leave empty, files of the newly selected ones are
+    /// downloaded like files to repair. The workspace stays locked until they
+    /// are. If the workspace isn't stable, the selection is applied by the next
+    /// update.
+    pub fn set_components<'a, R>(
+        &'a mut self,
+        repository: &'a R,
+        components: Option<BTreeSet<CleanName>>,
+        update_options: UpdateOptions,
+    ) -> GlobalProgressStream<'a>
+    where
+        R: RemoteRepository,
+    {
+        self::components::select(self, repository, components, Box::new(update_options))
             .try_flatten_stream()
             .boxed_local()
     }
 
+    /// What [`Workspace::update`] would download and apply, without writing
+    /// anything to disk
+    ///
+    /// The plan is computed from the cached workspace state.
+    pub async fn plan<R>(
+        &self,
+        repository: &R,
+        goal_version: Option<CleanName>,
+        update_options: &UpdateOptions,
+    ) -> Result<UpdatePlan, UpdateError>
+    where
+        R: RemoteRepository,
+    {
+        self::plan::plan(self, repository, goal_version, update_options).await
+    }
+
+    /// Check the workspace integrity by hashing every file, failures are
+    /// saved in the workspace state
     pub fn check<'a>(&'a mut self) -> GlobalCheckStream<'a> {
-        self::check::check(self).try_flatten_stream().boxed_local()
+        self.check_with(CheckOptions::default())
+    }
+
+    /// Same as [`Workspace::check`], with `check_options` (i.e. to only hash
+    /// the files that changed since they were last verified)
+    pub fn check_with<'a>(&'a mut self, check_options: CheckOptions) -> GlobalCheckStream<'a> {
+        self::check::check(self, check_options).try_flatten_stream().boxed_local()
+    }
+
+    /// Files and directories `check.json` doesn't list, except the ones
+    /// `allow_list` allows
+    pub fn scan_extraneous(&self, allow_list: &AllowList) -> io::Result<ExtraneousPaths> {
+        self::extraneous::scan(&self.file_manager, allow_list)
+    }
+
+    /// Remove paths found by [`Workspace::scan_extraneous`]
+    pub fn remove_extraneous(&self, extraneous: &ExtraneousPaths) -> io::Result<()> {
+        let _lock = self.lock_exclusive()?;
+        self::extraneous::remove(&self.file_manager, extraneous)
+    }
+
+    /// Restore the version backed up by the last update (see
+    /// [`UpdateOptions::backup`]) and returns it
+    ///
+    /// This works offline, files are restored from `.update/backup`.
+    pub fn rollback(&mut self) -> Result<CleanName, RollbackError> {
+        self::backup::rollback(self)
     }
 }
 
@@ -212,9 +408,3 @@ pub(crate) struct UpdatePosition {
     pub operation_idx: usize,
     pub byte_idx: u64,
 }
-
-impl UpdatePosition {
-    pub fn new() -> UpdatePosition {
-        UpdatePosition { operation_idx: 0, byte_idx: 0 }
-    }
-}
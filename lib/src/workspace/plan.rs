@@ -0,0 +1,287 @@
+//! Dry-run of an update: what would be downloaded and applied
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::download::download_ranges;
+use super::progress::{KeptFiles, UpdateProgress};
+use super::updater::{self, UpdateError, UpdateFilter, UpdateOptions};
+use super::Workspace;
+use crate::link::RemoteRepository;
+use crate::metadata::v1::{State, StateUpdating};
+use crate::metadata::{self, CleanName, CleanPath, Operation, OperationKind, Package};
+
+/// Everything an update would do, without touching the workspace
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UpdatePlan {
+    /// Current workspace version
+    #[serde(with = "crate::metadata::maybe_cleanname")]
+    pub from: Option<CleanName>,
+    /// Target version
+    pub to: CleanName,
+    /// Packages to go through, in order (repair packages come last)
+    pub packages: Vec<PackagePlan>,
+    /// Number of bytes to download
+    pub download_bytes: u64,
+    /// Free disk space the update requires
+    pub required_space: u64,
+}
+
+/// Work to do for one package
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PackagePlan {
+    /// Package data name
+    pub name: CleanName,
+    #[serde(with = "crate::metadata::maybe_cleanname")]
+    pub from: Option<CleanName>,
+    pub to: CleanName,
+    /// The package repairs files that failed to update
+    pub repair: bool,
+    /// Operations to apply
+    pub operations: Vec<OperationPlan>,
+    /// Byte ranges of the package data to download
+    pub ranges: Vec<Range<u64>>,
+    /// Number of bytes to download
+    pub download_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OperationPlan {
+    /// Index of the operation in the package
+    pub index: usize,
+    pub kind: OperationKind,
+    pub path: CleanPath,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slice: Option<CleanPath>,
+    pub data_size: u64,
+    pub final_size: u64,
+    pub check_size: u64,
+}
+
+impl UpdatePlan {
+    fn new(from: Option<CleanName>, to: CleanName) -> Self {
+        Self { from, to, packages: Vec::new(), download_bytes: 0, required_space: 0 }
+    }
+
+    /// Nothing to download nor to apply
+    pub fn is_empty(&self) -> bool {
+        self.packages.iter().all(|package| package.operations.is_empty())
+    }
+
+    async fn push_path<R>(
+        &mut self,
+        repository: &R,
+        initial_state: State,
+        filter: UpdateFilter,
+        update_options: &UpdateOptions,
+        workspace_dir: &Path,
+    ) -> Result<(), UpdateError>
+    where
+        R: RemoteRepository,
+    {
+        let repair = !filter.failures.is_empty();
+        let maybe_path = updater::update_path(
+            initial_state,
+            repository,
+            &self.to,
+            update_options.check,
+            update_options.trusted_key.as_ref(),
+        )
+        .await?;
+        let (packages_metadata, first_package_state) = match maybe_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut progress = UpdateProgress::new(self.to.clone());
+        let backup = update_options.backup && !first_package_state.check_only;
+        let kept_files = KeptFiles::new(update_options.staged, backup);
+        progress.push_steps(
+            &packages_metadata,
+            &first_package_state,
+            &filter,
+            workspace_dir,
+            kept_files,
+        );
+        self.required_space = self.required_space.max(progress.required_space);
+
+        let mut state = first_package_state;
+        for package_metadata in packages_metadata {
+            let package =
+                PackagePlan::new(&package_metadata, &state, &filter, repair, update_options);
+            self.download_bytes += package.download_bytes;
+            self.packages.push(package);
+            state = StateUpdating::new(None, self.to.clone(), Vec::new());
+        }
+        Ok(())
+    }
+}
+
+impl PackagePlan {
+    fn new(
+        package_metadata: &Arc<metadata::PackageMetadata>,
+        state: &StateUpdating,
+        filter: &UpdateFilter,
+        repair: bool,
+        update_options: &UpdateOptions,
+    ) -> Self {
+        let operations = updater::package_operations(
+            package_metadata,
+            state.check_only,
+            filter,
+            update_options.check,
+        );
+        let ranges = download_ranges(
+            operations
+                .iter()
+                .skip_while(|&&(idx, _)| idx < state.available.operation_idx)
+                .map(|(_, o)| o.deref()),
+            state.available.byte_idx,
+            update_options.download_concurrency,
+        );
+        let operations: Vec<OperationPlan> = operations
+            .iter()
+            .filter(|&&(idx, _)| !state.is_applied(idx))
+            .map(|(index, o)| OperationPlan {
+                index: *index,
+                kind: o.kind(),
+                path: o.path().clone(),
+                slice: o.slice().cloned(),
+                data_size: o.data_size(),
+                final_size: o.final_size(),
+                check_size: o.check_size(),
+            })
+            .collect();
+        Self {
+            name: package_metadata.package_data_name(),
+            from: package_metadata.from().cloned(),
+            to: package_metadata.to().clone(),
+            repair,
+            operations,
+            download_bytes: ranges.iter().map(|range| range.end - range.start).sum(),
+            ranges,
+        }
+    }
+}
+
+pub(super) async fn plan<R>(
+    workspace: &Workspace,
+    repository: &R,
+    goal_version: Option<CleanName>,
+    update_options: &UpdateOptions,
+) -> Result<UpdatePlan, UpdateError>
+where
+    R: RemoteRepository,
+{
+    let goal_version = match goal_version {
+        Some(goal_version) => goal_version,
+        None => {
+            let (goal_version, signed_at) =
+                updater::current_version(repository, update_options.trusted_key.as_ref()).await?;
+            updater::check_replay(workspace, signed_at)?;
+            goal_version
+        }
+    };
+
+    let mut state = workspace.state().clone();
+    let from = match &state {
+        State::New => None,
+        State::Stable { version } | State::Corrupted { version, .. } => Some(version.clone()),
+        State::Updating(state) => state.from.clone(),
+    };
+    let mut plan = UpdatePlan::new(from, goal_version);
+    if let State::Stable { version } = &state {
+        if version == &plan.to && !update_options.check {
+            return Ok(plan);
+        }
+    }
+
+    let mut failures = match &mut state {
+        State::Corrupted { failures, .. } => std::mem::take(failures),
+        State::Updating(state) => {
+            state.dedup_failures();
+            std::mem::take(&mut state.failures)
+        }
+        _ => Vec::new(),
+    };
+
+    // 1. the update itself
+    let workspace_dir = workspace.file_manager.dir();
+    let components = workspace.components().cloned();
+    let filter = UpdateFilter { components: components.clone(), ..UpdateFilter::allows_all() };
+    plan.push_path(repository, state, filter, update_options, workspace_dir).await?;
+
+    // 2. repair of files that already failed
+    if !failures.is_empty() {
+        failures.sort();
+        let filter = UpdateFilter { failures, components };
+        plan.push_path(repository, State::New, filter, update_options, workspace_dir).await?;
+    }
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::UpdatePosition;
+
+    #[test]
+    fn package_plan() {
+        let package_metadata: metadata::PackageMetadata = serde_json::from_value(serde_json::json!({
+            "version": "2",
+            "package": { "from": "v1", "to": "v2", "size": "300" },
+            "operations": [
+                { "type": "mkdir", "path": "d" },
+                {
+                    "type": "add", "path": "d/a",
+                    "dataOffset": "0", "dataSize": "100", "dataCompression": "raw",
+                    "dataHash": "sha1:a9993e364706816aba3e25717850c26c9cd0d89d",
+                    "finalSize": "100", "finalHash": "sha1:a9993e364706816aba3e25717850c26c9cd0d89d",
+                },
+                { "type": "rm", "path": "b" },
+                {
+                    "type": "add", "path": "d/c",
+                    "dataOffset": "100", "dataSize": "200", "dataCompression": "raw",
+                    "dataHash": "sha1:a9993e364706816aba3e25717850c26c9cd0d89d",
+                    "finalSize": "200", "finalHash": "sha1:a9993e364706816aba3e25717850c26c9cd0d89d",
+                },
+            ],
+        }))
+        .unwrap();
+        let package_metadata = Arc::new(package_metadata);
+        let options = UpdateOptions::default();
+
+        let mut state = StateUpdating::new(None, CleanName::new("v2".into()).unwrap(), Vec::new());
+        let plan = PackagePlan::new(
+            &package_metadata,
+            &state,
+            &UpdateFilter::allows_all(),
+            false,
+            &options,
+        );
+        assert_eq!(plan.operations.len(), 4);
+        assert_eq!(plan.ranges, vec![0..300]);
+        assert_eq!(plan.download_bytes, 300);
+
+        // resume: d/a downloaded and applied, d/c half downloaded
+        state.available = UpdatePosition { operation_idx: 3, byte_idx: 50 };
+        state.applied = UpdatePosition { operation_idx: 2, byte_idx: 0 };
+        let plan = PackagePlan::new(
+            &package_metadata,
+            &state,
+            &UpdateFilter::allows_all(),
+            false,
+            &options,
+        );
+        let kinds: Vec<_> = plan.operations.iter().map(|o| o.kind).collect();
+        assert_eq!(kinds, vec![OperationKind::Rm, OperationKind::Add]);
+        assert_eq!(plan.ranges, vec![150..300]);
+        assert_eq!(plan.download_bytes, 150);
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["operations"][0]["kind"], "rm");
+    }
+}
@@ -0,0 +1,127 @@
+//! Files the user is expected to modify (configuration, ...)
+//!
+//! Under the [`Preserve::IfModified`](metadata::v2::Preserve::IfModified)
+//! policy, a file that matches neither its previous nor its new version is
+//! kept, and the new version is written beside it as `<path>.new`.
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::io;
+use crate::metadata::{self, CleanPath, Hash};
+
+/// Hashes of the files with a preserve policy, as installed by the previous
+/// version of the workspace
+#[derive(Debug, Default)]
+pub(crate) struct PristineHashes {
+    hashes: HashMap<CleanPath, Hash>,
+}
+
+impl PristineHashes {
+    /// Hashes listed by `previous_checks` of the files `package_metadata`
+    /// preserves
+    pub(crate) fn new(
+        previous_checks: &metadata::WorkspaceChecks,
+        package_metadata: &metadata::PackageMetadata,
+    ) -> Self {
+        let preserved: Vec<CleanPath> = package_metadata
+            .operations()
+            .into_iter()
+            .filter_map(|o| o.common().filter(|common| common.preserve.is_some()).cloned())
+            .map(|common| common.path)
+            .collect();
+        let hashes = previous_checks
+            .operations()
+            .into_iter()
+            .filter_map(|o| match o {
+                metadata::v2::Operation::Check(check) if check.common.slice.is_none() => {
+                    Some((check.common.path, check.local_hash))
+                }
+                _ => None,
+            })
+            .filter(|(path, _)| preserved.contains(path))
+            .collect();
+        Self { hashes }
+    }
+
+    pub(crate) fn get(&self, path: &CleanPath) -> Option<&Hash> {
+        self.hashes.get(path)
+    }
+}
+
+/// Path the new version of a locally modified file is written to
+pub(crate) fn new_path(path: &Path) -> PathBuf {
+    let mut new_path = path.as_os_str().to_owned();
+    new_path.push(".new");
+    PathBuf::from(new_path)
+}
+
+/// `path` exists and matches none of `hashes`
+pub(crate) fn is_modified(path: &Path, hashes: &[&Hash]) -> io::Result<bool> {
+    let mut algorithms: Vec<_> = hashes.iter().map(|hash| hash.algorithm()).collect();
+    algorithms.dedup();
+    for algorithm in algorithms {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        let mut reader = io::CheckReader::with_algorithm(file, algorithm);
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        let hash = reader.hash();
+        if hashes.contains(&&hash) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// The file differs from the version of the workspace, but is preserved
+#[derive(Debug)]
+struct LocallyModified {
+    cause: io::Error,
+}
+
+impl fmt::Display for LocallyModified {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "locally modified ({})", self.cause)
+    }
+}
+
+impl Error for LocallyModified {}
+
+/// Mark the check error `cause` of a preserved file
+pub(crate) fn locally_modified(cause: io::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, LocallyModified { cause })
+}
+
+/// `err` was returned by [`locally_modified`]
+pub(crate) fn is_locally_modified(err: &io::Error) -> bool {
+    matches!(err.get_ref(), Some(inner) if inner.is::<LocallyModified>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preserve_modified() {
+        let dir = crate::tests::tmp_dir("preserve_modified");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("settings.ini");
+        fs::write(&path, b"abc").unwrap();
+        let hash: Hash = "sha1:a9993e364706816aba3e25717850c26c9cd0d89d".parse().unwrap();
+
+        assert!(!is_modified(&path, &[&hash]).unwrap());
+        fs::write(&path, b"abcd").unwrap();
+        assert!(is_modified(&path, &[&hash]).unwrap());
+        assert!(!is_modified(&new_path(&path), &[&hash]).unwrap());
+        assert_eq!(new_path(&path), dir.join("settings.ini.new"));
+
+        let err = locally_modified(io::Error::new(io::ErrorKind::InvalidData, "final hash"));
+        assert!(is_locally_modified(&err));
+        assert!(!is_locally_modified(&io::Error::new(io::ErrorKind::InvalidData, "final hash")));
+    }
+}
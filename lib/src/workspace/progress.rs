@@ -1,17 +1,48 @@
 //! Progression reporting helpers
 use std::cell::{Ref, RefCell, RefMut};
-use std::fmt;
+use std::collections::HashMap;
+use std::fs;
 use std::ops::{Add, AddAssign, Div, Sub, SubAssign};
+use std::path::Path;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::{cmp, fmt};
 
 use super::updater::UpdateFilter;
-use super::UpdatePosition;
 use crate::histogram::Histogram;
 use crate::io;
 use crate::metadata::v1::StateUpdating;
 use crate::metadata::{self, CleanName, CleanPath, Operation};
 
+/// Disk space overhead of each downloaded or written file (filesystem
+/// allocation rounding), an operation downloads and writes at most one file
+const FILE_SPACE_OVERHEAD: u64 = 4096;
+
+/// Files an update keeps on disk until it is done
+#[derive(Debug, Clone, Copy)]
+pub(super) enum KeptFiles {
+    /// Replaced files are removed as operations are applied
+    None,
+    /// New files are staged until every operation is applied
+    Staged,
+    /// Replaced files of the workspace are moved to its backup
+    Backup,
+}
+
+/// Path and slice of a file
+type FileKey<'a> = (&'a CleanPath, Option<&'a CleanPath>);
+
+impl KeptFiles {
+    pub(super) fn new(staged: bool, backup: bool) -> Self {
+        match (staged, backup) {
+            // staged files replace the workspace ones, backed up or not
+            (true, _) => KeptFiles::Staged,
+            (false, true) => KeptFiles::Backup,
+            (false, false) => KeptFiles::None,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct SharedCheckProgress {
     state: Rc<RefCell<CheckProgress>>,
@@ -44,6 +75,10 @@ pub struct CheckProgress {
 
     /// Global check progression histogram
     pub histogram: Histogram<CheckProgression>,
+
+    /// Files that differ from the workspace version, but that their preserve
+    /// policy keeps
+    pub modified_files: Vec<CleanPath>,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -109,6 +144,7 @@ impl CheckProgress {
             check_bytes: 0,
             checking_operation_idx: 0,
             histogram: Default::default(),
+            modified_files: Vec::new(),
         };
 
         for operation in this.metadata.iter() {
@@ -190,6 +226,15 @@ pub struct UpdateProgress {
     /// Number of bytes to install
     pub apply_output_bytes: u64,
 
+    /// Estimated free disk space required by the update
+    ///
+    /// This is the largest amount of bytes a step downloads and writes, as
+    /// downloaded data and temporary files coexist with replaced files until
+    /// operations are applied, on top of what the previous steps added. Staged
+    /// and backed up updates keep the replaced workspace files until the end
+    /// of the update.
+    pub required_space: u64,
+
     /// Current package beeing applied
     pub downloading_package_idx: usize,
     /// Current operation beeing downloaded
@@ -217,6 +262,7 @@ impl UpdateProgress {
             apply_files: 0,
             apply_input_bytes: 0,
             apply_output_bytes: 0,
+            required_space: 0,
             downloading_package_idx: 0,
             downloading_operation_idx: 0,
             applying_package_idx: 0,
@@ -253,16 +299,22 @@ impl UpdateProgress {
         packages_metadata: &[Arc<metadata::PackageMetadata>],
         first_package_state: &StateUpdating,
         filter: &UpdateFilter,
+        workspace_dir: &Path,
+        kept_files: KeptFiles,
     ) {
-        let (mut available, mut applied, check_only) = (
-            first_package_state.available.clone(),
-            first_package_state.applied.clone(),
-            first_package_state.check_only,
-        );
+        let mut state = first_package_state.clone();
+        let check_only = state.check_only;
+        // size of the files once the previous steps are applied
+        let mut sizes: HashMap<FileKey<'_>, u64> = HashMap::new();
+        // bytes written and freed by the previous steps
+        let (mut written, mut freed) = (0u64, 0u64);
         for package_metadata in packages_metadata.iter() {
             let mut step = UpdateStepState::new(package_metadata.clone());
             let mut delta = Progression::default();
+            let mut write_bytes = 0;
+            let (mut step_written, mut step_freed) = (0, 0);
 
+            let (available, applied) = (&state.available, &state.applied);
             delta.downloaded_files += applied.operation_idx;
             delta.applied_files += available.operation_idx;
 
@@ -271,7 +323,7 @@ impl UpdateProgress {
                     continue;
                 }
 
-                let (check_size, data_size, final_size) = if idx < applied.operation_idx {
+                let (check_size, data_size, final_size) = if state.is_applied(idx) {
                     (operation.check_size(), operation.data_size(), operation.final_size())
                 } else {
                     (0, 0, 0)
@@ -288,6 +340,14 @@ impl UpdateProgress {
 
                     delta.applied_input_bytes += data_size + check_size;
                     step.apply_input_bytes += operation.data_size() + operation.check_size();
+
+                    if !state.is_applied(idx) {
+                        write_bytes += operation.final_size() + 2 * FILE_SPACE_OVERHEAD;
+                        let (op_written, op_freed) =
+                            size_change(&mut sizes, workspace_dir, kept_files, operation);
+                        step_written += op_written;
+                        step_freed += op_freed;
+                    }
                 } else {
                     delta.applied_input_bytes += final_size + check_size;
                     step.apply_input_bytes += operation.final_size() + operation.check_size();
@@ -298,6 +358,13 @@ impl UpdateProgress {
                 step.apply_output_bytes += operation.final_size() + operation.check_size();
             }
 
+            // replaced files are only freed once the step is done
+            let transient =
+                step.download_bytes.saturating_sub(delta.downloaded_bytes) + write_bytes;
+            self.required_space =
+                cmp::max(self.required_space, (written + transient).saturating_sub(freed));
+            written += step_written;
+            freed += step_freed;
             self.histogram.inc(delta);
             self.download_files += step.download_files;
             self.download_bytes += step.download_bytes;
@@ -306,12 +373,38 @@ impl UpdateProgress {
             self.apply_output_bytes += step.apply_output_bytes;
 
             self.steps.push(step);
-            available = UpdatePosition::new();
-            applied = UpdatePosition::new();
+            state.clear_progress();
         }
     }
 }
 
+/// Bytes written and freed on disk once `operation` is applied
+///
+/// Workspace files replaced for the first time are kept by staged and backed
+/// up updates, the size of slices of the workspace isn't known.
+fn size_change<'a>(
+    sizes: &mut HashMap<FileKey<'a>, u64>,
+    workspace_dir: &Path,
+    kept_files: KeptFiles,
+    operation: &'a dyn Operation,
+) -> (u64, u64) {
+    let final_size = match operation.kind() {
+        metadata::OperationKind::Add | metadata::OperationKind::Patch => operation.final_size(),
+        metadata::OperationKind::Rm => 0,
+        _ => return (0, 0),
+    };
+    let freed = match sizes.insert((operation.path(), operation.slice()), final_size) {
+        Some(size) => size,
+        None => match (kept_files, operation.slice()) {
+            (KeptFiles::None, None) => {
+                fs::metadata(workspace_dir.join(operation.path())).map_or(0, |m| m.len())
+            }
+            _ => 0,
+        },
+    };
+    (final_size, freed)
+}
+
 /// Update step objectives
 #[derive(Debug)]
 pub struct UpdateStepState {
@@ -456,3 +549,72 @@ impl Div<f64> for &'_ Progression {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn package(from: &str, to: &str, files: &[(&str, u64)]) -> Arc<metadata::PackageMetadata> {
+        let mut offset = 0;
+        let operations: Vec<_> = files
+            .iter()
+            .map(|&(path, size)| {
+                offset += size;
+                serde_json::json!({
+                    "type": "add", "path": path,
+                    "dataOffset": (offset - size).to_string(), "dataSize": size.to_string(),
+                    "dataCompression": "raw",
+                    "dataHash": "sha1:a9993e364706816aba3e25717850c26c9cd0d89d",
+                    "finalSize": size.to_string(),
+                    "finalHash": "sha1:a9993e364706816aba3e25717850c26c9cd0d89d",
+                })
+            })
+            .collect();
+        Arc::new(
+            serde_json::from_value(serde_json::json!({
+                "version": "2",
+                "package": { "from": from, "to": to, "size": offset.to_string() },
+                "operations": operations,
+            }))
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn required_space_kept_files() {
+        let dir = crate::tests::tmp_dir("required_space_kept_files");
+        fs::write(dir.join("a"), vec![0u8; 1000]).unwrap();
+        let packages =
+            vec![package("v1", "v2", &[("a", 100)]), package("v2", "v3", &[("a", 300), ("b", 50)])];
+        let state = StateUpdating::new(None, CleanName::new("v3".into()).unwrap(), Vec::new());
+        let required_space = |kept_files| {
+            let mut progress = UpdateProgress::new(CleanName::new("v3".into()).unwrap());
+            let filter = UpdateFilter::allows_all();
+            progress.push_steps(&packages, &state, &filter, &dir, kept_files);
+            progress.required_space
+        };
+
+        // the second step downloads and writes 350 bytes, after the first one
+        // wrote 100 bytes
+        let second_step = 100 + 350 + 350 + 4 * FILE_SPACE_OVERHEAD;
+        // the first step replaced the 1000 bytes of `a`
+        assert_eq!(required_space(KeptFiles::None), second_step - 1000);
+        // the workspace `a` stays until the end of the update
+        assert_eq!(required_space(KeptFiles::Staged), second_step);
+        assert_eq!(required_space(KeptFiles::Backup), second_step);
+    }
+
+    #[test]
+    fn required_space_growth() {
+        let dir = crate::tests::tmp_dir("required_space_growth");
+        let packages = vec![package("v1", "v2", &[("a", 100)]), package("v2", "v3", &[("b", 200)])];
+        let state = StateUpdating::new(None, CleanName::new("v3".into()).unwrap(), Vec::new());
+        let mut progress = UpdateProgress::new(CleanName::new("v3".into()).unwrap());
+        let filter = UpdateFilter::allows_all();
+        progress.push_steps(&packages, &state, &filter, &dir, KeptFiles::None);
+        // `a` is still there while `b` is downloaded and written
+        assert_eq!(progress.required_space, 100 + 200 + 200 + 2 * FILE_SPACE_OVERHEAD);
+    }
+}
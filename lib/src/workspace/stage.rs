@@ -0,0 +1,226 @@
+//! Staged updates, new files are built in `.update/stage` and swapped into
+//! the workspace once every operation is applied
+//!
+//! The stage contains:
+//!
+//!  - `files/`: new and patched files, with the same layout as the workspace,
+//!  - `rm/` and `rmdir/`: one file per removed file or directory, named after
+//!    the operation and containing the removed path,
+//!  - `swap`: the target version, only while swapping.
+//!
+//! Each swap step removes what it has done from the stage, so an interrupted
+//! swap is resumed by running it again.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tracing::{info, warn};
+
+use super::{backup, WorkspaceFileManager};
+use crate::io;
+use crate::metadata::{self, CleanName, CleanPath};
+
+fn files_dir(stage_dir: &Path) -> PathBuf {
+    stage_dir.join("files")
+}
+
+fn rm_dir(stage_dir: &Path) -> PathBuf {
+    stage_dir.join("rm")
+}
+
+fn rmdir_dir(stage_dir: &Path) -> PathBuf {
+    stage_dir.join("rmdir")
+}
+
+fn swap_path(stage_dir: &Path) -> PathBuf {
+    stage_dir.join("swap")
+}
+
+/// Stage directory of the update starting from `state`
+///
+/// Interrupted updates keep their stage, even if `staged` is no longer set, as
+/// part of the new files are already in it.
+pub(super) fn prepare(
+    file_manager: &WorkspaceFileManager,
+    state: &metadata::v1::State,
+    staged: bool,
+) -> io::Result<Option<PathBuf>> {
+    let stage_dir = file_manager.stage_dir();
+    let in_progress = files_dir(&stage_dir).exists();
+    if in_progress && matches!(state, metadata::v1::State::Updating(_)) {
+        return Ok(Some(stage_dir));
+    }
+
+    super::ignore_not_found(fs::remove_dir_all(&stage_dir))?;
+    if !staged {
+        return Ok(None);
+    }
+    fs::create_dir_all(files_dir(&stage_dir))?;
+    fs::create_dir_all(rm_dir(&stage_dir))?;
+    fs::create_dir_all(rmdir_dir(&stage_dir))?;
+    Ok(Some(stage_dir))
+}
+
+/// Path where the new version of `path` is built
+pub(crate) fn file_path(stage_dir: &Path, path: &CleanPath) -> PathBuf {
+    files_dir(stage_dir).join(path)
+}
+
+/// Remove `path` from the stage and mark it for removal from the workspace
+///
+/// `name` must be unique for the update (i.e. `<package>-<operation_idx>`).
+pub(crate) fn remove_file(stage_dir: &Path, path: &CleanPath, name: &str) -> io::Result<()> {
+    io::remove_file(file_path(stage_dir, path))?;
+    fs::write(rm_dir(stage_dir).join(name), path.as_str())
+}
+
+/// Remove the directory `path` from the stage and mark it for removal from the
+/// workspace
+pub(crate) fn remove_dir(stage_dir: &Path, path: &CleanPath, name: &str) -> io::Result<()> {
+    if let Err(err) = fs::remove_dir(file_path(stage_dir, path)) {
+        if err.kind() != io::ErrorKind::NotFound {
+            return Err(err);
+        }
+    }
+    fs::write(rmdir_dir(stage_dir).join(name), path.as_str())
+}
+
+/// Version the workspace was being swapped to, if a swap was interrupted
+pub(super) fn pending_swap(file_manager: &WorkspaceFileManager) -> io::Result<Option<CleanName>> {
+    match fs::read_to_string(swap_path(&file_manager.stage_dir())) {
+        Ok(version) => CleanName::new(version)
+            .map(Some)
+            .map_err(|version| io::Error::new(io::ErrorKind::InvalidData, version)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn marked_paths(dir: &Path) -> io::Result<Vec<(PathBuf, CleanPath)>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let marker = entry?.path();
+        let path = CleanPath::new(fs::read_to_string(&marker)?)
+            .map_err(|path| io::Error::new(io::ErrorKind::InvalidData, path))?;
+        paths.push((marker, path));
+    }
+    Ok(paths)
+}
+
+/// Move staged files in `dir` into `workspace_dir`
+fn move_files(
+    dir: &Path,
+    prefix: &Path,
+    workspace_dir: &Path,
+    backup_dir: Option<&Path>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = prefix.join(entry.file_name());
+        let final_path = workspace_dir.join(&path);
+        if entry.file_type()?.is_dir() {
+            fs::create_dir_all(&final_path)?;
+            move_files(&entry.path(), &path, workspace_dir, backup_dir)?;
+        } else {
+            match (backup_dir, path.to_str().and_then(|p| CleanPath::new(p.into()).ok())) {
+                (Some(backup_dir), Some(clean_path)) => {
+                    backup::backup_file(&final_path, &backup::file_path(backup_dir, &clean_path))?
+                }
+                _ => io::remove_file(&final_path)?,
+            }
+            fs::rename(entry.path(), &final_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Swap the staged files into the workspace
+///
+/// Replaced and removed files are moved to `backup_dir` if set.
+pub(super) fn swap(
+    file_manager: &WorkspaceFileManager,
+    backup_dir: Option<&Path>,
+    version: &CleanName,
+) -> io::Result<()> {
+    let stage_dir = file_manager.stage_dir();
+    let workspace_dir = file_manager.dir();
+    info!("swap staged {} into the workspace", version);
+    fs::write(swap_path(&stage_dir), version.as_str())?;
+
+    for (marker, path) in marked_paths(&rm_dir(&stage_dir))? {
+        let final_path = workspace_dir.join(&path);
+        match backup_dir {
+            Some(backup_dir) => {
+                backup::backup_file(&final_path, &backup::file_path(backup_dir, &path))?
+            }
+            None => io::remove_file(&final_path)?,
+        }
+        fs::remove_file(marker)?;
+    }
+
+    move_files(&files_dir(&stage_dir), Path::new(""), workspace_dir, backup_dir)?;
+
+    let mut dirs = marked_paths(&rmdir_dir(&stage_dir))?;
+    // deepest directories first
+    dirs.sort_by(|a, b| b.1.cmp(&a.1));
+    for (_, path) in dirs {
+        if file_path(&stage_dir, &path).exists() {
+            continue; // created again by a later package
+        }
+        if let Err(err) = fs::remove_dir(workspace_dir.join(&path)) {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!("unable to remove directory {}: {}", path, err);
+            }
+        }
+    }
+
+    fs::remove_dir_all(&stage_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    #[test]
+    fn stage_swap() {
+        let dir = crate::tests::tmp_dir("stage_swap");
+        let file_manager = Workspace::open(&dir).unwrap().file_manager();
+        fs::write(dir.join("a"), b"a1").unwrap();
+        fs::write(dir.join("b"), b"b1").unwrap();
+        fs::create_dir(dir.join("d")).unwrap();
+
+        let updating = metadata::v1::State::Updating(metadata::v1::StateUpdating::new(
+            None,
+            CleanName::new("v2".into()).unwrap(),
+            Vec::new(),
+        ));
+        assert!(prepare(&file_manager, &updating, false).unwrap().is_none());
+        let stage_dir = prepare(&file_manager, &metadata::v1::State::New, true).unwrap().unwrap();
+        let (a, b, c, d) = (
+            CleanPath::new("a".into()).unwrap(),
+            CleanPath::new("b".into()).unwrap(),
+            CleanPath::new("e/c".into()).unwrap(),
+            CleanPath::new("d".into()).unwrap(),
+        );
+        fs::write(file_path(&stage_dir, &a), b"a2").unwrap();
+        fs::create_dir(file_path(&stage_dir, &CleanPath::new("e".into()).unwrap())).unwrap();
+        fs::write(file_path(&stage_dir, &c), b"c2").unwrap();
+        remove_file(&stage_dir, &b, "v2-1").unwrap();
+        remove_dir(&stage_dir, &d, "v2-2").unwrap();
+        // the workspace is untouched until the swap
+        assert_eq!(fs::read(dir.join("a")).unwrap(), b"a1");
+        assert!(dir.join("b").exists());
+        // interrupted updates keep their stage
+        assert_eq!(prepare(&file_manager, &updating, false).unwrap(), Some(stage_dir.clone()));
+
+        let v2 = CleanName::new("v2".into()).unwrap();
+        fs::write(swap_path(&stage_dir), v2.as_str()).unwrap();
+        assert_eq!(pending_swap(&file_manager).unwrap(), Some(v2.clone()));
+        swap(&file_manager, None, &v2).unwrap();
+        assert_eq!(fs::read(dir.join("a")).unwrap(), b"a2");
+        assert_eq!(fs::read(dir.join("e/c")).unwrap(), b"c2");
+        assert!(!dir.join("b").exists());
+        assert!(!dir.join("d").exists());
+        assert_eq!(pending_swap(&file_manager).unwrap(), None);
+    }
+}
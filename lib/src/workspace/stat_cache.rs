@@ -0,0 +1,165 @@
+//! Size, mtime and inode of the files verified by the last checks
+//!
+//! Quick checks only hash files whose stat changed since they were last
+//! verified against the same expected hash (see
+//! [`CheckOptions::quick`](super::CheckOptions::quick)).
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::WorkspaceFileManager;
+use crate::io;
+use crate::metadata::{CleanPath, Hash};
+
+/// Files modified this close to the time their stat was taken can be modified
+/// again without their mtime changing, filesystems timestamps can be as coarse
+/// as 2 seconds
+const RACY_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct FileStat {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    inode: u64,
+}
+
+impl FileStat {
+    pub(crate) fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok(Self {
+            size: metadata.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            inode: inode(&metadata),
+        })
+    }
+
+    /// The file was modified too close to `stat_time` for its mtime to tell
+    /// later modifications apart
+    fn is_racy(&self, stat_time: SystemTime) -> bool {
+        let mtime = Duration::new(self.mtime_secs, self.mtime_nanos);
+        let stat_time = stat_time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        mtime + RACY_DELAY > stat_time
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::ino(metadata)
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+/// Stat of a verified file and the hashes it matched, one per slice
+#[derive(Serialize, Deserialize, Debug)]
+struct Verified {
+    stat: FileStat,
+    hashes: Vec<Hash>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct StatCache {
+    files: BTreeMap<CleanPath, Verified>,
+}
+
+impl StatCache {
+    /// Load the cache, a missing or invalid cache is empty
+    pub(crate) fn load(file_manager: &WorkspaceFileManager) -> Self {
+        let res = fs::File::open(file_manager.stat_cache_path())
+            .and_then(|file| serde_json::from_reader(file).map_err(io::Error::from));
+        match res {
+            Ok(cache) => cache,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(err) => {
+                warn!("unable to load check cache: {}", err);
+                Self::default()
+            }
+        }
+    }
+
+    pub(crate) fn write(&self, file_manager: &WorkspaceFileManager) -> io::Result<()> {
+        io::atomic_write_json(file_manager.stat_cache_path(), self)
+    }
+
+    /// `path` was verified against `hash` and didn't change since
+    pub(crate) fn is_fresh(&self, path: &CleanPath, hash: &Hash, stat: &FileStat) -> bool {
+        match self.files.get(path) {
+            Some(verified) => &verified.stat == stat && verified.hashes.contains(hash),
+            None => false,
+        }
+    }
+
+    /// Record that `path` matched `hash`, `stat` being taken at `stat_time`
+    ///
+    /// Racy files, modified too close to `stat_time`, aren't recorded and are
+    /// hashed again by the next check.
+    pub(crate) fn insert(
+        &mut self,
+        path: CleanPath,
+        hash: Hash,
+        stat: FileStat,
+        stat_time: SystemTime,
+    ) {
+        if stat.is_racy(stat_time) {
+            self.files.remove(&path);
+            return;
+        }
+        match self.files.get_mut(&path) {
+            Some(verified) if verified.stat == stat => {
+                if !verified.hashes.contains(&hash) {
+                    verified.hashes.push(hash);
+                }
+            }
+            _ => {
+                self.files.insert(path, Verified { stat, hashes: vec![hash] });
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, path: &CleanPath) {
+        self.files.remove(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace::Workspace;
+
+    #[test]
+    fn stat_cache_freshness() {
+        let dir = crate::tests::tmp_dir("stat_cache_freshness");
+        let file_manager = Workspace::open(&dir).unwrap().file_manager();
+        file_manager.create_update_dirs().unwrap();
+        let path = CleanPath::new("a".into()).unwrap();
+        fs::write(dir.join("a"), b"a1").unwrap();
+
+        let hash: Hash = "sha1:0000000000000000000000000000000000000001".parse().unwrap();
+        let other_hash: Hash = "sha1:0000000000000000000000000000000000000002".parse().unwrap();
+
+        let mut cache = StatCache::load(&file_manager);
+        let stat = FileStat::of(&dir.join("a")).unwrap();
+        assert!(!cache.is_fresh(&path, &hash, &stat));
+        // just modified, the stat is racy
+        cache.insert(path.clone(), hash.clone(), stat, SystemTime::now());
+        assert!(!cache.is_fresh(&path, &hash, &stat));
+        cache.insert(path.clone(), hash.clone(), stat, SystemTime::now() + RACY_DELAY);
+        cache.write(&file_manager).unwrap();
+
+        let cache = StatCache::load(&file_manager);
+        assert!(cache.is_fresh(&path, &hash, &FileStat::of(&dir.join("a")).unwrap()));
+        // the expected hash changed
+        assert!(!cache.is_fresh(&path, &other_hash, &FileStat::of(&dir.join("a")).unwrap()));
+        fs::write(dir.join("a"), b"a22").unwrap();
+        assert!(!cache.is_fresh(&path, &hash, &FileStat::of(&dir.join("a")).unwrap()));
+    }
+}
@@ -1,5 +1,8 @@
 use std::cell::RefCell;
+use std::cmp;
+use std::collections::BTreeSet;
 use std::mem;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -10,13 +13,21 @@ use futures::future::Either;
 use futures::prelude::*;
 use tracing::{debug, error, info, warn};
 
-use super::apply::{apply_package, ApplyError, ApplyStream, AvailableForApply};
+use super::apply::{
+    apply_package, ApplyError, ApplyPackageProgression, ApplyStream, AvailableForApply,
+};
+use super::bandwidth::BandwidthLimit;
 use super::download::{download_package, DownloadStream};
-use super::progress::{Progression, SharedUpdateProgress, UpdateStage};
-use crate::link::{RemoteRepository, RepositoryError};
+use super::extraneous::{self, AllowList};
+use super::handle::UpdateHandle;
+use super::preserve::PristineHashes;
+use super::progress::{KeptFiles, Progression, SharedUpdateProgress, UpdateStage};
+use super::{backup, stage};
+use crate::link::{fetch_json, fetch_signed_json, RemoteRepository, RepositoryError};
 use crate::metadata::v1::{State, StateUpdating};
 use crate::metadata::{self, Operation, Package};
-use crate::workspace::{UpdatePosition, Workspace, WorkspaceFileManager};
+use crate::signature::{PublicKey, SignatureError};
+use crate::workspace::{is_contended, Workspace, WorkspaceFileManager, WorkspaceLock};
 
 #[derive(Debug)]
 pub enum UpdateError {
@@ -24,10 +35,21 @@ pub enum UpdateError {
     LocalCheckError(std::io::Error),
     LocalWorkspaceError(std::io::Error),
     Repository(RepositoryError),
+    /// Another process is using the workspace
+    WorkspaceLocked,
     NoPath,
     Download(RepositoryError),
     DownloadCache(std::io::Error),
-    Failed { files: usize },
+    Failed {
+        files: usize,
+    },
+    /// Not enough free disk space to start the update
+    InsufficientSpace {
+        required: u64,
+        available: u64,
+    },
+    /// Stopped by [`UpdateHandle::cancel`]
+    Cancelled,
     PoisonError,
 }
 
@@ -38,10 +60,17 @@ impl fmt::Display for UpdateError {
             UpdateError::LocalCheckError(err) => write!(f, "local check.json error:: {}", err),
             UpdateError::LocalWorkspaceError(err) => write!(f, "local workspace error: {}", err),
             UpdateError::Repository(err) => write!(f, "repository error: {}", err),
+            UpdateError::WorkspaceLocked => write!(f, "workspace is locked by another process"),
             UpdateError::NoPath => write!(f, "repository error: no update path found"),
             UpdateError::Download(err) => write!(f, "download error: {}", err),
             UpdateError::DownloadCache(err) => write!(f, "download cache error: {}", err),
             UpdateError::Failed { files } => write!(f, "update failed for {} files", files),
+            UpdateError::InsufficientSpace { required, available } => write!(
+                f,
+                "not enough disk space: {} bytes required, {} bytes available",
+                required, available
+            ),
+            UpdateError::Cancelled => write!(f, "update cancelled"),
             UpdateError::PoisonError => write!(f, "internal error: mutex poisonned"),
         }
     }
@@ -65,6 +94,45 @@ pub struct UpdateOptions {
     ///
     /// Default to `5s`.
     pub save_state_interval: Duration,
+    /// Maximum number of package ranges downloaded concurrently
+    ///
+    /// Downloaded bytes are still written in operation order.
+    ///
+    /// Default to `4`.
+    pub download_concurrency: usize,
+    /// Number of threads applying operations
+    ///
+    /// Operations on the same path are always applied by the same thread.
+    ///
+    /// Default to the number of CPUs.
+    pub apply_concurrency: usize,
+    /// Retry policy of package range downloads
+    pub retry: RetryPolicy,
+    /// Download rate limit, keep a clone to change it while the update runs
+    ///
+    /// Default to unlimited.
+    pub bandwidth_limit: BandwidthLimit,
+    /// If `true`, files replaced or removed by the update are moved to
+    /// `.update/backup/<version>` instead of being deleted, so
+    /// `Workspace::rollback` can restore the previous version
+    ///
+    /// Default to `false`.
+    pub backup: bool,
+    /// If `true`, new files are built in `.update/stage` and swapped into the
+    /// workspace once every operation is applied, so the workspace is never
+    /// partially updated
+    ///
+    /// Default to `false`.
+    pub staged: bool,
+    /// If set, files and directories the workspace doesn't track are removed
+    /// once the update succeeded, except the ones this list allows
+    ///
+    /// Default to `None`.
+    pub remove_extraneous: Option<AllowList>,
+    /// If set, repository metadata files must be signed by this key
+    ///
+    /// Default to `None`.
+    pub trusted_key: Option<PublicKey>,
 }
 
 impl Default for UpdateOptions {
@@ -74,6 +142,62 @@ impl Default for UpdateOptions {
             strict_meta: true,
             strict_fs: false,
             save_state_interval: Duration::from_secs(5),
+            download_concurrency: 4,
+            apply_concurrency: num_cpus::get(),
+            retry: RetryPolicy::default(),
+            bandwidth_limit: BandwidthLimit::unlimited(),
+            backup: false,
+            staged: false,
+            remove_extraneous: None,
+            trusted_key: None,
+        }
+    }
+}
+
+/// Retry policy of package range downloads
+///
+/// A failed range is requested again starting after the last downloaded byte.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts to download a range without receiving at
+    /// least 1MiB in a row, `1` disables retries
+    ///
+    /// Default to `5`.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each following attempt
+    ///
+    /// Default to `1s`.
+    pub initial_backoff: Duration,
+    /// Maximum delay between two attempts
+    ///
+    /// Default to `30s`.
+    pub max_backoff: Duration,
+    /// Returns `true` if the download can be retried after this error
+    ///
+    /// Default to `RepositoryError::is_transient`.
+    pub retryable: fn(&RepositoryError) -> bool,
+}
+
+impl RetryPolicy {
+    /// Policy that never retries
+    pub fn never() -> Self {
+        Self { max_attempts: 1, ..Self::default() }
+    }
+
+    /// Delay to wait after the failed `attempt` (starting at 1)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        cmp::min(self.initial_backoff.saturating_mul(factor), self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            retryable: RepositoryError::is_transient,
         }
     }
 }
@@ -83,9 +207,11 @@ struct UpdatePackageStream<'a> {
     shared_state: SharedUpdateProgress,
     download_stream: DownloadStream<'a>,
     apply_stream: ApplyStream,
+    handle: UpdateHandle,
 }
 
 impl<'a> UpdatePackageStream<'a> {
+    #[allow(clippy::too_many_arguments)]
     fn new<R>(
         update_options: UpdateOptions,
         state: Rc<RefCell<StateUpdating>>,
@@ -93,35 +219,40 @@ impl<'a> UpdatePackageStream<'a> {
         file_manager: WorkspaceFileManager,
         repository: &'a R,
         package_name: &metadata::CleanName,
-        operations: Vec<(usize, Arc<metadata::v1::Operation>)>,
+        backup_dir: Option<PathBuf>,
+        stage_dir: Option<PathBuf>,
+        pristine_hashes: PristineHashes,
+        operations: Vec<(usize, Arc<metadata::v2::Operation>)>,
+        handle: UpdateHandle,
     ) -> Result<UpdatePackageStream<'a>, UpdateError>
     where
         R: RemoteRepository,
     {
-        let (available, applied) = {
+        let (available, apply_operations) = {
             let state = &*state.borrow();
-            (state.available.clone(), state.applied.clone())
+            let apply_operations: Vec<(usize, _)> =
+                operations.iter().filter(|&&(idx, _)| !state.is_applied(idx)).cloned().collect();
+            (state.available.clone(), apply_operations)
         };
         let download_operations: Vec<(usize, _)> = operations
             .iter()
             .skip_while(|&&(idx, _)| idx < available.operation_idx)
             .cloned()
             .collect();
-        let apply_operations: Vec<(usize, _)> = operations
-            .iter()
-            .skip_while(|&&(idx, _)| idx < applied.operation_idx)
-            .cloned()
-            .collect();
 
         file_manager.create_update_dirs().map_err(UpdateError::LocalWorkspaceError)?;
 
         let i_available = AvailableForApply::new(available);
         let apply_stream = apply_package(
-            update_options,
+            update_options.clone(),
             file_manager.clone(),
             package_name,
+            backup_dir,
+            stage_dir,
+            Arc::new(pristine_hashes),
             apply_operations,
             i_available.clone(),
+            handle.clone(),
         );
         let download_stream = download_package(
             file_manager,
@@ -129,9 +260,70 @@ impl<'a> UpdatePackageStream<'a> {
             package_name,
             download_operations,
             available.clone(),
+            &update_options,
         );
 
-        Ok(UpdatePackageStream { state, shared_state, download_stream, apply_stream })
+        Ok(UpdatePackageStream { state, shared_state, download_stream, apply_stream, handle })
+    }
+
+    fn apply_progress(
+        &mut self,
+        apply_progress: Result<ApplyPackageProgression, ApplyError>,
+        delta: &mut Progression,
+    ) -> Result<(), UpdateError> {
+        match apply_progress {
+            Ok(apply_progress) => {
+                self.state
+                    .borrow_mut()
+                    .set_applied(apply_progress.operation_idx, apply_progress.applied_operations);
+                let mut state = self.shared_state.borrow_mut();
+                state.applying_operation_idx = apply_progress.operation_idx;
+                delta.applied_files += apply_progress.delta_applied_files;
+                delta.applied_input_bytes += apply_progress.delta_input_bytes;
+                delta.applied_output_bytes += apply_progress.delta_output_bytes;
+            }
+            Err(ApplyError::OperationFailed { path, slice, cause }) => {
+                warn!("{} failed: {}", path, cause);
+                let mut state = self.state.borrow_mut();
+                state.failures.push(match slice {
+                    Some(slice) => metadata::v1::Failure::Slice { path, slice },
+                    None => metadata::v1::Failure::Path { path },
+                });
+                delta.failed_files += 1;
+            }
+            Err(ApplyError::LocallyModified { path }) => {
+                info!("{} is locally modified, kept as is", path);
+            }
+            Err(ApplyError::Cancelled) => {}
+            Err(ApplyError::PoisonError) => return Err(UpdateError::PoisonError),
+        }
+        Ok(())
+    }
+
+    /// Stop downloading and wait for the apply threads to stop, operations
+    /// being committed are finished and accounted in the state
+    fn poll_cancelled(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<SharedUpdateProgress, UpdateError>>> {
+        self.apply_stream.cancel();
+        let mut delta = Progression::default();
+        let res = loop {
+            match self.apply_stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(apply_progress)) => {
+                    if let Err(err) = self.apply_progress(apply_progress, &mut delta) {
+                        break Poll::Ready(Some(Err(err)));
+                    }
+                }
+                Poll::Ready(None) => {
+                    debug!("update cancelled");
+                    break Poll::Ready(Some(Err(UpdateError::Cancelled)));
+                }
+                Poll::Pending => break Poll::Pending,
+            }
+        };
+        self.shared_state.borrow_mut().inc_progress(delta);
+        res
     }
 }
 
@@ -143,6 +335,12 @@ impl<'a> Stream for UpdatePackageStream<'a> {
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
+        match this.handle.poll_resumed(cx) {
+            Poll::Ready(true) => {}
+            Poll::Ready(false) => return this.poll_cancelled(cx),
+            // in-flight downloads are kept until the update is resumed
+            Poll::Pending => return Poll::Pending,
+        }
         let download_poll = this.download_stream.poll_next_unpin(cx);
         let apply_poll = this.apply_stream.poll_next_unpin(cx);
 
@@ -168,29 +366,8 @@ impl<'a> Stream for UpdatePackageStream<'a> {
                     this.apply_stream.notify(download_progress.available);
                 }
                 if let Poll::Ready(Some(apply_progress)) = apply_poll {
-                    match apply_progress {
-                        Ok(apply_progress) => {
-                            this.state.borrow_mut().applied.operation_idx =
-                                apply_progress.operation_idx;
-                            let mut state = this.shared_state.borrow_mut();
-                            state.applying_operation_idx = apply_progress.operation_idx;
-                            delta.applied_files = apply_progress.delta_applied_files;
-                            delta.applied_input_bytes = apply_progress.delta_input_bytes;
-                            delta.applied_output_bytes = apply_progress.delta_output_bytes;
-                        }
-                        Err(ApplyError::OperationFailed { path, slice, cause }) => {
-                            warn!("{} failed: {}", path, cause);
-                            let mut state = this.state.borrow_mut();
-                            state.failures.push(match slice {
-                                Some(slice) => metadata::v1::Failure::Slice { path, slice },
-                                None => metadata::v1::Failure::Path { path },
-                            });
-                            delta.failed_files = 1;
-                        }
-                        Err(ApplyError::Cancelled) => {}
-                        Err(ApplyError::PoisonError) => {
-                            return Poll::Ready(Some(Err(UpdateError::PoisonError)))
-                        }
+                    if let Err(err) = this.apply_progress(apply_progress, &mut delta) {
+                        return Poll::Ready(Some(Err(err)));
                     }
                 }
 
@@ -206,21 +383,31 @@ impl<'a> Stream for UpdatePackageStream<'a> {
 }
 
 pub(super) struct UpdateFilter {
-    failures: Vec<metadata::v1::Failure>,
+    pub(super) failures: Vec<metadata::v1::Failure>,
+    /// Selected optional components, `None` selects every component
+    pub(super) components: Option<BTreeSet<metadata::CleanName>>,
 }
 
 impl UpdateFilter {
-    fn allows_all() -> Self {
-        Self { failures: Vec::new() }
+    pub(super) fn allows_all() -> Self {
+        Self { failures: Vec::new(), components: None }
     }
 
-    pub(super) fn filter(&self, o: &metadata::v1::Operation) -> bool {
-        self.failures.is_empty()
-            || self.failures.binary_search_by_key(&o.path(), |f| f.path()).is_ok()
+    /// `o` belongs to the selected components
+    pub(super) fn selects(&self, o: &dyn Operation) -> bool {
+        metadata::is_selected(o.components(), self.components.as_ref())
     }
 
-    fn filter_map(&self, o: &metadata::v1::Operation) -> Option<metadata::v1::Operation> {
-        if self.failures.is_empty()
+    pub(super) fn filter(&self, o: &dyn Operation) -> bool {
+        self.selects(o)
+            && (self.failures.is_empty()
+                || self.failures.binary_search_by_key(&o.path(), |f| f.path()).is_ok())
+    }
+
+    fn filter_map(&self, o: &metadata::v2::Operation) -> Option<metadata::v2::Operation> {
+        if !self.selects(o) {
+            None
+        } else if self.failures.is_empty()
             || self
                 .failures
                 .binary_search_by_key(&(o.path(), o.slice()), |f| (f.path(), f.slice()))
@@ -243,22 +430,42 @@ pub type GlobalProgressStream<'a> =
 // get -> stream of bytes -> write -> progression
 // progression -> apply -> progression
 
+/// Update `workspace` to `goal_version`, the repository current version if
+/// `None`
+///
+/// `lock` is an exclusive lock the caller already holds (i.e. while selecting
+/// components), the workspace is locked here if it's `None`.
+///
+/// `update_options` is boxed because async fn arguments stay in their future
+/// until it completes and the options are moved again into the update stream:
+/// by value they take two thirds of the future, that `update_ret_size` keeps
+/// under 256 bytes.
 pub(crate) async fn update<'a, R>(
     workspace: &'a mut Workspace,
     repository: &'a R,
     goal_version: Option<metadata::CleanName>,
-    update_options: UpdateOptions,
+    update_options: Box<UpdateOptions>,
+    handle: UpdateHandle,
+    lock: Option<WorkspaceLock>,
 ) -> Result<impl Stream<Item = Result<SharedUpdateProgress, UpdateError>> + 'a, UpdateError>
 where
     R: RemoteRepository,
 {
-    let goal_version = if let Some(goal_version) = goal_version {
-        goal_version.to_owned()
-    } else {
-        let current_version = repository.current_version().map_err(UpdateError::Repository).await?;
-        current_version.version().clone()
+    let (goal_version, signed_at) = match goal_version {
+        Some(goal_version) => (goal_version, None),
+        None => current_version(repository, update_options.trusted_key.as_ref()).await?,
     };
     info!("update to {}", goal_version);
+    let update_options = *update_options;
+
+    // Held until the update stream is done or dropped
+    let lock = match lock {
+        Some(lock) => Some(lock),
+        None => workspace.lock_exclusive().map_err(|err| match is_contended(&err) {
+            true => UpdateError::WorkspaceLocked,
+            false => UpdateError::LocalWorkspaceError(err),
+        })?,
+    };
 
     // Load current workspace state
     workspace.file_manager().create_update_dirs().map_err(UpdateError::LocalWorkspaceError)?;
@@ -267,13 +474,37 @@ where
         warn!("unable to load current workspace state: {}", err);
     };
 
+    check_replay(workspace, signed_at)?;
+    if let Some(signed_at) = signed_at {
+        if workspace.current_signed_at() != Some(signed_at) {
+            workspace.set_current_signed_at(signed_at).map_err(UpdateError::LocalStateError)?;
+        }
+    }
+
+    // Finish the interrupted swap of a staged update
+    let file_manager = workspace.file_manager();
+    if let Some(version) =
+        stage::pending_swap(&file_manager).map_err(UpdateError::LocalWorkspaceError)?
+    {
+        swap_stage(&file_manager, update_options.backup, &version)?;
+        workspace.set_state(State::Stable { version }).map_err(UpdateError::LocalStateError)?;
+    }
+
     if let State::Stable { version } = workspace.state() {
         if version == &goal_version && !update_options.check {
             // Everything is uptodate, and nothing requires fixing
+            if let Some(allow_list) = &update_options.remove_extraneous {
+                remove_extraneous_paths(&file_manager, allow_list);
+            }
             return Ok(Either::Right(stream::empty()));
         }
     }
 
+    let stage_dir = stage::prepare(&file_manager, workspace.state(), update_options.staged)
+        .map_err(UpdateError::LocalWorkspaceError)?;
+    let staged = stage_dir.is_some();
+
+    let components = workspace.components().cloned();
     let mut workspace_state = workspace.state().clone();
     let failures = match &mut workspace_state {
         State::Corrupted { failures, .. } => std::mem::take(failures),
@@ -295,14 +526,21 @@ where
     let global_progression_nr = global_progression_n.clone();
     let global_progression_c = global_progression_n.clone();
 
-    let file_manager_n = workspace.file_manager();
+    let file_manager_n = file_manager;
     let file_manager_r = file_manager_n.clone();
+    let file_manager_c = file_manager_n.clone();
+
+    let stage_dir_r = stage_dir.clone();
 
     let goal_version_n = goal_version.clone();
     let goal_version_r = goal_version.clone();
 
     let update_options_r = update_options.clone();
+    let handle_r = handle.clone();
+    let components_r = components.clone();
     let update_options_s = update_options.clone();
+    let backup = update_options.backup;
+    let remove_extraneous = update_options.remove_extraneous.clone();
 
     let write_state_nr = Rc::new(RefCell::new(move || -> Result<(), UpdateError> {
         let state = &*shared_state_s.borrow();
@@ -331,8 +569,10 @@ where
         shared_state_n,
         repository,
         goal_version_n,
-        UpdateFilter::allows_all(),
+        UpdateFilter { components, ..UpdateFilter::allows_all() },
         UpdateStage::Updating,
+        stage_dir,
+        handle,
     )
     .try_flatten_stream();
 
@@ -355,8 +595,10 @@ where
                     shared_state_r,
                     repository,
                     goal_version_r,
-                    UpdateFilter { failures },
+                    UpdateFilter { failures, components: components_r },
                     UpdateStage::Repairing,
+                    stage_dir_r,
+                    handle_r,
                 )
                 .try_flatten_stream(),
             )
@@ -368,7 +610,13 @@ where
     .flatten_stream();
 
     let commit_stream = future::lazy(move |_| {
-        if let Err(err) = (&mut *write_state_c.borrow_mut())() {
+        let _lock = lock;
+        // Swap staged files before the workspace is marked as stable
+        let res = match staged && shared_state_c.borrow().failures.is_empty() {
+            true => swap_stage(&file_manager_c, backup, &goal_version),
+            false => Ok(()),
+        };
+        if let Err(err) = res.and_then(|()| (&mut *write_state_c.borrow_mut())()) {
             // Failed to write state
             return Either::Right(stream::once(async { Err(err) }));
         }
@@ -377,6 +625,9 @@ where
         state.previous_failures = Vec::new();
         let last_res = if state.failures.len() == 0 {
             info!("update to {} succeeded", goal_version);
+            if let Some(allow_list) = &remove_extraneous {
+                remove_extraneous_paths(&file_manager_c, allow_list);
+            }
             global_progression_c.borrow_mut().stage = UpdateStage::Uptodate;
             Ok(global_progression_c.clone())
         } else {
@@ -392,28 +643,107 @@ where
     let mut last_write = Instant::now();
     let final_stream = normal_stream
         .chain(repair_stream)
-        .inspect(move |_| {
+        .inspect(move |res| {
             let now = Instant::now();
-            if now.duration_since(last_write) > update_options_s.save_state_interval {
+            let cancelled = matches!(res, Err(UpdateError::Cancelled));
+            if cancelled || now.duration_since(last_write) > update_options_s.save_state_interval {
                 let _ignore_err = (&mut *write_state_nr.borrow_mut())();
                 last_write = now;
             }
         })
         .chain(commit_stream);
 
+    // Nothing is polled after a cancellation, the workspace is left updating
+    let final_stream = stream::unfold(Some(Box::pin(final_stream)), |final_stream| async move {
+        let mut final_stream = final_stream?;
+        let res = final_stream.next().await?;
+        match res {
+            Err(UpdateError::Cancelled) => Some((res, None)),
+            _ => Some((res, Some(final_stream))),
+        }
+    });
+
     Ok(Either::Left(final_stream))
 }
 
-async fn update_path<R>(
+/// Repository current version, with the time it was signed at if
+/// `trusted_key` is set
+pub(super) async fn current_version<R>(
+    repository: &R,
+    trusted_key: Option<&PublicKey>,
+) -> Result<(metadata::CleanName, Option<u64>), UpdateError>
+where
+    R: RemoteRepository,
+{
+    let (current_version, signed_at): (metadata::Current, _) = match trusted_key {
+        Some(trusted_key) => {
+            fetch_signed_json(repository, metadata::Current::filename(), trusted_key)
+                .boxed_local()
+                .await
+                .map(|(current, signed_at)| (current, Some(signed_at)))
+        }
+        None => repository.current_version().await.map(|current| (current, None)),
+    }
+    .map_err(UpdateError::Repository)?;
+    Ok((current_version.version().clone(), signed_at))
+}
+
+/// Fails if the repository `current` file was signed before the one the
+/// workspace already trusted, an outdated `current` can be replayed to
+/// downgrade workspaces
+pub(super) fn check_replay(
+    workspace: &Workspace,
+    signed_at: Option<u64>,
+) -> Result<(), UpdateError> {
+    match (signed_at, workspace.current_signed_at()) {
+        (Some(signed_at), Some(trusted)) if signed_at < trusted => {
+            Err(UpdateError::Repository(RepositoryError::Signature {
+                name: metadata::Current::filename().to_owned(),
+                err: SignatureError::Replayed,
+            }))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Operations of `package_metadata` the update applies
+pub(super) fn package_operations(
+    package_metadata: &metadata::PackageMetadata,
+    check_only: bool,
+    filter: &UpdateFilter,
+    check: bool,
+) -> Vec<(usize, Arc<metadata::v2::Operation>)> {
+    package_metadata
+        .operations()
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, o)| {
+            let maybe_o =
+                if !check_only { filter.filter_map(o).map(|o| (idx, Arc::new(o))) } else { None };
+            if maybe_o.is_none() && check && filter.selects(o) {
+                o.as_check_operation().map(|o| (idx, Arc::new(o)))
+            } else {
+                maybe_o
+            }
+        })
+        .collect()
+}
+
+pub(super) async fn update_path<R>(
     initial_state: State,
     repository: &R,
     goal_version: &metadata::CleanName,
     check: bool,
+    trusted_key: Option<&PublicKey>,
 ) -> Result<Option<(Vec<Arc<metadata::PackageMetadata>>, StateUpdating)>, UpdateError>
 where
     R: RemoteRepository,
 {
-    let packages = repository.packages().map_err(UpdateError::Repository).await?;
+    let packages: metadata::Packages = match trusted_key {
+        Some(_) => fetch_json(repository, metadata::Packages::filename(), trusted_key).await,
+        None => repository.packages().await,
+    }
+    .map_err(UpdateError::Repository)?;
     let maybe_path = shortest_path(initial_state, packages.as_slice(), goal_version, check)?;
     let (path, first_package_state) = match maybe_path {
         Some(x) => x,
@@ -426,7 +756,12 @@ where
     info!("found update path {:?}", package_names);
 
     let packages_metadata = stream::iter(package_names.into_iter())
-        .map(|package_name| repository.package_metadata(package_name))
+        .map(|package_name| async move {
+            match trusted_key {
+                Some(_) => fetch_json(repository, &package_name, trusted_key).await,
+                None => repository.package_metadata(package_name).await,
+            }
+        })
         .buffered(4)
         .map_ok(Arc::new)
         .try_collect()
@@ -446,21 +781,60 @@ async fn update_internal<'a, R>(
     goal_version: metadata::CleanName,
     filter: UpdateFilter,
     main_stage: UpdateStage,
+    stage_dir: Option<PathBuf>,
+    handle: UpdateHandle,
 ) -> Result<impl Stream<Item = Result<SharedUpdateProgress, UpdateError>> + 'a, UpdateError>
 where
     R: RemoteRepository,
 {
-    let maybe_path =
-        update_path(initial_state, repository, &goal_version, update_options.check).await?;
+    let maybe_path = update_path(
+        initial_state.clone(),
+        repository,
+        &goal_version,
+        update_options.check,
+        update_options.trusted_key.as_ref(),
+    )
+    .await?;
+    let mut backup_dir = None;
+    let mut previous_checks = None;
     let packages_metadata = match maybe_path {
         Some((packages_metadata, first_package_state)) => {
             // Update global progress with objectives
+            let backup = update_options.backup && !first_package_state.check_only;
             global_progression.borrow_mut().push_steps(
                 &packages_metadata,
                 &first_package_state,
                 &filter,
+                file_manager.dir(),
+                KeptFiles::new(stage_dir.is_some(), backup),
             );
 
+            // Fail early instead of filling the disk
+            check_space(&file_manager, global_progression.borrow().required_space)?;
+
+            if backup {
+                backup_dir = backup::prepare(&file_manager, &initial_state)
+                    .map_err(UpdateError::LocalWorkspaceError)?;
+            }
+
+            // check.json already lists the checks of a resumed package, the
+            // hashes of the files it preserves come from the version it updates
+            // from
+            previous_checks = match &initial_state {
+                State::Updating(state)
+                    if state.from == first_package_state.from
+                        && state.to == first_package_state.to =>
+                {
+                    version_checks(
+                        repository,
+                        state.from.as_ref(),
+                        update_options.trusted_key.as_ref(),
+                    )
+                    .await?
+                }
+                _ => file_manager.read_checks().ok(),
+            };
+
             // Setup shared workspace state
             shared_state.borrow_mut().update_with(first_package_state);
 
@@ -476,6 +850,10 @@ where
     let state_p = shared_state.clone();
 
     let update_package_stream = packages_metadata.into_iter().map(move |package_metadata| {
+        if handle.is_cancelled() {
+            return Err(UpdateError::Cancelled);
+        }
+
         // Update workspace updating state details
         let check_only = {
             let state = &mut *state_p.borrow_mut();
@@ -492,30 +870,20 @@ where
         };
 
         // Build list of operations to do
-        let operations: Vec<(usize, Arc<metadata::v1::Operation>)> = package_metadata
-            .iter()
-            .enumerate()
-            .filter_map(|(idx, o)| {
-                let maybe_o = if !check_only {
-                    filter.filter_map(o).map(|o| (idx, Arc::new(o)))
-                } else {
-                    None
-                };
-                if maybe_o.is_none() && update_options.check {
-                    o.as_check_operation().map(|o| (idx, Arc::new(o)))
-                } else {
-                    maybe_o
-                }
-            })
-            .collect();
+        let operations =
+            package_operations(&package_metadata, check_only, &filter, update_options.check);
+
+        // Hashes of the preserved files, before the package check file replaces
+        // the previous one
+        let pristine_hashes = match &previous_checks {
+            Some(previous_checks) => PristineHashes::new(previous_checks, &package_metadata),
+            None => PristineHashes::default(),
+        };
 
         // Write package check file
-        {
-            let check_operations: Vec<metadata::v1::Operation> =
-                package_metadata.iter().filter_map(|o| o.as_check_operation()).collect();
-            let checks = metadata::WorkspaceChecks::V1 { operations: check_operations };
-            file_manager.write_checks(&checks).map_err(UpdateError::LocalCheckError)?;
-        }
+        let checks = package_metadata.checks(filter.components.as_ref());
+        file_manager.write_checks(&checks).map_err(UpdateError::LocalCheckError)?;
+        previous_checks = Some(checks);
 
         // Build downloader & applier stream
         let normal_stream = UpdatePackageStream::new(
@@ -525,16 +893,19 @@ where
             file_manager.clone(),
             repository,
             &package_metadata.package_data_name(),
+            backup_dir.clone(),
+            stage_dir.clone(),
+            pristine_hashes,
             operations,
+            handle.clone(),
         )?;
 
         let state_c = state_p.clone();
         let global_progression_c = global_progression.clone();
         let commit_stream = future::lazy(move |_| {
             debug!("end update package");
-            let mut state = &mut *state_c.borrow_mut();
-            state.available = UpdatePosition::new();
-            state.applied = UpdatePosition::new();
+            let state = &mut *state_c.borrow_mut();
+            state.clear_progress();
             global_progression_c.borrow_mut().inc_package();
             stream::empty()
         })
@@ -548,6 +919,70 @@ where
     Ok(update_stream)
 }
 
+/// Checks of the workspace at `version`, from the metadata of a package to it
+async fn version_checks<R>(
+    repository: &R,
+    version: Option<&metadata::CleanName>,
+    trusted_key: Option<&PublicKey>,
+) -> Result<Option<metadata::WorkspaceChecks>, UpdateError>
+where
+    R: RemoteRepository,
+{
+    let version = match version {
+        Some(version) => version,
+        None => return Ok(None),
+    };
+    let packages: metadata::Packages = match trusted_key {
+        Some(_) => fetch_json(repository, metadata::Packages::filename(), trusted_key).await,
+        None => repository.packages().await,
+    }
+    .map_err(UpdateError::Repository)?;
+    let package_name = match packages.iter().find(|package| package.to() == version) {
+        Some(package) => package.package_metadata_name(),
+        None => return Ok(None),
+    };
+    let package_metadata: metadata::PackageMetadata = match trusted_key {
+        Some(_) => fetch_json(repository, &package_name, trusted_key).await,
+        None => repository.package_metadata(package_name).await,
+    }
+    .map_err(UpdateError::Repository)?;
+    Ok(Some(package_metadata.checks(None)))
+}
+
+/// Swap staged files into the workspace, backing up replaced files if `backup`
+fn swap_stage(
+    file_manager: &WorkspaceFileManager,
+    backup: bool,
+    version: &metadata::CleanName,
+) -> Result<(), UpdateError> {
+    let res = match backup {
+        true => backup::current(file_manager).map(|current| current.map(|(_, dir)| dir)),
+        false => Ok(None),
+    };
+    res.and_then(|backup_dir| stage::swap(file_manager, backup_dir.as_deref(), version))
+        .map_err(UpdateError::LocalWorkspaceError)
+}
+
+/// Remove what the workspace doesn't track, this is best effort as the update
+/// itself succeeded
+fn remove_extraneous_paths(file_manager: &WorkspaceFileManager, allow_list: &AllowList) {
+    let res = extraneous::scan(file_manager, allow_list)
+        .and_then(|paths| extraneous::remove(file_manager, &paths));
+    if let Err(err) = res {
+        warn!("unable to remove extraneous files: {}", err);
+    }
+}
+
+fn check_space(file_manager: &WorkspaceFileManager, required: u64) -> Result<(), UpdateError> {
+    file_manager.create_update_dirs().map_err(UpdateError::LocalWorkspaceError)?;
+    let available =
+        fs2::available_space(file_manager.dir()).map_err(UpdateError::LocalWorkspaceError)?;
+    if required > available {
+        return Err(UpdateError::InsufficientSpace { required, available });
+    }
+    Ok(())
+}
+
 fn shortest_path<'a, P>(
     working_state: State,
     packages: &'a [P],
@@ -615,18 +1050,130 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+
     use super::*;
+    use crate::repository::{PackageBuilder, Repository};
+    use crate::signature::signature_filename;
     use crate::AutoRepository;
 
     #[test]
     fn update_ret_size() {
-        fn size_of_fn4_ret<F, R, A, B, C, D>(_f: F) -> usize
+        fn size_of_fn6_ret<F, R, A, B, C, D, E, G>(_f: F) -> usize
         where
-            F: FnOnce(A, B, C, D) -> R,
+            F: FnOnce(A, B, C, D, E, G) -> R,
         {
             std::mem::size_of::<F::Output>()
         }
-        let update_ret_size = size_of_fn4_ret(update::<AutoRepository>);
+        let update_ret_size = size_of_fn6_ret(update::<AutoRepository>);
         assert!(update_ret_size < 256, "update_ret_size = {} < 128", update_ret_size);
     }
+
+    #[test]
+    fn replayed_current() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let dir = crate::tests::tmp_dir("updater_replayed_current");
+        let mut repository = Repository::new(dir.join("repository"));
+        fs::create_dir(repository.dir()).unwrap();
+        repository.init().unwrap();
+        let key = crate::signature::SigningKey::generate().unwrap();
+        let public_key = key.public_key();
+        repository.set_signing_key(Some(key));
+        for revision in ["v1", "v2"] {
+            let revision = metadata::CleanName::from_static_str(revision);
+            let version = metadata::v1::Version { revision, description: String::new() };
+            repository.register_version(&version).unwrap();
+        }
+        let current = repository.dir().join(metadata::Current::filename());
+        let signature = repository.dir().join(signature_filename(metadata::Current::filename()));
+        repository.set_current_version(&metadata::CleanName::from_static_str("v1")).unwrap();
+        let v1 = (fs::read(&current).unwrap(), fs::read(&signature).unwrap());
+        repository.set_current_version(&metadata::CleanName::from_static_str("v2")).unwrap();
+
+        let link = repository.link();
+        let mut workspace = Workspace::open(&dir.join("workspace")).unwrap();
+        workspace.file_manager().create_update_dirs().unwrap();
+        let (version, signed_at) = rt.block_on(current_version(&link, Some(&public_key))).unwrap();
+        assert_eq!(version.as_str(), "v2");
+        workspace.set_current_signed_at(signed_at.unwrap()).unwrap();
+
+        // the workspace refuses the outdated `current`, even correctly signed
+        fs::write(&current, v1.0).unwrap();
+        fs::write(&signature, v1.1).unwrap();
+        let (version, signed_at) = rt.block_on(current_version(&link, Some(&public_key))).unwrap();
+        assert_eq!(version.as_str(), "v1");
+        assert!(matches!(
+            check_replay(&workspace, signed_at),
+            Err(UpdateError::Repository(RepositoryError::Signature {
+                err: SignatureError::Replayed,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn cancel_and_resume() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let dir = crate::tests::tmp_dir("updater_cancel_and_resume");
+        let source_dir = dir.join("source");
+        fs::create_dir(&source_dir).unwrap();
+        let files: Vec<_> = (0..16u8).map(|i| (format!("{}.bin", i), vec![i; 256 << 10])).collect();
+        for (name, data) in &files {
+            fs::write(source_dir.join(name), data).unwrap();
+        }
+        let v1 = metadata::CleanName::from_static_str("v1");
+        let mut repository = Repository::new(dir.join("repository"));
+        fs::create_dir(repository.dir()).unwrap();
+        repository.init().unwrap();
+        let build_dir = dir.join("build");
+        let builder = PackageBuilder::new(build_dir.clone(), v1.clone(), source_dir);
+        rt.block_on(builder.build().try_for_each(|_| async { Ok(()) })).unwrap();
+        for name in &[builder.package_data_name(), builder.package_metadata_name()] {
+            fs::rename(build_dir.join(&**name), repository.dir().join(&**name)).unwrap();
+        }
+        repository.register_package(&builder.package_metadata_name()).unwrap();
+        let version = metadata::v1::Version { revision: v1.clone(), description: String::new() };
+        repository.register_version(&version).unwrap();
+
+        let link = repository.link();
+        let workspace_dir = dir.join("workspace");
+        let mut workspace = Workspace::open(&workspace_dir).unwrap();
+        let update_options =
+            UpdateOptions { download_concurrency: 1, apply_concurrency: 1, ..Default::default() };
+        rt.block_on(async {
+            let (mut stream, handle) =
+                workspace.update_with_handle(&link, Some(v1.clone()), update_options.clone());
+            while let Some(res) = stream.next().await {
+                if res.unwrap().borrow().histogram.progress().applied_files > 0 {
+                    break;
+                }
+            }
+            handle.pause();
+            handle.cancel();
+            assert!(matches!(stream.next().await, Some(Err(UpdateError::Cancelled))));
+
+            // the state is saved before the stream ends
+            let state = Workspace::open(&workspace_dir).unwrap().state().clone();
+            assert!(matches!(state, State::Updating(state) if state.is_applied(0)));
+            // files are either missing or completely written
+            for entry in fs::read_dir(&workspace_dir).unwrap() {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                if name != ".update" {
+                    let (_, data) = files.iter().find(|(n, _)| n == &name).unwrap();
+                    assert_eq!(&fs::read(workspace_dir.join(&name)).unwrap(), data);
+                }
+            }
+            assert!(stream.next().await.is_none());
+        });
+
+        // the next update starts from the applied operations
+        let mut stream = workspace.update(&link, Some(v1.clone()), update_options);
+        let progress = rt.block_on(stream.next()).unwrap().unwrap();
+        assert!(progress.borrow().histogram.progress().applied_input_bytes > 0);
+        rt.block_on(stream.try_for_each(|_| async { Ok(()) })).unwrap();
+        assert!(matches!(workspace.state(), State::Stable { version } if version == &v1));
+        for (name, data) in &files {
+            assert_eq!(&fs::read(workspace_dir.join(name)).unwrap(), data);
+        }
+    }
 }